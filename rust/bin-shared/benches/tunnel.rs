@@ -7,9 +7,202 @@ fn main() -> Result<()> {
 
     let rt = tokio::runtime::Runtime::new()?;
     rt.block_on(platform::perf())?;
+    crypto::perf()?;
     Ok(())
 }
 
+/// Synthetic performance test
+///
+/// Encrypts batches of packets for many connections, first on a single thread and then on all cores.
+mod crypto {
+    use anyhow::{bail, Result};
+    use secrecy::Secret;
+    use snownet::{ClientNode, Credentials, Event, RelaySocket, ServerNode};
+    use std::{
+        collections::BTreeSet,
+        net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+        time::{Duration, Instant},
+    };
+
+    const NUM_CLIENTS: u8 = 16;
+    const NUM_BATCHES: usize = 500;
+    const PACKETS_PER_CLIENT: usize = 8;
+    const PACKET_LEN: usize = 1_200;
+    const SERVER_ID: u8 = 0;
+    const RELAY_ID: u8 = 0;
+    const SERVER_ADDR: SocketAddr =
+        SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 1), 52625));
+    const RELAY_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 0, 2), 3478);
+
+    pub(crate) fn perf() -> Result<()> {
+        let num_threads = std::thread::available_parallelism()?.get();
+
+        let single_threaded = encapsulate_batches(1)?;
+        let multi_threaded = encapsulate_batches(num_threads)?;
+
+        tracing::info!(
+            num_connections = %NUM_CLIENTS,
+            ?single_threaded,
+            ?multi_threaded,
+            %num_threads,
+            speedup = %format!("{:.2}x", single_threaded.as_secs_f64() / multi_threaded.as_secs_f64()),
+            "Encrypted {} packets",
+            NUM_BATCHES * PACKETS_PER_CLIENT * usize::from(NUM_CLIENTS)
+        );
+
+        Ok(())
+    }
+
+    /// Returns how long it took to encrypt all batches with `num_threads`.
+    fn encapsulate_batches(num_threads: usize) -> Result<Duration> {
+        let (mut server, _clients) = connect(Instant::now())?;
+        server.set_crypto_threads(num_threads)?;
+
+        let mut elapsed = Duration::ZERO;
+
+        for _ in 0..NUM_BATCHES {
+            let now = Instant::now();
+            let batch = (1..=NUM_CLIENTS)
+                .flat_map(|cid| (0..PACKETS_PER_CLIENT).map(move |_| (cid, packet(cid))))
+                .collect::<Vec<_>>();
+
+            let start = Instant::now();
+            let encrypted = server.encapsulate_batch(batch, now);
+            elapsed += start.elapsed();
+
+            if encrypted.iter().any(|r| !matches!(r, Ok(Some(_)))) {
+                bail!("Failed to encrypt packet");
+            }
+        }
+
+        Ok(elapsed)
+    }
+
+    /// Connects a server to [`NUM_CLIENTS`] clients, exchanging all packets in memory.
+    fn connect(
+        mut now: Instant,
+    ) -> Result<(ServerNode<u8, u8>, Vec<(SocketAddr, ClientNode<u8, u8>)>)> {
+        let relays = BTreeSet::from([(
+            RELAY_ID,
+            RelaySocket::V4(RELAY_ADDR),
+            "user".to_owned(),
+            "pass".to_owned(),
+            "firezone".to_owned(),
+        )]);
+
+        let mut server = ServerNode::<u8, u8>::new([0; 32], now);
+        server.update_relays(BTreeSet::new(), &relays, now);
+        server.add_local_host_candidate(SERVER_ADDR)?;
+
+        let mut clients = Vec::new();
+
+        for cid in 1..=NUM_CLIENTS {
+            let addr = SocketAddr::from((Ipv4Addr::new(10, 0, 1, cid), 52625));
+            let session_key = [cid; 32];
+
+            let mut client = ClientNode::<u8, u8>::new([cid; 32], now);
+            client.update_relays(BTreeSet::new(), &relays, now);
+            client.add_local_host_candidate(addr)?;
+
+            client.upsert_connection(
+                SERVER_ID,
+                server.public_key(),
+                Secret::new(session_key),
+                credentials("client", cid),
+                credentials("server", cid),
+                now,
+            )?;
+            server.upsert_connection(
+                cid,
+                client.public_key(),
+                Secret::new(session_key),
+                credentials("server", cid),
+                credentials("client", cid),
+                now,
+            )?;
+
+            clients.push((addr, client));
+        }
+
+        let mut num_established = 0;
+
+        for _ in 0..10_000 {
+            while let Some(event) = server.poll_event() {
+                match event {
+                    Event::NewIceCandidate {
+                        connection,
+                        candidate,
+                    } => {
+                        let (_, client) = &mut clients[usize::from(connection - 1)];
+                        client.add_remote_candidate(SERVER_ID, candidate, now);
+                    }
+                    Event::ConnectionEstablished(_) => num_established += 1,
+                    Event::InvalidateIceCandidate { .. } | Event::ConnectionMigrated(_) => {}
+                    Event::ConnectionFailed(cid) | Event::ConnectionClosed(cid) => {
+                        bail!("Connection to client {cid} failed")
+                    }
+                }
+            }
+
+            if num_established == NUM_CLIENTS {
+                return Ok((server, clients));
+            }
+
+            for (cid, (_, client)) in (1..).zip(clients.iter_mut()) {
+                while let Some(event) = client.poll_event() {
+                    if let Event::NewIceCandidate { candidate, .. } = event {
+                        server.add_remote_candidate(cid, candidate, now);
+                    }
+                }
+            }
+
+            while let Some(transmit) = server.poll_transmit() {
+                let Some((_, client)) = clients.iter_mut().find(|(a, _)| *a == transmit.dst) else {
+                    continue; // Drop packets to the relay.
+                };
+
+                let _ = client.decapsulate(transmit.dst, SERVER_ADDR, &transmit.payload, now);
+            }
+
+            for (addr, client) in clients.iter_mut() {
+                while let Some(transmit) = client.poll_transmit() {
+                    if transmit.dst != SERVER_ADDR {
+                        continue; // Drop packets to the relay.
+                    }
+
+                    let _ = server.decapsulate(SERVER_ADDR, *addr, &transmit.payload, now);
+                }
+            }
+
+            now += Duration::from_millis(10);
+            server.handle_timeout(now);
+            for (_, client) in clients.iter_mut() {
+                client.handle_timeout(now);
+            }
+        }
+
+        bail!("Only {num_established} of {NUM_CLIENTS} connections were established")
+    }
+
+    fn credentials(role: &str, cid: u8) -> Credentials {
+        Credentials {
+            username: format!("{role}{cid}"),
+            password: format!("{role}-password-{cid}"),
+        }
+    }
+
+    fn packet(cid: u8) -> ip_packet::IpPacket {
+        ip_packet::make::udp_packet(
+            Ipv4Addr::new(10, 0, 2, 1),
+            Ipv4Addr::new(100, 64, 0, cid),
+            443,
+            50000,
+            vec![0; PACKET_LEN],
+        )
+        .unwrap()
+    }
+}
+
 #[cfg(not(target_os = "windows"))]
 mod platform {
     #[expect(
@@ -50,7 +243,7 @@ mod platform {
 
         let ipv4 = Ipv4Addr::from([100, 90, 215, 97]);
         let ipv6 = Ipv6Addr::from([0xfd00, 0x2021, 0x1111, 0x0, 0x0, 0x0, 0x0016, 0x588f]);
-        let mut device_manager = TunDeviceManager::new(MTU, NUM_THREADS)?;
+        let mut device_manager = TunDeviceManager::new(MTU, NUM_THREADS, Default::default())?;
         let mut tun = device_manager.make_tun()?;
 
         device_manager.set_ips(ipv4, ipv6).await?;
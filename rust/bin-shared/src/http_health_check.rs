@@ -1,16 +1,37 @@
-use axum::http::StatusCode;
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
 use axum::routing::get;
 use axum::Router;
 use std::net::SocketAddr;
 
+mod prometheus;
+
+pub use prometheus::Metrics;
+
 /// Runs an HTTP server that responds to `GET /healthz` with 200 OK or 400 BAD REQUEST, depending on the return value of `is_healthy`.
+///
+/// `GET /metrics` responds with all metrics collected by `metrics`, in the Prometheus text format.
 pub async fn serve(
     addr: impl Into<SocketAddr>,
     is_healthy: impl Fn() -> bool + Clone + Send + Sync + 'static,
+    metrics: Metrics,
 ) -> std::io::Result<()> {
     let addr = addr.into();
 
-    let service = Router::new()
+    axum::serve(
+        tokio::net::TcpListener::bind(addr).await?,
+        router(is_healthy, metrics).into_make_service(),
+    )
+    .await?;
+
+    Ok(())
+}
+
+fn router(
+    is_healthy: impl Fn() -> bool + Clone + Send + Sync + 'static,
+    metrics: Metrics,
+) -> Router {
+    Router::new()
         .route(
             "/healthz",
             get(move || async move {
@@ -21,11 +42,43 @@ pub async fn serve(
                 }
             }),
         )
-        .into_make_service();
+        .route(
+            "/metrics",
+            get(move || {
+                let metrics = metrics.clone();
 
-    axum::serve(tokio::net::TcpListener::bind(addr).await?, service).await?;
+                async move { encode_metrics(&metrics) }
+            }),
+        )
+}
 
-    Ok(())
+/// Installs a global [`opentelemetry`] meter provider whose metrics are collected by the returned [`Metrics`].
+///
+/// Pass the returned [`Metrics`] to [`serve`] to expose them on `/metrics`.
+pub fn install_prometheus_meter_provider() -> Metrics {
+    let (provider, metrics) = Metrics::new_meter_provider();
+
+    opentelemetry::global::set_meter_provider(provider);
+
+    metrics
+}
+
+fn encode_metrics(metrics: &Metrics) -> impl IntoResponse {
+    let body = match metrics.encode() {
+        Ok(body) => body,
+        Err(e) => {
+            tracing::debug!("Failed to collect metrics: {e}");
+
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    (
+        StatusCode::OK,
+        [(header::CONTENT_TYPE, prometheus::CONTENT_TYPE)],
+        body,
+    )
+        .into_response()
 }
 
 #[derive(clap::Args, Debug, Clone)]
@@ -33,6 +86,44 @@ pub struct HealthCheckArgs {
     /// The address of the local interface where we should serve our health-check endpoint.
     ///
     /// The actual health-check endpoint will be at `http://<health_check_addr>/healthz`.
+    /// Metrics are served in the Prometheus format at `http://<health_check_addr>/metrics`.
     #[arg(long, env, hide = true, default_value = "0.0.0.0:8080")]
     pub health_check_addr: SocketAddr,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::metrics::MeterProvider as _;
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    #[tokio::test]
+    async fn serves_metrics_in_prometheus_format() {
+        let (provider, metrics) = Metrics::new_meter_provider();
+        provider
+            .meter("test")
+            .u64_counter("data_sent_bytes")
+            .with_description("The number of bytes sent")
+            .init()
+            .add(1200, &[]);
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            axum::serve(listener, router(|| true, metrics).into_make_service()).await
+        });
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("content-type: text/plain; version=0.0.4"));
+        assert!(response.contains("# TYPE data_sent_bytes counter\n"));
+        assert!(response.contains("data_sent_bytes 1200\n"));
+    }
+}
@@ -0,0 +1,223 @@
+//! Renders the metrics of an [`opentelemetry_sdk`] meter provider in the Prometheus text format.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Weak};
+
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::metrics::data::{Gauge, Metric, ResourceMetrics, Sum, Temporality};
+use opentelemetry_sdk::metrics::reader::{MetricReader, TemporalitySelector};
+use opentelemetry_sdk::metrics::{InstrumentKind, ManualReader, Pipeline, SdkMeterProvider};
+use opentelemetry_sdk::Resource;
+
+pub(crate) const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Collects the metrics of a meter provider on demand, see [`Metrics::new_meter_provider`].
+///
+/// The default instance has no meter provider and thus no metrics.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    reader: Option<Arc<ManualReader>>,
+}
+
+impl Metrics {
+    /// Creates a meter provider whose metrics are collected by the returned [`Metrics`].
+    pub fn new_meter_provider() -> (SdkMeterProvider, Self) {
+        let reader = Arc::new(ManualReader::builder().build());
+
+        let provider = SdkMeterProvider::builder()
+            .with_reader(SharedReader(reader.clone()))
+            .build();
+
+        (
+            provider,
+            Self {
+                reader: Some(reader),
+            },
+        )
+    }
+
+    /// Collects all metrics and renders them in the Prometheus text format.
+    pub(crate) fn encode(&self) -> opentelemetry::metrics::Result<String> {
+        let Some(reader) = self.reader.as_ref() else {
+            return Ok(String::new());
+        };
+
+        let mut metrics = ResourceMetrics {
+            resource: Resource::empty(),
+            scope_metrics: Vec::new(),
+        };
+        reader.collect(&mut metrics)?;
+
+        let mut out = String::new();
+        let mut seen = HashSet::new();
+
+        for metric in metrics.scope_metrics.iter().flat_map(|s| s.metrics.iter()) {
+            encode_metric(metric, &mut seen, &mut out);
+        }
+
+        Ok(out)
+    }
+}
+
+/// Lets us register the [`ManualReader`] with the meter provider while still being able to collect from it.
+#[derive(Debug)]
+struct SharedReader(Arc<ManualReader>);
+
+impl TemporalitySelector for SharedReader {
+    fn temporality(&self, kind: InstrumentKind) -> Temporality {
+        self.0.temporality(kind)
+    }
+}
+
+impl MetricReader for SharedReader {
+    fn register_pipeline(&self, pipeline: Weak<Pipeline>) {
+        self.0.register_pipeline(pipeline)
+    }
+
+    fn collect(&self, rm: &mut ResourceMetrics) -> opentelemetry::metrics::Result<()> {
+        self.0.collect(rm)
+    }
+
+    fn force_flush(&self) -> opentelemetry::metrics::Result<()> {
+        self.0.force_flush()
+    }
+
+    fn shutdown(&self) -> opentelemetry::metrics::Result<()> {
+        self.0.shutdown()
+    }
+}
+
+fn encode_metric(metric: &Metric, seen: &mut HashSet<String>, out: &mut String) {
+    let data = metric.data.as_any();
+
+    let (kind, points) = if let Some(sum) = data.downcast_ref::<Sum<u64>>() {
+        (sum_kind(sum.is_monotonic), points(&sum.data_points))
+    } else if let Some(sum) = data.downcast_ref::<Sum<i64>>() {
+        (sum_kind(sum.is_monotonic), points(&sum.data_points))
+    } else if let Some(sum) = data.downcast_ref::<Sum<f64>>() {
+        (sum_kind(sum.is_monotonic), points(&sum.data_points))
+    } else if let Some(gauge) = data.downcast_ref::<Gauge<u64>>() {
+        ("gauge", points(&gauge.data_points))
+    } else if let Some(gauge) = data.downcast_ref::<Gauge<i64>>() {
+        ("gauge", points(&gauge.data_points))
+    } else if let Some(gauge) = data.downcast_ref::<Gauge<f64>>() {
+        ("gauge", points(&gauge.data_points))
+    } else {
+        tracing::debug!(name = %metric.name, "Skipping metric of unsupported type");
+        return;
+    };
+
+    let name = sanitize_name(&metric.name);
+
+    if seen.insert(name.clone()) {
+        out.push_str(&format!(
+            "# HELP {name} {}\n# TYPE {name} {kind}\n",
+            escape_help(&metric.description)
+        ));
+    }
+
+    for (attributes, value) in points {
+        out.push_str(&name);
+
+        if !attributes.is_empty() {
+            out.push('{');
+            for (i, kv) in attributes.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                out.push_str(&format!(
+                    "{}=\"{}\"",
+                    sanitize_name(kv.key.as_str()),
+                    escape_label_value(&kv.value.to_string())
+                ));
+            }
+            out.push('}');
+        }
+
+        out.push_str(&format!(" {value}\n"));
+    }
+}
+
+fn sum_kind(is_monotonic: bool) -> &'static str {
+    if is_monotonic {
+        "counter"
+    } else {
+        "gauge"
+    }
+}
+
+fn points<T: ToString>(
+    points: &[opentelemetry_sdk::metrics::data::DataPoint<T>],
+) -> Vec<(&[KeyValue], String)> {
+    points
+        .iter()
+        .map(|p| (p.attributes.as_slice(), p.value.to_string()))
+        .collect()
+}
+
+/// Replaces all characters that aren't allowed in Prometheus metric and label names.
+fn sanitize_name(name: &str) -> String {
+    name.chars()
+        .enumerate()
+        .map(|(i, c)| {
+            if c.is_ascii_alphabetic() || c == '_' || c == ':' || (i > 0 && c.is_ascii_digit()) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+fn escape_help(help: &str) -> String {
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn escape_label_value(value: &str) -> String {
+    escape_help(value).replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry::metrics::MeterProvider as _;
+
+    #[test]
+    fn encodes_counters_and_gauges() {
+        let (provider, metrics) = Metrics::new_meter_provider();
+        let meter = provider.meter("test");
+
+        let counter = meter
+            .u64_counter("requests_total")
+            .with_description("The number of requests")
+            .init();
+        counter.add(3, &[KeyValue::new("path", "/a\"b")]);
+        counter.add(1, &[KeyValue::new("path", "/c")]);
+        meter
+            .i64_up_down_counter("sessions")
+            .with_description("Active\nsessions")
+            .init()
+            .add(-2, &[]);
+
+        let text = metrics.encode().unwrap();
+
+        assert!(text.contains("# HELP requests_total The number of requests\n"));
+        assert!(text.contains("# TYPE requests_total counter\n"));
+        assert!(text.contains("requests_total{path=\"/a\\\"b\"} 3\n"));
+        assert!(text.contains("requests_total{path=\"/c\"} 1\n"));
+        assert!(text.contains("# HELP sessions Active\\nsessions\n"));
+        assert!(text.contains("# TYPE sessions gauge\n"));
+        assert!(text.contains("sessions -2\n"));
+    }
+
+    #[test]
+    fn default_has_no_metrics() {
+        assert_eq!(Metrics::default().encode().unwrap(), "");
+    }
+
+    #[test]
+    fn sanitizes_names() {
+        assert_eq!(sanitize_name("data.sent-bytes"), "data_sent_bytes");
+        assert_eq!(sanitize_name("1st"), "_st");
+    }
+}
@@ -1,6 +1,9 @@
 #![cfg_attr(test, allow(clippy::unwrap_used))]
 
 pub mod http_health_check;
+pub mod log_rotation;
+pub mod packet_capture;
+pub mod proxy;
 
 mod network_changes;
 mod tun_device_manager;
@@ -44,4 +47,4 @@ pub const FIREZONE_MARK: u32 = 0xfd002021;
 pub use network_changes::{new_dns_notifier, new_network_notifier};
 
 #[cfg(any(target_os = "linux", target_os = "windows"))]
-pub use tun_device_manager::TunDeviceManager;
+pub use tun_device_manager::{TunDeviceConfig, TunDeviceManager};
@@ -29,13 +29,21 @@ impl Default for DnsControlMethod {
 }
 
 pub fn tcp_socket_factory(socket_addr: &SocketAddr) -> io::Result<TcpSocket> {
+    tcp_socket_with_mark(socket_addr, FIREZONE_MARK)
+}
+
+pub fn udp_socket_factory(socket_addr: &SocketAddr) -> io::Result<UdpSocket> {
+    udp_socket_with_mark(socket_addr, FIREZONE_MARK)
+}
+
+pub(crate) fn tcp_socket_with_mark(socket_addr: &SocketAddr, mark: u32) -> io::Result<TcpSocket> {
     let socket = socket_factory::tcp(socket_addr)?;
-    setsockopt(&socket, sockopt::Mark, &FIREZONE_MARK)?;
+    setsockopt(&socket, sockopt::Mark, &mark)?;
     Ok(socket)
 }
 
-pub fn udp_socket_factory(socket_addr: &SocketAddr) -> io::Result<UdpSocket> {
+pub(crate) fn udp_socket_with_mark(socket_addr: &SocketAddr, mark: u32) -> io::Result<UdpSocket> {
     let socket = socket_factory::udp(socket_addr)?;
-    setsockopt(&socket, sockopt::Mark, &FIREZONE_MARK)?;
+    setsockopt(&socket, sockopt::Mark, &mark)?;
     Ok(socket)
 }
@@ -0,0 +1,70 @@
+use firezone_logging::file::Rotation;
+
+/// CLI args to opt into rotating and pruning log files.
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct LogRotationArgs {
+    /// Start a new log file once the current one reaches this size, e.g. `100M`.
+    ///
+    /// Accepts the suffixes `K`, `M` and `G`.
+    #[arg(long, env = "FIREZONE_LOG_MAX_FILE_SIZE", value_parser = parse_size)]
+    pub log_max_file_size: Option<u64>,
+
+    /// Start a new log file once the current one is this old, e.g. `1d`.
+    #[arg(long, env = "FIREZONE_LOG_MAX_FILE_AGE")]
+    pub log_max_file_age: Option<humantime::Duration>,
+
+    /// Delete the oldest log files so that at most this many remain, including the current one.
+    #[arg(long, env = "FIREZONE_LOG_MAX_FILES", value_parser = clap::value_parser!(u16).range(1..))]
+    pub log_max_files: Option<u16>,
+
+    /// Gzip log files once they have been rotated.
+    #[arg(long, env = "FIREZONE_LOG_COMPRESS", default_value_t = false)]
+    pub log_compress: bool,
+}
+
+impl LogRotationArgs {
+    pub fn rotation(&self) -> Rotation {
+        Rotation {
+            max_file_size: self.log_max_file_size,
+            max_file_age: self.log_max_file_age.map(Into::into),
+            max_files: self.log_max_files.map(usize::from),
+            compress: self.log_compress,
+        }
+    }
+}
+
+/// Parses a size in bytes with an optional `K`, `M` or `G` suffix, e.g. `100M`.
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let (digits, multiplier) = if let Some(n) = s.strip_suffix(['K', 'k']) {
+        (n, 1024)
+    } else if let Some(n) = s.strip_suffix(['M', 'm']) {
+        (n, 1024 * 1024)
+    } else if let Some(n) = s.strip_suffix(['G', 'g']) {
+        (n, 1024 * 1024 * 1024)
+    } else {
+        (s, 1)
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .filter(|n| *n > 0)
+        .ok_or_else(|| format!("`{s}` is not a valid size, try e.g. `100M`"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("10K"), Ok(10 * 1024));
+        assert_eq!(parse_size("100M"), Ok(100 * 1024 * 1024));
+        assert_eq!(parse_size("2g"), Ok(2 * 1024 * 1024 * 1024));
+        assert!(parse_size("0").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("1T").is_err());
+    }
+}
@@ -1,10 +1,42 @@
-//! Not implemented for Linux yet
+//! Listens for DNS changes via D-Bus and for network changes via rtnetlink
 
 use crate::platform::DnsControlMethod;
-use anyhow::Result;
-use futures::StreamExt as _;
-use std::time::Duration;
-use tokio::time::{Interval, MissedTickBehavior};
+use anyhow::{Context as _, Result};
+use futures::{StreamExt as _, TryStreamExt as _};
+use netlink_packet_core::{NetlinkMessage, NetlinkPayload};
+use netlink_packet_route::{
+    address::{AddressAttribute, AddressMessage},
+    link::{InfoKind, LinkAttribute, LinkInfo, LinkMessage, State},
+    route::{RouteAddress, RouteAttribute, RouteMessage},
+    RouteNetlinkMessage,
+};
+use rtnetlink::{
+    constants::{
+        RTMGRP_IPV4_IFADDR, RTMGRP_IPV4_ROUTE, RTMGRP_IPV6_IFADDR, RTMGRP_IPV6_ROUTE, RTMGRP_LINK,
+    },
+    sys::{AsyncSocket as _, SocketAddr},
+    IpVersion,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::IpAddr,
+    time::Duration,
+};
+use tokio::{
+    sync::mpsc,
+    task::AbortHandle,
+    time::{Instant, Interval, MissedTickBehavior},
+};
+
+/// How long the network must be quiet before we notify.
+///
+/// Bringing up an interface causes a burst of link, address and route events, we only want to reset once for all of them.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// The routing table that `ip route` shows by default.
+///
+/// Routes in other tables, e.g. the kernel's `local` table or Firezone's own, don't tell us anything about the network.
+const RT_TABLE_MAIN: u32 = 254;
 
 /// Parameters to tell `zbus` how to listen for a signal.
 struct SignalParams {
@@ -48,28 +80,15 @@ pub async fn new_dns_notifier(
     }
 }
 
-/// Listens for changes between Wi-Fi networks
+/// Listens for changes between Wi-Fi networks, cables being plugged in, etc.
 ///
-/// Should be similar to `dbus-monitor --system "type='signal',interface='org.freedesktop.NetworkManager',member='StateChanged'"`
+/// Should be similar to `ip monitor link address route`, minus changes to TUN devices and routes outside the main table.
+/// Unlike NetworkManager's D-Bus signals, this works on any system, no matter which DNS control method is used.
 pub async fn new_network_notifier(
     _tokio_handle: tokio::runtime::Handle,
-    method: DnsControlMethod,
+    _method: DnsControlMethod,
 ) -> Result<Worker> {
-    match method {
-        DnsControlMethod::Disabled | DnsControlMethod::EtcResolvConf => Ok(Worker {
-            just_started: true,
-            inner: Inner::Null,
-        }),
-        DnsControlMethod::SystemdResolved => {
-            Worker::new_dbus(SignalParams {
-                dest: "org.freedesktop.NetworkManager",
-                path: "/org/freedesktop/NetworkManager",
-                interface: "org.freedesktop.NetworkManager",
-                member: "StateChanged",
-            })
-            .await
-        }
-    }
+    Worker::new_netlink().await
 }
 
 pub struct Worker {
@@ -80,7 +99,20 @@ pub struct Worker {
 enum Inner {
     DBus(zbus::proxy::SignalStream<'static>),
     DnsPoller(Interval),
-    Null,
+    Netlink(Netlink),
+}
+
+struct Netlink {
+    rx: mpsc::Receiver<()>,
+    connection: AbortHandle,
+    listener: AbortHandle,
+}
+
+impl Drop for Netlink {
+    fn drop(&mut self) {
+        self.connection.abort();
+        self.listener.abort();
+    }
 }
 
 impl Worker {
@@ -110,6 +142,61 @@ impl Worker {
         })
     }
 
+    async fn new_netlink() -> Result<Self> {
+        let (mut connection, handle, messages) =
+            rtnetlink::new_connection().context("Failed to create netlink connection")?;
+        connection
+            .socket_mut()
+            .socket_mut()
+            .bind(&SocketAddr::new(
+                0,
+                RTMGRP_LINK
+                    | RTMGRP_IPV4_IFADDR
+                    | RTMGRP_IPV6_IFADDR
+                    | RTMGRP_IPV4_ROUTE
+                    | RTMGRP_IPV6_ROUTE,
+            ))
+            .context("Failed to subscribe to netlink events")?;
+        let connection = tokio::spawn(connection).abort_handle();
+
+        // We subscribed before dumping the current state, so we can't miss any TUN devices created in between.
+        let mut links = Links::default();
+        let mut existing = handle.link().get().execute();
+        while let Some(link) = existing
+            .try_next()
+            .await
+            .context("Failed to list network interfaces")?
+        {
+            links.is_relevant(&RouteNetlinkMessage::NewLink(link));
+        }
+        let mut existing = handle.address().get().execute();
+        while let Some(address) = existing
+            .try_next()
+            .await
+            .context("Failed to list IP addresses")?
+        {
+            links.is_relevant(&RouteNetlinkMessage::NewAddress(address));
+        }
+        for ip_version in [IpVersion::V4, IpVersion::V6] {
+            let mut existing = handle.route().get(ip_version).execute();
+            while let Some(route) = existing.try_next().await.context("Failed to list routes")? {
+                links.is_relevant(&RouteNetlinkMessage::NewRoute(route));
+            }
+        }
+
+        let (tx, rx) = mpsc::channel(1);
+        let listener = tokio::spawn(listen_netlink(messages, links, tx)).abort_handle();
+
+        Ok(Self {
+            just_started: true,
+            inner: Inner::Netlink(Netlink {
+                rx,
+                connection,
+                listener,
+            }),
+        })
+    }
+
     // Needed to match Windows
     pub fn close(self) -> Result<()> {
         Ok(())
@@ -130,8 +217,418 @@ impl Worker {
                 }
                 tracing::debug!("DBus notified us");
             }
-            Inner::Null => futures::future::pending::<()>().await,
+            Inner::Netlink(netlink) => {
+                if netlink.rx.recv().await.is_none() {
+                    tracing::warn!("Netlink listener stopped");
+                    futures::future::pending::<()>().await;
+                }
+                tracing::debug!("Netlink notified us");
+            }
         }
         Ok(())
     }
 }
+
+async fn listen_netlink(
+    mut messages: futures::channel::mpsc::UnboundedReceiver<(
+        NetlinkMessage<RouteNetlinkMessage>,
+        SocketAddr,
+    )>,
+    mut links: Links,
+    tx: mpsc::Sender<()>,
+) {
+    let mut deadline = None;
+    let mut notified = links.links.clone();
+
+    loop {
+        let message = match deadline {
+            Some(until) => {
+                match tokio::time::timeout_at(until, messages.next()).await {
+                    Ok(message) => message,
+                    Err(_) => {
+                        deadline = None;
+
+                        // E.g. an address that was removed and re-added within the debounce period.
+                        if links.links == notified {
+                            tracing::trace!("Network changed back to what we last notified about");
+                            continue;
+                        }
+                        notified = links.links.clone();
+
+                        // If the channel is full, a notification is already pending.
+                        let _ = tx.try_send(());
+                        continue;
+                    }
+                }
+            }
+            None => messages.next().await,
+        };
+        let Some((message, _)) = message else {
+            return;
+        };
+        let NetlinkPayload::InnerMessage(message) = message.payload else {
+            continue;
+        };
+
+        if links.is_relevant(&message) {
+            tracing::trace!(?message, "Network changed");
+            deadline = Some(Instant::now() + DEBOUNCE);
+        }
+    }
+}
+
+/// What we know about the network interfaces, to tell apart relevant changes.
+#[derive(Default)]
+struct Links {
+    /// Interfaces created by us or other VPNs.
+    ///
+    /// Changes to these are caused by the tunnel itself, resetting because of them would loop forever.
+    tun: BTreeSet<u32>,
+    /// The state of every other interface that matters for connectivity.
+    ///
+    /// The kernel sends `RTM_NEWLINK`, `RTM_NEWADDR` and `RTM_NEWROUTE` for many things we don't care about, like wireless scans or address lifetime updates.
+    /// Comparing against this tells us whether anything actually changed.
+    links: BTreeMap<u32, Link>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Link {
+    oper_state: Option<State>,
+    addresses: BTreeSet<IpAddr>,
+    /// Gateways of the default routes in the main table via this interface, `None` for on-link default routes.
+    default_routes: BTreeSet<Option<IpAddr>>,
+}
+
+impl Links {
+    /// Returns whether the message changed anything that may affect connectivity.
+    #[expect(
+        clippy::wildcard_enum_match_arm,
+        reason = "We only subscribed to link, address and route events"
+    )]
+    fn is_relevant(&mut self, message: &RouteNetlinkMessage) -> bool {
+        match message {
+            RouteNetlinkMessage::NewLink(link) => self.on_new_link(link),
+            RouteNetlinkMessage::DelLink(link) => {
+                let index = link.header.index;
+                self.tun.remove(&index);
+
+                self.links.remove(&index).is_some()
+            }
+            RouteNetlinkMessage::NewAddress(address) => {
+                self.relevant_address(address).is_some_and(|(index, ip)| {
+                    self.links.entry(index).or_default().addresses.insert(ip)
+                })
+            }
+            RouteNetlinkMessage::DelAddress(address) => {
+                self.relevant_address(address).is_some_and(|(index, ip)| {
+                    self.links
+                        .get_mut(&index)
+                        .is_some_and(|link| link.addresses.remove(&ip))
+                })
+            }
+            RouteNetlinkMessage::NewRoute(route) => {
+                self.relevant_default_route(route)
+                    .is_some_and(|(index, gateway)| {
+                        self.links
+                            .entry(index)
+                            .or_default()
+                            .default_routes
+                            .insert(gateway)
+                    })
+            }
+            RouteNetlinkMessage::DelRoute(route) => {
+                self.relevant_default_route(route)
+                    .is_some_and(|(index, gateway)| {
+                        self.links
+                            .get_mut(&index)
+                            .is_some_and(|link| link.default_routes.remove(&gateway))
+                    })
+            }
+            _ => false,
+        }
+    }
+
+    fn on_new_link(&mut self, link: &LinkMessage) -> bool {
+        let index = link.header.index;
+
+        if is_tun(link) {
+            self.tun.insert(index);
+            self.links.remove(&index);
+            return false;
+        }
+
+        let Some(state) = link.attributes.iter().find_map(|a| {
+            if let LinkAttribute::OperState(state) = a {
+                Some(*state)
+            } else {
+                None
+            }
+        }) else {
+            return false;
+        };
+
+        self.links
+            .entry(index)
+            .or_default()
+            .oper_state
+            .replace(state)
+            != Some(state)
+    }
+
+    /// Returns the interface and IP of an address that matters for connectivity.
+    fn relevant_address(&self, address: &AddressMessage) -> Option<(u32, IpAddr)> {
+        let index = address.header.index;
+
+        if self.tun.contains(&index) {
+            return None;
+        }
+
+        let ip = address.attributes.iter().find_map(|a| {
+            if let AddressAttribute::Address(ip) = a {
+                Some(*ip)
+            } else {
+                None
+            }
+        })?;
+
+        if is_link_local(ip) {
+            return None;
+        }
+
+        Some((index, ip))
+    }
+
+    /// Returns the interface and gateway of a default route in the main table.
+    fn relevant_default_route(&self, route: &RouteMessage) -> Option<(u32, Option<IpAddr>)> {
+        if route.header.destination_prefix_length != 0 {
+            return None;
+        }
+
+        let mut table = u32::from(route.header.table);
+        let mut oif = None;
+        let mut gateway = None;
+
+        for attribute in &route.attributes {
+            if let RouteAttribute::Table(t) = attribute {
+                table = *t;
+            }
+            if let RouteAttribute::Oif(index) = attribute {
+                oif = Some(*index);
+            }
+            if let RouteAttribute::Gateway(address) = attribute {
+                gateway = ip_from_route_address(address);
+            }
+        }
+
+        let oif = oif?;
+
+        if table != RT_TABLE_MAIN || self.tun.contains(&oif) {
+            return None;
+        }
+
+        Some((oif, gateway))
+    }
+}
+
+fn ip_from_route_address(address: &RouteAddress) -> Option<IpAddr> {
+    if let RouteAddress::Inet(ip) = address {
+        return Some(IpAddr::V4(*ip));
+    }
+    if let RouteAddress::Inet6(ip) = address {
+        return Some(IpAddr::V6(*ip));
+    }
+
+    None
+}
+
+fn is_tun(link: &LinkMessage) -> bool {
+    link.attributes.iter().any(|a| {
+        let LinkAttribute::LinkInfo(infos) = a else {
+            return false;
+        };
+
+        infos
+            .iter()
+            .any(|info| matches!(info, LinkInfo::Kind(InfoKind::Tun)))
+    })
+}
+
+/// Link-local IPv6 addresses come and go with their interface, the link event already covers that.
+fn is_link_local(ip: IpAddr) -> bool {
+    matches!(ip, IpAddr::V6(ip) if (ip.segments()[0] & 0xffc0) == 0xfe80)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    fn link(index: u32, attributes: Vec<LinkAttribute>) -> LinkMessage {
+        let mut link = LinkMessage::default();
+        link.header.index = index;
+        link.attributes = attributes;
+        link
+    }
+
+    fn tun(index: u32) -> LinkMessage {
+        link(
+            index,
+            vec![
+                LinkAttribute::LinkInfo(vec![LinkInfo::Kind(InfoKind::Tun)]),
+                LinkAttribute::OperState(State::Unknown),
+            ],
+        )
+    }
+
+    fn route(table: u32, oif: u32) -> RouteMessage {
+        let mut route = RouteMessage::default();
+        route.header.table = 252; // RT_TABLE_COMPAT, the kernel uses the attribute for tables above 255
+        route.attributes = vec![RouteAttribute::Table(table), RouteAttribute::Oif(oif)];
+        route
+    }
+
+    fn default_route(oif: u32, gateway: [u8; 4]) -> RouteMessage {
+        let mut route = route(RT_TABLE_MAIN, oif);
+        route
+            .attributes
+            .push(RouteAttribute::Gateway(RouteAddress::Inet(gateway.into())));
+        route
+    }
+
+    fn address(index: u32, ip: IpAddr) -> AddressMessage {
+        let mut address = AddressMessage::default();
+        address.header.index = index;
+        address.attributes = vec![AddressAttribute::Address(ip)];
+        address
+    }
+
+    #[test]
+    fn ignores_changes_to_tun_devices() {
+        let mut links = Links::default();
+
+        assert!(!links.is_relevant(&RouteNetlinkMessage::NewLink(tun(7))));
+        assert!(!links.is_relevant(&RouteNetlinkMessage::NewAddress(address(
+            7,
+            IpAddr::from([100, 64, 0, 1])
+        ))));
+        assert!(!links.is_relevant(&RouteNetlinkMessage::NewRoute(route(RT_TABLE_MAIN, 7))));
+        assert!(!links.is_relevant(&RouteNetlinkMessage::DelLink(tun(7))));
+    }
+
+    #[test]
+    fn only_oper_state_changes_of_links_are_relevant() {
+        let mut links = Links::default();
+        let up = || link(2, vec![LinkAttribute::OperState(State::Up)]);
+
+        assert!(links.is_relevant(&RouteNetlinkMessage::NewLink(up())));
+        assert!(!links.is_relevant(&RouteNetlinkMessage::NewLink(up())));
+        assert!(!links.is_relevant(&RouteNetlinkMessage::NewLink(link(2, vec![]))));
+        assert!(links.is_relevant(&RouteNetlinkMessage::NewLink(link(
+            2,
+            vec![LinkAttribute::OperState(State::Down)]
+        ))));
+    }
+
+    #[test]
+    fn only_routes_in_main_table_are_relevant() {
+        let mut links = Links::default();
+
+        assert!(links.is_relevant(&RouteNetlinkMessage::NewRoute(route(RT_TABLE_MAIN, 2))));
+        assert!(!links.is_relevant(&RouteNetlinkMessage::NewRoute(route(0x2021_fd00, 2))));
+        assert!(!links.is_relevant(&RouteNetlinkMessage::DelRoute(route(255, 2))));
+    }
+
+    #[test]
+    fn ignores_link_local_addresses() {
+        let mut links = Links::default();
+
+        assert!(links.is_relevant(&RouteNetlinkMessage::NewAddress(address(
+            2,
+            IpAddr::from([192, 168, 1, 10])
+        ))));
+        assert!(!links.is_relevant(&RouteNetlinkMessage::NewAddress(address(
+            2,
+            IpAddr::V6(Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1))
+        ))));
+    }
+
+    #[test]
+    fn repeated_address_and_route_events_are_not_relevant() {
+        let mut links = Links::default();
+        let ip = IpAddr::from([192, 168, 1, 10]);
+
+        assert!(links.is_relevant(&RouteNetlinkMessage::NewAddress(address(2, ip))));
+        assert!(!links.is_relevant(&RouteNetlinkMessage::NewAddress(address(2, ip))));
+        assert!(
+            links.is_relevant(&RouteNetlinkMessage::NewRoute(default_route(
+                2,
+                [192, 168, 1, 1]
+            )))
+        );
+        assert!(
+            !links.is_relevant(&RouteNetlinkMessage::NewRoute(default_route(
+                2,
+                [192, 168, 1, 1]
+            )))
+        );
+        assert!(
+            links.is_relevant(&RouteNetlinkMessage::NewRoute(default_route(
+                2,
+                [192, 168, 1, 254]
+            )))
+        );
+    }
+
+    #[test]
+    fn only_removing_known_addresses_and_routes_is_relevant() {
+        let mut links = Links::default();
+        let ip = IpAddr::from([192, 168, 1, 10]);
+
+        assert!(!links.is_relevant(&RouteNetlinkMessage::DelAddress(address(2, ip))));
+        assert!(
+            !links.is_relevant(&RouteNetlinkMessage::DelRoute(default_route(
+                2,
+                [192, 168, 1, 1]
+            )))
+        );
+        assert!(links.links.is_empty());
+
+        links.is_relevant(&RouteNetlinkMessage::NewAddress(address(2, ip)));
+        links.is_relevant(&RouteNetlinkMessage::NewRoute(default_route(
+            2,
+            [192, 168, 1, 1],
+        )));
+
+        assert!(links.is_relevant(&RouteNetlinkMessage::DelAddress(address(2, ip))));
+        assert!(
+            links.is_relevant(&RouteNetlinkMessage::DelRoute(default_route(
+                2,
+                [192, 168, 1, 1]
+            )))
+        );
+        assert_eq!(links.links[&2], Link::default());
+    }
+
+    #[test]
+    fn ignores_routes_other_than_default() {
+        let mut links = Links::default();
+        let mut subnet = route(RT_TABLE_MAIN, 2);
+        subnet.header.destination_prefix_length = 24;
+
+        assert!(!links.is_relevant(&RouteNetlinkMessage::NewRoute(subnet)));
+    }
+
+    #[test]
+    fn removing_link_forgets_its_state() {
+        let mut links = Links::default();
+
+        links.is_relevant(&RouteNetlinkMessage::NewAddress(address(
+            2,
+            IpAddr::from([192, 168, 1, 10]),
+        )));
+
+        assert!(links.is_relevant(&RouteNetlinkMessage::DelLink(link(2, vec![]))));
+        assert!(!links.is_relevant(&RouteNetlinkMessage::DelLink(link(2, vec![]))));
+        assert!(links.links.is_empty());
+    }
+}
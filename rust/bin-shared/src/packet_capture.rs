@@ -0,0 +1,56 @@
+//! Files for the packet captures of the Gateway and the Clients
+
+use anyhow::{Context as _, Result};
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Creates a new pcapng file in `dir`, named after the current time so we never overwrite an earlier capture.
+pub fn create_capture_file(dir: &Path) -> Result<(File, PathBuf)> {
+    std::fs::create_dir_all(dir)
+        .with_context(|| format!("Failed to create `{}`", dir.display()))?;
+
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let path = dir.join(format!("capture.{timestamp}.pcapng"));
+
+    let mut options = File::options();
+    options.write(true).create_new(true);
+
+    // Captures contain the unencrypted traffic of the tunnel, only the owner may read them.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt as _;
+
+        options.mode(0o600);
+    }
+
+    let file = options
+        .open(&path)
+        .with_context(|| format!("Failed to create `{}`", path.display()))?;
+
+    Ok((file, path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn only_owner_can_read_capture() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let dir = std::env::temp_dir().join(format!("firezone-capture-{}", std::process::id()));
+
+        let (file, _) = create_capture_file(&dir).unwrap();
+        let mode = file.metadata().unwrap().permissions().mode();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
@@ -11,7 +11,7 @@ pub mod windows;
 pub use windows as platform;
 
 #[cfg(any(target_os = "linux", target_os = "windows"))]
-pub use platform::TunDeviceManager;
+pub use platform::{TunDeviceConfig, TunDeviceManager};
 
 #[cfg(test)]
 #[cfg(any(target_os = "linux", target_os = "windows"))]
@@ -41,7 +41,7 @@ mod tests {
         let ipv4 = Ipv4Addr::from([100, 90, 215, 97]);
         let ipv6 = Ipv6Addr::from([0xfd00, 0x2021, 0x1111, 0x0, 0x0, 0x0, 0x0016, 0x588f]);
 
-        let mut device_manager = TunDeviceManager::new(1280, 1).unwrap();
+        let mut device_manager = TunDeviceManager::new(1280, 1, Default::default()).unwrap();
         let _tun = device_manager.make_tun().unwrap();
         device_manager.set_ips(ipv4, ipv6).await.unwrap();
 
@@ -71,7 +71,7 @@ mod tests {
         let ipv4 = Ipv4Addr::from([100, 90, 215, 97]);
         let ipv6 = Ipv6Addr::from([0xfd00, 0x2021, 0x1111, 0x0, 0x0, 0x0, 0x0016, 0x588f]);
 
-        let mut device_manager = TunDeviceManager::new(1280, 1).unwrap();
+        let mut device_manager = TunDeviceManager::new(1280, 1, Default::default()).unwrap();
         let _tun = device_manager.make_tun().unwrap();
         device_manager.set_ips(ipv4, ipv6).await.unwrap();
 
@@ -122,7 +122,7 @@ mod tests {
     /// Checks for regressions in issue #4765, un-initializing Wintun
     /// Redundant but harmless on Linux.
     fn tunnel_drop() {
-        let mut tun_device_manager = TunDeviceManager::new(1280, 1).unwrap();
+        let mut tun_device_manager = TunDeviceManager::new(1280, 1, Default::default()).unwrap();
 
         // Each cycle takes about half a second, so this will take a fair bit to run.
         for _ in 0..50 {
@@ -1,7 +1,9 @@
 //! Virtual network interface
 
+mod offload;
+
 use crate::FIREZONE_MARK;
-use anyhow::{anyhow, Context as _, Result};
+use anyhow::{anyhow, bail, Context as _, Result};
 use firezone_logging::err_with_src;
 use futures::{SinkExt, TryStreamExt};
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
@@ -11,10 +13,14 @@ use libc::{
     S_IFCHR,
 };
 use netlink_packet_route::route::{RouteProtocol, RouteScope};
-use netlink_packet_route::rule::RuleAction;
-use rtnetlink::{new_connection, Error::NetlinkError, Handle, RouteAddRequest, RuleAddRequest};
-use std::os::fd::{FromRawFd, OwnedFd};
-use std::path::Path;
+use netlink_packet_route::rule::{RuleAction, RuleAttribute, RuleFlag, RuleMessage};
+use nix::sched::{setns, CloneFlags};
+use rtnetlink::{
+    new_connection, Error::NetlinkError, Handle, IpVersion, RouteAddRequest, RuleAddRequest,
+};
+use socket_factory::{SocketFactory, TcpSocket, UdpSocket};
+use std::os::fd::{AsRawFd as _, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::{
@@ -26,10 +32,15 @@ use std::{
     fs, io,
     os::{fd::RawFd, unix::fs::PermissionsExt},
 };
+use tokio::io::{unix::AsyncFd, Interest};
 use tokio::sync::mpsc;
 use tun::ioctl;
 
 const TUNSETIFF: libc::c_ulong = 0x4004_54ca;
+const TUNSETOFFLOAD: libc::c_ulong = 0x4004_54d0;
+const TUN_F_CSUM: libc::c_ulong = 0x01;
+const TUN_F_TSO4: libc::c_ulong = 0x02;
+const TUN_F_TSO6: libc::c_ulong = 0x04;
 const TUN_DEV_MAJOR: u32 = 10;
 const TUN_DEV_MINOR: u32 = 200;
 
@@ -37,10 +48,164 @@ const TUN_FILE: &CStr = c"/dev/net/tun";
 
 const FIREZONE_TABLE: u32 = 0x2021_fd00;
 
+/// The bits of an fwmark that our ip rules look at.
+///
+/// It excludes the bits we derive from the interface name (see [`TunDeviceConfig::fwmark`]), so sockets of every Firezone tunnel on this host bypass the routing tables of all of them.
+const FWMARK_RULE_MASK: u32 = 0xff00_ffff;
+
+const DEFAULT_IFACE_NAME: &str = "tun-firezone";
+
+/// Where `ip netns add` puts named network namespaces.
+const NETNS_RUN_DIR: &str = "/run/netns";
+
+/// How many packets we at most take from the channel before merging and writing them to the TUN device.
+const MAX_SEND_BATCH: usize = 64;
+
+/// Settings that must differ between tunnels running on the same host.
+///
+/// Only the interface name has to be set per instance, the routing table and fwmark are derived from it unless set explicitly.
+#[derive(clap::Args, Debug, Clone, PartialEq, Eq)]
+pub struct TunDeviceConfig {
+    /// Name of the TUN interface.
+    #[arg(long = "tun-name", env = "FIREZONE_TUN_NAME", default_value = DEFAULT_IFACE_NAME, value_parser = parse_iface_name)]
+    pub name: String,
+
+    /// ID of the routing table for Firezone's routes, derived from the interface name if not set.
+    #[arg(long, env = "FIREZONE_ROUTING_TABLE", value_parser = parse_u32)]
+    pub routing_table: Option<u32>,
+
+    /// fwmark for Firezone's own sockets, so they don't get routed into the tunnel.
+    /// Derived from the interface name if not set.
+    ///
+    /// Sockets of other Firezone tunnels on this host only bypass this one if their fwmark differs in bits 16 to 23 only.
+    #[arg(long, env = "FIREZONE_FWMARK", value_parser = parse_u32)]
+    pub fwmark: Option<u32>,
+
+    /// Network namespace to run in, either a name from `ip netns` or a path like `/proc/<pid>/ns/net`.
+    #[arg(long, env = "FIREZONE_NETNS")]
+    pub netns: Option<PathBuf>,
+}
+
+impl Default for TunDeviceConfig {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_IFACE_NAME.to_owned(),
+            routing_table: None,
+            fwmark: None,
+            netns: None,
+        }
+    }
+}
+
+impl TunDeviceConfig {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn routing_table(&self) -> u32 {
+        self.routing_table.unwrap_or_else(|| {
+            if self.name == DEFAULT_IFACE_NAME {
+                return FIREZONE_TABLE;
+            }
+
+            FIREZONE_TABLE | self.discriminator()
+        })
+    }
+
+    pub fn fwmark(&self) -> u32 {
+        self.fwmark.unwrap_or_else(|| {
+            if self.name == DEFAULT_IFACE_NAME {
+                return FIREZONE_MARK;
+            }
+
+            FIREZONE_MARK | (self.discriminator() << 16)
+        })
+    }
+
+    /// The fwmark and mask of the ip rule that exempts marked sockets from our routing table.
+    fn rule_fwmark(&self) -> (u32, u32) {
+        (self.fwmark() & FWMARK_RULE_MASK, FWMARK_RULE_MASK)
+    }
+
+    /// A value in `1..=255` derived from the interface name.
+    ///
+    /// The corresponding bits are zero in [`FIREZONE_TABLE`] and [`FIREZONE_MARK`], so derived values never clash with the defaults.
+    fn discriminator(&self) -> u32 {
+        // FNV-1a, because it must be stable across releases.
+        let hash = self.name.bytes().fold(0x811c_9dc5_u32, |hash, b| {
+            (hash ^ u32::from(b)).wrapping_mul(0x0100_0193)
+        });
+
+        1 + hash % 255
+    }
+
+    /// Moves the current thread into the configured network namespace.
+    ///
+    /// Threads inherit the namespace of the thread that spawns them, so this must be called before starting any threads that should be in it, i.e. the Tokio runtime.
+    pub fn enter_netns(&self) -> Result<()> {
+        let Some(netns) = self.netns.as_deref() else {
+            return Ok(());
+        };
+
+        let path = if netns.components().count() == 1 {
+            Path::new(NETNS_RUN_DIR).join(netns)
+        } else {
+            netns.to_owned()
+        };
+
+        let file = fs::File::open(&path)
+            .with_context(|| format!("Failed to open network namespace `{}`", path.display()))?;
+        setns(file, CloneFlags::CLONE_NEWNET)
+            .with_context(|| format!("Failed to enter network namespace `{}`", path.display()))?;
+
+        tracing::info!(netns = %path.display(), "Entered network namespace");
+
+        Ok(())
+    }
+
+    /// TCP sockets that bypass this tunnel.
+    pub fn tcp_socket_factory(&self) -> Arc<dyn SocketFactory<TcpSocket>> {
+        let mark = self.fwmark();
+
+        Arc::new(move |addr: &std::net::SocketAddr| crate::linux::tcp_socket_with_mark(addr, mark))
+    }
+
+    /// UDP sockets that bypass this tunnel.
+    pub fn udp_socket_factory(&self) -> Arc<dyn SocketFactory<UdpSocket>> {
+        let mark = self.fwmark();
+
+        Arc::new(move |addr: &std::net::SocketAddr| crate::linux::udp_socket_with_mark(addr, mark))
+    }
+}
+
+fn parse_iface_name(name: &str) -> Result<String, String> {
+    if name.is_empty() || name.len() >= libc::IF_NAMESIZE {
+        return Err(format!(
+            "must be between 1 and {} bytes long",
+            libc::IF_NAMESIZE - 1
+        ));
+    }
+
+    if name.contains(|c: char| c == '/' || c == ':' || c.is_whitespace()) {
+        return Err("must not contain `/`, `:` or whitespace".to_owned());
+    }
+
+    Ok(name.to_owned())
+}
+
+/// Parses decimal or `0x`-prefixed hexadecimal numbers, like `ip rule` does.
+fn parse_u32(s: &str) -> Result<u32, std::num::ParseIntError> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => s.parse(),
+    }
+}
+
 /// For lack of a better name
 pub struct TunDeviceManager {
     mtu: u32,
     num_threads: usize,
+    config: TunDeviceConfig,
     connection: Connection,
     routes: HashSet<IpNetwork>,
 }
@@ -57,12 +222,10 @@ impl Drop for TunDeviceManager {
 }
 
 impl TunDeviceManager {
-    pub const IFACE_NAME: &'static str = "tun-firezone";
-
     /// Creates a new managed tunnel device.
     ///
     /// Panics if called without a Tokio runtime.
-    pub fn new(mtu: usize, num_threads: usize) -> Result<Self> {
+    pub fn new(mtu: usize, num_threads: usize, config: TunDeviceConfig) -> Result<Self> {
         let (cxn, handle, _) = new_connection().context("Failed to create netlink connection")?;
         let task = tokio::spawn(cxn);
         let connection = Connection { handle, task };
@@ -72,16 +235,19 @@ impl TunDeviceManager {
             routes: Default::default(),
             mtu: mtu as u32,
             num_threads,
+            config,
         })
     }
 
     pub fn make_tun(&mut self) -> Result<Tun> {
-        Ok(Tun::new(self.num_threads)?)
+        Ok(Tun::new(&self.config.name, self.num_threads)?)
     }
 
     #[tracing::instrument(level = "trace", skip(self))]
     pub async fn set_ips(&mut self, ipv4: Ipv4Addr, ipv6: Ipv6Addr) -> Result<()> {
-        let name = Self::IFACE_NAME;
+        let name = self.config.name.as_str();
+        let fwmark = self.config.rule_fwmark();
+        let table = self.config.routing_table();
 
         let handle = &self.connection.handle;
         let index = handle
@@ -128,8 +294,10 @@ impl TunDeviceManager {
             .await
             .context("Failed to bring up interface")?;
 
+        remove_stale_rules(handle, fwmark, table).await?;
+
         if res_v4.is_ok() {
-            if let Err(e) = make_rule(handle).v4().execute().await {
+            if let Err(e) = make_rule(handle, fwmark, table).v4().execute().await {
                 if !matches!(&e, NetlinkError(err) if err.raw_code() == -EEXIST) {
                     tracing::warn!(
                         "Couldn't add ip rule for ipv4: {e:?}, ipv4 packets won't be routed"
@@ -142,7 +310,7 @@ impl TunDeviceManager {
         }
 
         if res_v6.is_ok() {
-            if let Err(e) = make_rule(handle).v6().execute().await {
+            if let Err(e) = make_rule(handle, fwmark, table).v6().execute().await {
                 if !matches!(&e, NetlinkError(err) if err.raw_code() == -EEXIST) {
                     tracing::warn!(
                         "Couldn't add ip rule for ipv6: {e:?}, ipv6 packets won't be routed"
@@ -173,11 +341,12 @@ impl TunDeviceManager {
         tracing::info!(?new_routes, "Setting new routes");
 
         let handle = &self.connection.handle;
+        let table = self.config.routing_table();
 
         let index = handle
             .link()
             .get()
-            .match_name(Self::IFACE_NAME.to_string())
+            .match_name(self.config.name.clone())
             .execute()
             .try_next()
             .await?
@@ -186,11 +355,11 @@ impl TunDeviceManager {
             .index;
 
         for route in self.routes.difference(&new_routes) {
-            remove_route(route, index, handle).await;
+            remove_route(route, index, table, handle).await;
         }
 
         for route in &new_routes {
-            add_route(route, index, handle).await;
+            add_route(route, index, table, handle).await;
         }
 
         self.routes = new_routes;
@@ -198,18 +367,108 @@ impl TunDeviceManager {
     }
 }
 
-fn make_rule(handle: &Handle) -> RuleAddRequest {
+/// Deletes rules for our table left behind by earlier versions and fails if another tunnel's rule uses our routing table.
+///
+/// The rules of all Firezone tunnels share the same fwmark, see [`FWMARK_RULE_MASK`].
+/// Rules from a previous run of this instance use the same fwmark and table, so they don't count.
+async fn remove_stale_rules(handle: &Handle, fwmark: (u32, u32), table: u32) -> Result<()> {
+    for ip_version in [IpVersion::V4, IpVersion::V6] {
+        let rules = handle
+            .rule()
+            .get(ip_version)
+            .execute()
+            .try_collect::<Vec<_>>()
+            .await
+            .context("Failed to list ip rules")?;
+
+        for rule in rules {
+            match classify_rule(&rule, fwmark, table) {
+                ExistingRule::Unrelated | ExistingRule::Ours => {}
+                ExistingRule::Stale => {
+                    tracing::debug!(?rule, "Deleting stale ip rule");
+
+                    handle
+                        .rule()
+                        .del(rule)
+                        .execute()
+                        .await
+                        .context("Failed to delete stale ip rule")?;
+                }
+                ExistingRule::Conflicting { fwmark, fwmask } => {
+                    bail!("Routing table {table:#x} is already used with fwmark {fwmark:#x}/{fwmask:#x}, is another tunnel running? Set a different `--routing-table`");
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ExistingRule {
+    /// The rule doesn't use our table.
+    Unrelated,
+    /// The rule is the one we would add.
+    Ours,
+    /// Earlier versions matched our fwmark with the full mask, which breaks the bypass for other Firezone tunnels.
+    Stale,
+    /// Another tunnel's rule uses our table.
+    Conflicting { fwmark: u32, fwmask: u32 },
+}
+
+fn classify_rule(rule: &RuleMessage, (fwmark, fwmask): (u32, u32), table: u32) -> ExistingRule {
+    let mut rule_fwmark = None;
+    let mut rule_fwmask = u32::MAX; // The kernel omits the mask if all bits are set.
+    let mut rule_table = None;
+
+    for attribute in &rule.attributes {
+        if let RuleAttribute::FwMark(mark) = attribute {
+            rule_fwmark = Some(*mark);
+        }
+        if let RuleAttribute::FwMask(mask) = attribute {
+            rule_fwmask = *mask;
+        }
+        if let RuleAttribute::Table(table) = attribute {
+            rule_table = Some(*table);
+        }
+    }
+
+    let (Some(rule_fwmark), Some(rule_table)) = (rule_fwmark, rule_table) else {
+        return ExistingRule::Unrelated;
+    };
+
+    if rule_table != table {
+        return ExistingRule::Unrelated;
+    }
+
+    if (rule_fwmark, rule_fwmask) == (fwmark, fwmask) {
+        return ExistingRule::Ours;
+    }
+
+    if rule.header.flags.contains(&RuleFlag::Invert) && rule_fwmark & FWMARK_RULE_MASK == fwmark {
+        return ExistingRule::Stale;
+    }
+
+    ExistingRule::Conflicting {
+        fwmark: rule_fwmark,
+        fwmask: rule_fwmask,
+    }
+}
+
+/// Routes everything through our table, except packets whose fwmark matches under the mask.
+fn make_rule(handle: &Handle, (fwmark, fwmask): (u32, u32), table: u32) -> RuleAddRequest {
     let mut rule = handle
         .rule()
         .add()
-        .fw_mark(FIREZONE_MARK)
-        .table_id(FIREZONE_TABLE)
+        .fw_mark(fwmark)
+        .table_id(table)
         .action(RuleAction::ToTable);
 
     rule.message_mut()
-        .header
-        .flags
-        .push(netlink_packet_route::rule::RuleFlag::Invert);
+        .attributes
+        .push(RuleAttribute::FwMask(fwmask));
+
+    rule.message_mut().header.flags.push(RuleFlag::Invert);
 
     rule.message_mut()
         .attributes
@@ -220,32 +479,42 @@ fn make_rule(handle: &Handle) -> RuleAddRequest {
     rule
 }
 
-fn make_route(idx: u32, handle: &Handle) -> RouteAddRequest {
+fn make_route(idx: u32, table: u32, handle: &Handle) -> RouteAddRequest {
     handle
         .route()
         .add()
         .output_interface(idx)
         .protocol(RouteProtocol::Static)
         .scope(RouteScope::Universe)
-        .table_id(FIREZONE_TABLE)
+        .table_id(table)
 }
 
-fn make_route_v4(idx: u32, handle: &Handle, route: Ipv4Network) -> RouteAddRequest<Ipv4Addr> {
-    make_route(idx, handle)
+fn make_route_v4(
+    idx: u32,
+    table: u32,
+    handle: &Handle,
+    route: Ipv4Network,
+) -> RouteAddRequest<Ipv4Addr> {
+    make_route(idx, table, handle)
         .v4()
         .destination_prefix(route.network_address(), route.netmask())
 }
 
-fn make_route_v6(idx: u32, handle: &Handle, route: Ipv6Network) -> RouteAddRequest<Ipv6Addr> {
-    make_route(idx, handle)
+fn make_route_v6(
+    idx: u32,
+    table: u32,
+    handle: &Handle,
+    route: Ipv6Network,
+) -> RouteAddRequest<Ipv6Addr> {
+    make_route(idx, table, handle)
         .v6()
         .destination_prefix(route.network_address(), route.netmask())
 }
 
-async fn add_route(route: &IpNetwork, idx: u32, handle: &Handle) {
+async fn add_route(route: &IpNetwork, idx: u32, table: u32, handle: &Handle) {
     let res = match route {
-        IpNetwork::V4(ipnet) => make_route_v4(idx, handle, *ipnet).execute().await,
-        IpNetwork::V6(ipnet) => make_route_v6(idx, handle, *ipnet).execute().await,
+        IpNetwork::V4(ipnet) => make_route_v4(idx, table, handle, *ipnet).execute().await,
+        IpNetwork::V6(ipnet) => make_route_v6(idx, table, handle, *ipnet).execute().await,
     };
 
     let Err(err) = res else {
@@ -267,10 +536,14 @@ async fn add_route(route: &IpNetwork, idx: u32, handle: &Handle) {
     tracing::warn!(%route, "Failed to add route: {}", err_with_src(&err));
 }
 
-async fn remove_route(route: &IpNetwork, idx: u32, handle: &Handle) {
+async fn remove_route(route: &IpNetwork, idx: u32, table: u32, handle: &Handle) {
     let message = match route {
-        IpNetwork::V4(ipnet) => make_route_v4(idx, handle, *ipnet).message_mut().clone(),
-        IpNetwork::V6(ipnet) => make_route_v6(idx, handle, *ipnet).message_mut().clone(),
+        IpNetwork::V4(ipnet) => make_route_v4(idx, table, handle, *ipnet)
+            .message_mut()
+            .clone(),
+        IpNetwork::V6(ipnet) => make_route_v6(idx, table, handle, *ipnet)
+            .message_mut()
+            .clone(),
     };
 
     let res = handle.route().del(message).execute().await;
@@ -297,20 +570,25 @@ async fn remove_route(route: &IpNetwork, idx: u32, handle: &Handle) {
 
 #[derive(Debug)]
 pub struct Tun {
+    name: String,
     outbound_tx: flume::r#async::SendSink<'static, IpPacket>,
     inbound_rx: mpsc::Receiver<IpPacket>,
 }
 
 impl Tun {
-    pub fn new(num_threads: usize) -> io::Result<Self> {
+    pub fn new(name: &str, num_threads: usize) -> io::Result<Self> {
         create_tun_device()?;
 
         let (inbound_tx, inbound_rx) = mpsc::channel(1000);
         let (outbound_tx, outbound_rx) = flume::bounded(1000); // flume is an MPMC channel, therefore perfect for workstealing outbound packets.
 
-        for n in 0..num_threads {
-            let fd = Arc::new(open_tun()?);
-            let outbound_rx = outbound_rx.clone().into_stream();
+        let (first_fd, offload) = open_first_tun(name)?;
+        let fds =
+            std::iter::once(Ok(first_fd)).chain((1..num_threads).map(|_| open_tun(name, offload)));
+
+        for (n, fd) in fds.enumerate() {
+            let fd = Arc::new(fd?);
+            let outbound_rx = outbound_rx.clone();
             let inbound_tx = inbound_tx.clone();
 
             std::thread::Builder::new()
@@ -320,7 +598,7 @@ impl Tun {
 
                     move || {
                         firezone_logging::unwrap_or_warn!(
-                            tun::unix::tun_send(fd, outbound_rx, write),
+                            tun_send(fd, outbound_rx, offload),
                             "Failed to send to TUN device: {}"
                         )
                     }
@@ -333,7 +611,7 @@ impl Tun {
 
                     move || {
                         firezone_logging::unwrap_or_warn!(
-                            tun::unix::tun_recv(fd, inbound_tx, read),
+                            tun_recv(fd, inbound_tx, offload),
                             "Failed to recv from TUN device: {}"
                         )
                     }
@@ -342,34 +620,190 @@ impl Tun {
         }
 
         Ok(Self {
+            name: name.to_owned(),
             outbound_tx: outbound_tx.into_sink(),
             inbound_rx,
         })
     }
 }
 
-fn open_tun() -> Result<OwnedFd, io::Error> {
+/// Opens the first queue of the TUN device, with segmentation offload if the kernel supports it.
+///
+/// The kernel applies the flags of a queue to the entire device, so all other queues must be opened in the same mode.
+fn open_first_tun(name: &str) -> io::Result<(OwnedFd, bool)> {
+    match open_tun(name, true) {
+        Ok(fd) => Ok((fd, true)),
+        Err(e) => {
+            tracing::warn!(
+                "Failed to open TUN device with segmentation offload, falling back to one packet per read and write: {}",
+                err_with_src(&e)
+            );
+
+            Ok((open_tun(name, false)?, false))
+        }
+    }
+}
+
+fn open_tun(name: &str, offload: bool) -> Result<OwnedFd, io::Error> {
     let fd = match unsafe { open(TUN_FILE.as_ptr() as _, O_RDWR) } {
         -1 => return Err(get_last_error()),
         fd => fd,
     };
 
+    // Safety: We are not closing the FD.
+    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+    let request = ioctl::Request::<ioctl::SetTunFlagsPayload>::new(name);
+    let mut request = if offload {
+        request.with_vnet_hdr()
+    } else {
+        request
+    };
+
     unsafe {
-        ioctl::exec(
-            fd,
-            TUNSETIFF,
-            &mut ioctl::Request::<ioctl::SetTunFlagsPayload>::new(TunDeviceManager::IFACE_NAME),
-        )?;
+        ioctl::exec(fd.as_raw_fd(), TUNSETIFF, &mut request)?;
     }
 
-    set_non_blocking(fd)?;
+    set_non_blocking(fd.as_raw_fd())?;
 
-    // Safety: We are not closing the FD.
-    let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+    if !offload {
+        return Ok(fd);
+    }
+
+    // The `virtio_net_hdr` only pays off with offloads, don't run with one but not the other.
+    set_offload(fd.as_raw_fd()).map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("Failed to enable segmentation offload on TUN device: {e}"),
+        )
+    })?;
 
     Ok(fd)
 }
 
+/// Lets the kernel hand us TCP segments larger than the MTU, see [`offload`].
+fn set_offload(fd: RawFd) -> io::Result<()> {
+    // Safety: Within this module, the file descriptor is always valid.
+    match unsafe { libc::ioctl(fd, TUNSETOFFLOAD as _, TUN_F_CSUM | TUN_F_TSO4 | TUN_F_TSO6) } {
+        -1 => Err(get_last_error()),
+        _ => Ok(()),
+    }
+}
+
+/// Writes outbound packets to the TUN device.
+///
+/// With `offload`, consecutive TCP segments of the same flow are merged into one write.
+fn tun_send(fd: Arc<OwnedFd>, outbound_rx: flume::Receiver<IpPacket>, offload: bool) -> Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to create runtime")?
+        .block_on(async move {
+            let fd = AsyncFd::with_interest(fd, Interest::WRITABLE)?;
+            let mut batch = Vec::with_capacity(MAX_SEND_BATCH);
+            let mut buf = Vec::with_capacity(offload::MAX_OFFLOAD_SIZE);
+
+            while let Ok(packet) = outbound_rx.recv_async().await {
+                batch.push(packet);
+                batch.extend(outbound_rx.try_iter().take(MAX_SEND_BATCH - 1));
+
+                let mut start = 0;
+
+                while start < batch.len() {
+                    let bytes = if offload {
+                        start +=
+                            offload::coalesce(batch[start..].iter().map(|p| p.packet()), &mut buf);
+
+                        buf.as_slice()
+                    } else {
+                        start += 1;
+
+                        batch[start - 1].packet()
+                    };
+
+                    if let Err(e) = fd
+                        .async_io(Interest::WRITABLE, |fd| write(fd.as_raw_fd(), bytes))
+                        .await
+                    {
+                        tracing::warn!("Failed to write to TUN FD: {}", err_with_src(&e));
+                    }
+                }
+
+                batch.clear();
+            }
+
+            anyhow::Ok(())
+        })
+}
+
+/// Reads packets from the TUN device.
+///
+/// With `offload`, large TCP segments are split into packets that fit the MTU.
+fn tun_recv(fd: Arc<OwnedFd>, inbound_tx: mpsc::Sender<IpPacket>, offload: bool) -> Result<()> {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .context("Failed to create runtime")?
+        .block_on(async move {
+            let fd = AsyncFd::with_interest(fd, Interest::READABLE)?;
+            let mut buf = vec![0; offload::MAX_OFFLOAD_SIZE];
+            let mut scratch = Vec::with_capacity(offload::MAX_OFFLOAD_SIZE);
+            let mut packets = Vec::new();
+
+            loop {
+                let len = match fd
+                    .async_io(Interest::READABLE, |fd| read(fd.as_raw_fd(), &mut buf))
+                    .await
+                {
+                    Ok(0) => bail!("TUN file descriptor is closed"),
+                    Ok(len) => len,
+                    Err(e) => {
+                        tracing::warn!("Failed to read from TUN FD: {}", err_with_src(&e));
+                        continue;
+                    }
+                };
+
+                if !offload {
+                    packets.push(to_ip_packet(&buf[..len]));
+                } else if let Err(e) = offload::split(&buf[..len], &mut scratch, |packet| {
+                    packets.push(to_ip_packet(packet))
+                }) {
+                    tracing::warn!("Failed to split packet from TUN FD: {}", err_with_src(&e));
+                    continue;
+                }
+
+                for packet in packets.drain(..) {
+                    let packet = match packet {
+                        Ok(packet) => packet,
+                        Err(e) => {
+                            tracing::warn!(
+                                "Failed to parse packet from TUN FD: {}",
+                                err_with_src(&e)
+                            );
+                            continue;
+                        }
+                    };
+
+                    if inbound_tx.send(packet).await.is_err() {
+                        tracing::debug!("Inbound packet receiver gone, shutting down task");
+
+                        return Ok(());
+                    }
+                }
+            }
+        })
+}
+
+fn to_ip_packet(bytes: &[u8]) -> io::Result<IpPacket> {
+    let mut buf = IpPacketBuf::new();
+    buf.buf()
+        .get_mut(..bytes.len())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Packet exceeds the MTU"))?
+        .copy_from_slice(bytes);
+
+    IpPacket::new(buf, bytes.len()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
 impl tun::Tun for Tun {
     fn poll_send_ready(&mut self, cx: &mut Context) -> Poll<io::Result<()>> {
         self.outbound_tx
@@ -395,7 +829,7 @@ impl tun::Tun for Tun {
     }
 
     fn name(&self) -> &str {
-        TunDeviceManager::IFACE_NAME
+        &self.name
     }
 }
 
@@ -441,9 +875,7 @@ fn create_tun_device() -> io::Result<()> {
 }
 
 /// Read from the given file descriptor in the buffer.
-fn read(fd: RawFd, dst: &mut IpPacketBuf) -> io::Result<usize> {
-    let dst = dst.buf();
-
+fn read(fd: RawFd, dst: &mut [u8]) -> io::Result<usize> {
     // Safety: Within this module, the file descriptor is always valid.
     match unsafe { libc::read(fd, dst.as_mut_ptr() as _, dst.len()) } {
         -1 => Err(io::Error::last_os_error()),
@@ -451,13 +883,148 @@ fn read(fd: RawFd, dst: &mut IpPacketBuf) -> io::Result<usize> {
     }
 }
 
-/// Write the packet to the given file descriptor.
-fn write(fd: RawFd, packet: &IpPacket) -> io::Result<usize> {
-    let buf = packet.packet();
-
+/// Write the buffer to the given file descriptor.
+fn write(fd: RawFd, buf: &[u8]) -> io::Result<usize> {
     // Safety: Within this module, the file descriptor is always valid.
     match unsafe { libc::write(fd, buf.as_ptr() as _, buf.len() as _) } {
         -1 => Err(io::Error::last_os_error()),
         n => Ok(n as usize),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_name_uses_default_table_and_mark() {
+        let config = TunDeviceConfig::default();
+
+        assert_eq!(config.routing_table(), FIREZONE_TABLE);
+        assert_eq!(config.fwmark(), FIREZONE_MARK);
+    }
+
+    #[test]
+    fn other_names_derive_different_table_and_mark() {
+        let default = TunDeviceConfig::default();
+        let config = TunDeviceConfig {
+            name: "tun-fz-gateway".to_owned(),
+            ..Default::default()
+        };
+
+        assert_ne!(config.routing_table(), default.routing_table());
+        assert_ne!(config.fwmark(), default.fwmark());
+        assert_eq!(config.routing_table() & !0xff, FIREZONE_TABLE);
+        assert_eq!(config.fwmark() & !0x00ff_0000, FIREZONE_MARK);
+    }
+
+    #[test]
+    fn explicit_table_and_mark_take_precedence() {
+        let config = TunDeviceConfig {
+            name: "tun-fz-gateway".to_owned(),
+            routing_table: Some(100),
+            fwmark: Some(0x10),
+            netns: None,
+        };
+
+        assert_eq!(config.routing_table(), 100);
+        assert_eq!(config.fwmark(), 0x10);
+    }
+
+    #[test]
+    fn sockets_of_every_instance_bypass_all_tables() {
+        let client = TunDeviceConfig::default();
+        let gateway = TunDeviceConfig {
+            name: "tun-fz-gateway".to_owned(),
+            ..Default::default()
+        };
+
+        for (rule_owner, socket_owner) in [
+            (&client, &client),
+            (&client, &gateway),
+            (&gateway, &client),
+            (&gateway, &gateway),
+        ] {
+            let (mark, mask) = rule_owner.rule_fwmark();
+
+            assert_eq!(
+                socket_owner.fwmark() & mask,
+                mark,
+                "Sockets of {} should bypass the table of {}",
+                socket_owner.name,
+                rule_owner.name
+            );
+        }
+    }
+
+    #[test]
+    fn replaces_full_mask_rule_of_earlier_versions() {
+        let config = TunDeviceConfig::default();
+        let fwmark = config.rule_fwmark();
+        let table = config.routing_table();
+
+        // This is how the kernel reports the rule that earlier versions added, it omits the full mask.
+        let full_mask_rule = rule(FIREZONE_MARK, None, table, true);
+
+        assert_eq!(
+            classify_rule(&full_mask_rule, fwmark, table),
+            ExistingRule::Stale
+        );
+        assert_eq!(
+            classify_rule(&rule(fwmark.0, Some(fwmark.1), table, true), fwmark, table),
+            ExistingRule::Ours
+        );
+    }
+
+    #[test]
+    fn detects_other_tunnels_using_our_table() {
+        let config = TunDeviceConfig::default();
+        let fwmark = config.rule_fwmark();
+        let table = config.routing_table();
+
+        assert_eq!(
+            classify_rule(&rule(0x10, None, table, true), fwmark, table),
+            ExistingRule::Conflicting {
+                fwmark: 0x10,
+                fwmask: u32::MAX
+            }
+        );
+        assert_eq!(
+            classify_rule(&rule(FIREZONE_MARK, None, table, false), fwmark, table),
+            ExistingRule::Conflicting {
+                fwmark: FIREZONE_MARK,
+                fwmask: u32::MAX
+            }
+        );
+        assert_eq!(
+            classify_rule(&rule(FIREZONE_MARK, None, 100, true), fwmark, table),
+            ExistingRule::Unrelated
+        );
+    }
+
+    fn rule(fwmark: u32, fwmask: Option<u32>, table: u32, invert: bool) -> RuleMessage {
+        let mut rule = RuleMessage::default();
+        rule.attributes.push(RuleAttribute::FwMark(fwmark));
+        rule.attributes.extend(fwmask.map(RuleAttribute::FwMask));
+        rule.attributes.push(RuleAttribute::Table(table));
+        if invert {
+            rule.header.flags.push(RuleFlag::Invert);
+        }
+
+        rule
+    }
+
+    #[test]
+    fn rejects_invalid_interface_names() {
+        assert!(parse_iface_name("tun-firezone").is_ok());
+        assert!(parse_iface_name("").is_err());
+        assert!(parse_iface_name("tun-firezone-too-long").is_err());
+        assert!(parse_iface_name("tun/firezone").is_err());
+    }
+
+    #[test]
+    fn parses_hex_and_decimal() {
+        assert_eq!(parse_u32("0x2021fd00").unwrap(), 0x2021_fd00);
+        assert_eq!(parse_u32("100").unwrap(), 100);
+    }
+}
@@ -0,0 +1,612 @@
+//! Segmentation and coalescing of TCP packets for TUN devices opened with `IFF_VNET_HDR`.
+//!
+//! Every packet read from or written to such a device is prefixed with a `virtio_net_hdr`.
+//! With TSO enabled via `TUNSETOFFLOAD`, the kernel hands us TCP segments of up to 64 KiB which we split into packets that fit the MTU.
+//! In the other direction, we merge consecutive packets of the same TCP flow into one large segment, saving the kernel from processing each of them individually.
+
+use std::io;
+
+/// Size of the `virtio_net_hdr` in front of every packet.
+pub(crate) const VNET_HDR_LEN: usize = 10;
+
+/// The largest buffer we read from or write to the TUN device, including the `virtio_net_hdr`.
+pub(crate) const MAX_OFFLOAD_SIZE: usize = VNET_HDR_LEN + u16::MAX as usize;
+
+const VIRTIO_NET_HDR_F_NEEDS_CSUM: u8 = 1;
+
+const VIRTIO_NET_HDR_GSO_NONE: u8 = 0;
+const VIRTIO_NET_HDR_GSO_TCPV4: u8 = 1;
+const VIRTIO_NET_HDR_GSO_TCPV6: u8 = 4;
+const VIRTIO_NET_HDR_GSO_ECN: u8 = 0x80;
+
+const IPPROTO_TCP: u8 = 6;
+const IPV6_HEADER_LEN: usize = 40;
+
+const TCP_FIN: u8 = 0x01;
+const TCP_PSH: u8 = 0x08;
+const TCP_ACK: u8 = 0x10;
+const TCP_CWR: u8 = 0x80;
+
+/// Offset of the checksum within the TCP header.
+const TCP_CHECKSUM_OFFSET: usize = 16;
+
+/// Length of the TCP header without options.
+const TCP_MIN_HEADER_LEN: usize = 20;
+
+/// The `virtio_net_hdr` from `<linux/virtio_net.h>`, in native byte order.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct VirtioNetHdr {
+    flags: u8,
+    gso_type: u8,
+    hdr_len: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+}
+
+impl VirtioNetHdr {
+    fn parse(buf: &[u8]) -> io::Result<(Self, &[u8])> {
+        if buf.len() < VNET_HDR_LEN {
+            return Err(invalid_data("Packet is shorter than the virtio-net header"));
+        }
+        let (hdr, packet) = buf.split_at(VNET_HDR_LEN);
+
+        let hdr = Self {
+            flags: hdr[0],
+            gso_type: hdr[1],
+            hdr_len: u16::from_ne_bytes([hdr[2], hdr[3]]),
+            gso_size: u16::from_ne_bytes([hdr[4], hdr[5]]),
+            csum_start: u16::from_ne_bytes([hdr[6], hdr[7]]),
+            csum_offset: u16::from_ne_bytes([hdr[8], hdr[9]]),
+        };
+
+        Ok((hdr, packet))
+    }
+
+    fn encode(&self) -> [u8; VNET_HDR_LEN] {
+        let mut buf = [0; VNET_HDR_LEN];
+        buf[0] = self.flags;
+        buf[1] = self.gso_type;
+        buf[2..4].copy_from_slice(&self.hdr_len.to_ne_bytes());
+        buf[4..6].copy_from_slice(&self.gso_size.to_ne_bytes());
+        buf[6..8].copy_from_slice(&self.csum_start.to_ne_bytes());
+        buf[8..10].copy_from_slice(&self.csum_offset.to_ne_bytes());
+
+        buf
+    }
+}
+
+/// Splits a buffer read from the TUN device into IP packets, calling `on_packet` for each of them.
+///
+/// `scratch` is used to assemble the individual packets, pass the same one for every call to avoid allocations.
+pub(crate) fn split(
+    buf: &[u8],
+    scratch: &mut Vec<u8>,
+    mut on_packet: impl FnMut(&[u8]),
+) -> io::Result<()> {
+    let (hdr, packet) = VirtioNetHdr::parse(buf)?;
+
+    match hdr.gso_type & !VIRTIO_NET_HDR_GSO_ECN {
+        VIRTIO_NET_HDR_GSO_NONE if hdr.flags & VIRTIO_NET_HDR_F_NEEDS_CSUM == 0 => {
+            on_packet(packet);
+        }
+        VIRTIO_NET_HDR_GSO_NONE => {
+            scratch.clear();
+            scratch.extend_from_slice(packet);
+            complete_checksum(scratch, hdr.csum_start.into(), hdr.csum_offset.into())?;
+
+            on_packet(scratch);
+        }
+        VIRTIO_NET_HDR_GSO_TCPV4 | VIRTIO_NET_HDR_GSO_TCPV6 => {
+            segment_tcp(packet, hdr.gso_size.into(), scratch, on_packet)?;
+        }
+        gso_type => {
+            return Err(invalid_data(format!("Unsupported GSO type {gso_type}")));
+        }
+    }
+
+    Ok(())
+}
+
+/// Merges consecutive segments of the same TCP flow at the start of `packets` into `buf`, prefixed with a `virtio_net_hdr`.
+///
+/// Returns how many packets were merged, at least one unless `packets` is empty.
+/// Packets that aren't TCP or can't be merged are written on their own.
+pub(crate) fn coalesce<'a>(
+    packets: impl IntoIterator<Item = &'a [u8]>,
+    buf: &mut Vec<u8>,
+) -> usize {
+    let mut packets = packets.into_iter();
+    buf.clear();
+
+    let Some(first) = packets.next() else {
+        return 0;
+    };
+
+    buf.extend_from_slice(&VirtioNetHdr::default().encode());
+    buf.extend_from_slice(first);
+
+    let Some(segment) = TcpSegment::parse(first).filter(|s| s.can_start_batch()) else {
+        return 1;
+    };
+
+    let gso_size = segment.payload_len;
+    let mut next_seq = segment.next_seq();
+    let mut last_flags = segment.flags;
+    let mut count = 1;
+
+    for packet in packets {
+        let Some(next) = TcpSegment::parse(packet) else {
+            break;
+        };
+
+        if !segment.same_flow(&next)
+            || next.seq != next_seq
+            || next.payload_len == 0
+            || next.payload_len > gso_size
+            || next.flags & !TCP_PSH != TCP_ACK
+            || buf.len() - VNET_HDR_LEN + next.payload_len > u16::MAX as usize
+        {
+            break;
+        }
+
+        buf.extend_from_slice(&packet[next.header_len..]);
+        next_seq = next.next_seq();
+        last_flags = next.flags;
+        count += 1;
+
+        // A smaller segment or a push ends the batch; the kernel only accepts a short segment at the end.
+        if next.payload_len < gso_size || next.flags & TCP_PSH != 0 {
+            break;
+        }
+    }
+
+    if count == 1 {
+        return 1;
+    }
+
+    let packet = &mut buf[VNET_HDR_LEN..];
+    let total_len = packet.len();
+    let ip_header_len = segment.ip_header_len;
+
+    packet[ip_header_len + 13] = last_flags;
+
+    if segment.is_ipv4 {
+        packet[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+        set_ipv4_header_checksum(packet, ip_header_len);
+    } else {
+        packet[4..6].copy_from_slice(&((total_len - IPV6_HEADER_LEN) as u16).to_be_bytes());
+    }
+
+    // With `NEEDS_CSUM`, the kernel expects the checksum field to contain the (uncomplemented) checksum of the pseudo-header.
+    let pseudo_header = fold(pseudo_header_sum(
+        packet,
+        ip_header_len,
+        total_len - ip_header_len,
+    ));
+    packet[ip_header_len + TCP_CHECKSUM_OFFSET..][..2]
+        .copy_from_slice(&pseudo_header.to_be_bytes());
+
+    let hdr = VirtioNetHdr {
+        flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+        gso_type: if segment.is_ipv4 {
+            VIRTIO_NET_HDR_GSO_TCPV4
+        } else {
+            VIRTIO_NET_HDR_GSO_TCPV6
+        },
+        hdr_len: segment.header_len as u16,
+        gso_size: gso_size as u16,
+        csum_start: ip_header_len as u16,
+        csum_offset: TCP_CHECKSUM_OFFSET as u16,
+    };
+    buf[..VNET_HDR_LEN].copy_from_slice(&hdr.encode());
+
+    count
+}
+
+/// Splits a TCP segment into packets carrying at most `gso_size` bytes of payload each.
+fn segment_tcp(
+    packet: &[u8],
+    gso_size: usize,
+    scratch: &mut Vec<u8>,
+    mut on_packet: impl FnMut(&[u8]),
+) -> io::Result<()> {
+    let segment = TcpSegment::parse(packet).ok_or_else(|| invalid_data("Not a TCP segment"))?;
+
+    if gso_size == 0 {
+        return Err(invalid_data("GSO size must not be 0"));
+    }
+
+    let ip_header_len = segment.ip_header_len;
+    let headers = &packet[..segment.header_len];
+    let payload = &packet[segment.header_len..];
+    let num_segments = payload.len().div_ceil(gso_size);
+    let ipv4_id = u16::from_be_bytes([packet[4], packet[5]]);
+
+    for (i, chunk) in payload.chunks(gso_size).enumerate() {
+        scratch.clear();
+        scratch.extend_from_slice(headers);
+        scratch.extend_from_slice(chunk);
+
+        let total_len = scratch.len();
+
+        if segment.is_ipv4 {
+            scratch[2..4].copy_from_slice(&(total_len as u16).to_be_bytes());
+            scratch[4..6].copy_from_slice(&ipv4_id.wrapping_add(i as u16).to_be_bytes());
+            set_ipv4_header_checksum(scratch, ip_header_len);
+        } else {
+            scratch[4..6].copy_from_slice(&((total_len - IPV6_HEADER_LEN) as u16).to_be_bytes());
+        }
+
+        let tcp = &mut scratch[ip_header_len..];
+        let seq = segment.seq.wrapping_add((i * gso_size) as u32);
+        tcp[4..8].copy_from_slice(&seq.to_be_bytes());
+
+        let mut flags = segment.flags;
+        if i != 0 {
+            flags &= !TCP_CWR;
+        }
+        if i != num_segments - 1 {
+            flags &= !(TCP_FIN | TCP_PSH);
+        }
+        tcp[13] = flags;
+
+        set_tcp_checksum(scratch, ip_header_len);
+
+        on_packet(scratch);
+    }
+
+    Ok(())
+}
+
+/// The fields of a TCP/IP packet that matter for segmentation and coalescing.
+#[derive(Debug)]
+struct TcpSegment<'a> {
+    packet: &'a [u8],
+    is_ipv4: bool,
+    ip_header_len: usize,
+    /// Length of the IP and TCP header.
+    header_len: usize,
+    payload_len: usize,
+    seq: u32,
+    flags: u8,
+}
+
+impl<'a> TcpSegment<'a> {
+    fn parse(packet: &'a [u8]) -> Option<Self> {
+        let (is_ipv4, ip_header_len, ip_len) = match packet.first()? >> 4 {
+            4 => {
+                let ip_header_len = usize::from(packet[0] & 0x0f) * 4;
+                let ip_len = usize::from(u16::from_be_bytes([*packet.get(2)?, *packet.get(3)?]));
+                let is_fragment =
+                    u16::from_be_bytes([*packet.get(6)?, *packet.get(7)?]) & 0x3fff != 0;
+
+                if *packet.get(9)? != IPPROTO_TCP || is_fragment {
+                    return None;
+                }
+
+                (true, ip_header_len, ip_len)
+            }
+            6 => {
+                // We don't support extension headers.
+                if *packet.get(6)? != IPPROTO_TCP {
+                    return None;
+                }
+                let payload_len = usize::from(u16::from_be_bytes([packet[4], packet[5]]));
+
+                (false, IPV6_HEADER_LEN, IPV6_HEADER_LEN + payload_len)
+            }
+            _ => return None,
+        };
+
+        let tcp = packet.get(ip_header_len..ip_len)?;
+        let tcp_header_len = usize::from(tcp.get(12)? >> 4) * 4;
+        let header_len = ip_header_len + tcp_header_len;
+
+        if tcp_header_len < TCP_MIN_HEADER_LEN || header_len > ip_len || ip_len != packet.len() {
+            return None;
+        }
+
+        Some(Self {
+            packet,
+            is_ipv4,
+            ip_header_len,
+            header_len,
+            payload_len: ip_len - header_len,
+            seq: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+            flags: tcp[13],
+        })
+    }
+
+    fn next_seq(&self) -> u32 {
+        self.seq.wrapping_add(self.payload_len as u32)
+    }
+
+    /// Only plain data segments can be merged with the following ones.
+    fn can_start_batch(&self) -> bool {
+        self.payload_len > 0 && self.flags == TCP_ACK
+    }
+
+    /// The TCP options, e.g. timestamps and SACK blocks.
+    fn options(&self) -> &[u8] {
+        &self.packet[self.ip_header_len + TCP_MIN_HEADER_LEN..self.header_len]
+    }
+
+    /// Whether the headers of `other` are the same as ours, apart from lengths, IDs, sequence numbers, flags and checksums.
+    fn same_flow(&self, other: &TcpSegment<'_>) -> bool {
+        if self.is_ipv4 != other.is_ipv4 || self.header_len != other.header_len {
+            return false;
+        }
+
+        let (a, b) = (self.packet, other.packet);
+        let ip_header_len = self.ip_header_len;
+
+        let same_ip_header = if self.is_ipv4 {
+            // Version, IHL, TOS | flags, fragment offset, TTL, protocol | addresses and options
+            a[..2] == b[..2] && a[6..10] == b[6..10] && a[12..ip_header_len] == b[12..ip_header_len]
+        } else {
+            // Version, traffic class, flow label | next header, hop limit, addresses
+            a[..4] == b[..4] && a[6..IPV6_HEADER_LEN] == b[6..IPV6_HEADER_LEN]
+        };
+
+        let (a, b) = (&a[ip_header_len..], &b[ip_header_len..]);
+
+        // Ports | ack number, data offset | window | urgent pointer
+        let same_tcp_header = a[..4] == b[..4]
+            && a[8..13] == b[8..13]
+            && a[14..16] == b[14..16]
+            && a[18..20] == b[18..20];
+
+        // Like the kernel's GRO, we require identical options.
+        // The merged segment carries the options of the first one, so segments with different timestamps would lose theirs and break RTT measurement and PAWS.
+        same_ip_header && same_tcp_header && self.options() == other.options()
+    }
+}
+
+/// Completes a partial checksum as requested by `VIRTIO_NET_HDR_F_NEEDS_CSUM`.
+///
+/// The checksum field already contains the checksum of the pseudo-header, we only need to add the rest of the packet.
+fn complete_checksum(packet: &mut [u8], csum_start: usize, csum_offset: usize) -> io::Result<()> {
+    let field = csum_start + csum_offset;
+
+    if field + 2 > packet.len() {
+        return Err(invalid_data("Checksum offset is out of bounds"));
+    }
+
+    let checksum = !fold(sum(&packet[csum_start..], 0));
+    packet[field..field + 2].copy_from_slice(&checksum.to_be_bytes());
+
+    Ok(())
+}
+
+fn set_ipv4_header_checksum(packet: &mut [u8], ip_header_len: usize) {
+    packet[10..12].fill(0);
+    let checksum = !fold(sum(&packet[..ip_header_len], 0));
+    packet[10..12].copy_from_slice(&checksum.to_be_bytes());
+}
+
+fn set_tcp_checksum(packet: &mut [u8], ip_header_len: usize) {
+    let field = ip_header_len + TCP_CHECKSUM_OFFSET;
+    let tcp_len = packet.len() - ip_header_len;
+
+    packet[field..field + 2].fill(0);
+    let checksum = !fold(sum(
+        &packet[ip_header_len..],
+        pseudo_header_sum(packet, ip_header_len, tcp_len),
+    ));
+    packet[field..field + 2].copy_from_slice(&checksum.to_be_bytes());
+}
+
+fn pseudo_header_sum(packet: &[u8], ip_header_len: usize, tcp_len: usize) -> u64 {
+    let addresses = if ip_header_len == IPV6_HEADER_LEN && packet[0] >> 4 == 6 {
+        &packet[8..40]
+    } else {
+        &packet[12..20]
+    };
+
+    sum(addresses, u64::from(IPPROTO_TCP) + tcp_len as u64)
+}
+
+/// Adds up `data` as big-endian 16-bit words, as required for the internet checksum.
+fn sum(data: &[u8], initial: u64) -> u64 {
+    let mut chunks = data.chunks_exact(2);
+    let mut sum = initial;
+
+    for chunk in chunks.by_ref() {
+        sum += u64::from(u16::from_be_bytes([chunk[0], chunk[1]]));
+    }
+    if let [last] = chunks.remainder() {
+        sum += u64::from(*last) << 8;
+    }
+
+    sum
+}
+
+fn fold(mut sum: u64) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+
+    sum as u16
+}
+
+fn invalid_data(msg: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MSS: usize = 1200;
+
+    #[test]
+    fn splits_tso_segment_into_mss_sized_packets() {
+        let mut tso = tcp_v4(1000, 7, 3000, TCP_ACK | TCP_PSH);
+        let pseudo_header = fold(pseudo_header_sum(&tso, 20, tso.len() - 20));
+        tso[20 + TCP_CHECKSUM_OFFSET..][..2].copy_from_slice(&pseudo_header.to_be_bytes());
+
+        let mut buf = VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            gso_type: VIRTIO_NET_HDR_GSO_TCPV4,
+            hdr_len: 40,
+            gso_size: MSS as u16,
+            csum_start: 20,
+            csum_offset: TCP_CHECKSUM_OFFSET as u16,
+        }
+        .encode()
+        .to_vec();
+        buf.extend_from_slice(&tso);
+
+        let packets = split_all(&buf);
+
+        assert_eq!(
+            packets,
+            vec![
+                tcp_v4(1000, 7, MSS, TCP_ACK),
+                tcp_v4(1000 + MSS as u32, 8, MSS, TCP_ACK),
+                tcp_v4(1000 + 2 * MSS as u32, 9, 600, TCP_ACK | TCP_PSH),
+            ]
+        );
+    }
+
+    #[test]
+    fn coalesces_consecutive_segments_of_the_same_flow() {
+        let packets = [
+            tcp_v4(1000, 7, MSS, TCP_ACK),
+            tcp_v4(1000 + MSS as u32, 8, MSS, TCP_ACK),
+            tcp_v4(1000 + 2 * MSS as u32, 9, 600, TCP_ACK | TCP_PSH),
+            tcp_v4(1000 + 2 * MSS as u32 + 600, 10, MSS, TCP_ACK),
+        ];
+        let mut buf = Vec::new();
+
+        let count = coalesce(packets.iter().map(|p| p.as_slice()), &mut buf);
+
+        assert_eq!(count, 3);
+        assert_eq!(split_all(&buf), packets[..3]);
+    }
+
+    #[test]
+    fn does_not_coalesce_gaps_or_other_flows() {
+        let mut other_flow = tcp_v4(1000 + MSS as u32, 8, MSS, TCP_ACK);
+        other_flow[20..22].copy_from_slice(&443u16.to_be_bytes());
+        set_tcp_checksum(&mut other_flow, 20);
+
+        let gap = [tcp_v4(1000, 7, MSS, TCP_ACK), tcp_v4(5000, 8, MSS, TCP_ACK)];
+        let flows = [tcp_v4(1000, 7, MSS, TCP_ACK), other_flow];
+        let mut buf = Vec::new();
+
+        assert_eq!(coalesce(gap.iter().map(|p| p.as_slice()), &mut buf), 1);
+        assert_eq!(coalesce(flows.iter().map(|p| p.as_slice()), &mut buf), 1);
+        assert_eq!(buf[..VNET_HDR_LEN], VirtioNetHdr::default().encode());
+        assert_eq!(buf[VNET_HDR_LEN..], flows[0]);
+    }
+
+    #[test]
+    fn only_coalesces_segments_with_same_timestamps() {
+        let same = [
+            tcp_v4_with_timestamp(1000, 7, MSS, 100),
+            tcp_v4_with_timestamp(1000 + MSS as u32, 8, MSS, 100),
+        ];
+        let different = [
+            tcp_v4_with_timestamp(1000, 7, MSS, 100),
+            tcp_v4_with_timestamp(1000 + MSS as u32, 8, MSS, 101),
+        ];
+        let mut buf = Vec::new();
+
+        assert_eq!(coalesce(same.iter().map(|p| p.as_slice()), &mut buf), 2);
+        assert_eq!(split_all(&buf), same);
+
+        assert_eq!(
+            coalesce(different.iter().map(|p| p.as_slice()), &mut buf),
+            1
+        );
+        assert_eq!(buf[VNET_HDR_LEN..], different[0]);
+    }
+
+    #[test]
+    fn passes_through_non_tcp_packets() {
+        let mut udp = tcp_v4(1000, 7, 100, TCP_ACK);
+        udp[9] = 17;
+        let mut buf = Vec::new();
+
+        assert_eq!(coalesce([udp.as_slice(), udp.as_slice()], &mut buf), 1);
+        assert_eq!(split_all(&buf), vec![udp]);
+    }
+
+    #[test]
+    fn completes_partial_checksums() {
+        let expected = tcp_v4(1000, 7, 100, TCP_ACK);
+        let mut partial = expected.clone();
+        let pseudo_header = fold(pseudo_header_sum(&partial, 20, partial.len() - 20));
+        partial[20 + TCP_CHECKSUM_OFFSET..][..2].copy_from_slice(&pseudo_header.to_be_bytes());
+
+        let mut buf = VirtioNetHdr {
+            flags: VIRTIO_NET_HDR_F_NEEDS_CSUM,
+            csum_start: 20,
+            csum_offset: TCP_CHECKSUM_OFFSET as u16,
+            ..Default::default()
+        }
+        .encode()
+        .to_vec();
+        buf.extend_from_slice(&partial);
+
+        assert_eq!(split_all(&buf), vec![expected]);
+    }
+
+    fn split_all(buf: &[u8]) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        split(buf, &mut Vec::new(), |p| packets.push(p.to_vec())).unwrap();
+
+        packets
+    }
+
+    fn tcp_v4(seq: u32, id: u16, payload_len: usize, flags: u8) -> Vec<u8> {
+        tcp_v4_with_options(seq, id, payload_len, flags, &[])
+    }
+
+    /// A data segment with the TCP timestamp option, padded with two NOPs like Linux does.
+    fn tcp_v4_with_timestamp(seq: u32, id: u16, payload_len: usize, tsval: u32) -> Vec<u8> {
+        let mut options = vec![1, 1, 8, 10];
+        options.extend_from_slice(&tsval.to_be_bytes());
+        options.extend_from_slice(&42u32.to_be_bytes());
+
+        tcp_v4_with_options(seq, id, payload_len, TCP_ACK, &options)
+    }
+
+    fn tcp_v4_with_options(
+        seq: u32,
+        id: u16,
+        payload_len: usize,
+        flags: u8,
+        options: &[u8],
+    ) -> Vec<u8> {
+        let header_len = 40 + options.len();
+
+        let mut packet = vec![0; 40];
+        packet[0] = 0x45;
+        packet[2..4].copy_from_slice(&((header_len + payload_len) as u16).to_be_bytes());
+        packet[4..6].copy_from_slice(&id.to_be_bytes());
+        packet[6] = 0x40; // Don't fragment
+        packet[8] = 64;
+        packet[9] = IPPROTO_TCP;
+        packet[12..16].copy_from_slice(&[10, 0, 0, 1]);
+        packet[16..20].copy_from_slice(&[100, 64, 0, 1]);
+
+        packet[20..22].copy_from_slice(&80u16.to_be_bytes());
+        packet[22..24].copy_from_slice(&50000u16.to_be_bytes());
+        packet[24..28].copy_from_slice(&seq.to_be_bytes());
+        packet[28..32].copy_from_slice(&42u32.to_be_bytes());
+        packet[32] = ((header_len - 20) as u8 / 4) << 4;
+        packet[33] = flags;
+        packet[34..36].copy_from_slice(&1024u16.to_be_bytes());
+        packet.extend_from_slice(options);
+
+        packet.extend((0..payload_len).map(|i| (seq as usize + i) as u8));
+
+        set_ipv4_header_checksum(&mut packet, 20);
+        set_tcp_checksum(&mut packet, 20);
+
+        packet
+    }
+}
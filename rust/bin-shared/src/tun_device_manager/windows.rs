@@ -6,6 +6,7 @@ use firezone_logging::err_with_src;
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
 use ip_packet::{IpPacket, IpPacketBuf};
 use ring::digest;
+use socket_factory::{SocketFactory, TcpSocket, UdpSocket};
 use std::net::IpAddr;
 use std::task::ready;
 use std::time::Duration;
@@ -45,6 +46,32 @@ use wintun::Adapter;
 /// where that is configured.
 const RING_BUFFER_SIZE: u32 = 0x10_0000;
 
+/// Settings that must differ between tunnels running on the same host.
+///
+/// Wintun adapters are identified by [`TUNNEL_UUID`], so there is nothing to configure on Windows yet.
+#[derive(clap::Args, Debug, Clone, Default, PartialEq, Eq)]
+pub struct TunDeviceConfig {}
+
+impl TunDeviceConfig {
+    pub fn name(&self) -> &str {
+        TUNNEL_NAME
+    }
+
+    /// Network namespaces only exist on Linux.
+    #[expect(clippy::unnecessary_wraps, reason = "Fallible on Linux")]
+    pub fn enter_netns(&self) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn tcp_socket_factory(&self) -> Arc<dyn SocketFactory<TcpSocket>> {
+        Arc::new(crate::windows::tcp_socket_factory)
+    }
+
+    pub fn udp_socket_factory(&self) -> Arc<dyn SocketFactory<UdpSocket>> {
+        Arc::new(crate::windows::udp_socket_factory)
+    }
+}
+
 pub struct TunDeviceManager {
     mtu: u32,
 
@@ -58,7 +85,7 @@ pub struct TunDeviceManager {
 
 impl TunDeviceManager {
     #[expect(clippy::unnecessary_wraps, reason = "Fallible on Linux")]
-    pub fn new(mtu: usize, _num_threads: usize) -> Result<Self> {
+    pub fn new(mtu: usize, _num_threads: usize, _config: TunDeviceConfig) -> Result<Self> {
         Ok(Self {
             iface_idx: None,
             luid: None,
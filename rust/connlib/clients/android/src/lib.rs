@@ -22,6 +22,7 @@ use jni::{
 use phoenix_channel::get_user_agent;
 use phoenix_channel::LoginUrl;
 use phoenix_channel::PhoenixChannel;
+use phoenix_channel::ProxyConfig;
 use secrecy::{Secret, SecretString};
 use socket_factory::{SocketFactory, TcpSocket, UdpSocket};
 use std::{io, net::IpAddr, os::fd::AsRawFd, path::Path, sync::Arc};
@@ -367,6 +368,7 @@ fn connect(
                 .build()
         },
         tcp_socket_factory,
+        ProxyConfig::default(),
     )?;
     let session = Session::connect(
         Arc::new(protected_tcp_socket_factory(callbacks.clone())),
@@ -18,6 +18,7 @@ use ip_network::{Ipv4Network, Ipv6Network};
 use phoenix_channel::get_user_agent;
 use phoenix_channel::LoginUrl;
 use phoenix_channel::PhoenixChannel;
+use phoenix_channel::ProxyConfig;
 use secrecy::{Secret, SecretString};
 use std::sync::OnceLock;
 use std::{
@@ -277,6 +278,7 @@ impl WrappedSession {
                     .build()
             },
             Arc::new(socket_factory::tcp),
+            ProxyConfig::default(),
         )?;
         let session = Session::connect(
             Arc::new(socket_factory::tcp),
@@ -1,4 +1,4 @@
-use connlib_model::ResourceView;
+use connlib_model::{GatewayConnectionView, ResourceView};
 use ip_network::{Ipv4Network, Ipv6Network};
 use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
@@ -29,6 +29,9 @@ pub trait Callbacks: Clone + Send + Sync {
     /// or if all Resources for a user are disabled by policy.
     fn on_update_resources(&self, _: Vec<ResourceView>) {}
 
+    /// Called periodically with the path, RTT and traffic of each connection to a Gateway.
+    fn on_update_connection_stats(&self, _: Vec<GatewayConnectionView>) {}
+
     /// Called when the tunnel is disconnected.
     fn on_disconnect(&self, _: DisconnectError) {}
 }
@@ -107,6 +110,14 @@ where
         });
     }
 
+    fn on_update_connection_stats(&self, connections: Vec<GatewayConnectionView>) {
+        let callbacks = self.inner.clone();
+
+        self.threadpool.spawn(move || {
+            callbacks.on_update_connection_stats(connections);
+        });
+    }
+
     fn on_disconnect(&self, error: DisconnectError) {
         let callbacks = self.inner.clone();
 
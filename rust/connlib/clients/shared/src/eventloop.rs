@@ -6,10 +6,10 @@ use firezone_tunnel::messages::client::{
     EgressMessages, FailReason, FlowCreated, FlowCreationFailed, GatewayIceCandidates,
     GatewaysIceCandidates, IngressMessages, InitClient,
 };
-use firezone_tunnel::messages::RelaysPresence;
-use firezone_tunnel::ClientTunnel;
+use firezone_tunnel::messages::{RelaysPresence, SplitDnsRule};
+use firezone_tunnel::{CandidatePolicy, ClientTunnel};
 use phoenix_channel::{ErrorReply, OutboundRequestId, PhoenixChannel, PublicKeyParam};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 use std::{
     collections::BTreeSet,
     io,
@@ -18,12 +18,17 @@ use std::{
 };
 use tun::Tun;
 
+/// How often we report connection statistics to the [`Callbacks`].
+const CONNECTION_STATS_INTERVAL: Duration = Duration::from_secs(5);
+
 pub struct Eventloop<C: Callbacks> {
     tunnel: ClientTunnel,
     callbacks: C,
 
     portal: PhoenixChannel<(), IngressMessages, (), PublicKeyParam>,
     rx: tokio::sync::mpsc::UnboundedReceiver<Command>,
+
+    connection_stats_interval: tokio::time::Interval,
 }
 
 /// Commands that can be sent to the [`Eventloop`].
@@ -31,8 +36,12 @@ pub enum Command {
     Stop,
     Reset,
     SetDns(Vec<IpAddr>),
+    SetSplitDns(Vec<SplitDnsRule>),
     SetTun(Box<dyn Tun>),
     SetDisabledResources(BTreeSet<ResourceId>),
+    SetCandidatePolicy(CandidatePolicy),
+    StartPacketCapture { file: std::fs::File, max_size: u64 },
+    StopPacketCapture,
 }
 
 impl<C: Callbacks> Eventloop<C> {
@@ -44,11 +53,15 @@ impl<C: Callbacks> Eventloop<C> {
     ) -> Self {
         portal.connect(PublicKeyParam(tunnel.public_key().to_bytes()));
 
+        let mut connection_stats_interval = tokio::time::interval(CONNECTION_STATS_INTERVAL);
+        connection_stats_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
         Self {
             tunnel,
             portal,
             rx,
             callbacks,
+            connection_stats_interval,
         }
     }
 }
@@ -66,10 +79,28 @@ where
 
                     continue;
                 }
+                Poll::Ready(Some(Command::SetSplitDns(rules))) => {
+                    self.tunnel.state_mut().set_split_dns_rules(rules);
+                    continue;
+                }
                 Poll::Ready(Some(Command::SetDisabledResources(resources))) => {
                     self.tunnel.state_mut().set_disabled_resources(resources);
                     continue;
                 }
+                Poll::Ready(Some(Command::SetCandidatePolicy(policy))) => {
+                    self.tunnel.state_mut().set_candidate_policy(policy);
+                    continue;
+                }
+                Poll::Ready(Some(Command::StartPacketCapture { file, max_size })) => {
+                    if let Err(e) = self.tunnel.start_packet_capture(file, max_size) {
+                        tracing::warn!("Failed to start packet capture: {}", err_with_src(&e));
+                    }
+                    continue;
+                }
+                Poll::Ready(Some(Command::StopPacketCapture)) => {
+                    self.tunnel.stop_packet_capture();
+                    continue;
+                }
                 Poll::Ready(Some(Command::SetTun(tun))) => {
                     self.tunnel.set_tun(tun);
                     continue;
@@ -116,6 +147,13 @@ where
                 Poll::Pending => {}
             }
 
+            if self.connection_stats_interval.poll_tick(cx).is_ready() {
+                let connections = self.tunnel.state_mut().connection_stats(Instant::now());
+                self.callbacks.on_update_connection_stats(connections);
+
+                continue;
+            }
+
             return Poll::Pending;
         }
     }
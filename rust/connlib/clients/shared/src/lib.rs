@@ -5,6 +5,8 @@ pub use callbacks::{Callbacks, DisconnectError};
 pub use connlib_model::StaticSecret;
 pub use eventloop::Eventloop;
 pub use firezone_tunnel::messages::client::{IngressMessages, ResourceDescription};
+pub use firezone_tunnel::messages::SplitDnsRule;
+pub use firezone_tunnel::CandidatePolicy;
 
 use connlib_model::ResourceId;
 use eventloop::Command;
@@ -93,12 +95,37 @@ impl Session {
         let _ = self.channel.send(Command::SetDns(new_dns));
     }
 
+    /// Sets the locally configured split DNS rules for this [`Session`].
+    ///
+    /// These are applied on top of the rules from the portal and win if both have a rule for the same domain.
+    pub fn set_split_dns(&self, rules: Vec<SplitDnsRule>) {
+        let _ = self.channel.send(Command::SetSplitDns(rules));
+    }
+
     pub fn set_disabled_resources(&self, disabled_resources: BTreeSet<ResourceId>) {
         let _ = self
             .channel
             .send(Command::SetDisabledResources(disabled_resources));
     }
 
+    /// Restricts which kinds of candidates are used for new connections to Gateways.
+    pub fn set_candidate_policy(&self, policy: CandidatePolicy) {
+        let _ = self.channel.send(Command::SetCandidatePolicy(policy));
+    }
+
+    /// Writes the traffic of the tunnel to `file` in the pcapng format, until it reaches `max_size` bytes.
+    ///
+    /// Replaces a capture that is already running.
+    pub fn start_packet_capture(&self, file: std::fs::File, max_size: u64) {
+        let _ = self
+            .channel
+            .send(Command::StartPacketCapture { file, max_size });
+    }
+
+    pub fn stop_packet_capture(&self) {
+        let _ = self.channel.send(Command::StopPacketCapture);
+    }
+
     /// Sets a new [`Tun`] device handle.
     pub fn set_tun(&self, new_tun: Box<dyn Tun>) {
         let _ = self.channel.send(Command::SetTun(new_tun));
@@ -3,9 +3,12 @@ use ip_network::IpNetwork;
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
 use std::fmt::Debug;
+use std::net::SocketAddr;
+use std::time::Duration;
 
 use crate::ResourceId;
 use crate::Site;
+use crate::{GatewayId, RelayId};
 
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub enum ResourceStatus {
@@ -134,6 +137,51 @@ pub struct InternetResourceView {
     pub status: ResourceStatus,
 }
 
+/// Description of the connection to a Gateway.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct GatewayConnectionView {
+    pub gateway_id: GatewayId,
+    /// The path our traffic takes to the Gateway, `None` whilst we are still connecting.
+    pub path: Option<ConnectionPathView>,
+    /// Round-trip time to the Gateway.
+    pub rtt: Option<Duration>,
+    /// Time since the last WireGuard handshake with the Gateway.
+    pub handshake_age: Option<Duration>,
+    pub tx_bytes: u64,
+    pub rx_bytes: u64,
+}
+
+/// The candidate pair that was selected for a connection.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct ConnectionPathView {
+    pub local: SocketAddr,
+    pub local_type: CandidateType,
+    pub remote: SocketAddr,
+    pub remote_type: CandidateType,
+    /// The relay our traffic flows through, if any.
+    pub relay: Option<RelayId>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CandidateType {
+    Host,
+    ServerReflexive,
+    PeerReflexive,
+    Relayed,
+}
+
+impl fmt::Display for CandidateType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CandidateType::Host => write!(f, "host"),
+            CandidateType::ServerReflexive => write!(f, "srflx"),
+            CandidateType::PeerReflexive => write!(f, "prflx"),
+            CandidateType::Relayed => write!(f, "relay"),
+        }
+    }
+}
+
 impl PartialOrd for ResourceView {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         Some(self.cmp(other))
@@ -1,6 +1,6 @@
 use crate::{
     backoff::{self, ExponentialBackoff},
-    node::{SessionId, Transmit},
+    node::{SessionId, Transmit, Transport},
 };
 use bytecodec::{DecodeExt as _, EncodeExt as _};
 use firezone_logging::err_with_src;
@@ -10,7 +10,7 @@ use ringbuffer::{AllocRingBuffer, RingBuffer as _};
 use std::{
     collections::{BTreeMap, VecDeque},
     iter,
-    net::{SocketAddr, SocketAddrV4, SocketAddrV6},
+    net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
     time::{Duration, Instant},
 };
 use str0m::{net::Protocol, Candidate};
@@ -87,6 +87,11 @@ pub struct Allocation {
 
     credentials: Option<Credentials>,
 
+    /// The transport we are currently using to talk to the relay.
+    transport: Transport,
+    /// Whether we should retry via TCP and then TLS if the relay does not respond to any of our BINDING requests via UDP.
+    tcp_fallback: bool,
+
     explicit_failure: Option<FreeReason>,
 }
 
@@ -149,6 +154,14 @@ impl RelaySocket {
 
         matches_v4 || matches_v6
     }
+
+    /// Whether the relay uses the given IP, regardless of the port.
+    pub fn matches_ip(&self, ip: IpAddr) -> bool {
+        let matches_v4 = self.as_v4().is_some_and(|v4| IpAddr::V4(*v4.ip()) == ip);
+        let matches_v6 = self.as_v6().is_some_and(|v6| IpAddr::V6(*v6.ip()) == ip);
+
+        matches_v4 || matches_v6
+    }
 }
 
 impl From<SocketAddr> for RelaySocket {
@@ -234,6 +247,8 @@ impl Allocation {
             buffered_channel_bindings: AllocRingBuffer::new(100),
             software: Software::new(format!("snownet; session={session_id}"))
                 .expect("description has less then 128 chars"),
+            transport: Transport::Udp,
+            tcp_fallback: false,
             explicit_failure: Default::default(),
         };
 
@@ -242,6 +257,13 @@ impl Allocation {
         allocation
     }
 
+    /// Allow this [`Allocation`] to fall back to TCP and then TLS in case the relay is unreachable via UDP.
+    pub fn with_tcp_fallback(mut self, enabled: bool) -> Self {
+        self.tcp_fallback = enabled;
+
+        self
+    }
+
     pub fn current_relay_candidates(&self) -> impl Iterator<Item = Candidate> {
         [self.ip4_allocation.clone(), self.ip6_allocation.clone()]
             .into_iter()
@@ -262,6 +284,7 @@ impl Allocation {
             tracing::debug!("Attempting to make a new allocation");
 
             self.active_socket = None;
+            self.transport = Transport::Udp; // We might be on a different network now, always start with UDP again.
             self.send_binding_requests(now);
             return;
         }
@@ -493,14 +516,19 @@ impl Allocation {
         match message.method() {
             BINDING => {
                 // First, process the binding request itself.
-                let current_srflx_candidate = match original_dst {
-                    SocketAddr::V4(_) => &mut self.ip4_srflx_candidate,
-                    SocketAddr::V6(_) => &mut self.ip6_srflx_candidate,
-                };
+                // The address observed on a TCP or TLS connection is useless as a UDP server-reflexive candidate.
+                if self.transport == Transport::Udp {
+                    let current_srflx_candidate = match original_dst {
+                        SocketAddr::V4(_) => &mut self.ip4_srflx_candidate,
+                        SocketAddr::V6(_) => &mut self.ip6_srflx_candidate,
+                    };
 
-                let maybe_candidate = message.attributes().find_map(|a| srflx_candidate(local, a));
-                if update_candidate(maybe_candidate, current_srflx_candidate, &mut self.events) {
-                    self.log_update(now);
+                    let maybe_candidate =
+                        message.attributes().find_map(|a| srflx_candidate(local, a));
+                    if update_candidate(maybe_candidate, current_srflx_candidate, &mut self.events)
+                    {
+                        self.log_update(now);
+                    }
                 }
 
                 // Second, check if we have already determined which socket to use for this relay.
@@ -713,6 +741,16 @@ impl Allocation {
             backoff.handle_timeout(now);
         }
 
+        // If none of our BINDING requests made it to the relay, the current transport is likely blocked on this network.
+        if self.tcp_fallback && !self.received_any_response() && self.sent_requests.is_empty() {
+            if let Some(fallback) = self.transport.fallback() {
+                tracing::info!(relay_socket = ?self.server, "Relay did not respond via {:?}, falling back to {fallback:?}", self.transport);
+
+                self.transport = fallback;
+                self.send_binding_requests(now);
+            }
+        }
+
         if let Some(refresh_at) = self.refresh_allocation_at() {
             if (now >= refresh_at) && !self.refresh_in_flight() {
                 tracing::debug!("Allocation is due for a refresh");
@@ -842,6 +880,7 @@ impl Allocation {
 
         Some(EncodeOk {
             socket: active_socket,
+            transport: self.transport,
         })
     }
 
@@ -942,6 +981,10 @@ impl Allocation {
         self.server
     }
 
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
     pub fn ip4_socket(&self) -> Option<Socket> {
         let address = self.ip4_allocation.as_ref().map(|c| c.addr())?;
 
@@ -1086,6 +1129,7 @@ impl Allocation {
             src: None,
             dst,
             payload: encode(message).into(),
+            transport: self.transport,
         });
 
         true
@@ -1123,6 +1167,7 @@ impl Allocation {
 
 pub struct EncodeOk {
     pub socket: SocketAddr,
+    pub transport: Transport,
 }
 
 impl ActiveSocket {
@@ -1965,6 +2010,74 @@ mod tests {
         assert!(expected_backoffs.is_empty())
     }
 
+    #[test]
+    fn falls_back_to_tcp_if_relay_does_not_respond_via_udp() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test_ip4(start).with_tcp_fallback(true);
+
+        let binding = allocation.poll_transmit().unwrap();
+        assert_eq!(binding.transport, Transport::Udp);
+
+        while let Some(timeout) = allocation.poll_timeout() {
+            allocation.handle_timeout(timeout);
+
+            let transmit = allocation.poll_transmit().unwrap();
+
+            if transmit.transport == Transport::Tcp {
+                assert_eq!(
+                    decode(&transmit.payload).unwrap().unwrap().method(),
+                    BINDING
+                );
+                return;
+            }
+        }
+
+        panic!("Allocation did not fall back to TCP");
+    }
+
+    #[test]
+    fn falls_back_to_tls_if_relay_does_not_respond_via_tcp() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test_ip4(start).with_tcp_fallback(true);
+
+        while let Some(timeout) = allocation.poll_timeout() {
+            allocation.handle_timeout(timeout);
+
+            let transmit = allocation.poll_transmit().unwrap();
+
+            if transmit.transport == Transport::Tls {
+                assert_eq!(
+                    decode(&transmit.payload).unwrap().unwrap().method(),
+                    BINDING
+                );
+                return;
+            }
+        }
+
+        panic!("Allocation did not fall back to TLS");
+    }
+
+    #[test]
+    fn does_not_emit_srflx_candidate_for_tcp_binding() {
+        let start = Instant::now();
+        let mut allocation = Allocation::for_test_ip4(start).with_tcp_fallback(true);
+
+        while allocation.transport() == Transport::Udp {
+            let timeout = allocation.poll_timeout().unwrap();
+            allocation.handle_timeout(timeout);
+        }
+
+        let binding = iter::from_fn(|| allocation.poll_transmit()).last().unwrap();
+        allocation.handle_test_input_ip4(
+            &binding_response(&decode(&binding.payload).unwrap().unwrap(), PEER1),
+            start,
+        );
+
+        assert!(iter::from_fn(|| allocation.poll_event())
+            .collect::<Vec<_>>()
+            .is_empty());
+    }
+
     #[test]
     fn given_no_ip6_allocation_does_not_attempt_to_bind_channel_to_ip6_address() {
         let mut allocation = Allocation::for_test_ip4(Instant::now())
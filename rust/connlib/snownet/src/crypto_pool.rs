@@ -0,0 +1,78 @@
+//! Spreads the per-packet WireGuard crypto of a batch across several cores.
+
+use rayon::iter::{IntoParallelRefMutIterator as _, ParallelIterator as _};
+
+/// A pool of threads for encrypting and decrypting packets.
+///
+/// Without threads, all work happens inline on the calling thread.
+#[derive(Default)]
+pub(crate) struct CryptoPool {
+    pool: Option<rayon::ThreadPool>,
+}
+
+impl CryptoPool {
+    pub(crate) fn new(num_threads: usize) -> Result<Self, rayon::ThreadPoolBuildError> {
+        if num_threads <= 1 {
+            return Ok(Self::default());
+        }
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(num_threads)
+            .thread_name(|i| format!("wg-crypto-{i}"))
+            .build()?;
+
+        Ok(Self { pool: Some(pool) })
+    }
+
+    /// Calls `f` on every group and returns once all of them are done.
+    ///
+    /// Groups may run in parallel but each group is handled by a single thread.
+    /// Use one group per connection to keep its packets in order.
+    pub(crate) fn for_each<G>(&self, groups: &mut [G], f: impl Fn(&mut G) + Send + Sync)
+    where
+        G: Send,
+    {
+        match &self.pool {
+            Some(pool) if groups.len() > 1 => pool.install(|| groups.par_iter_mut().for_each(f)),
+            Some(_) | None => groups.iter_mut().for_each(f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread::ThreadId;
+
+    #[test]
+    fn handles_each_group_on_one_thread_in_order() {
+        let pool = CryptoPool::new(4).unwrap();
+        let mut groups = (0..8)
+            .map(|_| (Vec::new(), Vec::<ThreadId>::new()))
+            .collect::<Vec<_>>();
+
+        pool.for_each(&mut groups, |(items, threads)| {
+            for i in 0..100 {
+                items.push(i);
+                threads.push(std::thread::current().id());
+            }
+        });
+
+        for (items, threads) in groups {
+            assert_eq!(items, (0..100).collect::<Vec<_>>());
+            assert!(threads.iter().all(|t| *t == threads[0]));
+        }
+    }
+
+    #[test]
+    fn runs_inline_without_threads() {
+        let pool = CryptoPool::new(1).unwrap();
+        let mut groups = vec![None, None];
+
+        pool.for_each(&mut groups, |thread| {
+            *thread = Some(std::thread::current().id());
+        });
+
+        assert_eq!(groups, vec![Some(std::thread::current().id()); 2]);
+    }
+}
@@ -7,16 +7,18 @@ mod allocation;
 mod backoff;
 mod candidate_set;
 mod channel_data;
+mod crypto_pool;
 mod index;
 mod node;
 mod stats;
 mod utils;
 
 pub use allocation::RelaySocket;
+pub use connlib_model::CandidateType;
 #[allow(deprecated)] // Rust bug: `expect` doesn't seem to work on imports?
 pub use node::{Answer, Offer};
 pub use node::{
-    Client, ClientNode, Credentials, EncryptedPacket, Error, Event, NoTurnServers, Node, Server,
-    ServerNode, Transmit, HANDSHAKE_TIMEOUT,
+    CandidatePolicy, Client, ClientNode, Credentials, EncryptedPacket, Error, Event, NoTurnServers,
+    Node, Server, ServerNode, Transmit, Transport, HANDSHAKE_TIMEOUT,
 };
-pub use stats::{ConnectionStats, NodeStats};
+pub use stats::{CandidatePair, ConnectionStats, HumanBytes, NodeStats};
@@ -1,12 +1,14 @@
 use crate::allocation::{self, Allocation, RelaySocket, Socket};
 use crate::candidate_set::CandidateSet;
+use crate::crypto_pool::CryptoPool;
 use crate::index::IndexLfsr;
-use crate::stats::{ConnectionStats, NodeStats};
+use crate::stats::{CandidatePair, ConnectionStats, NodeStats};
 use crate::utils::{channel_data_packet_buffer, earliest};
 use boringtun::noise::errors::WireGuardError;
 use boringtun::noise::{Tunn, TunnResult};
 use boringtun::x25519::PublicKey;
 use boringtun::{noise::rate_limiter::RateLimiter, x25519::StaticSecret};
+use connlib_model::CandidateType;
 use core::fmt;
 use firezone_logging::err_with_src;
 use hex_display::HexDisplayExt;
@@ -22,6 +24,7 @@ use std::collections::btree_map::Entry;
 use std::collections::{BTreeMap, BTreeSet};
 use std::hash::Hash;
 use std::mem;
+use std::net::{Ipv4Addr, Ipv6Addr};
 use std::ops::ControlFlow;
 use std::time::{Duration, Instant};
 use std::{collections::VecDeque, net::SocketAddr, sync::Arc};
@@ -40,6 +43,16 @@ const CANDIDATE_TIMEOUT: Duration = Duration::from_secs(10);
 /// How long we will at most wait for an [`Answer`] from the remote.
 pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(20);
 
+/// How long we wait for any packet on the nominated path whilst sending data before we consider the path dead and fail over to another one.
+///
+/// Whilst connected, the [`IceAgent`] sends STUN binding requests on the nominated pair at least every 1.5s (see [`apply_default_stun_timings`]) which the peer must answer.
+const DEAD_PATH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long we wait before we hand our candidate of a dead path back to ICE.
+///
+/// The connectivity checks on its pairs then tell us whether it works again, e.g. because the interface came back, in which case ICE may nominate it again.
+const DEAD_CANDIDATE_RETRY_AFTER: Duration = Duration::from_secs(30);
+
 /// Manages a set of wireguard connections for a server.
 pub type ServerNode<TId, RId> = Node<Server, TId, RId>;
 /// Manages a set of wireguard connections for a client.
@@ -123,6 +136,9 @@ pub struct Node<T, TId, RId> {
     next_rate_limiter_reset: Option<Instant>,
 
     allocations: BTreeMap<RId, Allocation>,
+    /// Whether new [`Allocation`]s may fall back to TCP and TLS if a relay is unreachable via UDP.
+    relay_tcp_fallback: bool,
+    candidate_policy: CandidatePolicy,
 
     connections: Connections<TId, RId>,
     pending_events: VecDeque<Event<TId>>,
@@ -131,11 +147,44 @@ pub struct Node<T, TId, RId> {
     // All access to [`Node`] happens in the same thread, so we should never get contention which makes a spinlock ideal.
     // This is wrapped in an `Arc` so we can use `pull_owned`.
     buffer_pool: Arc<lockfree_object_pool::SpinLockObjectPool<Vec<u8>>>,
+    crypto_pool: CryptoPool,
 
     mode: T,
     rng: StdRng,
 }
 
+/// Which kinds of candidates a [`Node`] may use for its connections.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum CandidatePolicy {
+    /// Use host, server-reflexive and relay candidates.
+    #[default]
+    All,
+    /// Never relay traffic, neither via our relays nor via those of the remote.
+    DirectOnly,
+    /// Only use relay candidates, forcing all traffic through our relays.
+    RelayOnly,
+}
+
+impl CandidatePolicy {
+    fn allows_local(&self, kind: CandidateKind) -> bool {
+        let is_relayed = kind == CandidateKind::Relayed;
+
+        match self {
+            CandidatePolicy::All => true,
+            CandidatePolicy::DirectOnly => !is_relayed,
+            CandidatePolicy::RelayOnly => is_relayed,
+        }
+    }
+
+    /// Sending to a remote's relay candidate relays our traffic, even if we don't use relay candidates ourselves.
+    fn allows_remote(&self, kind: CandidateKind) -> bool {
+        match self {
+            CandidatePolicy::All | CandidatePolicy::RelayOnly => true,
+            CandidatePolicy::DirectOnly => kind != CandidateKind::Relayed,
+        }
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error("Unknown interface")]
@@ -182,12 +231,15 @@ where
             next_rate_limiter_reset: None,
             pending_events: VecDeque::default(),
             allocations: Default::default(),
+            relay_tcp_fallback: false,
+            candidate_policy: CandidatePolicy::default(),
             connections: Default::default(),
             stats: Default::default(),
             buffer_pool: Arc::new(lockfree_object_pool::SpinLockObjectPool::new(
                 || vec![0; ip_packet::MAX_FZ_PAYLOAD],
                 |v| v.fill(0),
             )),
+            crypto_pool: CryptoPool::default(),
         }
     }
 
@@ -303,6 +355,21 @@ where
         self.public_key
     }
 
+    /// Allow allocations to fall back to TCP and then TLS in case a relay is unreachable via UDP.
+    ///
+    /// Only affects relays added after this call.
+    /// Upper layers MUST be able to send [`Transmit`]s with [`Transport::Tcp`] and [`Transport::Tls`] if this is enabled.
+    pub fn set_relay_tcp_fallback(&mut self, enabled: bool) {
+        self.relay_tcp_fallback = enabled;
+    }
+
+    /// Restrict which kinds of candidates are used for connections.
+    ///
+    /// Existing connections keep the candidates they already have.
+    pub fn set_candidate_policy(&mut self, policy: CandidatePolicy) {
+        self.candidate_policy = policy;
+    }
+
     pub fn connection_id(&self, key: PublicKey, now: Instant) -> Option<TId> {
         self.connections.iter_established().find_map(|(id, c)| {
             (c.remote_pub_key == key && c.tunnel.time_since_last_handshake_at(now).is_some())
@@ -310,8 +377,14 @@ where
         })
     }
 
-    pub fn stats(&self) -> (NodeStats, impl Iterator<Item = (TId, ConnectionStats)> + '_) {
-        (self.stats, self.connections.stats())
+    pub fn stats(
+        &self,
+        now: Instant,
+    ) -> (
+        NodeStats,
+        impl Iterator<Item = (TId, ConnectionStats<RId>)> + '_,
+    ) {
+        (self.stats, self.connections.stats(now))
     }
 
     /// Add an address as a `host` candidate.
@@ -353,6 +426,20 @@ where
             }
         };
 
+        if candidate.kind() == CandidateKind::Relayed {
+            // Remember the remote's relays even if we ignore them, traffic arriving through them is relayed.
+            self.connections
+                .remote_relay_candidates
+                .entry(cid)
+                .or_default()
+                .insert(candidate.addr());
+        }
+
+        if !self.candidate_policy.allows_remote(candidate.kind()) {
+            tracing::debug!(ignored_candidate = %candidate, policy = ?self.candidate_policy, "Candidate is not allowed by policy");
+            return;
+        }
+
         let Some((agent, relay)) = self.connections.connecting_agent_mut(cid) else {
             tracing::debug!(ignored_candidate = %candidate, "Unknown connection or socket has already been nominated");
             return;
@@ -371,6 +458,11 @@ where
             | CandidateKind::PeerReflexive => {}
         }
 
+        // We never send via our relay, so binding a channel is pointless.
+        if self.candidate_policy == CandidatePolicy::DirectOnly {
+            return;
+        }
+
         let Some(allocation) = relay.and_then(|r| self.allocations.get_mut(&r)) else {
             tracing::debug!(rid = ?relay, "Unknown relay");
             return;
@@ -389,6 +481,10 @@ where
             }
         };
 
+        if let Some(relays) = self.connections.remote_relay_candidates.get_mut(&cid) {
+            relays.remove(&candidate.addr());
+        }
+
         if let Some(agent) = self.connections.agent_mut(cid) {
             agent.invalidate_candidate(&candidate);
             agent.handle_timeout(now); // We may have invalidated the last candidate, ensure we check our nomination state.
@@ -409,6 +505,159 @@ where
         packet: &[u8],
         now: Instant,
     ) -> Result<Option<(TId, IpPacket)>, Error> {
+        let Some((cid, mut decryption)) = self.prepare_decapsulate(local, from, packet, now)?
+        else {
+            return Ok(None);
+        };
+        let handshake_complete_before = decryption.handshake_complete_before;
+
+        let conn = self
+            .connections
+            .get_established_mut(&cid)
+            .ok_or(Error::NotConnected)?;
+        decryption.run(&mut conn.tunnel, now);
+
+        let result = self.finish_decapsulate(cid, decryption, now);
+        self.on_decapsulated(cid, handshake_complete_before, now);
+
+        result
+    }
+
+    /// Decapsulate a batch of incoming packets, given as `(local, from, packet)`.
+    ///
+    /// The decryption is spread across the threads configured via [`Node::set_crypto_threads`].
+    /// Packets of the same connection are decrypted in order by a single thread.
+    ///
+    /// Returns one result per packet, in the same order and with the same meaning as [`Node::decapsulate`].
+    pub fn decapsulate_batch<'p>(
+        &mut self,
+        packets: impl IntoIterator<Item = (SocketAddr, SocketAddr, &'p [u8])>,
+        now: Instant,
+    ) -> Vec<Result<Option<(TId, IpPacket)>, Error>>
+    where
+        TId: Send,
+    {
+        let mut results = Vec::new();
+        let mut decryptions = BTreeMap::<TId, Vec<(usize, Decryption<'p>)>>::new();
+
+        for (index, (local, from, packet)) in packets.into_iter().enumerate() {
+            match self.prepare_decapsulate(local, from, packet, now) {
+                Ok(Some((cid, decryption))) => {
+                    decryptions
+                        .entry(cid)
+                        .or_default()
+                        .push((index, decryption));
+                    results.push(Ok(None));
+                }
+                Ok(None) => results.push(Ok(None)),
+                Err(e) => results.push(Err(e)),
+            }
+        }
+
+        let decrypted = self.run_crypto(decryptions, |tunnel, decryption| {
+            decryption.run(tunnel, now)
+        });
+
+        for (cid, decryptions) in decrypted {
+            // All packets of a connection were prepared before any of them were decrypted.
+            let handshake_complete_before = decryptions
+                .first()
+                .is_some_and(|(_, d)| d.handshake_complete_before);
+
+            for (index, decryption) in decryptions {
+                results[index] = self.finish_decapsulate(cid, decryption, now);
+            }
+
+            self.on_decapsulated(cid, handshake_complete_before, now);
+        }
+
+        results
+    }
+
+    /// Encapsulate an outgoing IP packet.
+    ///
+    /// Wireguard is an IP tunnel, so we "enforce" that only IP packets are sent through it.
+    /// We say "enforce" an [`IpPacket`] can be created from an (almost) arbitrary byte buffer at virtually no cost.
+    /// Nevertheless, using [`IpPacket`] in our API has good documentation value.
+    pub fn encapsulate(
+        &mut self,
+        connection: TId,
+        packet: IpPacket,
+        now: Instant,
+    ) -> Result<Option<EncryptedPacket>, Error> {
+        let Some(mut encryption) = self.prepare_encapsulate(connection, packet, now)? else {
+            return Ok(None);
+        };
+
+        let conn = self
+            .connections
+            .get_established_mut(&connection)
+            .ok_or(Error::NotConnected)?;
+        encryption.run(&mut conn.tunnel, now);
+
+        self.finish_encapsulate(connection, encryption, now)
+    }
+
+    /// Encapsulate a batch of outgoing IP packets.
+    ///
+    /// The encryption is spread across the threads configured via [`Node::set_crypto_threads`].
+    /// Packets of the same connection are encrypted in order by a single thread.
+    ///
+    /// Returns one result per packet, in the same order and with the same meaning as [`Node::encapsulate`].
+    pub fn encapsulate_batch(
+        &mut self,
+        packets: impl IntoIterator<Item = (TId, IpPacket)>,
+        now: Instant,
+    ) -> Vec<Result<Option<EncryptedPacket>, Error>>
+    where
+        TId: Send,
+    {
+        let mut results = Vec::new();
+        let mut encryptions = BTreeMap::<TId, Vec<(usize, Encryption)>>::new();
+
+        for (index, (cid, packet)) in packets.into_iter().enumerate() {
+            match self.prepare_encapsulate(cid, packet, now) {
+                Ok(Some(encryption)) => {
+                    encryptions
+                        .entry(cid)
+                        .or_default()
+                        .push((index, encryption));
+                    results.push(Ok(None));
+                }
+                Ok(None) => results.push(Ok(None)),
+                Err(e) => results.push(Err(e)),
+            }
+        }
+
+        let encrypted = self.run_crypto(encryptions, |tunnel, encryption| {
+            encryption.run(tunnel, now)
+        });
+
+        for (cid, encryptions) in encrypted {
+            for (index, encryption) in encryptions {
+                results[index] = self.finish_encapsulate(cid, encryption, now);
+            }
+        }
+
+        results
+    }
+
+    /// Encrypt and decrypt the packets of different connections in [`Node::encapsulate_batch`] and [`Node::decapsulate_batch`] on this many threads.
+    ///
+    /// With a single thread (the default), all crypto happens on the calling thread.
+    pub fn set_crypto_threads(&mut self, num_threads: usize) -> std::io::Result<()> {
+        self.crypto_pool = CryptoPool::new(num_threads).map_err(std::io::Error::other)?;
+
+        Ok(())
+    }
+
+    fn prepare_decapsulate<'p>(
+        &mut self,
+        local: SocketAddr,
+        from: SocketAddr,
+        packet: &'p [u8],
+        now: Instant,
+    ) -> Result<Option<(TId, Decryption<'p>)>, Error> {
         self.add_local_as_host_candidate(local)?;
 
         let (from, packet, relayed) = match self.allocations_try_handle(from, local, packet, now) {
@@ -425,26 +674,92 @@ where
             ControlFlow::Break(Err(e)) => return Err(e),
         };
 
-        let (id, packet) = match self.connections_try_handle(from, packet, now) {
-            ControlFlow::Continue(c) => c,
-            ControlFlow::Break(Ok(())) => return Ok(None),
-            ControlFlow::Break(Err(e)) => return Err(e),
+        match self.connections_try_handle(from, packet, now) {
+            ControlFlow::Continue(c) => Ok(Some(c)),
+            ControlFlow::Break(Ok(())) => Ok(None),
+            ControlFlow::Break(Err(e)) => Err(e),
+        }
+    }
+
+    fn finish_decapsulate(
+        &mut self,
+        cid: TId,
+        decryption: Decryption<'_>,
+        now: Instant,
+    ) -> Result<Option<(TId, IpPacket)>, Error> {
+        let conn = self
+            .connections
+            .get_established_mut(&cid)
+            .ok_or(Error::NotConnected)?;
+        let _guard = conn.span.enter();
+
+        let packet: IpPacket = match decryption.result {
+            Decrypted::Done => return Ok(None),
+            Decrypted::Failed(e) => return Err(Error::Decapsulate(e)),
+            Decrypted::Ipv4 { len, src } => {
+                let ipv4_packet = ConvertibleIpv4Packet::new(decryption.buffer, len)
+                    .expect("boringtun verifies validity");
+                debug_assert_eq!(ipv4_packet.get_source(), src);
+
+                ipv4_packet.into()
+            }
+            Decrypted::Ipv6 { len, src } => {
+                let ipv6_packet = ConvertibleIpv6Packet::new(decryption.buffer, len)
+                    .expect("boringtun verifies validity");
+                debug_assert_eq!(ipv6_packet.get_source(), src);
+
+                ipv6_packet.into()
+            }
+            Decrypted::ToNetwork(packets) => {
+                match &mut conn.state {
+                    ConnectionState::Connecting { buffered, .. } => {
+                        tracing::debug!("No socket has been nominated yet, buffering WG packet");
+
+                        for packet in packets {
+                            buffered.push(packet);
+                        }
+                    }
+                    ConnectionState::Connected { peer_socket, .. }
+                    | ConnectionState::Idle { peer_socket } => {
+                        let peer_socket = *peer_socket;
+
+                        self.buffered_transmits
+                            .extend(packets.iter().filter_map(|packet| {
+                                make_owned_transmit(peer_socket, packet, &mut self.allocations, now)
+                            }));
+                    }
+                    ConnectionState::Failed => {}
+                }
+
+                return Ok(None);
+            }
         };
 
-        Ok(Some((id, packet)))
+        conn.state.on_incoming(&mut conn.agent, &packet, now);
+
+        Ok(Some((cid, packet)))
     }
 
-    /// Encapsulate an outgoing IP packet.
-    ///
-    /// Wireguard is an IP tunnel, so we "enforce" that only IP packets are sent through it.
-    /// We say "enforce" an [`IpPacket`] can be created from an (almost) arbitrary byte buffer at virtually no cost.
-    /// Nevertheless, using [`IpPacket`] in our API has good documentation value.
-    pub fn encapsulate(
+    fn on_decapsulated(&mut self, cid: TId, handshake_complete_before: bool, now: Instant) {
+        let Some(conn) = self.connections.get_established_mut(&cid) else {
+            return;
+        };
+
+        // I can't think of a better way to detect this ...
+        if !handshake_complete_before && conn.wg_handshake_complete(now) {
+            tracing::info!(%cid, duration_since_intent = ?conn.duration_since_intent(now), "Completed wireguard handshake");
+
+            self.pending_events
+                .push_back(Event::ConnectionEstablished(cid))
+        }
+    }
+
+    fn prepare_encapsulate(
         &mut self,
         connection: TId,
         packet: IpPacket,
         now: Instant,
-    ) -> Result<Option<EncryptedPacket>, Error> {
+    ) -> Result<Option<Encryption>, Error> {
         let conn = self
             .connections
             .get_established_mut(&connection)
@@ -456,17 +771,32 @@ where
             return Ok(None);
         }
 
-        let mut buffer = self.buffer_pool.pull_owned();
+        conn.state.on_outgoing(&mut conn.agent, &packet, now);
 
-        // Encode the packet with an offset of 4 bytes, in case we need to wrap it in a channel-data message.
-        let Some(packet_len) = conn
-            .encapsulate(packet, &mut buffer[4..], now)?
-            .map(|p| p.len())
-        // Mapping to len() here terminate the mutable borrow of buffer, allowing re-borrowing further down.
-        else {
+        Ok(Some(Encryption {
+            packet,
+            buffer: self.buffer_pool.pull_owned(),
+            result: Ok(None),
+        }))
+    }
+
+    fn finish_encapsulate(
+        &mut self,
+        connection: TId,
+        encryption: Encryption,
+        now: Instant,
+    ) -> Result<Option<EncryptedPacket>, Error> {
+        let conn = self
+            .connections
+            .get_established_mut(&connection)
+            .ok_or(Error::NotConnected)?;
+
+        let Some(packet_len) = encryption.result.map_err(Error::Encapsulate)? else {
             return Ok(None);
         };
+        conn.stats.tx_bytes += packet_len;
 
+        let mut buffer = encryption.buffer;
         let packet_start = 4;
         let packet_end = 4 + packet_len;
 
@@ -497,6 +827,7 @@ where
             } => Ok(Some(EncryptedPacket {
                 src: Some(source),
                 dst: remote,
+                transport: Transport::Udp,
                 packet_start,
                 packet_len,
                 buffer,
@@ -516,6 +847,7 @@ where
                 Ok(Some(EncryptedPacket {
                     src: None,
                     dst: encode_ok.socket,
+                    transport: encode_ok.transport,
                     packet_start: 0,
                     packet_len: packet_end,
                     buffer,
@@ -524,6 +856,30 @@ where
         }
     }
 
+    /// Runs `crypto` on all jobs, in parallel across connections but in order within each connection.
+    fn run_crypto<J>(
+        &mut self,
+        jobs: BTreeMap<TId, Vec<(usize, J)>>,
+        crypto: impl Fn(&mut Tunn, &mut J) + Send + Sync,
+    ) -> Vec<(TId, Vec<(usize, J)>)>
+    where
+        TId: Send,
+        J: Send,
+    {
+        let mut groups = self.connections.established_tunnels_mut(jobs);
+
+        self.crypto_pool.for_each(&mut groups, |(_, tunnel, jobs)| {
+            for (_, job) in jobs.iter_mut() {
+                crypto(tunnel, job);
+            }
+        });
+
+        groups
+            .into_iter()
+            .map(|(cid, _, jobs)| (cid, jobs))
+            .collect()
+    }
+
     /// Returns a pending [`Event`] from the pool.
     #[must_use]
     pub fn poll_event(&mut self) -> Option<Event<TId>> {
@@ -575,7 +931,14 @@ where
         self.allocations_drain_events();
 
         for (id, connection) in self.connections.iter_established_mut() {
-            connection.handle_timeout(id, now, &mut self.allocations, &mut self.buffered_transmits);
+            connection.handle_timeout(
+                id,
+                now,
+                self.candidate_policy,
+                &mut self.allocations,
+                &mut self.buffered_transmits,
+                &mut self.pending_events,
+            );
         }
 
         for (id, connection) in self.connections.initial.iter_mut() {
@@ -666,14 +1029,17 @@ where
 
             match self.allocations.entry(*rid) {
                 Entry::Vacant(v) => {
-                    v.insert(Allocation::new(
-                        *server,
-                        username,
-                        password.clone(),
-                        realm,
-                        now,
-                        self.session_id.clone(),
-                    ));
+                    v.insert(
+                        Allocation::new(
+                            *server,
+                            username,
+                            password.clone(),
+                            realm,
+                            now,
+                            self.session_id.clone(),
+                        )
+                        .with_tcp_fallback(self.relay_tcp_fallback),
+                    );
 
                     tracing::info!(%rid, address = ?server, "Added new TURN server");
                 }
@@ -693,14 +1059,17 @@ where
                         &mut self.pending_events,
                     );
 
-                    o.insert(Allocation::new(
-                        *server,
-                        username,
-                        password.clone(),
-                        realm,
-                        now,
-                        self.session_id.clone(),
-                    ));
+                    o.insert(
+                        Allocation::new(
+                            *server,
+                            username,
+                            password.clone(),
+                            realm,
+                            now,
+                            self.session_id.clone(),
+                        )
+                        .with_tcp_fallback(self.relay_tcp_fallback),
+                    );
 
                     tracing::info!(%rid, address = ?server, "Replaced TURN server");
                 }
@@ -753,8 +1122,9 @@ where
                 now,
             ),
             next_wg_timer_update: now,
+            path_liveness: PathLiveness::new(now),
+            dead_candidates: Vec::new(),
             stats: Default::default(),
-            buffer: vec![0; ip_packet::MAX_FZ_PAYLOAD],
             intent_sent_at,
             signalling_completed_at: now,
             remote_pub_key: remote,
@@ -779,7 +1149,13 @@ where
         }
 
         for (cid, agent, _span) in self.connections.agents_mut() {
-            add_local_candidate(cid, agent, host_candidate.clone(), &mut self.pending_events);
+            add_local_candidate(
+                cid,
+                agent,
+                host_candidate.clone(),
+                self.candidate_policy,
+                &mut self.pending_events,
+            );
         }
 
         Ok(())
@@ -851,6 +1227,17 @@ where
         }
     }
 
+    /// Whether packets from this address arrive through a relay.
+    ///
+    /// Besides the relay candidates signalled by our remotes, this covers all addresses of our own relays as the remotes typically use the same ones.
+    fn is_relayed(&self, addr: SocketAddr) -> bool {
+        self.connections.is_remote_relay_candidate(addr)
+            || self
+                .allocations
+                .values()
+                .any(|a| a.server().matches_ip(addr.ip()))
+    }
+
     #[must_use]
     fn agents_try_handle(
         &mut self,
@@ -863,7 +1250,16 @@ where
             return ControlFlow::Continue(());
         };
 
-        for (_, agent, _span) in self.connections.agents_mut() {
+        // The agent would learn the relay as a peer-reflexive candidate and might nominate it, relaying our traffic after all.
+        if self.candidate_policy == CandidatePolicy::DirectOnly && self.is_relayed(from) {
+            tracing::trace!(%from, "Dropping STUN message that arrived through a relay");
+
+            return ControlFlow::Break(Ok(()));
+        }
+
+        let mut handled_by = None;
+
+        for (cid, agent, _span) in self.connections.agents_mut() {
             if agent.accepts_message(&message) {
                 agent.handle_packet(
                     now,
@@ -875,50 +1271,51 @@ where
                     },
                 );
 
-                return ControlFlow::Break(Ok(()));
+                handled_by = Some(cid);
+                break;
             }
         }
 
-        tracing::trace!("Packet was a STUN message but no agent handled it. Already disconnected?");
+        let Some(cid) = handled_by else {
+            tracing::trace!(
+                "Packet was a STUN message but no agent handled it. Already disconnected?"
+            );
+
+            return ControlFlow::Break(Ok(()));
+        };
+
+        // STUN traffic from the peer, like answers to our binding requests, tells us that the path is alive, even if no data flows back.
+        if let Some(conn) = self.connections.get_established_mut(&cid) {
+            conn.on_packet_from(from, now);
+        }
 
         ControlFlow::Break(Ok(()))
     }
 
     #[must_use]
-    fn connections_try_handle(
+    fn connections_try_handle<'p>(
         &mut self,
         from: SocketAddr,
-        packet: &[u8],
+        packet: &'p [u8],
         now: Instant,
-    ) -> ControlFlow<Result<(), Error>, (TId, IpPacket)> {
+    ) -> ControlFlow<Result<(), Error>, (TId, Decryption<'p>)> {
         for (cid, conn) in self.connections.iter_established_mut() {
             if !conn.accepts(&from) {
                 continue;
             }
 
-            let handshake_complete_before_decapsulate = conn.wg_handshake_complete(now);
-
-            let control_flow = conn.decapsulate(
-                packet,
-                &mut self.allocations,
-                &mut self.buffered_transmits,
-                now,
-            );
-
-            let handshake_complete_after_decapsulate = conn.wg_handshake_complete(now);
-
-            // I can't think of a better way to detect this ...
-            if !handshake_complete_before_decapsulate && handshake_complete_after_decapsulate {
-                tracing::info!(%cid, duration_since_intent = ?conn.duration_since_intent(now), "Completed wireguard handshake");
-
-                self.pending_events
-                    .push_back(Event::ConnectionEstablished(cid))
-            }
-
-            return match control_flow {
-                ControlFlow::Continue(c) => ControlFlow::Continue((cid, c)),
-                ControlFlow::Break(b) => ControlFlow::Break(b),
-            };
+            conn.on_packet_from(from, now);
+            conn.stats.rx_bytes += packet.len();
+
+            return ControlFlow::Continue((
+                cid,
+                Decryption {
+                    packet,
+                    buffer: IpPacketBuf::new(),
+                    handshake_complete_before: conn.wg_handshake_complete(now),
+                    result: Decrypted::Done,
+                },
+            ));
         }
 
         match Tunn::parse_incoming_packet(packet) {
@@ -948,7 +1345,13 @@ where
                 allocation::Event::New(candidate) => {
                     for (cid, agent, _span) in self.connections.connecting_agents_by_relay_mut(rid)
                     {
-                        add_local_candidate(cid, agent, candidate.clone(), &mut self.pending_events)
+                        add_local_candidate(
+                            cid,
+                            agent,
+                            candidate.clone(),
+                            self.candidate_policy,
+                            &mut self.pending_events,
+                        )
                     }
                 }
                 allocation::Event::Invalid(candidate) => {
@@ -1151,7 +1554,13 @@ where
         agent: &mut IceAgent,
     ) {
         for candidate in self.shared_candidates.iter().cloned() {
-            add_local_candidate(connection, agent, candidate, &mut self.pending_events);
+            add_local_candidate(
+                connection,
+                agent,
+                candidate,
+                self.candidate_policy,
+                &mut self.pending_events,
+            );
         }
 
         let Some(allocation) = self.allocations.get(&selected_relay) else {
@@ -1160,7 +1569,13 @@ where
         };
 
         for candidate in allocation.current_relay_candidates() {
-            add_local_candidate(connection, agent, candidate, &mut self.pending_events);
+            add_local_candidate(
+                connection,
+                agent,
+                candidate,
+                self.candidate_policy,
+                &mut self.pending_events,
+            );
         }
     }
 }
@@ -1168,6 +1583,8 @@ where
 struct Connections<TId, RId> {
     initial: BTreeMap<TId, InitialConnection<RId>>,
     established: BTreeMap<TId, Connection<RId>>,
+    /// The relay candidates signalled by the remote of each connection, including those our [`CandidatePolicy`] ignores.
+    remote_relay_candidates: BTreeMap<TId, BTreeSet<SocketAddr>>,
 }
 
 impl<TId, RId> Default for Connections<TId, RId> {
@@ -1175,6 +1592,7 @@ impl<TId, RId> Default for Connections<TId, RId> {
         Self {
             initial: Default::default(),
             established: Default::default(),
+            remote_relay_candidates: Default::default(),
         }
     }
 }
@@ -1202,6 +1620,9 @@ where
 
             true
         });
+
+        self.remote_relay_candidates
+            .retain(|id, _| self.initial.contains_key(id) || self.established.contains_key(id));
     }
 
     fn check_relays_available(
@@ -1265,8 +1686,10 @@ where
         }
     }
 
-    fn stats(&self) -> impl Iterator<Item = (TId, ConnectionStats)> + '_ {
-        self.established.iter().map(move |(id, c)| (*id, c.stats))
+    fn stats(&self, now: Instant) -> impl Iterator<Item = (TId, ConnectionStats<RId>)> + '_ {
+        self.established
+            .iter()
+            .map(move |(id, c)| (*id, c.stats(now)))
     }
 
     fn agent_mut(&mut self, id: TId) -> Option<&mut IceAgent> {
@@ -1331,6 +1754,31 @@ where
         self.established.get_mut(id)
     }
 
+    /// Pairs each of `jobs` with the [`Tunn`] of its established connection, dropping jobs of unknown connections.
+    fn established_tunnels_mut<J>(
+        &mut self,
+        mut jobs: BTreeMap<TId, J>,
+    ) -> Vec<(TId, &mut Tunn, J)> {
+        // Most batches only contain packets of a single connection, look it up directly.
+        if jobs.len() == 1 {
+            let Some((id, job)) = jobs.pop_first() else {
+                return Vec::new();
+            };
+
+            return self
+                .established
+                .get_mut(&id)
+                .map(|c| (id, &mut c.tunnel, job))
+                .into_iter()
+                .collect();
+        }
+
+        self.established
+            .iter_mut()
+            .filter_map(|(id, c)| Some((*id, &mut c.tunnel, jobs.remove(id)?)))
+            .collect()
+    }
+
     fn iter_initial_mut(&mut self) -> impl Iterator<Item = (TId, &mut InitialConnection<RId>)> {
         self.initial.iter_mut().map(|(id, conn)| (*id, conn))
     }
@@ -1350,6 +1798,13 @@ where
     fn clear(&mut self) {
         self.initial.clear();
         self.established.clear();
+        self.remote_relay_candidates.clear();
+    }
+
+    fn is_remote_relay_candidate(&self, addr: SocketAddr) -> bool {
+        self.remote_relay_candidates
+            .values()
+            .any(|relays| relays.contains(&addr))
     }
 
     fn iter_ids(&self) -> impl Iterator<Item = TId> + '_ {
@@ -1365,10 +1820,15 @@ fn add_local_candidate<TId>(
     id: TId,
     agent: &mut IceAgent,
     candidate: Candidate,
+    policy: CandidatePolicy,
     pending_events: &mut VecDeque<Event<TId>>,
 ) where
     TId: fmt::Display,
 {
+    if !policy.allows_local(candidate.kind()) {
+        return;
+    }
+
     // srflx candidates don't need to be added to the local agent because we always send from the `base` anyway.
     if candidate.kind() == CandidateKind::ServerReflexive {
         pending_events.push_back(Event::NewIceCandidate {
@@ -1458,6 +1918,15 @@ impl From<Credentials> for str0m::IceCreds {
     }
 }
 
+fn candidate_type(kind: CandidateKind) -> CandidateType {
+    match kind {
+        CandidateKind::Host => CandidateType::Host,
+        CandidateKind::ServerReflexive => CandidateType::ServerReflexive,
+        CandidateKind::PeerReflexive => CandidateType::PeerReflexive,
+        CandidateKind::Relayed => CandidateType::Relayed,
+    }
+}
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Event<TId> {
     /// We created a new candidate for this connection and ask to signal it to the remote party.
@@ -1485,11 +1954,17 @@ pub enum Event<TId> {
 
     /// We closed a connection (e.g. due to inactivity, roaming, etc).
     ConnectionClosed(TId),
+
+    /// ICE nominated a different path for an active connection, e.g. because the previous one died.
+    ///
+    /// The WireGuard session is not affected by this.
+    ConnectionMigrated(TId),
 }
 
 pub struct EncryptedPacket {
     pub(crate) src: Option<SocketAddr>,
     pub(crate) dst: SocketAddr,
+    pub(crate) transport: Transport,
     pub(crate) packet_start: usize,
     pub(crate) packet_len: usize,
     pub(crate) buffer: lockfree_object_pool::SpinLockOwnedReusable<Vec<u8>>,
@@ -1501,6 +1976,7 @@ impl EncryptedPacket {
             src: self.src,
             dst: self.dst,
             payload: Cow::Borrowed(self.payload()),
+            transport: self.transport,
         }
     }
 
@@ -1512,6 +1988,10 @@ impl EncryptedPacket {
         self.dst
     }
 
+    pub fn transport(&self) -> Transport {
+        self.transport
+    }
+
     pub fn payload(&self) -> &[u8] {
         &self.buffer[self.packet_start..(self.packet_start + self.packet_len)]
     }
@@ -1530,14 +2010,43 @@ pub struct Transmit<'a> {
     pub dst: SocketAddr,
     /// The data that should be sent.
     pub payload: Cow<'a, [u8]>,
+    /// The transport to use for sending this packet.
+    ///
+    /// This will always be [`Transport::Udp`] unless we had to fall back to TCP or TLS for talking to a relay.
+    pub transport: Transport,
 }
 
-impl fmt::Debug for Transmit<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Transmit")
-            .field("src", &self.src)
-            .field("dst", &self.dst)
+/// The transport protocol for a [`Transmit`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Transport {
+    #[default]
+    Udp,
+    /// STUN and channel-data messages framed on a TCP stream to a relay, see <https://www.rfc-editor.org/rfc/rfc8656#section-12.5>.
+    Tcp,
+    /// Like [`Transport::Tcp`] but wrapped in TLS and sent to the relay's HTTPS port.
+    ///
+    /// Networks that block UDP often only allow web traffic.
+    Tls,
+}
+
+impl Transport {
+    /// The transport to try next if a relay does not respond via this one.
+    pub(crate) fn fallback(&self) -> Option<Self> {
+        match self {
+            Transport::Udp => Some(Transport::Tcp),
+            Transport::Tcp => Some(Transport::Tls),
+            Transport::Tls => None,
+        }
+    }
+}
+
+impl fmt::Debug for Transmit<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transmit")
+            .field("src", &self.src)
+            .field("dst", &self.dst)
             .field("len", &self.payload.len())
+            .field("transport", &self.transport)
             .finish()
     }
 }
@@ -1548,6 +2057,7 @@ impl Transmit<'_> {
             src: self.src,
             dst: self.dst,
             payload: Cow::Owned(self.payload.into_owned()),
+            transport: self.transport,
         }
     }
 }
@@ -1606,16 +2116,18 @@ struct Connection<RId> {
     next_wg_timer_update: Instant,
 
     state: ConnectionState<RId>,
+    /// Whether the nominated path is still alive.
+    path_liveness: PathLiveness,
+    /// Our candidates of dead paths together with when we add them back to the [`IceAgent`].
+    dead_candidates: Vec<(Candidate, Instant)>,
 
     /// Socket addresses from which we might receive data (even before we are connected).
     possible_sockets: BTreeSet<SocketAddr>,
 
-    stats: ConnectionStats,
+    stats: ConnectionStats<RId>,
     intent_sent_at: Instant,
     signalling_completed_at: Instant,
 
-    buffer: Vec<u8>,
-
     span: tracing::Span,
 }
 
@@ -1745,6 +2257,35 @@ fn idle_at(last_incoming: Instant, last_outgoing: Instant) -> Instant {
     last_incoming.max(last_outgoing) + MAX_IDLE
 }
 
+/// Tracks whether we still hear from the peer on the nominated path.
+///
+/// ICE only fails a pair after all its STUN retransmissions failed, which takes a while.
+/// Whilst we are sending data, we already consider the path dead once neither answers to our STUN binding requests nor WireGuard packets arrive on it anymore.
+#[derive(Debug, Clone, Copy)]
+struct PathLiveness {
+    /// When we last received a packet on the nominated path.
+    last_seen: Instant,
+}
+
+impl PathLiveness {
+    fn new(now: Instant) -> Self {
+        Self { last_seen: now }
+    }
+
+    fn on_packet(&mut self, now: Instant) {
+        self.last_seen = now;
+    }
+
+    fn poll_timeout(&self, last_outgoing: Instant) -> Option<Instant> {
+        // No need to check the path if we haven't sent anything since we last heard from the peer.
+        if last_outgoing <= self.last_seen {
+            return None;
+        }
+
+        Some(self.last_seen + DEAD_PATH_TIMEOUT)
+    }
+}
+
 /// The socket of the peer we are connected to.
 #[derive(Debug, PartialEq, Clone, Copy)]
 enum PeerSocket<RId> {
@@ -1766,6 +2307,29 @@ enum PeerSocket<RId> {
     },
 }
 
+impl<RId> PeerSocket<RId>
+where
+    RId: Copy,
+{
+    fn dest(&self) -> SocketAddr {
+        match self {
+            PeerSocket::PeerToPeer { dest, .. }
+            | PeerSocket::PeerToRelay { dest, .. }
+            | PeerSocket::RelayToPeer { dest, .. }
+            | PeerSocket::RelayToRelay { dest, .. } => *dest,
+        }
+    }
+
+    fn relay(&self) -> Option<RId> {
+        match self {
+            PeerSocket::PeerToPeer { .. } | PeerSocket::PeerToRelay { .. } => None,
+            PeerSocket::RelayToPeer { relay, .. } | PeerSocket::RelayToRelay { relay, .. } => {
+                Some(*relay)
+            }
+        }
+    }
+}
+
 impl<RId> Connection<RId>
 where
     RId: PartialEq + Eq + Hash + fmt::Debug + Copy + Ord,
@@ -1791,10 +2355,26 @@ where
         from_nominated || self.possible_sockets.contains(addr)
     }
 
+    fn on_packet_from(&mut self, from: SocketAddr, now: Instant) {
+        if self.socket().is_some_and(|s| s.dest() == from) {
+            self.path_liveness.on_packet(now);
+        }
+    }
+
     fn wg_handshake_complete(&self, now: Instant) -> bool {
         self.tunnel.time_since_last_handshake_at(now).is_some()
     }
 
+    fn stats(&self, now: Instant) -> ConnectionStats<RId> {
+        let (_, _, _, _, rtt_ms) = self.tunnel.stats();
+
+        ConnectionStats {
+            rtt: rtt_ms.map(|ms| Duration::from_millis(ms as u64)),
+            wg_handshake_age: self.tunnel.time_since_last_handshake_at(now),
+            ..self.stats
+        }
+    }
+
     fn duration_since_intent(&self, now: Instant) -> Duration {
         now.duration_since(self.intent_sent_at)
     }
@@ -1805,13 +2385,27 @@ where
         let next_wg_timer = Some(self.next_wg_timer_update);
         let candidate_timeout = self.candidate_timeout();
         let idle_timeout = self.state.poll_timeout();
+        let path_timeout = earliest(self.path_timeout(), self.dead_candidate_timeout());
 
         earliest(
-            idle_timeout,
+            earliest(idle_timeout, path_timeout),
             earliest(agent_timeout, earliest(next_wg_timer, candidate_timeout)),
         )
     }
 
+    fn dead_candidate_timeout(&self) -> Option<Instant> {
+        self.dead_candidates.iter().map(|(_, at)| *at).min()
+    }
+
+    /// We only check the path whilst we are actively sending data.
+    fn path_timeout(&self) -> Option<Instant> {
+        let ConnectionState::Connected { last_outgoing, .. } = self.state else {
+            return None;
+        };
+
+        self.path_liveness.poll_timeout(last_outgoing)
+    }
+
     fn candidate_timeout(&self) -> Option<Instant> {
         if !self.agent.remote_candidates().is_empty() {
             return None;
@@ -1825,8 +2419,10 @@ where
         &mut self,
         cid: TId,
         now: Instant,
+        policy: CandidatePolicy,
         allocations: &mut BTreeMap<RId, Allocation>,
         transmits: &mut VecDeque<Transmit<'static>>,
+        events: &mut VecDeque<Event<TId>>,
     ) where
         TId: Copy + Ord + fmt::Display,
         RId: Copy + Ord + fmt::Display,
@@ -1845,6 +2441,17 @@ where
 
         self.handle_tunnel_timeout(now, allocations, transmits);
 
+        if self.path_timeout().is_some_and(|timeout| now >= timeout) {
+            self.handle_path_timeout(cid, now, events);
+        }
+
+        if self
+            .dead_candidate_timeout()
+            .is_some_and(|timeout| now >= timeout)
+        {
+            self.handle_dead_candidate_timeout(cid, now, policy, allocations, events);
+        }
+
         // If this was a scheduled update, hop to the next interval.
         if now >= self.next_wg_timer_update {
             self.next_wg_timer_update = now + Duration::from_secs(1); // TODO: Remove fixed interval in favor of precise `next_timer_update` function in `boringtun`.
@@ -1898,6 +2505,9 @@ where
                             dest: destination,
                         },
                     };
+                    self.stats.selected_pair =
+                        Some(candidate_pair(&self.agent, remote_socket, source));
+                    self.path_liveness = PathLiveness::new(now);
 
                     let old = match mem::replace(&mut self.state, ConnectionState::Failed) {
                         ConnectionState::Connecting { buffered, .. } => {
@@ -1939,6 +2549,7 @@ where
                                 last_incoming,
                                 last_outgoing,
                             };
+                            events.push_back(Event::ConnectionMigrated(cid));
 
                             Some(peer_socket)
                         }
@@ -1978,6 +2589,7 @@ where
                     src: Some(source),
                     dst,
                     payload: Cow::Owned(stun_packet.into()),
+                    transport: Transport::Udp,
                 });
                 continue;
             };
@@ -1999,10 +2611,97 @@ where
                 src: None,
                 dst: encode_ok.socket,
                 payload: Cow::Owned(data_channel_packet),
+                transport: encode_ok.transport,
             });
         }
     }
 
+    /// Hands a dead nominated path back to ICE so it can nominate a different pair.
+    ///
+    /// To do so, we invalidate our candidate of the dead pair, e.g. the one of the relay that stopped forwarding our traffic.
+    /// The agent then nominates one of the remaining pairs which migrates the connection, see [`IceAgentEvent::NominatedSend`].
+    /// If none of them work either, ICE fails the connection, just like it would once the checks on the dead pair time out.
+    ///
+    /// The path may only be dead temporarily, thus we add the candidate back after [`DEAD_CANDIDATE_RETRY_AFTER`].
+    fn handle_path_timeout<TId>(
+        &mut self,
+        cid: TId,
+        now: Instant,
+        events: &mut VecDeque<Event<TId>>,
+    ) where
+        TId: fmt::Display,
+    {
+        let (Some(peer_socket), Some(selected_pair)) = (self.socket(), self.stats.selected_pair)
+        else {
+            return;
+        };
+
+        // Whether we migrate or not, give the path we end up on a fresh chance.
+        self.path_liveness = PathLiveness::new(now);
+
+        let local = selected_pair.local;
+        let candidates = self.agent.local_candidates();
+        let dead_candidate = candidates
+            .iter()
+            .find(|c| c.addr() == local && c.kind() != CandidateKind::ServerReflexive)
+            .cloned();
+        let has_alternative = candidates.iter().any(|c| c.addr() != local);
+
+        let (Some(candidate), true) = (dead_candidate, has_alternative) else {
+            tracing::debug!(
+                ?peer_socket,
+                "Path appears dead but there is no alternative"
+            );
+
+            // Keep going, ICE will eventually either fail the connection or nominate a different pair.
+            return;
+        };
+
+        tracing::info!(?peer_socket, candidate = %candidate.to_sdp_string(), "Path is dead; invalidating our candidate");
+
+        remove_local_candidate(cid, &mut self.agent, &candidate, events);
+        self.agent.handle_timeout(now); // Ensure the agent re-evaluates its nomination right away.
+
+        self.dead_candidates
+            .push((candidate, now + DEAD_CANDIDATE_RETRY_AFTER));
+    }
+
+    /// Adds the candidates of dead paths back to the agent so ICE checks whether they work again.
+    fn handle_dead_candidate_timeout<TId>(
+        &mut self,
+        cid: TId,
+        now: Instant,
+        policy: CandidatePolicy,
+        allocations: &BTreeMap<RId, Allocation>,
+        events: &mut VecDeque<Event<TId>>,
+    ) where
+        TId: fmt::Display,
+    {
+        let (due, pending) = mem::take(&mut self.dead_candidates)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(_, at)| now >= *at);
+        self.dead_candidates = pending;
+
+        for (candidate, _) in due {
+            // Don't bring back the candidate of an allocation that is gone by now.
+            if candidate.kind() == CandidateKind::Relayed
+                && !allocations.values().any(|allocation| {
+                    allocation
+                        .current_relay_candidates()
+                        .any(|c| c.addr() == candidate.addr())
+                })
+            {
+                continue;
+            }
+
+            tracing::debug!(candidate = %candidate.to_sdp_string(), "Retrying candidate of dead path");
+
+            add_local_candidate(cid, &mut self.agent, candidate, policy, events);
+        }
+
+        self.agent.handle_timeout(now);
+    }
+
     fn handle_tunnel_timeout(
         &mut self,
         now: Instant,
@@ -2039,122 +2738,6 @@ where
         };
     }
 
-    fn encapsulate<'b>(
-        &mut self,
-        packet: IpPacket,
-        buffer: &'b mut [u8],
-        now: Instant,
-    ) -> Result<Option<&'b [u8]>, Error> {
-        let _guard = self.span.enter();
-
-        self.state.on_outgoing(&mut self.agent, &packet, now);
-
-        let len = match self.tunnel.encapsulate_at(packet.packet(), buffer, now) {
-            TunnResult::Done => return Ok(None),
-            TunnResult::Err(e) => return Err(Error::Encapsulate(e)),
-            TunnResult::WriteToNetwork(packet) => packet.len(),
-            TunnResult::WriteToTunnelV4(_, _) | TunnResult::WriteToTunnelV6(_, _) => {
-                unreachable!("never returned from encapsulate")
-            }
-        };
-
-        Ok(Some(&buffer[..len]))
-    }
-
-    fn decapsulate(
-        &mut self,
-        packet: &[u8],
-        allocations: &mut BTreeMap<RId, Allocation>,
-        transmits: &mut VecDeque<Transmit<'static>>,
-        now: Instant,
-    ) -> ControlFlow<Result<(), Error>, IpPacket> {
-        let _guard = self.span.enter();
-        let mut ip_packet = IpPacketBuf::new();
-
-        let control_flow = match self
-            .tunnel
-            .decapsulate_at(None, packet, ip_packet.buf(), now)
-        {
-            TunnResult::Done => ControlFlow::Break(Ok(())),
-            TunnResult::Err(e) => ControlFlow::Break(Err(Error::Decapsulate(e))),
-
-            // For WriteToTunnel{V4,V6}, boringtun returns the source IP of the packet that was tunneled to us.
-            // I am guessing this was done for convenience reasons.
-            // In our API, we parse the packets directly as an IpPacket.
-            // Thus, the caller can query whatever data they'd like, not just the source IP so we don't return it in addition.
-            TunnResult::WriteToTunnelV4(packet, ip) => {
-                let packet_len = packet.len();
-                let ipv4_packet = ConvertibleIpv4Packet::new(ip_packet, packet_len)
-                    .expect("boringtun verifies validity");
-                debug_assert_eq!(ipv4_packet.get_source(), ip);
-
-                ControlFlow::Continue(ipv4_packet.into())
-            }
-            TunnResult::WriteToTunnelV6(packet, ip) => {
-                // For ipv4 we need to use buffer to create the ip packet because we need the extra 20 bytes at the beginning
-                // for ipv6 we just need this to convince the borrow-checker that `packet`'s lifetime isn't `'b`, otherwise it's taken
-                // as `'b` for all branches.
-                let packet_len = packet.len();
-                let ipv6_packet = ConvertibleIpv6Packet::new(ip_packet, packet_len)
-                    .expect("boringtun verifies validity");
-                debug_assert_eq!(ipv6_packet.get_source(), ip);
-
-                ControlFlow::Continue(ipv6_packet.into())
-            }
-
-            // During normal operation, i.e. when the tunnel is active, decapsulating a packet straight yields the decrypted packet.
-            // However, in case `Tunn` has buffered packets, they may be returned here instead.
-            // This should be fairly rare which is why we just allocate these and return them from `poll_transmit` instead.
-            // Overall, this results in a much nicer API for our caller and should not affect performance.
-            TunnResult::WriteToNetwork(bytes) => {
-                match &mut self.state {
-                    ConnectionState::Connecting { buffered, .. } => {
-                        tracing::debug!("No socket has been nominated yet, buffering WG packet");
-
-                        buffered.push(bytes.to_owned());
-
-                        while let TunnResult::WriteToNetwork(packet) =
-                            self.tunnel
-                                .decapsulate_at(None, &[], self.buffer.as_mut(), now)
-                        {
-                            buffered.push(packet.to_owned());
-                        }
-                    }
-                    ConnectionState::Connected { peer_socket, .. }
-                    | ConnectionState::Idle { peer_socket } => {
-                        transmits.extend(make_owned_transmit(
-                            *peer_socket,
-                            bytes,
-                            allocations,
-                            now,
-                        ));
-
-                        while let TunnResult::WriteToNetwork(packet) =
-                            self.tunnel
-                                .decapsulate_at(None, &[], self.buffer.as_mut(), now)
-                        {
-                            transmits.extend(make_owned_transmit(
-                                *peer_socket,
-                                packet,
-                                allocations,
-                                now,
-                            ));
-                        }
-                    }
-                    ConnectionState::Failed => {}
-                }
-
-                ControlFlow::Break(Ok(()))
-            }
-        };
-
-        if let ControlFlow::Continue(packet) = &control_flow {
-            self.state.on_incoming(&mut self.agent, packet, now);
-        }
-
-        control_flow
-    }
-
     fn force_handshake(
         &mut self,
         allocations: &mut BTreeMap<RId, Allocation>,
@@ -2202,6 +2785,87 @@ where
 }
 
 #[must_use]
+/// An outgoing packet on its way through [`Tunn::encapsulate_at`], possibly on a different thread.
+struct Encryption {
+    packet: IpPacket,
+    /// Holds the encrypted packet with an offset of 4 bytes, in case we need to wrap it in a channel-data message.
+    buffer: lockfree_object_pool::SpinLockOwnedReusable<Vec<u8>>,
+    /// The length of the encrypted packet, if there is one.
+    result: Result<Option<usize>, WireGuardError>,
+}
+
+impl Encryption {
+    fn run(&mut self, tunnel: &mut Tunn, now: Instant) {
+        self.result = match tunnel.encapsulate_at(self.packet.packet(), &mut self.buffer[4..], now)
+        {
+            TunnResult::Done => Ok(None),
+            TunnResult::Err(e) => Err(e),
+            TunnResult::WriteToNetwork(packet) => Ok(Some(packet.len())),
+            TunnResult::WriteToTunnelV4(_, _) | TunnResult::WriteToTunnelV6(_, _) => {
+                unreachable!("never returned from encapsulate")
+            }
+        };
+    }
+}
+
+/// An incoming packet on its way through [`Tunn::decapsulate_at`], possibly on a different thread.
+struct Decryption<'p> {
+    packet: &'p [u8],
+    buffer: IpPacketBuf,
+    handshake_complete_before: bool,
+    result: Decrypted,
+}
+
+enum Decrypted {
+    /// The decrypted packet is at the start of [`Decryption::buffer`].
+    ///
+    /// boringtun also returns the source IP of the packet, we only use it to double-check our parsing.
+    Ipv4 {
+        len: usize,
+        src: Ipv4Addr,
+    },
+    Ipv6 {
+        len: usize,
+        src: Ipv6Addr,
+    },
+    /// Packets that we need to send to the remote, e.g. a handshake response.
+    ToNetwork(Vec<Vec<u8>>),
+    Done,
+    Failed(WireGuardError),
+}
+
+impl Decryption<'_> {
+    fn run(&mut self, tunnel: &mut Tunn, now: Instant) {
+        self.result = match tunnel.decapsulate_at(None, self.packet, self.buffer.buf(), now) {
+            TunnResult::Done => Decrypted::Done,
+            TunnResult::Err(e) => Decrypted::Failed(e),
+            TunnResult::WriteToTunnelV4(packet, src) => Decrypted::Ipv4 {
+                len: packet.len(),
+                src,
+            },
+            TunnResult::WriteToTunnelV6(packet, src) => Decrypted::Ipv6 {
+                len: packet.len(),
+                src,
+            },
+
+            // During normal operation, i.e. when the tunnel is active, decapsulating a packet straight yields the decrypted packet.
+            // However, in case `Tunn` has buffered packets, they may be returned here instead.
+            // This should be fairly rare which is why we just allocate these and return them from `poll_transmit` instead.
+            TunnResult::WriteToNetwork(bytes) => {
+                let mut packets = vec![bytes.to_owned()];
+
+                while let TunnResult::WriteToNetwork(packet) =
+                    tunnel.decapsulate_at(None, &[], self.buffer.buf(), now)
+                {
+                    packets.push(packet.to_owned());
+                }
+
+                Decrypted::ToNetwork(packets)
+            }
+        };
+    }
+}
+
 fn make_owned_transmit<RId>(
     socket: PeerSocket<RId>,
     message: &[u8],
@@ -2223,6 +2887,7 @@ where
             src: Some(source),
             dst: remote,
             payload: Cow::Owned(message.into()),
+            transport: Transport::Udp,
         },
         PeerSocket::RelayToPeer { relay, dest: peer }
         | PeerSocket::RelayToRelay { relay, dest: peer } => {
@@ -2235,6 +2900,7 @@ where
                 src: None,
                 dst: encode_ok.socket,
                 payload: Cow::Owned(buffer),
+                transport: encode_ok.transport,
             }
         }
     };
@@ -2242,6 +2908,47 @@ where
     Some(transmit)
 }
 
+fn candidate_pair<RId>(
+    agent: &IceAgent,
+    socket: PeerSocket<RId>,
+    source: SocketAddr,
+) -> CandidatePair<RId>
+where
+    RId: Copy,
+{
+    let remote = socket.dest();
+    let remote_type = agent
+        .remote_candidates()
+        .iter()
+        .find(|c| c.addr() == remote)
+        .map(|c| candidate_type(c.kind()))
+        .unwrap_or(CandidateType::PeerReflexive); // Remote candidates we didn't get signalled are learned from incoming STUN requests.
+    let relay = socket.relay();
+
+    // ICE pairs server-reflexive candidates by their base, so the agent only ever tells us the host address we send from.
+    // Unless the peer is reachable on one of its host candidates, our packets leave through the NAT that mapped our server-reflexive candidate.
+    let is_behind_nat = agent
+        .local_candidates()
+        .iter()
+        .any(|c| c.kind() == CandidateKind::ServerReflexive && c.base() == source);
+
+    let local_type = if relay.is_some() {
+        CandidateType::Relayed
+    } else if is_behind_nat && remote_type != CandidateType::Host {
+        CandidateType::ServerReflexive
+    } else {
+        CandidateType::Host
+    };
+
+    CandidatePair {
+        local: source,
+        local_type,
+        remote,
+        remote_type,
+        relay,
+    }
+}
+
 fn new_agent() -> IceAgent {
     let mut agent = IceAgent::new();
     agent.set_timing_advance(Duration::ZERO);
@@ -2283,3 +2990,616 @@ impl fmt::Display for SessionId {
         write!(f, "{:X}", &self.0.hex())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::iter;
+    use std::net::{IpAddr, SocketAddrV4};
+
+    #[test]
+    fn direct_only_never_uses_relay_candidates() {
+        let policy = CandidatePolicy::DirectOnly;
+
+        assert!(policy.allows_local(CandidateKind::Host));
+        assert!(policy.allows_local(CandidateKind::ServerReflexive));
+        assert!(!policy.allows_local(CandidateKind::Relayed));
+        assert!(!policy.allows_remote(CandidateKind::Relayed));
+    }
+
+    #[test]
+    fn reports_candidate_types_of_selected_pair() {
+        let host = SocketAddr::from(([10, 0, 0, 1], 52625));
+        let srflx = SocketAddr::from(([1, 1, 1, 1], 40000));
+        let peer_host = SocketAddr::from(([10, 0, 0, 2], 52625));
+        let peer_srflx = SocketAddr::from(([2, 2, 2, 2], 40000));
+        let peer_prflx = SocketAddr::from(([3, 3, 3, 3], 50000));
+
+        let mut agent = new_agent();
+        agent.add_local_candidate(Candidate::host(host, Protocol::Udp).unwrap());
+        agent.add_remote_candidate(Candidate::host(peer_host, Protocol::Udp).unwrap());
+        agent.add_remote_candidate(
+            Candidate::server_reflexive(peer_srflx, peer_host, Protocol::Udp).unwrap(),
+        );
+
+        let pair = |agent: &IceAgent, socket| {
+            let pair = candidate_pair::<u8>(agent, socket, host);
+
+            (pair.local_type, pair.remote_type, pair.relay)
+        };
+        let direct = |dest| PeerSocket::PeerToPeer { source: host, dest };
+
+        // Without a server-reflexive candidate, we aren't behind a NAT.
+        assert_eq!(
+            pair(&agent, direct(peer_srflx)),
+            (CandidateType::Host, CandidateType::ServerReflexive, None)
+        );
+
+        agent.add_local_candidate(Candidate::server_reflexive(srflx, host, Protocol::Udp).unwrap());
+
+        assert_eq!(
+            pair(&agent, direct(peer_host)),
+            (CandidateType::Host, CandidateType::Host, None)
+        );
+        assert_eq!(
+            pair(&agent, direct(peer_srflx)),
+            (
+                CandidateType::ServerReflexive,
+                CandidateType::ServerReflexive,
+                None
+            )
+        );
+        assert_eq!(
+            pair(
+                &agent,
+                PeerSocket::RelayToPeer {
+                    relay: 1,
+                    dest: peer_srflx
+                }
+            ),
+            (
+                CandidateType::Relayed,
+                CandidateType::ServerReflexive,
+                Some(1)
+            )
+        );
+        assert_eq!(
+            pair(
+                &agent,
+                PeerSocket::PeerToPeer {
+                    source: host,
+                    dest: peer_prflx
+                }
+            ),
+            (
+                CandidateType::ServerReflexive,
+                CandidateType::PeerReflexive,
+                None
+            )
+        );
+    }
+
+    #[test]
+    fn relay_only_only_uses_local_relay_candidates() {
+        let policy = CandidatePolicy::RelayOnly;
+
+        assert!(!policy.allows_local(CandidateKind::Host));
+        assert!(!policy.allows_local(CandidateKind::ServerReflexive));
+        assert!(policy.allows_local(CandidateKind::Relayed));
+        assert!(policy.allows_remote(CandidateKind::Host));
+    }
+
+    #[test]
+    fn relay_only_neither_signals_nor_uses_host_candidates() {
+        let mut peers = Peers::new(&[&[CLIENT_1_ADDR_1]], CandidatePolicy::RelayOnly);
+        peers.advance(Duration::from_secs(5));
+
+        let events = &peers.clients[&1].events;
+
+        assert!(!events
+            .iter()
+            .any(|e| matches!(e, Event::NewIceCandidate { .. })));
+        assert!(!events.contains(&Event::ConnectionEstablished(SERVER_ID)));
+        assert_eq!(peers.server_received, 0);
+    }
+
+    #[test]
+    fn direct_only_ignores_remote_relay_candidates() {
+        let mut peers = Peers::new(&[&[CLIENT_1_ADDR_1]], CandidatePolicy::DirectOnly);
+        let relayed = Candidate::relayed(RELAY_ADDR.into(), Protocol::Udp).unwrap();
+
+        let client = peers.clients.get_mut(&1).unwrap();
+        client
+            .node
+            .add_remote_candidate(SERVER_ID, relayed.to_sdp_string(), peers.now);
+
+        assert!(client
+            .node
+            .connections
+            .agent_mut(SERVER_ID)
+            .unwrap()
+            .remote_candidates()
+            .is_empty());
+
+        peers.advance(Duration::from_secs(1));
+
+        assert!(peers.clients[&1]
+            .events
+            .contains(&Event::ConnectionEstablished(SERVER_ID)));
+        assert_eq!(
+            peers.client_stats(1).selected_pair.unwrap().remote,
+            SERVER_ADDR
+        );
+    }
+
+    #[test]
+    fn direct_only_server_ignores_checks_through_client_relays() {
+        let signalled_relay = SocketAddr::from(([10, 0, 3, 1], 49152));
+        let shared_relay = SocketAddr::new(IpAddr::V4(*RELAY_ADDR.ip()), 49153);
+
+        let mut peers = Peers::new(
+            &[&[CLIENT_1_ADDR_1], &[CLIENT_2_ADDR]],
+            CandidatePolicy::All,
+        );
+        peers
+            .server
+            .set_candidate_policy(CandidatePolicy::DirectOnly);
+        peers.relayed.insert(CLIENT_1_ADDR_1, signalled_relay);
+        peers.relayed.insert(CLIENT_2_ADDR, shared_relay);
+        peers.server.add_remote_candidate(
+            1,
+            Candidate::relayed(signalled_relay, Protocol::Udp)
+                .unwrap()
+                .to_sdp_string(),
+            peers.now,
+        );
+
+        peers.advance(Duration::from_secs(1));
+
+        for (cid, relay) in [(1, signalled_relay), (2, shared_relay)] {
+            let agent = peers.server.connections.agent_mut(cid).unwrap();
+
+            assert!(
+                agent.remote_candidates().iter().all(|c| c.addr() != relay),
+                "Checks through the relay of client {cid} should not become a candidate"
+            );
+        }
+    }
+
+    #[test]
+    fn path_times_out_only_whilst_sending_without_response() {
+        let now = Instant::now();
+        let mut liveness = PathLiveness::new(now);
+
+        assert_eq!(liveness.poll_timeout(now), None);
+
+        let sent_at = now + Duration::from_millis(100);
+        assert_eq!(
+            liveness.poll_timeout(sent_at),
+            Some(now + DEAD_PATH_TIMEOUT)
+        );
+
+        liveness.on_packet(now + Duration::from_millis(150));
+        assert_eq!(liveness.poll_timeout(sent_at), None);
+    }
+
+    #[test]
+    fn dead_path_fails_over_to_other_pair() {
+        let mut peers = Peers::connect(&[&[CLIENT_1_ADDR_1, CLIENT_1_ADDR_2]]);
+        peers.send_for(1, Duration::from_secs(2));
+
+        let dead = peers.client_stats(1).selected_pair.unwrap().local;
+        peers.unreachable.insert(dead);
+        peers.send_for(1, Duration::from_secs(10));
+
+        let events = &peers.clients[&1].events;
+        assert_ne!(peers.client_stats(1).selected_pair.unwrap().local, dead);
+        assert!(events.contains(&Event::ConnectionMigrated(SERVER_ID)));
+        assert!(!events.contains(&Event::ConnectionFailed(SERVER_ID)));
+
+        let received = peers.server_received;
+        peers.send_for(1, Duration::from_secs(1));
+
+        assert!(peers.server_received > received);
+    }
+
+    #[test]
+    fn dead_path_candidate_is_retried_later() {
+        let mut peers = Peers::connect(&[&[CLIENT_1_ADDR_1, CLIENT_1_ADDR_2]]);
+        peers.send_for(1, Duration::from_secs(2));
+
+        let dead = peers.client_stats(1).selected_pair.unwrap().local;
+        peers.unreachable.insert(dead);
+        peers.send_for(1, Duration::from_secs(10));
+        peers.unreachable.remove(&dead);
+
+        let num_events = peers.clients[&1].events.len();
+        peers.send_for(1, DEAD_CANDIDATE_RETRY_AFTER);
+
+        assert!(peers.clients[&1].events[num_events..]
+            .iter()
+            .any(|e| matches!(
+                e,
+                Event::NewIceCandidate { candidate, .. }
+                    if Candidate::from_sdp_string(candidate).unwrap().addr() == dead
+            )));
+        assert!(peers
+            .server
+            .connections
+            .agent_mut(1)
+            .unwrap()
+            .remote_candidates()
+            .iter()
+            .any(|c| c.addr() == dead));
+
+        let received = peers.server_received;
+        peers.send_for(1, Duration::from_secs(1));
+
+        assert!(peers.server_received > received);
+    }
+
+    #[test]
+    fn healthy_one_way_flow_does_not_migrate() {
+        let mut peers = Peers::connect(&[&[CLIENT_1_ADDR_1, CLIENT_1_ADDR_2]]);
+        peers.send_for(1, Duration::from_secs(2));
+
+        let selected_pair = peers.client_stats(1).selected_pair;
+        peers.send_for(1, Duration::from_secs(30));
+
+        assert_eq!(peers.client_stats(1).selected_pair, selected_pair);
+        assert!(!peers.clients[&1]
+            .events
+            .contains(&Event::ConnectionMigrated(SERVER_ID)));
+        assert!(
+            peers.client_stats(1).wg_handshake_age.unwrap() > Duration::from_secs(30),
+            "Sending in one direction only should not trigger a new WireGuard handshake"
+        );
+    }
+
+    #[test]
+    fn encapsulate_batch_keeps_order_across_connections() {
+        let mut peers = Peers::connect(&[&[CLIENT_1_ADDR_1], &[CLIENT_2_ADDR]]);
+        peers.send_for(1, Duration::from_secs(1));
+        peers.send_for(2, Duration::from_secs(1));
+        peers.server.set_crypto_threads(2).unwrap();
+
+        let batch = [(1, 0), (2, 1), (1, 2), (1, 3), (2, 4)];
+        let results = peers
+            .server
+            .encapsulate_batch(batch.map(|(cid, n)| (cid, numbered_packet(n))), peers.now);
+
+        assert_eq!(results.len(), batch.len());
+
+        for ((cid, n), result) in batch.into_iter().zip(results) {
+            let transmit = result.unwrap().unwrap().to_transmit().into_owned();
+            let client = peers.clients.get_mut(&cid).unwrap();
+
+            assert!(client.addrs.contains(&transmit.dst()));
+
+            let (_, packet) = client
+                .node
+                .decapsulate(
+                    transmit.dst(),
+                    transmit.src().unwrap(),
+                    transmit.payload(),
+                    peers.now,
+                )
+                .unwrap()
+                .unwrap();
+
+            assert_eq!(packet_number(&packet), n);
+        }
+    }
+
+    #[test]
+    fn decapsulate_batch_keeps_order_across_connections() {
+        let mut peers = Peers::connect(&[&[CLIENT_1_ADDR_1], &[CLIENT_2_ADDR]]);
+        peers.send_for(1, Duration::from_secs(1));
+        peers.send_for(2, Duration::from_secs(1));
+        peers.server.set_crypto_threads(2).unwrap();
+
+        let batch = [(1, 0), (2, 1), (1, 2), (1, 3), (2, 4)];
+        let now = peers.now;
+        let transmits = batch.map(|(cid, n)| {
+            peers
+                .clients
+                .get_mut(&cid)
+                .unwrap()
+                .node
+                .encapsulate(SERVER_ID, numbered_packet(n), now)
+                .unwrap()
+                .unwrap()
+                .to_transmit()
+                .into_owned()
+        });
+
+        let results = peers.server.decapsulate_batch(
+            transmits
+                .iter()
+                .map(|t| (t.dst(), t.src().unwrap(), t.payload())),
+            now,
+        );
+
+        assert_eq!(results.len(), batch.len());
+
+        for ((cid, n), result) in batch.into_iter().zip(results) {
+            let (from, packet) = result.unwrap().unwrap();
+
+            assert_eq!(from, cid);
+            assert_eq!(packet_number(&packet), n);
+        }
+    }
+
+    const SERVER_ID: u8 = 0;
+    const CLIENT_1_ADDR_1: SocketAddr =
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)), 52625);
+    const CLIENT_1_ADDR_2: SocketAddr =
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2)), 52625);
+    const CLIENT_2_ADDR: SocketAddr =
+        SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 3)), 52625);
+    const SERVER_ADDR: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(10, 0, 1, 1)), 52625);
+    const RELAY_ADDR: SocketAddrV4 = SocketAddrV4::new(Ipv4Addr::new(10, 0, 2, 1), 3478);
+
+    /// A server and its clients that exchange their packets and signalling messages in memory.
+    ///
+    /// Packets to the relay are dropped, thus only host candidates are used.
+    struct Peers {
+        server: ServerNode<u8, u8>,
+        /// Indexed by the ID of their connection on the server.
+        clients: BTreeMap<u8, TestClient>,
+        /// Packets from or to these addresses are dropped.
+        unreachable: BTreeSet<SocketAddr>,
+        /// Packets from these client addresses reach the server through a relay, i.e. from the given relayed address.
+        relayed: BTreeMap<SocketAddr, SocketAddr>,
+        /// How many packets the server decrypted.
+        server_received: usize,
+        now: Instant,
+    }
+
+    struct TestClient {
+        node: ClientNode<u8, u8>,
+        addrs: Vec<SocketAddr>,
+        events: Vec<Event<u8>>,
+    }
+
+    impl Peers {
+        /// Connects a client with the given host candidates for each entry, their IDs start at 1.
+        fn connect(clients: &[&[SocketAddr]]) -> Self {
+            let mut peers = Self::new(clients, CandidatePolicy::All);
+            peers.advance(Duration::from_secs(1));
+
+            for client in peers.clients.values() {
+                assert!(
+                    client
+                        .events
+                        .contains(&Event::ConnectionEstablished(SERVER_ID)),
+                    "ICE should complete within 1s"
+                );
+            }
+
+            peers
+        }
+
+        /// Sets up the connections of all clients without running ICE, the clients use the given [`CandidatePolicy`].
+        fn new(clients: &[&[SocketAddr]], policy: CandidatePolicy) -> Self {
+            let now = Instant::now();
+            let relays = BTreeSet::from([(
+                0,
+                RelaySocket::V4(RELAY_ADDR),
+                "user".to_owned(),
+                "pass".to_owned(),
+                "firezone".to_owned(),
+            )]);
+
+            let mut server = ServerNode::<u8, u8>::new([0; 32], now);
+            server.update_relays(BTreeSet::new(), &relays, now);
+            server.add_local_host_candidate(SERVER_ADDR).unwrap();
+
+            let clients = (1..)
+                .zip(clients)
+                .map(|(cid, addrs)| {
+                    let mut node = ClientNode::<u8, u8>::new([cid; 32], now);
+                    node.set_candidate_policy(policy);
+                    node.update_relays(BTreeSet::new(), &relays, now);
+                    for addr in addrs.iter() {
+                        node.add_local_host_candidate(*addr).unwrap();
+                    }
+
+                    node.upsert_connection(
+                        SERVER_ID,
+                        server.public_key(),
+                        Secret::new([cid; 32]),
+                        credentials("client", cid),
+                        credentials("server", cid),
+                        now,
+                    )
+                    .unwrap();
+                    server
+                        .upsert_connection(
+                            cid,
+                            node.public_key(),
+                            Secret::new([cid; 32]),
+                            credentials("server", cid),
+                            credentials("client", cid),
+                            now,
+                        )
+                        .unwrap();
+
+                    let client = TestClient {
+                        node,
+                        addrs: addrs.to_vec(),
+                        events: Vec::new(),
+                    };
+
+                    (cid, client)
+                })
+                .collect();
+
+            Self {
+                server,
+                clients,
+                unreachable: BTreeSet::new(),
+                relayed: BTreeMap::new(),
+                server_received: 0,
+                now,
+            }
+        }
+
+        /// Sends a packet from the given client to the server every 10ms.
+        fn send_for(&mut self, cid: u8, duration: Duration) {
+            let end = self.now + duration;
+
+            while self.now < end {
+                let now = self.now;
+                let client = self.clients.get_mut(&cid).unwrap();
+
+                if let Some(packet) = client
+                    .node
+                    .encapsulate(SERVER_ID, numbered_packet(0), now)
+                    .unwrap()
+                {
+                    let transmit = packet.to_transmit().into_owned();
+                    self.deliver(transmit);
+                }
+
+                self.advance(Duration::from_millis(10));
+            }
+        }
+
+        fn advance(&mut self, duration: Duration) {
+            let end = self.now + duration;
+
+            while self.now < end {
+                for (cid, client) in self.clients.iter_mut() {
+                    while let Some(event) = client.node.poll_event() {
+                        match &event {
+                            Event::NewIceCandidate { candidate, .. } => self
+                                .server
+                                .add_remote_candidate(*cid, candidate.clone(), self.now),
+                            Event::InvalidateIceCandidate { candidate, .. } => self
+                                .server
+                                .remove_remote_candidate(*cid, candidate.clone(), self.now),
+                            Event::ConnectionEstablished(_)
+                            | Event::ConnectionFailed(_)
+                            | Event::ConnectionClosed(_)
+                            | Event::ConnectionMigrated(_) => {}
+                        }
+
+                        client.events.push(event);
+                    }
+                }
+
+                while let Some(event) = self.server.poll_event() {
+                    match event {
+                        Event::NewIceCandidate {
+                            connection,
+                            candidate,
+                        } => self
+                            .clients
+                            .get_mut(&connection)
+                            .unwrap()
+                            .node
+                            .add_remote_candidate(SERVER_ID, candidate, self.now),
+                        Event::InvalidateIceCandidate {
+                            connection,
+                            candidate,
+                        } => self
+                            .clients
+                            .get_mut(&connection)
+                            .unwrap()
+                            .node
+                            .remove_remote_candidate(SERVER_ID, candidate, self.now),
+                        Event::ConnectionEstablished(_)
+                        | Event::ConnectionFailed(_)
+                        | Event::ConnectionClosed(_)
+                        | Event::ConnectionMigrated(_) => {}
+                    }
+                }
+
+                let client_transmits = self
+                    .clients
+                    .values_mut()
+                    .flat_map(|c| iter::from_fn(move || c.node.poll_transmit()))
+                    .collect::<Vec<_>>();
+                let server_transmits =
+                    iter::from_fn(|| self.server.poll_transmit()).collect::<Vec<_>>();
+
+                for transmit in client_transmits.into_iter().chain(server_transmits) {
+                    self.deliver(transmit);
+                }
+
+                self.now += Duration::from_millis(10);
+                self.server.handle_timeout(self.now);
+                for client in self.clients.values_mut() {
+                    client.node.handle_timeout(self.now);
+                }
+            }
+        }
+
+        fn deliver(&mut self, transmit: Transmit<'static>) {
+            let Some(src) = transmit.src() else {
+                return; // Packets to relays don't have a source.
+            };
+            let dst = transmit.dst();
+
+            if self.unreachable.contains(&src) || self.unreachable.contains(&dst) {
+                return;
+            }
+
+            let src = if dst == SERVER_ADDR {
+                self.relayed.get(&src).copied().unwrap_or(src)
+            } else {
+                src
+            };
+
+            if dst == SERVER_ADDR {
+                if let Ok(Some(_)) = self
+                    .server
+                    .decapsulate(dst, src, transmit.payload(), self.now)
+                {
+                    self.server_received += 1;
+                }
+                return;
+            }
+
+            let Some(client) = self.clients.values_mut().find(|c| c.addrs.contains(&dst)) else {
+                return;
+            };
+
+            let _ = client
+                .node
+                .decapsulate(dst, src, transmit.payload(), self.now);
+        }
+
+        fn client_stats(&self, cid: u8) -> ConnectionStats<u8> {
+            let (_, mut connections) = self.clients[&cid].node.stats(self.now);
+
+            connections
+                .find_map(|(id, stats)| (id == SERVER_ID).then_some(stats))
+                .unwrap()
+        }
+    }
+
+    fn credentials(role: &str, cid: u8) -> Credentials {
+        Credentials {
+            username: format!("{role}{cid}"),
+            password: format!("{role}-password-{cid}"),
+        }
+    }
+
+    /// A UDP packet that carries `n` as its payload.
+    fn numbered_packet(n: u8) -> IpPacket {
+        ip_packet::make::udp_packet(
+            Ipv4Addr::new(100, 64, 0, 1),
+            Ipv4Addr::new(10, 10, 0, 1),
+            50000,
+            443,
+            vec![n],
+        )
+        .unwrap()
+    }
+
+    fn packet_number(packet: &IpPacket) -> u8 {
+        packet.as_udp().unwrap().payload()[0]
+    }
+}
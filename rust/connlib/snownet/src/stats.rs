@@ -1,4 +1,5 @@
-use std::ops::AddAssign;
+use connlib_model::CandidateType;
+use std::{net::SocketAddr, ops::AddAssign, time::Duration};
 
 #[derive(Default, Debug, Clone, Copy)]
 pub struct NodeStats {
@@ -6,12 +7,49 @@ pub struct NodeStats {
     pub stun_bytes_to_relays: HumanBytes,
 }
 
-#[derive(Default, Debug, Clone, Copy)]
-pub struct ConnectionStats {
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionStats<RId> {
     /// How many bytes we sent as part of exchanging STUN messages to other peers directly.
     pub stun_bytes_to_peer_direct: HumanBytes,
     /// How many bytes we sent as part of exchanging STUN messages to other peers via relays.
     pub stun_bytes_to_peer_relayed: HumanBytes,
+
+    /// The candidate pair ICE nominated for this connection, `None` until ICE completes.
+    pub selected_pair: Option<CandidatePair<RId>>,
+    /// The round-trip time to the peer, as estimated by WireGuard.
+    pub rtt: Option<Duration>,
+    /// How long ago the last WireGuard handshake completed, `None` if there hasn't been one yet.
+    pub wg_handshake_age: Option<Duration>,
+    /// How many bytes of WireGuard packets we sent to the peer.
+    pub tx_bytes: HumanBytes,
+    /// How many bytes of WireGuard packets we received from the peer.
+    pub rx_bytes: HumanBytes,
+}
+
+// Manual impl to avoid the `RId: Default` bound of `#[derive(Default)]`.
+impl<RId> Default for ConnectionStats<RId> {
+    fn default() -> Self {
+        Self {
+            stun_bytes_to_peer_direct: HumanBytes::default(),
+            stun_bytes_to_peer_relayed: HumanBytes::default(),
+            selected_pair: None,
+            rtt: None,
+            wg_handshake_age: None,
+            tx_bytes: HumanBytes::default(),
+            rx_bytes: HumanBytes::default(),
+        }
+    }
+}
+
+/// The local and remote candidate that a connection's traffic flows through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CandidatePair<RId> {
+    pub local: SocketAddr,
+    pub local_type: CandidateType,
+    pub remote: SocketAddr,
+    pub remote_type: CandidateType,
+    /// The relay we send through, if `local_type` is [`CandidateType::Relayed`].
+    pub relay: Option<RId>,
 }
 
 #[derive(Default, Clone, Copy)]
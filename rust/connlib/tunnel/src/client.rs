@@ -1,3 +1,4 @@
+mod dns_cache;
 mod resource;
 
 pub(crate) use resource::{CidrResource, Resource};
@@ -6,29 +7,34 @@ pub(crate) use resource::{DnsResource, InternetResource};
 use ringbuffer::{AllocRingBuffer, RingBuffer};
 
 use crate::dns::StubResolver;
-use crate::messages::{DnsServer, Interface as InterfaceConfig, IpDnsServer};
+use crate::messages::{DnsServer, Interface as InterfaceConfig, IpDnsServer, SplitDnsRule};
 use crate::messages::{IceCredentials, SecretKey};
 use crate::peer_store::PeerStore;
 use crate::{dns, p2p_control, TunConfig};
 use anyhow::Context;
 use bimap::BiMap;
+use connlib_model::{ConnectionPathView, GatewayConnectionView, Site, SiteId};
 use connlib_model::{
     DomainName, GatewayId, PublicKey, RelayId, ResourceId, ResourceStatus, ResourceView,
 };
-use connlib_model::{Site, SiteId};
+use dns_cache::DnsCache;
 use firezone_logging::{err_with_src, telemetry_event, unwrap_or_debug, unwrap_or_warn};
 use ip_network::{IpNetwork, Ipv4Network, Ipv6Network};
 use ip_network_table::IpNetworkTable;
 use ip_packet::{IpPacket, UdpSlice, MAX_UDP_PAYLOAD};
 use itertools::Itertools;
 
+use crate::metrics::PeerMetrics;
 use crate::peer::GatewayOnClient;
 use crate::utils::earliest;
 use crate::ClientEvent;
 use domain::base::Message;
+use domain::dep::octseq::OctetsInto as _;
 use lru::LruCache;
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
 use secrecy::{ExposeSecret as _, Secret};
-use snownet::{ClientNode, NoTurnServers, RelaySocket, Transmit};
+use snownet::{CandidatePolicy, ClientNode, NoTurnServers, RelaySocket, Transmit};
 use std::collections::hash_map::Entry;
 use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
@@ -116,15 +122,21 @@ pub struct ClientState {
     ///
     /// Has priority over system-configured DNS servers.
     upstream_dns: Vec<DnsServer>,
+    /// The split DNS rules configured in the portal.
+    portal_split_dns: Vec<SplitDnsRule>,
+    /// The split DNS rules configured locally, these win over the portal's rules for the same domain.
+    local_split_dns: Vec<SplitDnsRule>,
 
     /// Maps from connlib-assigned IP of a DNS server back to the originally configured system DNS resolver.
     dns_mapping: BiMap<IpAddr, DnsServer>,
     /// DNS queries that had their destination IP mangled because the servers is a CIDR resource.
     ///
-    /// The [`Instant`] tracks when the DNS query expires.
-    mangled_dns_queries: HashMap<(SocketAddr, u16), Instant>,
+    /// The [`IpAddr`] is the sentinel the query was originally sent to, the [`Instant`] tracks when the DNS query expires.
+    mangled_dns_queries: HashMap<(SocketAddr, u16), (IpAddr, Instant)>,
     /// Manages internal dns records and emits forwarding event when not internally handled
     stub_resolver: StubResolver,
+    /// Responses to recursive DNS queries, so we don't need to forward repeated queries.
+    dns_cache: DnsCache,
 
     /// Configuration of the TUN device, when it is up.
     tun_config: Option<TunConfig>,
@@ -147,6 +159,9 @@ pub struct ClientState {
     buffered_packets: VecDeque<IpPacket>,
     buffered_transmits: VecDeque<Transmit<'static>>,
     buffered_dns_queries: VecDeque<dns::RecursiveQuery>,
+
+    metrics: PeerMetrics<GatewayId>,
+    dns_queries_counter: Counter<u64>,
 }
 
 enum DnsResourceNatState {
@@ -219,17 +234,25 @@ impl ClientState {
             gateways_site: Default::default(),
             mangled_dns_queries: Default::default(),
             stub_resolver: Default::default(),
+            dns_cache: DnsCache::new(),
             disabled_resources: Default::default(),
             buffered_transmits: Default::default(),
             internet_resource: None,
             recently_connected_gateways: LruCache::new(MAX_REMEMBERED_GATEWAYS),
             upstream_dns: Default::default(),
+            portal_split_dns: Default::default(),
+            local_split_dns: Default::default(),
             buffered_dns_queries: Default::default(),
             tcp_dns_client: dns_over_tcp::Client::new(now, seed),
             tcp_dns_server: dns_over_tcp::Server::new(now),
             tcp_dns_sockets_by_upstream_and_query_id: Default::default(),
             pending_flows: Default::default(),
             dns_resource_nat_by_gateway: BTreeMap::new(),
+            metrics: PeerMetrics::new(),
+            dns_queries_counter: opentelemetry::global::meter("connlib")
+                .u64_counter("dns_queries_total")
+                .with_description("The number of DNS queries we received on the TUN device")
+                .init(),
         }
     }
 
@@ -300,6 +323,36 @@ impl ClientState {
         self.node.public_key()
     }
 
+    pub(crate) fn set_relay_tcp_fallback(&mut self, enabled: bool) {
+        self.node.set_relay_tcp_fallback(enabled);
+    }
+
+    pub fn set_candidate_policy(&mut self, policy: CandidatePolicy) {
+        self.node.set_candidate_policy(policy);
+    }
+
+    /// Path, RTT and traffic information for each connected Gateway.
+    pub fn connection_stats(&self, now: Instant) -> Vec<GatewayConnectionView> {
+        let (_, connections) = self.node.stats(now);
+
+        connections
+            .map(|(gateway_id, stats)| GatewayConnectionView {
+                gateway_id,
+                path: stats.selected_pair.map(|pair| ConnectionPathView {
+                    local: pair.local,
+                    local_type: pair.local_type,
+                    remote: pair.remote,
+                    remote_type: pair.remote_type,
+                    relay: pair.relay,
+                }),
+                rtt: stats.rtt,
+                handshake_age: stats.wg_handshake_age,
+                tx_bytes: stats.tx_bytes.0 as u64,
+                rx_bytes: stats.rx_bytes.0 as u64,
+            })
+            .collect()
+    }
+
     /// Updates the NAT for all domains resolved by the stub resolver on the corresponding gateway.
     ///
     /// In order to route traffic for DNS resources, the designated gateway needs to set up NAT from
@@ -531,17 +584,19 @@ impl ClientState {
             .inspect_err(|e| tracing::debug!(%gid, %local, %from, "{e}"))
             .ok()?;
 
+        self.metrics.record_received(gid, packet.packet().len());
+
         let packet = maybe_mangle_dns_response_from_cidr_resource(
             packet,
-            &self.dns_mapping,
             &mut self.mangled_dns_queries,
+            &mut self.dns_cache,
             now,
         );
 
         Some(packet)
     }
 
-    pub(crate) fn handle_dns_response(&mut self, response: dns::RecursiveResponse) {
+    pub(crate) fn handle_dns_response(&mut self, response: dns::RecursiveResponse, now: Instant) {
         let qid = response.query.header().id();
         let server = response.server;
         let domain = response
@@ -551,13 +606,17 @@ impl ClientState {
             .map(|q| q.into_qname())
             .map(tracing::field::display);
 
-        let _span = tracing::debug_span!("handle_dns_response", %qid, %server, domain).entered();
+        let _span = tracing::debug_span!("handle_dns_response", %qid, ?server, domain).entered();
+
+        if let Ok(message) = &response.message {
+            self.dns_cache.insert(&server, message, now);
+        }
 
         match (response.transport, response.message) {
             (dns::Transport::Udp { .. }, Err(e)) if e.kind() == io::ErrorKind::TimedOut => {
                 tracing::debug!("Recursive UDP DNS query timed out")
             }
-            (dns::Transport::Udp { source }, result) => {
+            (dns::Transport::Udp { source, sentinel }, result) => {
                 let message = result
                     .inspect(|message| {
                         tracing::trace!("Received recursive UDP DNS response");
@@ -573,7 +632,7 @@ impl ClientState {
                     });
 
                 unwrap_or_warn!(
-                    self.try_queue_udp_dns_response(server, source, message),
+                    self.try_queue_udp_dns_response(sentinel, source, message),
                     "Failed to queue UDP DNS response: {}"
                 );
             }
@@ -631,6 +690,8 @@ impl ClientState {
 
         let gid = peer.id();
 
+        self.metrics.record_sent(gid, packet.packet().len());
+
         let transmit = self
             .node
             .encapsulate(gid, packet, now)
@@ -642,17 +703,17 @@ impl ClientState {
 
     fn try_queue_udp_dns_response(
         &mut self,
-        from: SocketAddr,
+        sentinel: IpAddr,
         dst: SocketAddr,
         message: Message<Vec<u8>>,
     ) -> anyhow::Result<()> {
-        let saddr = *self
-            .dns_mapping
-            .get_by_right(&DnsServer::from(from))
-            .context("Unknown DNS server")?;
+        anyhow::ensure!(
+            self.dns_mapping.contains_left(&sentinel),
+            "Unknown DNS sentinel {sentinel}"
+        );
 
         let ip_packet = ip_packet::make::udp_packet(
-            saddr,
+            sentinel,
             dst.ip(),
             DNS_PORT,
             dst.port(),
@@ -786,10 +847,39 @@ impl ClientState {
             .is_some()
     }
 
+    /// Picks the server for a query that is not for a DNS resource and whether it must be reached through the tunnel.
+    ///
+    /// Servers of split DNS rules are only reached through the tunnel if they are within a CIDR resource.
+    /// Unlike the default upstream servers, it doesn't matter whether they were configured in the portal.
+    ///
+    /// Plain-text queries through the tunnel are forwarded to the gateway directly.
+    /// Encrypted ones are sent from the host on a socket that is routed through the tunnel, see [`dns::RecursiveQuery::through_tunnel`].
+    fn recursive_dns_server(
+        &self,
+        upstream: DnsServer,
+        split_dns_server: Option<DnsServer>,
+    ) -> (DnsServer, bool) {
+        match split_dns_server {
+            Some(server) => {
+                let via_tunnel = self
+                    .active_cidr_resources
+                    .longest_match(server.ip())
+                    .is_some();
+
+                (server, via_tunnel)
+            }
+            None => {
+                let via_tunnel = self.should_forward_dns_query_to_gateway(upstream.ip());
+
+                (upstream, via_tunnel)
+            }
+        }
+    }
+
     /// Handles UDP & TCP packets targeted at our stub resolver.
     fn try_handle_dns(&mut self, packet: IpPacket, now: Instant) -> ControlFlow<(), IpPacket> {
         let dst = packet.destination();
-        let Some(upstream) = self.dns_mapping.get_by_left(&dst).map(|s| s.address()) else {
+        let Some(upstream) = self.dns_mapping.get_by_left(&dst).cloned() else {
             return ControlFlow::Continue(packet); // Not for our DNS resolver.
         };
 
@@ -873,6 +963,7 @@ impl ClientState {
         let upstream_resolvers = self
             .dns_mapping
             .right_values()
+            .filter(|s| !s.is_encrypted())
             .map(|s| s.address())
             .collect();
 
@@ -984,6 +1075,7 @@ impl ClientState {
         tracing::debug!(servers = ?new_dns, "Received system-defined DNS servers");
 
         self.system_resolvers = new_dns;
+        self.dns_cache.clear();
 
         self.update_dns_mapping()
     }
@@ -1015,10 +1107,29 @@ impl ClientState {
         }
 
         self.upstream_dns = config.upstream_dns;
+        self.portal_split_dns = config.split_dns;
 
+        self.update_split_dns();
         self.update_dns_mapping()
     }
 
+    /// Sets the split DNS rules configured locally, on top of the ones from the portal.
+    pub fn set_split_dns_rules(&mut self, rules: Vec<SplitDnsRule>) {
+        tracing::debug!(?rules, "Setting local split DNS rules");
+
+        self.local_split_dns = rules;
+        self.update_split_dns();
+    }
+
+    fn update_split_dns(&mut self) {
+        self.stub_resolver.set_split_dns(
+            self.portal_split_dns
+                .iter()
+                .chain(&self.local_split_dns)
+                .cloned(),
+        );
+    }
+
     pub fn poll_packets(&mut self) -> Option<IpPacket> {
         self.buffered_packets
             .pop_front()
@@ -1028,7 +1139,11 @@ impl ClientState {
     pub fn poll_timeout(&mut self) -> Option<Instant> {
         // The number of mangled DNS queries is expected to be fairly small because we only track them whilst connecting to a CIDR resource that is a DNS server.
         // Thus, sorting these values on-demand even within `poll_timeout` is expected to be performant enough.
-        let next_dns_query_expiry = self.mangled_dns_queries.values().min().copied();
+        let next_dns_query_expiry = self
+            .mangled_dns_queries
+            .values()
+            .map(|(_, expires_at)| *expires_at)
+            .min();
 
         earliest(
             earliest(
@@ -1043,7 +1158,7 @@ impl ClientState {
         self.node.handle_timeout(now);
         self.drain_node_events();
 
-        self.mangled_dns_queries.retain(|_, exp| now < *exp);
+        self.mangled_dns_queries.retain(|_, (_, exp)| now < *exp);
 
         self.advance_dns_tcp_sockets(now);
     }
@@ -1087,14 +1202,17 @@ impl ClientState {
                     continue;
                 };
 
-                self.handle_dns_response(dns::RecursiveResponse {
-                    server,
-                    query: query_result.query,
-                    message: query_result
-                        .result
-                        .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{e:#}"))),
-                    transport: dns::Transport::Tcp { source },
-                });
+                self.handle_dns_response(
+                    dns::RecursiveResponse {
+                        server: DnsServer::from(server),
+                        query: query_result.query,
+                        message: query_result
+                            .result
+                            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{e:#}"))),
+                        transport: dns::Transport::Tcp { source },
+                    },
+                    now,
+                );
                 continue;
             }
 
@@ -1104,7 +1222,7 @@ impl ClientState {
 
     fn handle_udp_dns_query(
         &mut self,
-        upstream: SocketAddr,
+        upstream: DnsServer,
         mut packet: IpPacket,
         now: Instant,
     ) -> ControlFlow<(), IpPacket> {
@@ -1123,6 +1241,10 @@ impl ClientState {
         };
 
         let source = SocketAddr::new(packet.source(), datagram.source_port());
+        let sentinel = packet.destination();
+
+        self.dns_queries_counter
+            .add(1, &[KeyValue::new("transport", "udp")]);
 
         match self.stub_resolver.handle(message) {
             dns::ResolveStrategy::LocalResponse(response) => {
@@ -1130,18 +1252,33 @@ impl ClientState {
                 self.update_dns_resource_nat(now, iter::empty());
 
                 unwrap_or_debug!(
-                    self.try_queue_udp_dns_response(upstream, source, response),
+                    self.try_queue_udp_dns_response(sentinel, source, response),
                     "Failed to queue UDP DNS response: {}"
                 );
             }
-            dns::ResolveStrategy::Recurse => {
+            dns::ResolveStrategy::Recurse { server } => {
                 let query_id = message.header().id();
+                let (upstream, via_tunnel) = self.recursive_dns_server(upstream, server);
+
+                if let Some(response) = self.dns_cache.try_answer(&upstream, message, now) {
+                    tracing::trace!(server = ?upstream, %query_id, "Answering UDP DNS query from cache");
+
+                    unwrap_or_debug!(
+                        self.try_queue_udp_dns_response(sentinel, source, response),
+                        "Failed to queue UDP DNS response: {}"
+                    );
+                    return ControlFlow::Break(());
+                }
+
+                if via_tunnel && !upstream.is_encrypted() {
+                    let upstream = upstream.address();
 
-                if self.should_forward_dns_query_to_gateway(upstream.ip()) {
                     tracing::trace!(server = %upstream, %query_id, "Forwarding UDP DNS query via tunnel");
 
-                    self.mangled_dns_queries
-                        .insert((upstream, message.header().id()), now + IDS_EXPIRE);
+                    self.mangled_dns_queries.insert(
+                        (upstream, message.header().id()),
+                        (sentinel, now + IDS_EXPIRE),
+                    );
                     packet.set_dst(upstream.ip());
                     packet.update_checksum();
 
@@ -1150,10 +1287,12 @@ impl ClientState {
 
                 let query_id = message.header().id();
 
-                tracing::trace!(server = %upstream, %query_id, "Forwarding UDP DNS query directly via host");
+                tracing::trace!(server = ?upstream, %query_id, "Forwarding UDP DNS query directly via host");
 
                 self.buffered_dns_queries
-                    .push_back(dns::RecursiveQuery::via_udp(source, upstream, message));
+                    .push_back(dns::RecursiveQuery::via_udp(
+                        source, sentinel, upstream, message, via_tunnel,
+                    ));
             }
         }
 
@@ -1163,11 +1302,13 @@ impl ClientState {
     fn handle_tcp_dns_query(&mut self, query: dns_over_tcp::Query, now: Instant) {
         let message = query.message;
 
-        let Some(upstream) = self.dns_mapping.get_by_left(&query.local.ip()) else {
+        let Some(upstream) = self.dns_mapping.get_by_left(&query.local.ip()).cloned() else {
             // This is highly-unlikely but might be possible if our DNS mapping changes whilst the TCP DNS server is processing a request.
             return;
         };
-        let server = upstream.address();
+
+        self.dns_queries_counter
+            .add(1, &[KeyValue::new("transport", "tcp")]);
 
         match self.stub_resolver.handle(message.for_slice_ref()) {
             dns::ResolveStrategy::LocalResponse(response) => {
@@ -1179,10 +1320,25 @@ impl ClientState {
                     "Failed to send TCP DNS response: {}"
                 );
             }
-            dns::ResolveStrategy::Recurse => {
+            dns::ResolveStrategy::Recurse { server } => {
                 let query_id = message.header().id();
+                let (upstream, via_tunnel) = self.recursive_dns_server(upstream, server);
+                let server = upstream.address();
+
+                if let Some(response) =
+                    self.dns_cache
+                        .try_answer(&upstream, message.for_slice_ref(), now)
+                {
+                    tracing::trace!(server = ?upstream, %query_id, "Answering TCP DNS query from cache");
+
+                    unwrap_or_debug!(
+                        self.tcp_dns_server.send_message(query.socket, response),
+                        "Failed to send TCP DNS response: {}"
+                    );
+                    return;
+                }
 
-                if self.should_forward_dns_query_to_gateway(server.ip()) {
+                if via_tunnel && !upstream.is_encrypted() {
                     match self.tcp_dns_client.send_query(server, message.clone()) {
                         Ok(()) => {}
                         Err(e) => {
@@ -1208,10 +1364,15 @@ impl ClientState {
                     return;
                 }
 
-                tracing::trace!(%server, %query_id, "Forwarding TCP DNS query");
+                tracing::trace!(server = ?upstream, %query_id, "Forwarding TCP DNS query");
 
                 self.buffered_dns_queries
-                    .push_back(dns::RecursiveQuery::via_tcp(query.socket, server, message));
+                    .push_back(dns::RecursiveQuery::via_tcp(
+                        query.socket,
+                        upstream,
+                        message,
+                        via_tunnel,
+                    ));
             }
         };
     }
@@ -1298,6 +1459,8 @@ impl ClientState {
         let mut removed_ice_candidates = BTreeMap::<GatewayId, BTreeSet<String>>::default();
 
         while let Some(event) = self.node.poll_event() {
+            self.metrics.record_event(&event);
+
             match event {
                 snownet::Event::ConnectionFailed(id) | snownet::Event::ConnectionClosed(id) => {
                     self.cleanup_connected_gateway(&id);
@@ -1325,6 +1488,7 @@ impl ClientState {
                     self.update_site_status_by_gateway(&id, ResourceStatus::Online);
                     resources_changed = true;
                 }
+                snownet::Event::ConnectionMigrated(_) => {}
             }
         }
 
@@ -1374,6 +1538,7 @@ impl ClientState {
 
         self.recently_connected_gateways.clear(); // Ensure we don't have sticky gateways when we roam.
         self.dns_resource_nat_by_gateway.clear(); // Clear all state related to DNS resource NATs.
+        self.dns_cache.clear(); // Cached responses may be specific to the network we were on.
         self.drain_node_events();
 
         // Resetting the client will trigger a failed `QueryResult` for each one that is in-progress.
@@ -1790,10 +1955,14 @@ fn is_definitely_not_a_resource(ip: IpAddr) -> bool {
 
 fn maybe_mangle_dns_response_from_cidr_resource(
     mut packet: IpPacket,
-    dns_mapping: &BiMap<IpAddr, DnsServer>,
-    mangeled_dns_queries: &mut HashMap<(SocketAddr, u16), Instant>,
+    mangeled_dns_queries: &mut HashMap<(SocketAddr, u16), (IpAddr, Instant)>,
+    dns_cache: &mut DnsCache,
     now: Instant,
 ) -> IpPacket {
+    if mangeled_dns_queries.is_empty() {
+        return packet;
+    }
+
     let src_ip = packet.source();
 
     let Some(udp) = packet.as_udp() else {
@@ -1803,17 +1972,13 @@ fn maybe_mangle_dns_response_from_cidr_resource(
     let src_port = udp.source_port();
     let src_socket = SocketAddr::new(src_ip, src_port);
 
-    let Some(sentinel) = dns_mapping.get_by_right(&DnsServer::from(src_socket)) else {
-        return packet;
-    };
-
     let Ok(message) = domain::base::Message::from_slice(udp.payload()) else {
         return packet;
     };
 
-    let Some(query_sent_at) = mangeled_dns_queries
+    let Some((sentinel, query_sent_at)) = mangeled_dns_queries
         .remove(&(src_socket, message.header().id()))
-        .map(|expires_at| expires_at - IDS_EXPIRE)
+        .map(|(sentinel, expires_at)| (sentinel, expires_at - IDS_EXPIRE))
     else {
         return packet;
     };
@@ -1828,7 +1993,9 @@ fn maybe_mangle_dns_response_from_cidr_resource(
 
     tracing::trace!(server = %src_ip, query_id = %message.header().id(), ?rtt, domain, "Received UDP DNS response via tunnel");
 
-    packet.set_src(*sentinel);
+    dns_cache.insert(&DnsServer::from(src_socket), &message.octets_into(), now);
+
+    packet.set_src(sentinel);
     packet.update_checksum();
 
     packet
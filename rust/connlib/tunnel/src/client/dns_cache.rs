@@ -0,0 +1,497 @@
+//! A cache for the responses to recursive DNS queries.
+
+use crate::messages::DnsServer;
+use connlib_model::DomainName;
+use domain::{
+    base::{
+        iana::{Class, Rcode, Rtype},
+        message_builder::StaticCompressor,
+        Message, MessageBuilder, ParsedName, Record, RecordSection, Ttl,
+    },
+    rdata::{AllRecordData, Soa},
+};
+use lru::LruCache;
+use opentelemetry::metrics::Counter;
+use std::{
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+/// How many responses we cache at most before we evict the least recently used ones.
+const CAPACITY: NonZeroUsize = unsafe { NonZeroUsize::new_unchecked(1024) };
+
+/// Upper bound for caching positive responses, regardless of their TTL.
+const MAX_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Upper bound for caching negative responses, see <https://www.rfc-editor.org/rfc/rfc2308#section-5>.
+const MAX_NEGATIVE_TTL: Duration = Duration::from_secs(15 * 60);
+
+pub(crate) struct DnsCache {
+    entries: LruCache<Key, Entry>,
+
+    hits: Counter<u64>,
+    misses: Counter<u64>,
+}
+
+/// Responses are cached per upstream server because different servers may give different answers, e.g. with split DNS.
+///
+/// Whether the query uses EDNS, asks for DNSSEC records (DO) or disables validation (CD) changes the response too.
+/// Servers echo these in their responses, so the key of a response matches the key of its query.
+#[derive(Debug, PartialEq, Eq, Hash)]
+struct Key {
+    server: DnsServer,
+    qname: DomainName,
+    qtype: Rtype,
+    qclass: Class,
+    edns: bool,
+    dnssec_ok: bool,
+    checking_disabled: bool,
+}
+
+struct Entry {
+    response: Message<Vec<u8>>,
+    inserted_at: Instant,
+    expires_at: Instant,
+}
+
+type CachedRecord<'a> = Record<ParsedName<&'a [u8]>, AllRecordData<&'a [u8], ParsedName<&'a [u8]>>>;
+
+impl DnsCache {
+    pub(crate) fn new() -> Self {
+        let meter = opentelemetry::global::meter("connlib");
+
+        Self {
+            entries: LruCache::new(CAPACITY),
+            hits: meter
+                .u64_counter("dns_cache_hits_total")
+                .with_description("The number of recursive DNS queries answered from the cache")
+                .init(),
+            misses: meter
+                .u64_counter("dns_cache_misses_total")
+                .with_description("The number of recursive DNS queries not found in the cache")
+                .init(),
+        }
+    }
+
+    /// Answers the query from the cache if we have an unexpired response to it from `server`.
+    ///
+    /// The TTLs in the returned response are reduced by the time it spent in the cache.
+    pub(crate) fn try_answer(
+        &mut self,
+        server: &DnsServer,
+        query: Message<&[u8]>,
+        now: Instant,
+    ) -> Option<Message<Vec<u8>>> {
+        let response = Key::new(server, query).and_then(|key| {
+            let entry = self.entries.get(&key)?;
+
+            if now >= entry.expires_at {
+                self.entries.pop(&key);
+                return None;
+            }
+
+            let elapsed = now.duration_since(entry.inserted_at).as_secs();
+
+            make_response(
+                &entry.response,
+                query,
+                u32::try_from(elapsed).unwrap_or(u32::MAX),
+            )
+        });
+
+        match response {
+            Some(_) => self.hits.add(1, &[]),
+            None => self.misses.add(1, &[]),
+        }
+
+        response
+    }
+
+    /// Caches a response from `server` unless it is an error, truncated or has a TTL of 0.
+    ///
+    /// Negative responses (NXDOMAIN and NODATA) are cached as per RFC 2308, i.e. only if they carry an SOA record.
+    pub(crate) fn insert(&mut self, server: &DnsServer, response: &Message<Vec<u8>>, now: Instant) {
+        let Some(key) = Key::new(server, response.for_slice_ref()) else {
+            return;
+        };
+        let Some(ttl) = cacheable_ttl(response) else {
+            return;
+        };
+        if ttl.is_zero() {
+            return;
+        }
+
+        tracing::trace!(domain = %key.qname, qtype = %key.qtype, ?ttl, "Caching DNS response");
+
+        self.entries.put(
+            key,
+            Entry {
+                response: response.clone(),
+                inserted_at: now,
+                expires_at: now + ttl,
+            },
+        );
+    }
+
+    pub(crate) fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+impl Key {
+    fn new(server: &DnsServer, message: Message<&[u8]>) -> Option<Self> {
+        let question = message.sole_question().ok()?;
+        let opt = message.opt();
+
+        Some(Self {
+            server: server.clone(),
+            qname: question.qname().to_vec(),
+            qtype: question.qtype(),
+            qclass: question.qclass(),
+            edns: opt.is_some(),
+            dnssec_ok: opt.is_some_and(|opt| opt.dnssec_ok()),
+            checking_disabled: message.header().cd(),
+        })
+    }
+}
+
+/// Creates the response to `query` from a cached response.
+///
+/// The question is taken from the query because it may differ in the case of the domain, e.g. with DNS 0x20 encoding.
+fn make_response(
+    cached: &Message<Vec<u8>>,
+    query: Message<&[u8]>,
+    elapsed_secs: u32,
+) -> Option<Message<Vec<u8>>> {
+    let mut builder = MessageBuilder::from_target(StaticCompressor::new(Vec::new())).ok()?;
+    *builder.header_mut() = cached.header();
+    builder.header_mut().set_id(query.header().id());
+
+    let mut builder = builder.question();
+    for question in query.question() {
+        builder.push(question.ok()?).ok()?;
+    }
+
+    let mut builder = builder.answer();
+    for record in aged_records(cached.answer().ok()?, elapsed_secs)? {
+        builder.push(record).ok()?;
+    }
+
+    let mut builder = builder.authority();
+    for record in aged_records(cached.authority().ok()?, elapsed_secs)? {
+        builder.push(record).ok()?;
+    }
+
+    let mut builder = builder.additional();
+    for record in aged_records(cached.additional().ok()?, elapsed_secs)? {
+        builder.push(record).ok()?;
+    }
+
+    Message::from_octets(builder.finish().into_target()).ok()
+}
+
+/// Parses all records of a section, with their TTLs reduced by the time they spent in the cache.
+fn aged_records(
+    section: RecordSection<'_, Vec<u8>>,
+    elapsed_secs: u32,
+) -> Option<Vec<CachedRecord<'_>>> {
+    section
+        .map(|record| {
+            let mut record = record.ok()?.into_any_record::<AllRecordData<_, _>>().ok()?;
+
+            // The "TTL" of the OPT pseudo record carries EDNS flags instead.
+            if record.rtype() != Rtype::OPT {
+                record.set_ttl(Ttl::from_secs(
+                    record.ttl().as_secs().saturating_sub(elapsed_secs),
+                ));
+            }
+
+            Some(record)
+        })
+        .collect()
+}
+
+/// Returns how long we may cache the given response, if at all.
+fn cacheable_ttl(response: &Message<Vec<u8>>) -> Option<Duration> {
+    let header = response.header();
+    if !header.qr() || header.tc() {
+        return None;
+    }
+
+    let rcode = header.rcode();
+    if rcode != Rcode::NOERROR && rcode != Rcode::NXDOMAIN {
+        return None;
+    }
+
+    let min_answer_ttl = response
+        .answer()
+        .ok()?
+        .map(|record| record.map(|r| r.ttl()))
+        .collect::<Result<Vec<_>, _>>()
+        .ok()?
+        .into_iter()
+        .min();
+
+    if rcode == Rcode::NOERROR {
+        if let Some(ttl) = min_answer_ttl {
+            return Some(Duration::from_secs(ttl.as_secs().into()).min(MAX_TTL));
+        }
+    }
+
+    // NXDOMAIN or NODATA: The negative TTL is the minimum of the SOA's own TTL and its MINIMUM field.
+    let soa = response
+        .authority()
+        .ok()?
+        .filter_map(Result::ok)
+        .find(|record| record.rtype() == Rtype::SOA)?
+        .to_record::<Soa<_>>()
+        .ok()??;
+    let ttl = soa.ttl().min(soa.data().minimum());
+
+    Some(Duration::from_secs(ttl.as_secs().into()).min(MAX_NEGATIVE_TTL))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::{
+        base::{message_builder::AdditionalBuilder, Question, Record as DnsRecord, Serial},
+        rdata::{Soa, A},
+    };
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn answers_from_cache_with_reduced_ttl() {
+        let mut cache = DnsCache::new();
+        let now = Instant::now();
+
+        cache.insert(&server(), &a_response("example.com", 300), now);
+
+        let query = query("EXAMPLE.com", 42);
+        let response = cache
+            .try_answer(
+                &server(),
+                query.for_slice_ref(),
+                now + Duration::from_secs(100),
+            )
+            .unwrap();
+
+        assert_eq!(response.header().id(), 42);
+        assert_eq!(
+            response.sole_question().unwrap().qname().to_string(),
+            "EXAMPLE.com"
+        );
+        assert_eq!(answer_ttls(&response), vec![200]);
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        let mut cache = DnsCache::new();
+        let now = Instant::now();
+
+        cache.insert(&server(), &a_response("example.com", 300), now);
+
+        let query = query("example.com", 1);
+
+        assert!(cache
+            .try_answer(
+                &server(),
+                query.for_slice_ref(),
+                now + Duration::from_secs(300)
+            )
+            .is_none());
+    }
+
+    #[test]
+    fn caches_per_server() {
+        let mut cache = DnsCache::new();
+        let now = Instant::now();
+        let other = DnsServer::from(([8, 8, 8, 8], 53));
+
+        cache.insert(&server(), &a_response("example.com", 300), now);
+
+        assert!(cache
+            .try_answer(&other, query("example.com", 1).for_slice_ref(), now)
+            .is_none());
+    }
+
+    #[test]
+    fn caches_per_edns_and_dnssec_flags() {
+        let mut cache = DnsCache::new();
+        let now = Instant::now();
+
+        cache.insert(&server(), &dnssec_a_response("example.com", 300), now);
+
+        let plain = query("example.com", 1);
+        let mut checking_disabled = query("example.com", 1);
+        checking_disabled.header_mut().set_cd(true);
+
+        assert!(cache
+            .try_answer(&server(), plain.for_slice_ref(), now)
+            .is_none());
+        assert!(cache
+            .try_answer(&server(), checking_disabled.for_slice_ref(), now)
+            .is_none());
+
+        let response = cache
+            .try_answer(
+                &server(),
+                dnssec_query("example.com", 1).for_slice_ref(),
+                now,
+            )
+            .unwrap();
+
+        assert!(response.opt().unwrap().dnssec_ok());
+    }
+
+    #[test]
+    fn negative_ttl_is_minimum_of_soa_ttl_and_minimum_field() {
+        let response = nxdomain_response("nope.example.com", 3600, 60);
+
+        assert_eq!(cacheable_ttl(&response), Some(Duration::from_secs(60)));
+    }
+
+    #[test]
+    fn does_not_cache_negative_response_without_soa() {
+        let query = query("nope.example.com", 1);
+        let response = MessageBuilder::new_vec()
+            .start_answer(&query, Rcode::NXDOMAIN)
+            .unwrap()
+            .into_message();
+
+        assert_eq!(cacheable_ttl(&response), None);
+    }
+
+    #[test]
+    fn does_not_cache_servfail() {
+        let query = query("example.com", 1);
+        let response = MessageBuilder::new_vec()
+            .start_answer(&query, Rcode::SERVFAIL)
+            .unwrap()
+            .into_message();
+
+        assert_eq!(cacheable_ttl(&response), None);
+    }
+
+    #[test]
+    fn clear_removes_all_entries() {
+        let mut cache = DnsCache::new();
+        let now = Instant::now();
+
+        cache.insert(&server(), &a_response("example.com", 300), now);
+        cache.clear();
+
+        assert!(cache
+            .try_answer(&server(), query("example.com", 1).for_slice_ref(), now)
+            .is_none());
+    }
+
+    fn server() -> DnsServer {
+        DnsServer::from(([1, 1, 1, 1], 53))
+    }
+
+    fn query(domain: &str, id: u16) -> Message<Vec<u8>> {
+        let mut builder = MessageBuilder::new_vec();
+        builder.header_mut().set_id(id);
+
+        let mut builder = builder.question();
+        builder
+            .push(Question::new_in(
+                domain.parse::<DomainName>().unwrap(),
+                Rtype::A,
+            ))
+            .unwrap();
+
+        builder.into_message()
+    }
+
+    fn a_response(domain: &str, ttl: u32) -> Message<Vec<u8>> {
+        let query = query(domain, 1);
+        let mut builder = MessageBuilder::new_vec()
+            .start_answer(&query, Rcode::NOERROR)
+            .unwrap();
+        builder
+            .push(DnsRecord::new(
+                domain.parse::<DomainName>().unwrap(),
+                Class::IN,
+                Ttl::from_secs(ttl),
+                A::new(Ipv4Addr::new(93, 184, 215, 14)),
+            ))
+            .unwrap();
+
+        builder.into_message()
+    }
+
+    fn nxdomain_response(domain: &str, soa_ttl: u32, minimum: u32) -> Message<Vec<u8>> {
+        let query = query(domain, 1);
+        let mut builder = MessageBuilder::new_vec()
+            .start_answer(&query, Rcode::NXDOMAIN)
+            .unwrap()
+            .authority();
+        let zone = "example.com".parse::<DomainName>().unwrap();
+        builder
+            .push(DnsRecord::new(
+                zone.clone(),
+                Class::IN,
+                Ttl::from_secs(soa_ttl),
+                Soa::new(
+                    zone.clone(),
+                    zone,
+                    Serial(1),
+                    Ttl::from_secs(7200),
+                    Ttl::from_secs(3600),
+                    Ttl::from_secs(1209600),
+                    Ttl::from_secs(minimum),
+                ),
+            ))
+            .unwrap();
+
+        builder.into_message()
+    }
+
+    fn answer_ttls(response: &Message<Vec<u8>>) -> Vec<u32> {
+        response
+            .answer()
+            .unwrap()
+            .map(|r| r.unwrap().ttl().as_secs())
+            .collect()
+    }
+
+    fn with_dnssec_ok(mut builder: AdditionalBuilder<Vec<u8>>) -> Message<Vec<u8>> {
+        builder
+            .opt(|opt| {
+                opt.set_dnssec_ok(true);
+                Ok(())
+            })
+            .unwrap();
+
+        builder.into_message()
+    }
+
+    fn dnssec_query(domain: &str, id: u16) -> Message<Vec<u8>> {
+        let query = query(domain, id);
+        let mut builder = MessageBuilder::new_vec();
+        *builder.header_mut() = query.header();
+        let mut builder = builder.question();
+        builder.push(query.sole_question().unwrap()).unwrap();
+
+        with_dnssec_ok(builder.additional())
+    }
+
+    fn dnssec_a_response(domain: &str, ttl: u32) -> Message<Vec<u8>> {
+        let query = dnssec_query(domain, 1);
+        let mut builder = MessageBuilder::new_vec()
+            .start_answer(&query, Rcode::NOERROR)
+            .unwrap();
+        builder
+            .push(DnsRecord::new(
+                domain.parse::<DomainName>().unwrap(),
+                Class::IN,
+                Ttl::from_secs(ttl),
+                A::new(Ipv4Addr::new(93, 184, 215, 14)),
+            ))
+            .unwrap();
+
+        with_dnssec_ok(builder.additional())
+    }
+}
@@ -1,4 +1,5 @@
 use crate::client::IpProvider;
+use crate::messages::{DnsServer, SplitDnsRule};
 use anyhow::{Context, Result};
 use connlib_model::{DomainName, ResourceId};
 use dns_over_tcp::SocketHandle;
@@ -45,52 +46,74 @@ pub struct StubResolver {
     ip_provider: IpProvider,
     /// All DNS resources we know about, indexed by the glob pattern they match against.
     dns_resources: BTreeMap<Pattern, ResourceId>,
+    /// Upstream servers for queries that don't match a DNS resource, indexed by the glob pattern of the domains they are responsible for.
+    split_dns: BTreeMap<Pattern, DnsServer>,
 }
 
 /// A query that needs to be forwarded to an upstream DNS server for resolution.
 #[derive(Debug)]
 pub(crate) struct RecursiveQuery {
-    pub server: SocketAddr,
+    pub server: DnsServer,
     pub message: Message<Vec<u8>>,
     pub transport: Transport,
+    /// Whether the server is within a resource and must be reached through the tunnel.
+    ///
+    /// Only encrypted servers are queried that way, plain-text queries to resources are forwarded to the gateway instead.
+    pub through_tunnel: bool,
 }
 
 /// A response to a [`RecursiveQuery`].
 #[derive(Debug)]
 pub(crate) struct RecursiveResponse {
-    pub server: SocketAddr,
+    pub server: DnsServer,
     pub query: Message<Vec<u8>>,
     pub message: io::Result<Message<Vec<u8>>>,
     pub transport: Transport,
 }
 
 impl RecursiveQuery {
-    pub(crate) fn via_udp(source: SocketAddr, server: SocketAddr, message: Message<&[u8]>) -> Self {
+    pub(crate) fn via_udp(
+        source: SocketAddr,
+        sentinel: IpAddr,
+        server: DnsServer,
+        message: Message<&[u8]>,
+        through_tunnel: bool,
+    ) -> Self {
         Self {
             server,
             message: message.octets_into(),
-            transport: Transport::Udp { source },
+            transport: Transport::Udp { source, sentinel },
+            through_tunnel,
         }
     }
 
     pub(crate) fn via_tcp(
         source: SocketHandle,
-        server: SocketAddr,
+        server: DnsServer,
         message: Message<Vec<u8>>,
+        through_tunnel: bool,
     ) -> Self {
         Self {
             server,
             message,
             transport: Transport::Tcp { source },
+            through_tunnel,
         }
     }
 }
 
+/// How the original query reached us and thus, how we need to respond to it.
+///
+/// This is independent of how we forward the query, see [`DnsServer`].
 #[derive(Debug)]
 pub(crate) enum Transport {
     Udp {
         /// The original source we received the DNS query on.
         source: SocketAddr,
+        /// The sentinel IP the query was sent to and thus, the IP the response needs to come from.
+        ///
+        /// With split DNS, this is not necessarily the address of the server we forwarded the query to.
+        sentinel: IpAddr,
     },
     Tcp {
         source: SocketHandle,
@@ -103,7 +126,12 @@ pub(crate) enum ResolveStrategy {
     /// The query is for a Resource, we have an IP mapped already, and we can respond instantly
     LocalResponse(Message<Vec<u8>>),
     /// The query is for a non-Resource, forward it to an upstream or system resolver.
-    Recurse,
+    Recurse {
+        /// The server of the split DNS rule matching the domain, if any.
+        ///
+        /// If `None`, the query goes to the upstream resolver behind the sentinel it was sent to.
+        server: Option<DnsServer>,
+    },
 }
 
 impl Default for StubResolver {
@@ -113,6 +141,7 @@ impl Default for StubResolver {
             ips_to_fqdn: Default::default(),
             ip_provider: IpProvider::for_resources(),
             dns_resources: Default::default(),
+            split_dns: Default::default(),
         }
     }
 }
@@ -152,6 +181,33 @@ impl StubResolver {
         self.dns_resources.retain(|_, r| *r != id);
     }
 
+    /// Replaces all split DNS rules.
+    ///
+    /// A plain domain like `corp.internal` matches the domain itself and all its subdomains.
+    /// Domains with wildcards are matched exactly like the address of a DNS resource.
+    /// If several rules have the same domain, the last one wins.
+    pub(crate) fn set_split_dns(&mut self, rules: impl IntoIterator<Item = SplitDnsRule>) {
+        self.split_dns.clear();
+
+        for SplitDnsRule { domain, server } in rules {
+            let pattern = if domain.contains(['*', '?']) {
+                domain
+            } else {
+                format!("**.{domain}")
+            };
+
+            let parsed_pattern = match Pattern::new(&pattern) {
+                Ok(p) => p,
+                Err(e) => {
+                    tracing::warn!(%pattern, "Split DNS domain is not valid: {}", err_with_src(&e));
+                    continue;
+                }
+            };
+
+            self.split_dns.insert(parsed_pattern, server);
+        }
+    }
+
     fn get_or_assign_a_records(
         &mut self,
         fqdn: DomainName,
@@ -214,6 +270,25 @@ impl StubResolver {
         None
     }
 
+    /// Attempts to match the given domain against our split DNS rules, most specific first.
+    ///
+    /// Like [`StubResolver::match_resource_linear`], this is O(N).
+    fn match_split_dns_linear(&self, domain: &DomainName) -> Option<DnsServer> {
+        if self.split_dns.is_empty() {
+            return None;
+        }
+
+        let name = Candidate::from_domain(domain);
+        let (pattern, server) = self
+            .split_dns
+            .iter()
+            .find(|(pattern, _)| pattern.matches(&name))?;
+
+        tracing::trace!(%pattern, %domain, ?server, "Matched split DNS rule");
+
+        Some(server.clone())
+    }
+
     fn resource_address_name_by_reservse_dns(
         &self,
         reverse_dns_name: &DomainName,
@@ -276,7 +351,9 @@ impl StubResolver {
             }
             (Rtype::PTR, _) => {
                 let Some(fqdn) = self.resource_address_name_by_reservse_dns(&domain) else {
-                    return Ok(ResolveStrategy::Recurse);
+                    return Ok(ResolveStrategy::Recurse {
+                        server: self.match_split_dns_linear(&domain),
+                    });
                 };
 
                 vec![AllRecordData::Ptr(domain::rdata::Ptr::new(fqdn))]
@@ -288,7 +365,11 @@ impl StubResolver {
                 let response = build_dns_with_answer(message, domain, Vec::default())?;
                 return Ok(ResolveStrategy::LocalResponse(response));
             }
-            _ => return Ok(ResolveStrategy::Recurse),
+            _ => {
+                return Ok(ResolveStrategy::Recurse {
+                    server: self.match_split_dns_linear(&domain),
+                })
+            }
         };
 
         tracing::trace!(%qtype, %domain, records = ?resource_records, "Forming DNS response");
@@ -711,6 +792,78 @@ mod tests {
         assert_eq!(response.header().rcode(), Rcode::NXDOMAIN);
         assert_eq!(response.answer().unwrap().count(), 0);
     }
+
+    #[test]
+    fn split_dns_routes_by_most_specific_domain() {
+        let mut resolver = StubResolver::default();
+        let corp = DnsServer::from(([10, 0, 0, 53], 53));
+        let internal = DnsServer::from(([10, 0, 0, 1], 53));
+        let lab = DnsServer::from(([192, 168, 1, 1], 53));
+
+        resolver.set_split_dns([
+            SplitDnsRule {
+                domain: "internal".to_owned(),
+                server: internal.clone(),
+            },
+            SplitDnsRule {
+                domain: "corp.internal".to_owned(),
+                server: corp.clone(),
+            },
+            SplitDnsRule {
+                domain: "*.lab".to_owned(),
+                server: lab.clone(),
+            },
+        ]);
+
+        assert_eq!(
+            recurse_to(&mut resolver, "corp.internal"),
+            Some(corp.clone())
+        );
+        assert_eq!(recurse_to(&mut resolver, "git.corp.internal"), Some(corp));
+        assert_eq!(recurse_to(&mut resolver, "other.internal"), Some(internal));
+        assert_eq!(recurse_to(&mut resolver, "printer.lab"), Some(lab));
+        assert_eq!(recurse_to(&mut resolver, "a.printer.lab"), None);
+        assert_eq!(recurse_to(&mut resolver, "example.com"), None);
+    }
+
+    #[test]
+    fn dns_resources_take_precedence_over_split_dns() {
+        let mut resolver = StubResolver::default();
+        resolver.add_resource(ResourceId::from_u128(0), "app.corp.internal".to_owned());
+        resolver.set_split_dns([SplitDnsRule {
+            domain: "corp.internal".to_owned(),
+            server: DnsServer::from(([10, 0, 0, 53], 53)),
+        }]);
+
+        let query = query("app.corp.internal", Rtype::A);
+
+        assert!(matches!(
+            resolver.handle(query.for_slice_ref()),
+            ResolveStrategy::LocalResponse(_)
+        ));
+    }
+
+    fn recurse_to(resolver: &mut StubResolver, domain: &str) -> Option<DnsServer> {
+        let query = query(domain, Rtype::A);
+
+        let ResolveStrategy::Recurse { server } = resolver.handle(query.for_slice_ref()) else {
+            panic!("Unexpected result")
+        };
+
+        server
+    }
+
+    fn query(domain: &str, rtype: Rtype) -> Message<Vec<u8>> {
+        let mut builder = MessageBuilder::new_vec().question();
+        builder
+            .push(Question::new_in(
+                domain.parse::<DomainName>().unwrap(),
+                rtype,
+            ))
+            .unwrap();
+
+        builder.into_message()
+    }
 }
 
 #[cfg(feature = "divan")]
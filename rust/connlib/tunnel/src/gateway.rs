@@ -1,7 +1,8 @@
-use crate::messages::gateway::ResourceDescription;
+use crate::messages::gateway::{RateLimit, ResourceDescription};
 use crate::messages::{Answer, IceCredentials, ResolveRequest, SecretKey};
+use crate::metrics::PeerMetrics;
 use crate::utils::earliest;
-use crate::{p2p_control, GatewayEvent};
+use crate::{p2p_control, FlowCloseReason, GatewayEvent};
 use crate::{peer::ClientOnGateway, peer_store::PeerStore};
 use anyhow::{Context, Result};
 use boringtun::x25519::PublicKey;
@@ -9,9 +10,11 @@ use chrono::{DateTime, Utc};
 use connlib_model::{ClientId, DomainName, RelayId, ResourceId};
 use ip_network::{Ipv4Network, Ipv6Network};
 use ip_packet::{FzP2pControlSlice, IpPacket};
+use opentelemetry::metrics::Gauge;
 use secrecy::{ExposeSecret as _, Secret};
-use snownet::{Credentials, NoTurnServers, RelaySocket, ServerNode, Transmit};
+use snownet::{CandidatePolicy, Credentials, NoTurnServers, RelaySocket, ServerNode, Transmit};
 use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::iter;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::time::{Duration, Instant};
 
@@ -43,25 +46,41 @@ pub struct GatewayState {
 
     buffered_events: VecDeque<GatewayEvent>,
     buffered_transmits: VecDeque<Transmit<'static>>,
+
+    /// Whether we track the flows of clients and emit [`GatewayEvent::Flow`]s.
+    flow_logs_enabled: bool,
+    /// The throughput limit of every client, on top of the limits of the individual resources.
+    client_rate_limit: RateLimit,
+
+    metrics: PeerMetrics<ClientId>,
+    nat_sessions_gauge: Gauge<u64>,
 }
 
 #[derive(Debug)]
 pub struct DnsResourceNatEntry {
     domain: DomainName,
     proxy_ips: Vec<IpAddr>,
-    resolved_ips: Vec<IpAddr>,
+    resolved: ResolvedAddresses,
 }
 
 impl DnsResourceNatEntry {
-    pub fn new(request: ResolveRequest, resolved_ips: Vec<IpAddr>) -> Self {
+    pub fn new(request: ResolveRequest, resolved: ResolvedAddresses) -> Self {
         Self {
             domain: request.name,
             proxy_ips: request.proxy_ips,
-            resolved_ips,
+            resolved,
         }
     }
 }
 
+/// The addresses a domain resolved to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResolvedAddresses {
+    pub addresses: Vec<IpAddr>,
+    /// For how long the addresses are valid, i.e. the smallest TTL of all records.
+    pub ttl: Duration,
+}
+
 impl GatewayState {
     pub(crate) fn new(seed: [u8; 32], now: Instant) -> Self {
         Self {
@@ -70,6 +89,13 @@ impl GatewayState {
             next_expiry_resources_check: Default::default(),
             buffered_events: VecDeque::default(),
             buffered_transmits: VecDeque::default(),
+            flow_logs_enabled: false,
+            client_rate_limit: RateLimit::default(),
+            metrics: PeerMetrics::new(),
+            nat_sessions_gauge: opentelemetry::global::meter("connlib")
+                .u64_gauge("nat_sessions")
+                .with_description("The number of active NAT sessions for DNS resources")
+                .init(),
         }
     }
 
@@ -77,12 +103,70 @@ impl GatewayState {
         self.node.public_key()
     }
 
+    pub(crate) fn set_relay_tcp_fallback(&mut self, enabled: bool) {
+        self.node.set_relay_tcp_fallback(enabled);
+    }
+
+    pub fn set_candidate_policy(&mut self, policy: CandidatePolicy) {
+        self.node.set_candidate_policy(policy);
+    }
+
+    /// Enables tracking of flows for all clients that connect from now on.
+    pub fn enable_flow_logs(&mut self) {
+        self.flow_logs_enabled = true;
+    }
+
+    /// Limits the throughput of every client, regardless of which resources they access.
+    ///
+    /// Packets exceeding the limit are dropped.
+    pub fn set_client_rate_limit(&mut self, limit: RateLimit) {
+        self.client_rate_limit = limit;
+
+        for peer in self.peers.iter_mut() {
+            peer.set_rate_limit(limit);
+        }
+    }
+
     /// Handles packets received on the TUN device.
-    pub(crate) fn handle_tun_input(
+    ///
+    /// The packets of different clients are encrypted in parallel.
+    /// Returns one result per packet, in the same order.
+    pub(crate) fn handle_tun_input_batch(
+        &mut self,
+        packets: impl IntoIterator<Item = IpPacket>,
+        now: Instant,
+    ) -> Vec<Result<Option<snownet::EncryptedPacket>>> {
+        let mut results = Vec::new();
+        let mut routed = Vec::new();
+        let mut indices = Vec::new();
+
+        for packet in packets {
+            match self.route_tun_input(packet, now) {
+                Ok(Some(packet)) => {
+                    indices.push(results.len());
+                    routed.push(packet);
+                    results.push(Ok(None));
+                }
+                Ok(None) => results.push(Ok(None)),
+                Err(e) => results.push(Err(e)),
+            }
+        }
+
+        let encrypted = self.node.encapsulate_batch(routed, now);
+
+        for (index, result) in indices.into_iter().zip(encrypted) {
+            results[index] = result.context("Failed to encapsulate");
+        }
+
+        results
+    }
+
+    /// Finds the client a packet from the TUN device is destined for and translates it.
+    fn route_tun_input(
         &mut self,
         packet: IpPacket,
         now: Instant,
-    ) -> Result<Option<snownet::EncryptedPacket>> {
+    ) -> Result<Option<(ClientId, IpPacket)>> {
         let dst = packet.destination();
 
         if !is_client(dst) {
@@ -102,38 +186,48 @@ impl GatewayState {
             return Ok(None);
         };
 
-        let Some(encrypted_packet) = self
-            .node
-            .encapsulate(cid, packet, now)
-            .context("Failed to encapsulate")?
-        else {
-            return Ok(None);
-        };
+        self.metrics.record_sent(cid, packet.packet().len());
 
-        Ok(Some(encrypted_packet))
+        Ok(Some((cid, packet)))
     }
 
-    /// Handles UDP packets received on the network interface.
+    /// Handles UDP packets received on the network interface, given as `(local, from, packet)`.
     ///
     /// Most of these packets will be WireGuard encrypted IP packets and will thus yield an [`IpPacket`].
     /// Some of them will however be handled internally, for example, TURN control packets exchanged with relays.
+    /// The packets of different clients are decrypted in parallel.
     ///
-    /// In case this function returns `None`, you should call [`GatewayState::handle_timeout`] next to fully advance the internal state.
-    pub(crate) fn handle_network_input(
+    /// Returns one result per packet, in the same order.
+    /// For every `None`, you should call [`GatewayState::handle_timeout`] next to fully advance the internal state.
+    pub(crate) fn handle_network_input_batch<'p>(
         &mut self,
-        local: SocketAddr,
-        from: SocketAddr,
-        packet: &[u8],
+        packets: impl IntoIterator<Item = (SocketAddr, SocketAddr, &'p [u8])>,
         now: Instant,
-    ) -> Result<Option<IpPacket>> {
-        let Some((cid, packet)) = self
-            .node
-            .decapsulate(local, from, packet, now)
-            .context("Failed to decapsulate")?
-        else {
-            return Ok(None);
-        };
+    ) -> Vec<Result<Option<IpPacket>>> {
+        self.node
+            .decapsulate_batch(packets, now)
+            .into_iter()
+            .map(|result| {
+                let Some((cid, packet)) = result.context("Failed to decapsulate")? else {
+                    return Ok(None);
+                };
+
+                self.handle_decrypted(cid, packet, now)
+            })
+            .collect()
+    }
+
+    /// Encrypt and decrypt the packets of different clients on this many threads.
+    pub fn set_crypto_threads(&mut self, num_threads: usize) -> std::io::Result<()> {
+        self.node.set_crypto_threads(num_threads)
+    }
 
+    fn handle_decrypted(
+        &mut self,
+        cid: ClientId,
+        packet: IpPacket,
+        now: Instant,
+    ) -> Result<Option<IpPacket>> {
         let peer = self
             .peers
             .get_mut(&cid)
@@ -160,11 +254,15 @@ impl GatewayState {
             .translate_outbound(packet, now)
             .context("Failed to translate outbound packet")?;
 
+        if let Some(packet) = packet.as_ref() {
+            self.metrics.record_received(cid, packet.packet().len());
+        }
+
         Ok(packet)
     }
 
     pub fn cleanup_connection(&mut self, id: &ClientId) {
-        self.peers.remove(id);
+        self.remove_peer(id);
     }
 
     pub fn add_ice_candidate(&mut self, conn_id: ClientId, ice_candidate: String, now: Instant) {
@@ -187,8 +285,11 @@ impl GatewayState {
         };
 
         peer.remove_resource(resource);
+        self.buffered_events
+            .extend(iter::from_fn(|| peer.poll_flow_record()).map(GatewayEvent::Flow));
+
         if peer.is_emptied() {
-            self.peers.remove(client);
+            self.remove_peer(client);
         }
 
         tracing::debug!("Access removed");
@@ -247,7 +348,7 @@ impl GatewayState {
             now,
         )?;
 
-        let result = self.allow_access(client_id, ipv4, ipv6, expires_at, resource, None);
+        let result = self.allow_access(client_id, ipv4, ipv6, expires_at, resource, None, now);
         debug_assert!(
             result.is_ok(),
             "`allow_access` should never fail without a `DnsResourceEntry`"
@@ -256,6 +357,7 @@ impl GatewayState {
         Ok(())
     }
 
+    #[expect(clippy::too_many_arguments)]
     pub fn allow_access(
         &mut self,
         client: ClientId,
@@ -264,20 +366,28 @@ impl GatewayState {
         expires_at: Option<DateTime<Utc>>,
         resource: ResourceDescription,
         dns_resource_nat: Option<DnsResourceNatEntry>,
+        now: Instant,
     ) -> anyhow::Result<()> {
         let peer = self
             .peers
             .entry(client)
             .or_insert_with(|| ClientOnGateway::new(client, ipv4, ipv6));
 
+        if self.flow_logs_enabled {
+            peer.enable_flow_tracking();
+        }
+        peer.set_rate_limit(self.client_rate_limit);
+
         peer.add_resource(resource.clone(), expires_at);
 
         if let Some(entry) = dns_resource_nat {
             peer.setup_nat(
                 entry.domain,
                 resource.id(),
-                BTreeSet::from_iter(entry.resolved_ips),
+                BTreeSet::from_iter(entry.resolved.addresses),
                 BTreeSet::from_iter(entry.proxy_ips),
+                entry.resolved.ttl,
+                now,
             )?;
         }
 
@@ -290,25 +400,54 @@ impl GatewayState {
     pub fn handle_domain_resolved(
         &mut self,
         req: ResolveDnsRequest,
-        resolve_result: Result<Vec<IpAddr>>,
+        resolve_result: Result<ResolvedAddresses>,
         now: Instant,
     ) -> anyhow::Result<()> {
         use p2p_control::dns_resource_nat;
 
-        let nat_status = resolve_result
-            .and_then(|addresses| {
-                self.peers
-                    .get_mut(&req.client)
-                    .context("Unknown peer")?
-                    .setup_nat(
-                        req.domain.clone(),
-                        req.resource,
-                        BTreeSet::from_iter(addresses),
-                        BTreeSet::from_iter(req.proxy_ips),
-                    )?;
-
-                Ok(dns_resource_nat::NatStatus::Active)
-            })
+        let setup_result = resolve_result.and_then(|resolved| {
+            // An empty answer on refresh most likely means a hiccup of the upstream resolver; keep the existing translations.
+            anyhow::ensure!(
+                !(req.is_refresh && resolved.addresses.is_empty()),
+                "Domain resolved to no addresses"
+            );
+
+            let peer = self.peers.get_mut(&req.client).context("Unknown peer")?;
+            let resolved_ips = BTreeSet::from_iter(resolved.addresses);
+            let proxy_ips = BTreeSet::from_iter(req.proxy_ips);
+
+            if req.is_refresh {
+                peer.refresh_nat(
+                    req.domain.clone(),
+                    req.resource,
+                    resolved_ips,
+                    proxy_ips,
+                    resolved.ttl,
+                    now,
+                )
+            } else {
+                peer.setup_nat(
+                    req.domain.clone(),
+                    req.resource,
+                    resolved_ips,
+                    proxy_ips,
+                    resolved.ttl,
+                    now,
+                )
+            }
+        });
+
+        // The client already knows about the NAT, refreshing it happens transparently.
+        if req.is_refresh {
+            if let Err(e) = setup_result {
+                tracing::debug!(domain = %req.domain, "Failed to refresh DNS resource NAT: {e:#}");
+            }
+
+            return Ok(());
+        }
+
+        let nat_status = setup_result
+            .map(|()| dns_resource_nat::NatStatus::Active)
             .unwrap_or_else(|e| {
                 tracing::warn!("Failed to setup DNS resource NAT: {e:#}");
 
@@ -341,8 +480,21 @@ impl GatewayState {
                     p.expire_resources(utc_now);
                     p.handle_timeout(now)
                 });
+
+                // Flush the records of all flows before we drop peers without any resources.
+                for peer in self.peers.iter_mut().filter(|p| p.is_emptied()) {
+                    self.buffered_events
+                        .extend(iter::from_fn(|| peer.poll_flow_record()).map(GatewayEvent::Flow));
+                }
                 self.peers.retain(|_, p| !p.is_emptied());
 
+                let num_nat_sessions = self
+                    .peers
+                    .iter_mut()
+                    .map(|p| p.num_nat_sessions())
+                    .sum::<usize>();
+                self.nat_sessions_gauge.record(num_nat_sessions as u64, &[]);
+
                 self.next_expiry_resources_check = Some(now + EXPIRE_RESOURCES_INTERVAL);
             }
             None => self.next_expiry_resources_check = Some(now + EXPIRE_RESOURCES_INTERVAL),
@@ -355,9 +507,11 @@ impl GatewayState {
         let mut removed_ice_candidates = BTreeMap::<ClientId, BTreeSet<String>>::default();
 
         while let Some(event) = self.node.poll_event() {
+            self.metrics.record_event(&event);
+
             match event {
                 snownet::Event::ConnectionFailed(id) | snownet::Event::ConnectionClosed(id) => {
-                    self.peers.remove(&id);
+                    self.remove_peer(&id);
                 }
                 snownet::Event::NewIceCandidate {
                     connection,
@@ -377,7 +531,8 @@ impl GatewayState {
                         .or_default()
                         .insert(candidate);
                 }
-                snownet::Event::ConnectionEstablished(_) => {}
+                snownet::Event::ConnectionEstablished(_)
+                | snownet::Event::ConnectionMigrated(_) => {}
             }
         }
 
@@ -398,6 +553,17 @@ impl GatewayState {
         }
     }
 
+    /// Removes a peer, ending all its flows.
+    fn remove_peer(&mut self, id: &ClientId) {
+        let Some(mut peer) = self.peers.remove(id) else {
+            return;
+        };
+
+        peer.close_all_flows(FlowCloseReason::ClientDisconnected);
+        self.buffered_events
+            .extend(iter::from_fn(|| peer.poll_flow_record()).map(GatewayEvent::Flow));
+    }
+
     pub(crate) fn poll_transmit(&mut self) -> Option<snownet::Transmit<'static>> {
         self.buffered_transmits
             .pop_front()
@@ -465,6 +631,7 @@ fn handle_p2p_control_packet(
                 client: peer.id(),
                 resource: req.resource,
                 proxy_ips: req.proxy_ips,
+                is_refresh: false,
             }));
         }
         code => {
@@ -498,9 +665,26 @@ pub struct ResolveDnsRequest {
     client: ClientId,
     resource: ResourceId,
     proxy_ips: Vec<IpAddr>,
+    /// Whether we are re-resolving a domain for which the NAT is already set up.
+    is_refresh: bool,
 }
 
 impl ResolveDnsRequest {
+    pub(crate) fn refresh(
+        domain: DomainName,
+        client: ClientId,
+        resource: ResourceId,
+        proxy_ips: Vec<IpAddr>,
+    ) -> Self {
+        Self {
+            domain,
+            client,
+            resource,
+            proxy_ips,
+            is_refresh: true,
+        }
+    }
+
     pub fn domain(&self) -> &DomainName {
         &self.domain
     }
@@ -1,22 +1,31 @@
+mod encrypted_dns;
 mod gso_queue;
+mod packet_capture;
+mod tcp_relays;
 
-use crate::{device_channel::Device, dns, sockets::Sockets};
+use crate::{device_channel::Device, dns, messages::DnsServer, sockets::Sockets};
 use domain::base::Message;
+use encrypted_dns::EncryptedDnsConnections;
 use firezone_logging::{telemetry_event, telemetry_span};
 use futures_bounded::FuturesTupleSet;
 use futures_util::FutureExt as _;
 use gso_queue::GsoQueue;
 use ip_packet::{IpPacket, MAX_FZ_PAYLOAD};
+use itertools::Either;
+use packet_capture::{CaptureWriter, Direction, Stopped};
+use snownet::Transport;
 use socket_factory::{DatagramIn, SocketFactory, TcpSocket, UdpSocket};
 use std::{
     collections::VecDeque,
-    io,
+    fs::File,
+    io, iter,
     net::{Ipv4Addr, Ipv6Addr, SocketAddr},
     pin::Pin,
     sync::Arc,
     task::{ready, Context, Poll},
     time::{Duration, Instant},
 };
+use tcp_relays::TcpRelays;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tracing::Instrument;
 use tun::Tun;
@@ -43,22 +52,29 @@ pub struct Io {
     /// The UDP sockets used to send & receive packets from the network.
     sockets: Sockets,
     gso_queue: GsoQueue,
+    /// TCP and TLS connections to relays that we cannot reach via UDP.
+    tcp_relays: TcpRelays,
 
     tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
     udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
 
     dns_queries: FuturesTupleSet<io::Result<Message<Vec<u8>>>, DnsQueryMetaData>,
+    /// Connections to DoT and DoH resolvers that can be reused for further queries.
+    encrypted_dns: EncryptedDnsConnections,
 
     timeout: Option<Pin<Box<tokio::time::Sleep>>>,
 
     tun: Device,
     outbound_packet_buffer: VecDeque<IpPacket>,
+
+    /// Records the packets we read and write, if a capture is running.
+    capture: Option<CaptureWriter>,
 }
 
 #[derive(Debug)]
 struct DnsQueryMetaData {
     query: Message<Vec<u8>>,
-    server: SocketAddr,
+    server: DnsServer,
     transport: dns::Transport,
 }
 
@@ -66,6 +82,7 @@ pub(crate) struct Buffers {
     ip: Vec<IpPacket>,
     udp4: Vec<u8>,
     udp6: Vec<u8>,
+    tcp: Vec<u8>,
 }
 
 impl Default for Buffers {
@@ -74,6 +91,7 @@ impl Default for Buffers {
             ip: Vec::with_capacity(MAX_INBOUND_PACKET_BATCH),
             udp4: Vec::from([0; MAX_UDP_SIZE]),
             udp6: Vec::from([0; MAX_UDP_SIZE]),
+            tcp: Vec::from([0; MAX_UDP_SIZE]),
         }
     }
 }
@@ -102,11 +120,14 @@ impl Io {
             outbound_packet_buffer: VecDeque::with_capacity(10), // It is unlikely that we process more than 10 packets after 1 GRO call.
             timeout: None,
             sockets,
+            tcp_relays: TcpRelays::default(),
             tcp_socket_factory,
             udp_socket_factory,
             dns_queries: FuturesTupleSet::new(DNS_QUERY_TIMEOUT, 1000),
+            encrypted_dns: EncryptedDnsConnections::default(),
             gso_queue: GsoQueue::new(),
             tun: Device::new(),
+            capture: None,
         }
     }
 
@@ -129,13 +150,34 @@ impl Io {
             self.sockets
                 .poll_recv_from(&mut buffers.udp4, &mut buffers.udp6, cx)?
         {
-            return Poll::Ready(Ok(Input::Network(network.filter(is_max_wg_packet_size))));
+            return Poll::Ready(Ok(Input::Network(
+                Either::Left(network).filter(is_max_wg_packet_size),
+            )));
+        }
+
+        if let Poll::Ready(received) = self.tcp_relays.poll_recv(cx) {
+            let len = received.packet.len();
+            buffers.tcp[..len].copy_from_slice(&received.packet);
+
+            let datagram = DatagramIn {
+                local: received.local,
+                from: received.from,
+                packet: &buffers.tcp[..len],
+            };
+
+            return Poll::Ready(Ok(Input::Network(
+                Either::Right(iter::once(datagram)).filter(is_max_wg_packet_size),
+            )));
         }
 
         if let Poll::Ready(num_packets) =
             self.tun
                 .poll_read_many(cx, &mut buffers.ip, MAX_INBOUND_PACKET_BATCH)
         {
+            for packet in &buffers.ip[..num_packets] {
+                self.capture(|c| c.record_ip(Direction::Inbound, packet.packet()));
+            }
+
             return Poll::Ready(Ok(Input::Device(buffers.ip.drain(..num_packets))));
         }
 
@@ -207,6 +249,7 @@ impl Io {
             };
 
             // Third, send the packet.
+            self.capture(|c| c.record_ip(Direction::Outbound, packet.packet()));
             self.tun.send(packet)?;
         }
 
@@ -224,7 +267,9 @@ impl Io {
     pub fn reset(&mut self) {
         self.sockets.rebind(self.udp_socket_factory.as_ref());
         self.gso_queue.clear();
+        self.tcp_relays.clear();
         self.dns_queries = FuturesTupleSet::new(DNS_QUERY_TIMEOUT, 1000);
+        self.encrypted_dns = EncryptedDnsConnections::default(); // Connections from the old network are likely dead.
     }
 
     pub fn reset_timeout(&mut self, timeout: Instant) {
@@ -239,22 +284,115 @@ impl Io {
         }
     }
 
-    pub fn send_network(&mut self, src: Option<SocketAddr>, dst: SocketAddr, payload: &[u8]) {
-        self.gso_queue.enqueue(src, dst, payload, Instant::now())
+    pub fn send_network(
+        &mut self,
+        src: Option<SocketAddr>,
+        dst: SocketAddr,
+        payload: &[u8],
+        transport: Transport,
+    ) {
+        self.capture(|c| c.record_datagram(Direction::Outbound, src, dst, payload));
+
+        match transport {
+            Transport::Udp => self.gso_queue.enqueue(src, dst, payload, Instant::now()),
+            Transport::Tcp | Transport::Tls => {
+                self.tcp_relays
+                    .send(&self.tcp_socket_factory, dst, transport, payload)
+            }
+        }
+    }
+
+    /// Records a datagram that we received from the network.
+    ///
+    /// The network input is handed out as a lazy iterator, so this has to be called by whoever consumes it.
+    pub fn capture_received(&mut self, datagram: &DatagramIn<'_>) {
+        self.capture(|c| {
+            c.record_datagram(
+                Direction::Inbound,
+                Some(datagram.from),
+                datagram.local,
+                datagram.packet,
+            )
+        });
+    }
+
+    /// Starts writing all packets and datagrams to `file`, replacing any running capture.
+    pub fn start_packet_capture(&mut self, file: File, max_size: u64) -> io::Result<()> {
+        self.stop_packet_capture();
+        self.capture = Some(CaptureWriter::start(file, max_size)?);
+
+        tracing::info!(%max_size, "Started packet capture");
+
+        Ok(())
+    }
+
+    pub fn is_capturing_packets(&self) -> bool {
+        self.capture.is_some()
+    }
+
+    /// Stops the running capture, the writer thread flushes all queued packets in the background.
+    pub fn stop_packet_capture(&mut self) {
+        let Some(capture) = self.capture.take() else {
+            return;
+        };
+
+        tracing::info!(dropped = %capture.dropped(), "Stopped packet capture");
+    }
+
+    /// Applies `record` to the running capture and stops it once its writer has stopped, e.g. because it is full.
+    fn capture(&mut self, record: impl FnOnce(&mut CaptureWriter) -> Result<(), Stopped>) {
+        let Some(capture) = self.capture.as_mut() else {
+            return;
+        };
+
+        if record(capture).is_err() {
+            self.stop_packet_capture();
+        }
     }
 
     pub fn send_dns_query(&mut self, query: dns::RecursiveQuery) {
+        // Encrypted resolvers are always queried via TCP, regardless of how the query reached us.
+        if query.server.is_encrypted() {
+            let connections = self.encrypted_dns.clone();
+            // Our own sockets bypass the tunnel. To reach a resolver within a resource, we need a regular socket that the OS routes through the tunnel.
+            let factory: Arc<dyn SocketFactory<TcpSocket>> = if query.through_tunnel {
+                Arc::new(socket_factory::tcp)
+            } else {
+                self.tcp_socket_factory.clone()
+            };
+            let meta = DnsQueryMetaData {
+                query: query.message.clone(),
+                server: query.server.clone(),
+                transport: query.transport,
+            };
+
+            if self
+                .dns_queries
+                .try_push(
+                    connections
+                        .query(factory, query.server, query.through_tunnel, query.message)
+                        .instrument(telemetry_span!("recursive_encrypted_dns_query")),
+                    meta,
+                )
+                .is_err()
+            {
+                tracing::debug!("Failed to queue encrypted DNS query")
+            }
+
+            return;
+        }
+
         match query.transport {
             dns::Transport::Udp { .. } => {
                 let factory = self.udp_socket_factory.clone();
-                let server = query.server;
-                let bind_addr = match query.server {
+                let server = query.server.address();
+                let bind_addr = match server {
                     SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
                     SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
                 };
                 let meta = DnsQueryMetaData {
                     query: query.message.clone(),
-                    server,
+                    server: query.server,
                     transport: query.transport,
                 };
 
@@ -286,10 +424,10 @@ impl Io {
             }
             dns::Transport::Tcp { .. } => {
                 let factory = self.tcp_socket_factory.clone();
-                let server = query.server;
+                let server = query.server.address();
                 let meta = DnsQueryMetaData {
                     query: query.message.clone(),
-                    server,
+                    server: query.server,
                     transport: query.transport,
                 };
 
@@ -392,6 +530,7 @@ mod tests {
         ip: Vec::new(),
         udp4: Vec::new(),
         udp6: Vec::new(),
+        tcp: Vec::new(),
     };
 
     /// Helper functions to make the test more concise.
@@ -0,0 +1,394 @@
+//! Forwarding of DNS queries to encrypted upstream resolvers, i.e. DNS-over-TLS (RFC 7858) and DNS-over-HTTPS (RFC 8484).
+//!
+//! Establishing a TLS session is expensive, so we keep a few idle connections to each resolver around and reuse them for subsequent queries.
+//! For DNS-over-HTTPS, we speak HTTP/1.1 with keep-alive which is sufficient given that we only ever have one query in-flight per connection.
+
+use crate::messages::{DnsServer, DohDnsServer, DotDnsServer};
+use domain::base::Message;
+use socket_factory::{SocketFactory, TcpSocket, TcpStream};
+use std::{
+    collections::HashMap,
+    io,
+    sync::{Arc, LazyLock, Mutex},
+};
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tokio_rustls::{
+    client::TlsStream,
+    rustls::{self, pki_types::ServerName},
+    TlsConnector,
+};
+
+/// How many idle connections we keep around per resolver.
+const MAX_IDLE_CONNECTIONS_PER_SERVER: usize = 4;
+
+/// The maximum size of the response headers of a DoH server that we are willing to buffer.
+const MAX_HTTP_HEADER_SIZE: usize = 8 * 1024;
+
+const DNS_MESSAGE_CONTENT_TYPE: &str = "application/dns-message";
+
+static DOT_TLS_CONFIG: LazyLock<Arc<rustls::ClientConfig>> = LazyLock::new(|| tls_config(b"dot"));
+static DOH_TLS_CONFIG: LazyLock<Arc<rustls::ClientConfig>> =
+    LazyLock::new(|| tls_config(b"http/1.1"));
+
+/// Idle connections to encrypted resolvers, shared across all in-flight queries.
+///
+/// Connections are indexed by the resolver and whether they are routed through the tunnel.
+/// That way, we don't keep using a connection that bypasses the tunnel once the resolver became part of a resource and vice versa.
+#[derive(Default, Clone)]
+pub(crate) struct EncryptedDnsConnections {
+    idle: Arc<Mutex<HashMap<(DnsServer, bool), Vec<TlsStream<TcpStream>>>>>,
+}
+
+impl EncryptedDnsConnections {
+    /// Sends the query to the given resolver and waits for the response.
+    ///
+    /// If a reused connection turns out to be broken (e.g. because the server closed it in the meantime), we retry once on a new connection.
+    pub(crate) async fn query(
+        self,
+        factory: Arc<dyn SocketFactory<TcpSocket>>,
+        server: DnsServer,
+        through_tunnel: bool,
+        query: Message<Vec<u8>>,
+    ) -> io::Result<Message<Vec<u8>>> {
+        let key = (server, through_tunnel);
+        let server = &key.0;
+
+        let response = match self.take_idle(&key) {
+            Some(stream) => match self.exchange(stream, &key, query.as_slice()).await {
+                Ok(response) => response,
+                Err(e) => {
+                    tracing::debug!(?server, "Idle connection to resolver failed: {e}");
+
+                    let stream = connect(factory.as_ref(), server).await?;
+                    self.exchange(stream, &key, query.as_slice()).await?
+                }
+            },
+            None => {
+                let stream = connect(factory.as_ref(), server).await?;
+                self.exchange(stream, &key, query.as_slice()).await?
+            }
+        };
+
+        let message = Message::from_octets(response)
+            .map_err(|_| io::Error::other("Failed to parse DNS message"))?;
+
+        Ok(message)
+    }
+
+    async fn exchange(
+        &self,
+        mut stream: TlsStream<TcpStream>,
+        key: &(DnsServer, bool),
+        query: &[u8],
+    ) -> io::Result<Vec<u8>> {
+        let (response, keep_alive) = match &key.0 {
+            DnsServer::DnsOverTls(_) => (exchange_length_prefixed(&mut stream, query).await?, true),
+            DnsServer::DnsOverHttps(DohDnsServer { url, .. }) => {
+                exchange_https(&mut stream, url, query).await?
+            }
+            DnsServer::IpPort(_) => return Err(io::Error::other("Not an encrypted DNS server")),
+        };
+
+        if keep_alive {
+            self.put_idle(key, stream);
+        }
+
+        Ok(response)
+    }
+
+    fn take_idle(&self, key: &(DnsServer, bool)) -> Option<TlsStream<TcpStream>> {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+
+        idle.get_mut(key)?.pop()
+    }
+
+    fn put_idle(&self, key: &(DnsServer, bool), stream: TlsStream<TcpStream>) {
+        let mut idle = self.idle.lock().unwrap_or_else(|e| e.into_inner());
+        let streams = idle.entry(key.clone()).or_default();
+
+        if streams.len() < MAX_IDLE_CONNECTIONS_PER_SERVER {
+            streams.push(stream);
+        }
+    }
+}
+
+async fn connect(
+    factory: &dyn SocketFactory<TcpSocket>,
+    server: &DnsServer,
+) -> io::Result<TlsStream<TcpStream>> {
+    let (config, server_name) = match server {
+        DnsServer::DnsOverTls(DotDnsServer { hostname, .. }) => (
+            DOT_TLS_CONFIG.clone(),
+            ServerName::try_from(hostname.clone()).map_err(io::Error::other)?,
+        ),
+        DnsServer::DnsOverHttps(DohDnsServer { url, .. }) => {
+            let server_name = match url.host() {
+                Some(url::Host::Domain(domain)) => {
+                    ServerName::try_from(domain.to_owned()).map_err(io::Error::other)?
+                }
+                Some(url::Host::Ipv4(ip)) => ServerName::IpAddress(ip.into()),
+                Some(url::Host::Ipv6(ip)) => ServerName::IpAddress(ip.into()),
+                None => return Err(io::Error::other("DoH URL has no host")),
+            };
+
+            (DOH_TLS_CONFIG.clone(), server_name)
+        }
+        DnsServer::IpPort(_) => return Err(io::Error::other("Not an encrypted DNS server")),
+    };
+
+    let address = server.address();
+    let tcp_stream = factory(&address)?.connect(address).await?;
+    let tls_stream = TlsConnector::from(config)
+        .connect(server_name, tcp_stream)
+        .await?;
+
+    Ok(tls_stream)
+}
+
+/// DNS-over-TLS uses the same framing as DNS over TCP: Each message is prefixed with its length as a u16.
+async fn exchange_length_prefixed<S>(stream: &mut S, query: &[u8]) -> io::Result<Vec<u8>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let dns_message_length = (query.len() as u16).to_be_bytes();
+
+    stream.write_all(&dns_message_length).await?;
+    stream.write_all(query).await?;
+    stream.flush().await?;
+
+    let mut response_length = [0u8; 2];
+    stream.read_exact(&mut response_length).await?;
+    let response_length = u16::from_be_bytes(response_length) as usize;
+
+    // A u16 is at most 65k, meaning we are okay to allocate here based on what the remote is sending.
+    let mut response = vec![0u8; response_length];
+    stream.read_exact(&mut response).await?;
+
+    Ok(response)
+}
+
+/// Sends the query as an HTTP/1.1 `POST` request and returns the response body and whether the connection can be reused.
+async fn exchange_https<S>(
+    stream: &mut S,
+    url: &url::Url,
+    query: &[u8],
+) -> io::Result<(Vec<u8>, bool)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let host = url
+        .host_str()
+        .ok_or_else(|| io::Error::other("DoH URL has no host"))?;
+    let path = &url[url::Position::BeforePath..];
+
+    let request = format!(
+        "POST {path} HTTP/1.1\r\nHost: {host}\r\nContent-Type: {DNS_MESSAGE_CONTENT_TYPE}\r\nAccept: {DNS_MESSAGE_CONTENT_TYPE}\r\nContent-Length: {}\r\n\r\n",
+        query.len()
+    );
+
+    stream.write_all(request.as_bytes()).await?;
+    stream.write_all(query).await?;
+    stream.flush().await?;
+
+    let mut buffer = Vec::with_capacity(1024);
+    let mut chunk = [0u8; 1024];
+
+    let (header_len, content_length, keep_alive) = loop {
+        let num_read = stream.read(&mut chunk).await?;
+        if num_read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        buffer.extend_from_slice(&chunk[..num_read]);
+
+        let mut headers = [httparse::EMPTY_HEADER; 32];
+        let mut response = httparse::Response::new(&mut headers);
+
+        match response.parse(&buffer).map_err(io::Error::other)? {
+            httparse::Status::Complete(header_len) => {
+                if response.code != Some(200) {
+                    return Err(io::Error::other(format!(
+                        "DoH server responded with {:?}",
+                        response.code
+                    )));
+                }
+
+                let content_length = header_value(response.headers, "content-length")
+                    .and_then(|v| v.parse::<usize>().ok())
+                    .ok_or_else(|| io::Error::other("DoH response has no valid Content-Length"))?;
+                let keep_alive = !header_value(response.headers, "connection")
+                    .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+
+                break (header_len, content_length, keep_alive);
+            }
+            httparse::Status::Partial if buffer.len() > MAX_HTTP_HEADER_SIZE => {
+                return Err(io::Error::other("DoH response headers are too large"));
+            }
+            httparse::Status::Partial => continue,
+        }
+    };
+
+    if content_length > u16::MAX as usize {
+        return Err(io::Error::other("DoH response is too large"));
+    }
+
+    let mut body = buffer.split_off(header_len);
+    if body.len() > content_length {
+        return Err(io::Error::other("DoH server sent more data than announced"));
+    }
+
+    let num_received = body.len();
+    body.resize(content_length, 0);
+    stream.read_exact(&mut body[num_received..]).await?;
+
+    Ok((body, keep_alive))
+}
+
+fn header_value<'h>(headers: &[httparse::Header<'h>], name: &str) -> Option<&'h str> {
+    let header = headers.iter().find(|h| h.name.eq_ignore_ascii_case(name))?;
+
+    std::str::from_utf8(header.value).ok().map(str::trim)
+}
+
+pub(super) fn tls_config(alpn: &[u8]) -> Arc<rustls::ClientConfig> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+
+    let mut config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .expect("ring supports the default protocol versions")
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    config.alpn_protocols = vec![alpn.to_vec()];
+
+    Arc::new(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::DuplexStream;
+
+    #[tokio::test]
+    async fn dot_exchange_uses_length_prefix() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let resolver = tokio::spawn(async move {
+            let query = read_length_prefixed(&mut server).await;
+
+            server.write_all(&[0, 3]).await.unwrap();
+            server.write_all(b"res").await.unwrap();
+
+            query
+        });
+
+        let response = exchange_length_prefixed(&mut client, b"query")
+            .await
+            .unwrap();
+
+        assert_eq!(response, b"res");
+        assert_eq!(resolver.await.unwrap(), b"query");
+    }
+
+    #[tokio::test]
+    async fn doh_exchange_posts_query_and_reads_body() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        let resolver = tokio::spawn(async move {
+            let request = read_http_request(&mut server).await;
+
+            // Split the response to make sure we handle partial reads.
+            server
+                .write_all(b"HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\n")
+                .await
+                .unwrap();
+            server.flush().await.unwrap();
+            server
+                .write_all(b"Content-Length: 8\r\n\r\nresp")
+                .await
+                .unwrap();
+            server.flush().await.unwrap();
+            server.write_all(b"onse").await.unwrap();
+
+            request
+        });
+
+        let url = url::Url::parse("https://dns.example.com/dns-query?x=1").unwrap();
+        let (response, keep_alive) = exchange_https(&mut client, &url, b"query").await.unwrap();
+
+        assert_eq!(response, b"response");
+        assert!(keep_alive);
+
+        let request = resolver.await.unwrap();
+        assert!(request.starts_with("POST /dns-query?x=1 HTTP/1.1\r\n"));
+        assert!(request.contains("Host: dns.example.com\r\n"));
+        assert!(request.contains("Content-Type: application/dns-message\r\n"));
+        assert!(request.ends_with("Content-Length: 5\r\n\r\nquery"));
+    }
+
+    #[tokio::test]
+    async fn doh_exchange_does_not_reuse_closed_connection() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        tokio::spawn(async move {
+            read_http_request(&mut server).await;
+
+            server
+                .write_all(b"HTTP/1.1 200 OK\r\nConnection: close\r\nContent-Length: 3\r\n\r\nres")
+                .await
+                .unwrap();
+        });
+
+        let url = url::Url::parse("https://dns.example.com/dns-query").unwrap();
+        let (response, keep_alive) = exchange_https(&mut client, &url, b"query").await.unwrap();
+
+        assert_eq!(response, b"res");
+        assert!(!keep_alive);
+    }
+
+    #[tokio::test]
+    async fn doh_exchange_fails_on_error_status() {
+        let (mut client, mut server) = tokio::io::duplex(1024);
+
+        tokio::spawn(async move {
+            read_http_request(&mut server).await;
+
+            server
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .unwrap();
+        });
+
+        let url = url::Url::parse("https://dns.example.com/dns-query").unwrap();
+        let result = exchange_https(&mut client, &url, b"query").await;
+
+        assert!(result.is_err());
+    }
+
+    async fn read_length_prefixed(stream: &mut DuplexStream) -> Vec<u8> {
+        let mut len = [0u8; 2];
+        stream.read_exact(&mut len).await.unwrap();
+
+        let mut message = vec![0u8; u16::from_be_bytes(len) as usize];
+        stream.read_exact(&mut message).await.unwrap();
+
+        message
+    }
+
+    /// Reads an HTTP request with a body of 5 bytes.
+    async fn read_http_request(stream: &mut DuplexStream) -> String {
+        let mut request = Vec::new();
+
+        while !request.ends_with(b"\r\n\r\n") {
+            request.push(stream.read_u8().await.unwrap());
+        }
+
+        let mut body = [0u8; 5];
+        stream.read_exact(&mut body).await.unwrap();
+        request.extend_from_slice(&body);
+
+        String::from_utf8(request).unwrap()
+    }
+}
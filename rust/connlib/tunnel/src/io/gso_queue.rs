@@ -1,5 +1,6 @@
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
+    iter,
     net::SocketAddr,
     sync::Arc,
     time::{Duration, Instant},
@@ -19,6 +20,10 @@ const MAX_SEGMENT_SIZE: usize =
 /// The buffer is then flushed using GSO in a single syscall.
 pub struct GsoQueue {
     inner: HashMap<Key, DatagramBuffer>,
+    /// Batches that end in a shorter datagram and therefore cannot take any more.
+    sealed: VecDeque<DatagramOut<lockfree_object_pool::SpinLockOwnedReusable<BytesMut>>>,
+    /// The size of the last datagram we enqueued per source and destination.
+    last_segment_size: HashMap<(Option<SocketAddr>, SocketAddr), usize>,
     buffer_pool: Arc<lockfree_object_pool::SpinLockObjectPool<BytesMut>>,
 }
 
@@ -26,6 +31,8 @@ impl GsoQueue {
     pub fn new() -> Self {
         Self {
             inner: Default::default(),
+            sealed: Default::default(),
+            last_segment_size: Default::default(),
             buffer_pool: Arc::new(lockfree_object_pool::SpinLockObjectPool::new(
                 || {
                     tracing::debug!("Initialising new buffer for GSO queue");
@@ -64,6 +71,34 @@ impl GsoQueue {
             "MAX_SEGMENT_SIZE is miscalculated"
         );
 
+        // GSO allows the last datagram of a batch to be shorter, e.g. the tail of a large TCP segment that we read from the TUN device.
+        // Appending it, instead of queueing it separately, also ensures it isn't sent ahead of the others.
+        if let Some(batch_size) = self
+            .last_segment_size
+            .insert((src, dst), segment_size)
+            .filter(|batch_size| segment_size < *batch_size)
+        {
+            let key = Key {
+                src,
+                dst,
+                segment_size: batch_size,
+            };
+
+            if let Some(mut packet) = self.inner.get_mut(&key).and_then(|b| b.inner.take()) {
+                packet.extend_from_slice(payload);
+
+                self.sealed.push_back(DatagramOut {
+                    src,
+                    dst,
+                    packet,
+                    segment_size: Some(batch_size),
+                });
+                self.last_segment_size.remove(&(src, dst));
+
+                return;
+            }
+        }
+
         let buffer = self
             .inner
             .entry(Key {
@@ -87,25 +122,34 @@ impl GsoQueue {
         &mut self,
     ) -> impl Iterator<Item = DatagramOut<lockfree_object_pool::SpinLockOwnedReusable<BytesMut>>> + '_
     {
-        self.inner.iter_mut().filter_map(|(key, buffer)| {
-            // It is really important that we `take` the buffer here, otherwise it is not returned to the pool after.
-            let buffer = buffer.inner.take()?;
-
-            if buffer.is_empty() {
-                return None;
-            }
-
-            Some(DatagramOut {
-                src: key.src,
-                dst: key.dst,
-                packet: buffer,
-                segment_size: Some(key.segment_size),
-            })
-        })
+        // Once flushed, there is no batch to append a shorter datagram to.
+        self.last_segment_size.clear();
+
+        let sealed = &mut self.sealed;
+
+        iter::from_fn(move || sealed.pop_front()).chain(self.inner.iter_mut().filter_map(
+            |(key, buffer)| {
+                // It is really important that we `take` the buffer here, otherwise it is not returned to the pool after.
+                let buffer = buffer.inner.take()?;
+
+                if buffer.is_empty() {
+                    return None;
+                }
+
+                Some(DatagramOut {
+                    src: key.src,
+                    dst: key.dst,
+                    packet: buffer,
+                    segment_size: Some(key.segment_size),
+                })
+            },
+        ))
     }
 
     pub fn clear(&mut self) {
-        self.inner.clear()
+        self.inner.clear();
+        self.sealed.clear();
+        self.last_segment_size.clear();
     }
 }
 
@@ -185,6 +229,41 @@ mod tests {
         }
     }
 
+    #[test]
+    fn appends_shorter_datagram_to_batch() {
+        let now = Instant::now();
+        let mut send_queue = GsoQueue::new();
+
+        send_queue.enqueue(None, DST, b"foobar", now);
+        send_queue.enqueue(None, DST, b"bazbaz", now);
+        send_queue.enqueue(None, DST, b"end", now);
+        send_queue.enqueue(None, DST, b"foobar", now);
+
+        let datagrams = send_queue.datagrams().collect::<Vec<_>>();
+
+        assert_eq!(datagrams.len(), 2);
+        assert_eq!(datagrams[0].packet.as_ref(), b"foobarbazbazend");
+        assert_eq!(datagrams[0].segment_size, Some(6));
+        assert_eq!(datagrams[1].packet.as_ref(), b"foobar");
+        assert_eq!(datagrams[1].segment_size, Some(6));
+    }
+
+    #[test]
+    fn does_not_append_to_flushed_batch() {
+        let now = Instant::now();
+        let mut send_queue = GsoQueue::new();
+
+        send_queue.enqueue(None, DST, b"foobar", now);
+        for _entry in send_queue.datagrams() {}
+        send_queue.enqueue(None, DST, b"end", now);
+
+        let datagrams = send_queue.datagrams().collect::<Vec<_>>();
+
+        assert_eq!(datagrams.len(), 1);
+        assert_eq!(datagrams[0].packet.as_ref(), b"end");
+        assert_eq!(datagrams[0].segment_size, Some(3));
+    }
+
     const DST: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1234));
     const DST_2: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 5678));
 }
@@ -0,0 +1,622 @@
+//! On-demand capture of the traffic passing through [`Io`](super::Io) into a pcapng file.
+//!
+//! Plaintext IP packets read from and written to the TUN device are recorded on the `tun` interface.
+//! Encrypted datagrams sent to and received from the network are recorded on the `network` interface.
+//! pcapng has no link-type for bare UDP payloads, so we prefix those with a synthesized IP and UDP header.
+//! The UDP checksum of those headers is always 0.
+//!
+//! Writing happens on a dedicated thread so a slow disk never stalls the event-loop.
+//! If the thread falls behind by more than [`QUEUE_SIZE`] packets, we drop packets from the capture.
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::mpsc::{self, SyncSender, TrySendError},
+    thread::{self, JoinHandle},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// How many packets may wait for the writer thread before we start dropping them.
+const QUEUE_SIZE: usize = 4096;
+
+const SECTION_HEADER_BLOCK: u32 = 0x0A0D_0D0A;
+const INTERFACE_DESCRIPTION_BLOCK: u32 = 0x0000_0001;
+const ENHANCED_PACKET_BLOCK: u32 = 0x0000_0006;
+
+const BYTE_ORDER_MAGIC: u32 = 0x1A2B_3C4D;
+/// Packets start with an IPv4 or IPv6 header, there is no link-layer header.
+const LINKTYPE_RAW: u16 = 101;
+
+const OPT_ENDOFOPT: u16 = 0;
+const OPT_IF_NAME: u16 = 2;
+const OPT_EPB_FLAGS: u16 = 2;
+
+const IPV4_HEADER_LEN: usize = 20;
+const IPV6_HEADER_LEN: usize = 40;
+const UDP_HEADER_LEN: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Interface {
+    /// Plaintext IP packets at the TUN device.
+    Tun = 0,
+    /// Encrypted UDP datagrams at our sockets.
+    Network = 1,
+}
+
+impl Interface {
+    const ALL: [Interface; 2] = [Interface::Tun, Interface::Network];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Interface::Tun => "tun",
+            Interface::Network => "network",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    Inbound,
+    Outbound,
+}
+
+impl Direction {
+    /// The value of the `epb_flags` option.
+    fn flags(&self) -> u32 {
+        match self {
+            Direction::Inbound => 0b01,
+            Direction::Outbound => 0b10,
+        }
+    }
+}
+
+/// A packet on its way to the writer thread.
+enum Record {
+    Ip {
+        direction: Direction,
+        timestamp: SystemTime,
+        packet: Vec<u8>,
+    },
+    Datagram {
+        direction: Direction,
+        timestamp: SystemTime,
+        src: Option<SocketAddr>,
+        dst: SocketAddr,
+        payload: Vec<u8>,
+    },
+}
+
+/// Hands packets to a thread that writes them to a [`PacketCapture`].
+///
+/// Dropping this stops the capture once the thread has written all queued packets.
+pub(crate) struct CaptureWriter {
+    sender: SyncSender<Record>,
+    dropped: u64,
+}
+
+/// The writer thread has stopped, e.g. because the capture reached its size limit.
+#[derive(Debug)]
+pub(crate) struct Stopped;
+
+impl CaptureWriter {
+    pub(crate) fn start(file: File, max_size: u64) -> io::Result<Self> {
+        let (writer, _) = Self::spawn(PacketCapture::new(file, max_size)?, QUEUE_SIZE)?;
+
+        Ok(writer)
+    }
+
+    fn spawn<W>(
+        mut capture: PacketCapture<W>,
+        queue_size: usize,
+    ) -> io::Result<(Self, JoinHandle<()>)>
+    where
+        W: Write + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::sync_channel(queue_size);
+
+        let handle = thread::Builder::new()
+            .name("packet-capture".to_owned())
+            .spawn(move || {
+                for record in receiver {
+                    if let Err(e) = capture.write(record) {
+                        tracing::info!("Stopping packet capture: {e}");
+                        break;
+                    }
+                }
+
+                if let Err(e) = capture.flush() {
+                    tracing::warn!("Failed to flush packet capture: {e}");
+                }
+            })?;
+
+        Ok((Self { sender, dropped: 0 }, handle))
+    }
+
+    /// Queues an IP packet read from or written to the TUN device.
+    pub(crate) fn record_ip(&mut self, direction: Direction, packet: &[u8]) -> Result<(), Stopped> {
+        self.send(Record::Ip {
+            direction,
+            timestamp: SystemTime::now(),
+            packet: packet.to_vec(),
+        })
+    }
+
+    /// Queues a datagram sent to or received from the network.
+    pub(crate) fn record_datagram(
+        &mut self,
+        direction: Direction,
+        src: Option<SocketAddr>,
+        dst: SocketAddr,
+        payload: &[u8],
+    ) -> Result<(), Stopped> {
+        self.send(Record::Datagram {
+            direction,
+            timestamp: SystemTime::now(),
+            src,
+            dst,
+            payload: payload.to_vec(),
+        })
+    }
+
+    /// The number of packets we dropped because the writer thread couldn't keep up.
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped
+    }
+
+    fn send(&mut self, record: Record) -> Result<(), Stopped> {
+        match self.sender.try_send(record) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                self.dropped += 1;
+
+                Ok(())
+            }
+            Err(TrySendError::Disconnected(_)) => Err(Stopped),
+        }
+    }
+}
+
+/// Writes packets to a pcapng file until it reaches its size limit.
+pub(crate) struct PacketCapture<W = BufWriter<File>> {
+    writer: W,
+    written: u64,
+    max_size: u64,
+}
+
+impl PacketCapture {
+    fn new(file: File, max_size: u64) -> io::Result<Self> {
+        Self::with_writer(BufWriter::new(file), max_size)
+    }
+}
+
+impl<W> PacketCapture<W>
+where
+    W: Write,
+{
+    fn with_writer(writer: W, max_size: u64) -> io::Result<Self> {
+        let mut capture = Self {
+            writer,
+            written: 0,
+            max_size,
+        };
+
+        capture.write_block(SECTION_HEADER_BLOCK, |body| {
+            body.extend_from_slice(&BYTE_ORDER_MAGIC.to_le_bytes());
+            body.extend_from_slice(&1_u16.to_le_bytes()); // Major version
+            body.extend_from_slice(&0_u16.to_le_bytes()); // Minor version
+            body.extend_from_slice(&(-1_i64).to_le_bytes()); // Section length is unknown
+        })?;
+
+        for interface in Interface::ALL {
+            capture.write_block(INTERFACE_DESCRIPTION_BLOCK, |body| {
+                body.extend_from_slice(&LINKTYPE_RAW.to_le_bytes());
+                body.extend_from_slice(&0_u16.to_le_bytes()); // Reserved
+                body.extend_from_slice(&0_u32.to_le_bytes()); // No snap length
+                write_option(body, OPT_IF_NAME, interface.name().as_bytes());
+                write_option(body, OPT_ENDOFOPT, &[]);
+            })?;
+        }
+
+        Ok(capture)
+    }
+
+    fn write(&mut self, record: Record) -> io::Result<()> {
+        match record {
+            Record::Ip {
+                direction,
+                timestamp,
+                packet,
+            } => self.record_ip(direction, timestamp, &packet),
+            Record::Datagram {
+                direction,
+                timestamp,
+                src,
+                dst,
+                payload,
+            } => self.record_datagram(direction, timestamp, src, dst, &payload),
+        }
+    }
+
+    /// Records an IP packet read from or written to the TUN device.
+    fn record_ip(
+        &mut self,
+        direction: Direction,
+        timestamp: SystemTime,
+        packet: &[u8],
+    ) -> io::Result<()> {
+        self.write_packet(Interface::Tun, direction, timestamp, &[packet])
+    }
+
+    /// Records a datagram sent to or received from the network.
+    ///
+    /// If we don't know the local address, we use the unspecified address of the same family as `dst`.
+    fn record_datagram(
+        &mut self,
+        direction: Direction,
+        timestamp: SystemTime,
+        src: Option<SocketAddr>,
+        dst: SocketAddr,
+        payload: &[u8],
+    ) -> io::Result<()> {
+        let src = src.unwrap_or_else(|| match dst {
+            SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+            SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+        });
+        let headers = ip_udp_headers(src, dst, payload.len());
+
+        self.write_packet(
+            Interface::Network,
+            direction,
+            timestamp,
+            &[&headers, payload],
+        )
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    fn write_packet(
+        &mut self,
+        interface: Interface,
+        direction: Direction,
+        timestamp: SystemTime,
+        parts: &[&[u8]],
+    ) -> io::Result<()> {
+        let len = parts.iter().map(|p| p.len()).sum::<usize>();
+        let len = u32::try_from(len).map_err(io::Error::other)?;
+        let timestamp = timestamp
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_micros() as u64; // Interfaces without `if_tsresol` use microseconds.
+
+        self.write_block(ENHANCED_PACKET_BLOCK, |body| {
+            body.extend_from_slice(&(interface as u32).to_le_bytes());
+            body.extend_from_slice(&((timestamp >> 32) as u32).to_le_bytes());
+            body.extend_from_slice(&(timestamp as u32).to_le_bytes());
+            body.extend_from_slice(&len.to_le_bytes()); // Captured length
+            body.extend_from_slice(&len.to_le_bytes()); // Original length
+            for part in parts {
+                body.extend_from_slice(part);
+            }
+            pad(body);
+            write_option(body, OPT_EPB_FLAGS, &direction.flags().to_le_bytes());
+            write_option(body, OPT_ENDOFOPT, &[]);
+        })
+    }
+
+    /// Writes a block with the body produced by `write_body`, unless that would exceed our size limit.
+    fn write_block(
+        &mut self,
+        block_type: u32,
+        write_body: impl FnOnce(&mut Vec<u8>),
+    ) -> io::Result<()> {
+        let mut block = Vec::with_capacity(128);
+        block.extend_from_slice(&block_type.to_le_bytes());
+        block.extend_from_slice(&[0; 4]); // Total length, filled in below.
+        write_body(&mut block);
+
+        let total_len = block.len() + 4;
+        let total_len_bytes = u32::try_from(total_len)
+            .map_err(io::Error::other)?
+            .to_le_bytes();
+        block[4..8].copy_from_slice(&total_len_bytes);
+        block.extend_from_slice(&total_len_bytes);
+
+        let written = self.written + block.len() as u64;
+        if written > self.max_size {
+            return Err(io::Error::new(
+                io::ErrorKind::FileTooLarge,
+                format!(
+                    "Packet capture reached its size limit of {} bytes",
+                    self.max_size
+                ),
+            ));
+        }
+
+        self.writer.write_all(&block)?;
+        self.written = written;
+
+        Ok(())
+    }
+
+    #[cfg(test)]
+    fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+fn write_option(body: &mut Vec<u8>, code: u16, value: &[u8]) {
+    body.extend_from_slice(&code.to_le_bytes());
+    body.extend_from_slice(&(value.len() as u16).to_le_bytes());
+    body.extend_from_slice(value);
+    pad(body);
+}
+
+/// Pads `body` to a multiple of 32 bits, as pcapng requires for packet data and options.
+fn pad(body: &mut Vec<u8>) {
+    body.resize(body.len().next_multiple_of(4), 0);
+}
+
+/// Synthesizes the IP and UDP header of a datagram with `payload_len` bytes.
+///
+/// Mixed address families can't happen on a single socket but we map both addresses to IPv6 just in case.
+fn ip_udp_headers(src: SocketAddr, dst: SocketAddr, payload_len: usize) -> Vec<u8> {
+    let udp_len = u16::try_from(UDP_HEADER_LEN + payload_len).unwrap_or(u16::MAX);
+
+    let mut headers = match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src), IpAddr::V4(dst)) => ipv4_header(src, dst, udp_len),
+        (src, dst) => ipv6_header(to_ipv6(src), to_ipv6(dst), udp_len),
+    };
+
+    headers.extend_from_slice(&src.port().to_be_bytes());
+    headers.extend_from_slice(&dst.port().to_be_bytes());
+    headers.extend_from_slice(&udp_len.to_be_bytes());
+    headers.extend_from_slice(&0_u16.to_be_bytes()); // Checksum
+
+    headers
+}
+
+fn ipv4_header(src: Ipv4Addr, dst: Ipv4Addr, udp_len: u16) -> Vec<u8> {
+    let total_len = udp_len.saturating_add(IPV4_HEADER_LEN as u16);
+
+    let mut header = Vec::with_capacity(IPV4_HEADER_LEN + UDP_HEADER_LEN);
+    header.push(0x45); // Version 4, 5 words of header
+    header.push(0); // DSCP & ECN
+    header.extend_from_slice(&total_len.to_be_bytes());
+    header.extend_from_slice(&[0, 0]); // Identification
+    header.extend_from_slice(&[0x40, 0]); // Don't fragment
+    header.push(64); // TTL
+    header.push(IPPROTO_UDP);
+    header.extend_from_slice(&[0, 0]); // Checksum, filled in below.
+    header.extend_from_slice(&src.octets());
+    header.extend_from_slice(&dst.octets());
+
+    let checksum = ipv4_checksum(&header);
+    header[10..12].copy_from_slice(&checksum.to_be_bytes());
+
+    header
+}
+
+fn ipv6_header(src: Ipv6Addr, dst: Ipv6Addr, udp_len: u16) -> Vec<u8> {
+    let mut header = Vec::with_capacity(IPV6_HEADER_LEN + UDP_HEADER_LEN);
+    header.extend_from_slice(&[0x60, 0, 0, 0]); // Version 6, no traffic class or flow label
+    header.extend_from_slice(&udp_len.to_be_bytes());
+    header.push(IPPROTO_UDP);
+    header.push(64); // Hop limit
+    header.extend_from_slice(&src.octets());
+    header.extend_from_slice(&dst.octets());
+
+    header
+}
+
+fn ipv4_checksum(header: &[u8]) -> u16 {
+    let sum = header
+        .chunks_exact(2)
+        .map(|word| u32::from(u16::from_be_bytes([word[0], word[1]])))
+        .sum::<u32>();
+    let folded = (sum & 0xFFFF) + (sum >> 16);
+
+    !(((folded & 0xFFFF) + (folded >> 16)) as u16)
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex, MutexGuard};
+
+    #[test]
+    fn writes_section_and_interfaces_first() {
+        let capture = PacketCapture::with_writer(Vec::new(), 1024).unwrap();
+        let blocks = blocks(&capture.into_inner());
+
+        assert_eq!(blocks.len(), 3);
+        assert_eq!(blocks[0].0, SECTION_HEADER_BLOCK);
+        assert_eq!(&blocks[0].1[..4], &BYTE_ORDER_MAGIC.to_le_bytes());
+        assert_eq!(blocks[1].0, INTERFACE_DESCRIPTION_BLOCK);
+        assert_eq!(&blocks[1].1[..2], &LINKTYPE_RAW.to_le_bytes());
+        assert_eq!(&blocks[1].1[12..15], b"tun");
+        assert_eq!(&blocks[2].1[12..19], b"network");
+    }
+
+    #[test]
+    fn records_ip_packets_on_tun_interface() {
+        let mut capture = PacketCapture::with_writer(Vec::new(), 1024).unwrap();
+        capture
+            .record_ip(Direction::Outbound, SystemTime::now(), &[0x45, 1, 2, 3, 4])
+            .unwrap();
+
+        let blocks = blocks(&capture.into_inner());
+        let (block_type, body) = &blocks[3];
+
+        assert_eq!(*block_type, ENHANCED_PACKET_BLOCK);
+        assert_eq!(u32_at(body, 0), Interface::Tun as u32);
+        assert_eq!(u32_at(body, 12), 5);
+        assert_eq!(&body[20..25], &[0x45, 1, 2, 3, 4]);
+        assert_eq!(u16_at(body, 28), OPT_EPB_FLAGS);
+        assert_eq!(u32_at(body, 32), Direction::Outbound.flags());
+    }
+
+    #[test]
+    fn wraps_datagrams_in_ip_and_udp_header() {
+        let mut capture = PacketCapture::with_writer(Vec::new(), 1024).unwrap();
+        capture
+            .record_datagram(
+                Direction::Inbound,
+                SystemTime::now(),
+                Some("10.0.0.1:51820".parse().unwrap()),
+                "10.0.0.2:3478".parse().unwrap(),
+                b"hello",
+            )
+            .unwrap();
+
+        let blocks = blocks(&capture.into_inner());
+        let body = &blocks[3].1;
+        let packet = &body[20..20 + 33];
+
+        assert_eq!(u32_at(body, 0), Interface::Network as u32);
+        assert_eq!(u32_at(body, 12), 33);
+        assert_eq!(ipv4_checksum(&packet[..IPV4_HEADER_LEN]), 0);
+        assert_eq!(&packet[12..16], &[10, 0, 0, 1]);
+        assert_eq!(&packet[16..20], &[10, 0, 0, 2]);
+        assert_eq!(u16::from_be_bytes([packet[20], packet[21]]), 51820);
+        assert_eq!(u16::from_be_bytes([packet[22], packet[23]]), 3478);
+        assert_eq!(u16::from_be_bytes([packet[24], packet[25]]), 13);
+        assert_eq!(&packet[28..], b"hello");
+    }
+
+    #[test]
+    fn stops_at_size_limit() {
+        let mut capture = PacketCapture::with_writer(Vec::new(), 200).unwrap();
+
+        capture
+            .record_ip(Direction::Inbound, SystemTime::now(), &[0; 20])
+            .unwrap();
+        let e = capture
+            .record_ip(Direction::Inbound, SystemTime::now(), &[0; 20])
+            .unwrap_err();
+
+        assert_eq!(e.kind(), io::ErrorKind::FileTooLarge);
+        assert_eq!(blocks(&capture.into_inner()).len(), 4);
+    }
+
+    #[test]
+    fn writer_thread_writes_queued_packets() {
+        let file = SharedBuffer::default();
+        let capture = PacketCapture::with_writer(file.clone(), 1024).unwrap();
+        let (mut writer, thread) = CaptureWriter::spawn(capture, 8).unwrap();
+
+        writer.record_ip(Direction::Inbound, &[0x45; 20]).unwrap();
+        writer
+            .record_datagram(
+                Direction::Outbound,
+                None,
+                "10.0.0.2:3478".parse().unwrap(),
+                b"hello",
+            )
+            .unwrap();
+        drop(writer);
+        thread.join().unwrap();
+
+        let blocks = blocks(&file.bytes());
+
+        assert_eq!(blocks.len(), 5);
+        assert_eq!(u32_at(&blocks[3].1, 0), Interface::Tun as u32);
+        assert_eq!(u32_at(&blocks[4].1, 0), Interface::Network as u32);
+    }
+
+    #[test]
+    fn drops_packets_when_writer_falls_behind() {
+        let file = SharedBuffer::default();
+        let capture = PacketCapture::with_writer(file.clone(), 1024).unwrap();
+        let (mut writer, thread) = CaptureWriter::spawn(capture, 1).unwrap();
+
+        let stalled = file.stall();
+        for _ in 0..3 {
+            writer.record_ip(Direction::Inbound, &[0x45; 20]).unwrap();
+        }
+        let dropped = writer.dropped();
+        drop(stalled);
+        drop(writer);
+        thread.join().unwrap();
+
+        assert!(dropped >= 1);
+        assert_eq!(blocks(&file.bytes()).len() as u64, 3 + 3 - dropped);
+    }
+
+    #[test]
+    fn reports_stopped_writer() {
+        let capture = PacketCapture::with_writer(Vec::new(), 200).unwrap();
+        let (mut writer, thread) = CaptureWriter::spawn(capture, 8).unwrap();
+
+        writer.record_ip(Direction::Inbound, &[0; 20]).unwrap();
+        writer.record_ip(Direction::Inbound, &[0; 20]).unwrap();
+        thread.join().unwrap();
+
+        assert!(writer.record_ip(Direction::Inbound, &[0; 20]).is_err());
+    }
+
+    /// A file that we can inspect after the writer thread is done and whose writes we can hold up.
+    #[derive(Default, Clone)]
+    struct SharedBuffer {
+        bytes: Arc<Mutex<Vec<u8>>>,
+        stall: Arc<Mutex<()>>,
+    }
+
+    impl SharedBuffer {
+        fn bytes(&self) -> Vec<u8> {
+            self.bytes.lock().unwrap().clone()
+        }
+
+        /// Blocks all writes until the returned guard is dropped.
+        fn stall(&self) -> MutexGuard<'_, ()> {
+            self.stall.lock().unwrap()
+        }
+    }
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let _stall = self.stall.lock().unwrap();
+            self.bytes.lock().unwrap().extend_from_slice(buf);
+
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn blocks(bytes: &[u8]) -> Vec<(u32, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        let mut rest = bytes;
+
+        while !rest.is_empty() {
+            let block_type = u32_at(rest, 0);
+            let len = u32_at(rest, 4) as usize;
+            assert_eq!(u32_at(rest, len - 4) as usize, len);
+
+            blocks.push((block_type, rest[8..len - 4].to_vec()));
+            rest = &rest[len..];
+        }
+
+        blocks
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+    }
+}
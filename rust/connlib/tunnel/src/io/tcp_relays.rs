@@ -0,0 +1,233 @@
+//! TCP and TLS connections to relays that we cannot reach via UDP.
+//!
+//! Thanks to the framing in [`turn_over_tcp`], we can send and receive the same messages as via UDP and pass them to `snownet` as if they were datagrams.
+
+use snownet::Transport;
+use socket_factory::{SocketFactory, TcpSocket};
+use std::{
+    collections::HashMap,
+    io,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, LazyLock},
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_rustls::{
+    rustls::{self, pki_types::ServerName},
+    TlsConnector,
+};
+use tracing::Instrument as _;
+use turn_over_tcp::{write_message, MessageReader};
+
+/// How many messages we buffer for a single relay before we start dropping them.
+const MAX_BUFFERED_MESSAGES: usize = 1000;
+
+/// Relays accept TURN over TLS on the HTTPS port because that is the one most likely to be open on restrictive networks.
+const RELAY_TLS_PORT: u16 = 443;
+
+/// See <https://www.rfc-editor.org/rfc/rfc7443#section-6>.
+static TLS_CONFIG: LazyLock<Arc<rustls::ClientConfig>> =
+    LazyLock::new(|| super::encrypted_dns::tls_config(b"stun.turn"));
+
+pub(crate) struct TcpRelays {
+    connections: HashMap<SocketAddr, Connection>,
+
+    inbound_tx: mpsc::Sender<Received>,
+    inbound_rx: mpsc::Receiver<Received>,
+}
+
+struct Connection {
+    transport: Transport,
+    outbound: mpsc::Sender<Vec<u8>>,
+    task: JoinHandle<()>,
+}
+
+/// A message received from a relay via TCP or TLS.
+pub(crate) struct Received {
+    pub(crate) local: SocketAddr,
+    pub(crate) from: SocketAddr,
+    pub(crate) packet: Vec<u8>,
+}
+
+impl Default for TcpRelays {
+    fn default() -> Self {
+        let (inbound_tx, inbound_rx) = mpsc::channel(MAX_BUFFERED_MESSAGES);
+
+        Self {
+            connections: Default::default(),
+            inbound_tx,
+            inbound_rx,
+        }
+    }
+}
+
+impl TcpRelays {
+    /// Sends a message to the given relay, connecting to it first if necessary.
+    ///
+    /// `dst` is the relay's TURN address, the connection for [`Transport::Tls`] goes to [`RELAY_TLS_PORT`] instead.
+    pub(crate) fn send(
+        &mut self,
+        factory: &Arc<dyn SocketFactory<TcpSocket>>,
+        dst: SocketAddr,
+        transport: Transport,
+        message: &[u8],
+    ) {
+        let connection = self
+            .connections
+            .entry(dst)
+            .and_modify(|c| {
+                if c.task.is_finished() || c.transport != transport {
+                    tracing::debug!(%dst, ?transport, "Reconnecting to relay");
+
+                    c.task.abort();
+                    *c = Connection::new(factory.clone(), dst, transport, self.inbound_tx.clone());
+                }
+            })
+            .or_insert_with(|| {
+                tracing::debug!(%dst, ?transport, "Connecting to relay");
+
+                Connection::new(factory.clone(), dst, transport, self.inbound_tx.clone())
+            });
+
+        match connection.outbound.try_send(message.to_vec()) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                tracing::debug!(%dst, "Connection to relay is congested, dropping message");
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                tracing::debug!(%dst, "Connection to relay is closed, dropping message");
+            }
+        }
+    }
+
+    pub(crate) fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Received> {
+        self.inbound_rx
+            .poll_recv(cx)
+            .map(|r| r.expect("we always hold a sender"))
+    }
+
+    /// Closes all connections.
+    pub(crate) fn clear(&mut self) {
+        for (_, connection) in self.connections.drain() {
+            connection.task.abort();
+        }
+    }
+}
+
+impl Drop for TcpRelays {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+impl Connection {
+    fn new(
+        factory: Arc<dyn SocketFactory<TcpSocket>>,
+        dst: SocketAddr,
+        transport: Transport,
+        inbound: mpsc::Sender<Received>,
+    ) -> Self {
+        let (outbound, outbound_rx) = mpsc::channel(MAX_BUFFERED_MESSAGES);
+
+        let task = tokio::spawn(
+            async move {
+                if let Err(e) = run(factory, dst, transport, outbound_rx, inbound).await {
+                    tracing::debug!("Connection to relay failed: {e}");
+                }
+            }
+            .instrument(tracing::debug_span!("tcp_relay", %dst, ?transport)),
+        );
+
+        Self {
+            transport,
+            outbound,
+            task,
+        }
+    }
+}
+
+async fn run(
+    factory: Arc<dyn SocketFactory<TcpSocket>>,
+    dst: SocketAddr,
+    transport: Transport,
+    outbound: mpsc::Receiver<Vec<u8>>,
+    inbound: mpsc::Sender<Received>,
+) -> io::Result<()> {
+    match transport {
+        Transport::Tcp => {
+            let stream = factory(&dst)?.connect(dst).await?;
+
+            relay_messages(stream, dst, outbound, inbound).await
+        }
+        Transport::Tls => {
+            let tls_dst = SocketAddr::new(dst.ip(), RELAY_TLS_PORT);
+
+            // Relays are only known by their IP, thus their certificate must be valid for it.
+            let stream = factory(&tls_dst)?.connect(tls_dst).await?;
+            let stream = TlsConnector::from(TLS_CONFIG.clone())
+                .connect(ServerName::IpAddress(dst.ip().into()), stream)
+                .await?;
+
+            relay_messages(stream, dst, outbound, inbound).await
+        }
+        Transport::Udp => Err(io::Error::other("UDP is not a stream transport")),
+    }
+}
+
+async fn relay_messages<S>(
+    stream: S,
+    dst: SocketAddr,
+    mut outbound: mpsc::Receiver<Vec<u8>>,
+    inbound: mpsc::Sender<Received>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (reader, mut writer) = tokio::io::split(stream);
+
+    // We don't learn which local address the stream is bound to.
+    // `snownet` only needs the IP version to match.
+    let local = match dst {
+        SocketAddr::V4(_) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0),
+        SocketAddr::V6(_) => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), 0),
+    };
+
+    let send = async {
+        while let Some(message) = outbound.recv().await {
+            write_message(&mut writer, &message).await?;
+        }
+
+        io::Result::Ok(())
+    };
+
+    let recv = async {
+        let mut reader = MessageReader::new(reader);
+
+        loop {
+            let packet = reader
+                .read()
+                .await?
+                .ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))?;
+
+            if inbound
+                .send(Received {
+                    local,
+                    from: dst,
+                    packet,
+                })
+                .await
+                .is_err()
+            {
+                return Ok(());
+            }
+        }
+    };
+
+    futures::future::try_join(send, recv).await?;
+
+    Ok(())
+}
@@ -27,6 +27,7 @@ mod dns;
 mod gateway;
 mod io;
 pub mod messages;
+mod metrics;
 mod p2p_control;
 mod peer;
 mod peer_store;
@@ -52,7 +53,11 @@ pub type GatewayTunnel = Tunnel<GatewayState>;
 pub type ClientTunnel = Tunnel<ClientState>;
 
 pub use client::ClientState;
-pub use gateway::{DnsResourceNatEntry, GatewayState, ResolveDnsRequest, IPV4_PEERS, IPV6_PEERS};
+pub use gateway::{
+    DnsResourceNatEntry, GatewayState, ResolveDnsRequest, ResolvedAddresses, IPV4_PEERS, IPV6_PEERS,
+};
+pub use peer::flow_tracker::{FlowCloseReason, FlowEnd, FlowProtocol, FlowRecord};
+pub use snownet::CandidatePolicy;
 pub use utils::turn;
 
 /// [`Tunnel`] glues together connlib's [`Io`] component and the respective (pure) state of a client or gateway.
@@ -78,6 +83,26 @@ impl<TRoleState> Tunnel<TRoleState> {
     pub fn set_tun(&mut self, tun: Box<dyn Tun>) {
         self.io.set_tun(tun);
     }
+
+    /// Writes the plaintext packets on the TUN device and the encrypted datagrams on the network to `file` in the pcapng format.
+    ///
+    /// The capture stops once `file` would grow beyond `max_size` bytes.
+    pub fn start_packet_capture(
+        &mut self,
+        file: std::fs::File,
+        max_size: u64,
+    ) -> std::io::Result<()> {
+        self.io.start_packet_capture(file, max_size)
+    }
+
+    pub fn stop_packet_capture(&mut self) {
+        self.io.stop_packet_capture();
+    }
+
+    /// Whether a capture is running, it may have stopped by itself after reaching its size limit.
+    pub fn is_capturing_packets(&self) -> bool {
+        self.io.is_capturing_packets()
+    }
 }
 
 impl ClientTunnel {
@@ -85,9 +110,12 @@ impl ClientTunnel {
         tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
         udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
     ) -> Self {
+        let mut role_state = ClientState::new(rand::random(), Instant::now());
+        role_state.set_relay_tcp_fallback(true);
+
         Self {
             io: Io::new(tcp_socket_factory, udp_socket_factory),
-            role_state: ClientState::new(rand::random(), Instant::now()),
+            role_state,
             buffers: Buffers::default(),
         }
     }
@@ -115,7 +143,8 @@ impl ClientTunnel {
             }
 
             if let Some(trans) = self.role_state.poll_transmit() {
-                self.io.send_network(trans.src, trans.dst, &trans.payload);
+                self.io
+                    .send_network(trans.src, trans.dst, &trans.payload, trans.transport);
                 continue;
             }
 
@@ -142,8 +171,12 @@ impl ClientTunnel {
                             continue;
                         };
 
-                        self.io
-                            .send_network(packet.src(), packet.dst(), packet.payload());
+                        self.io.send_network(
+                            packet.src(),
+                            packet.dst(),
+                            packet.payload(),
+                            packet.transport(),
+                        );
                     }
 
                     continue;
@@ -152,6 +185,8 @@ impl ClientTunnel {
                     let now = Instant::now();
 
                     for received in packets {
+                        self.io.capture_received(&received);
+
                         let Some(packet) = self.role_state.handle_network_input(
                             received.local,
                             received.from,
@@ -168,8 +203,10 @@ impl ClientTunnel {
                     continue;
                 }
                 Poll::Ready(io::Input::DnsResponse(packet)) => {
-                    self.role_state.handle_dns_response(packet);
-                    self.role_state.handle_timeout(Instant::now());
+                    let now = Instant::now();
+
+                    self.role_state.handle_dns_response(packet, now);
+                    self.role_state.handle_timeout(now);
                     continue;
                 }
                 Poll::Pending => {}
@@ -189,9 +226,12 @@ impl GatewayTunnel {
         tcp_socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
         udp_socket_factory: Arc<dyn SocketFactory<UdpSocket>>,
     ) -> Self {
+        let mut role_state = GatewayState::new(rand::random(), Instant::now());
+        role_state.set_relay_tcp_fallback(true);
+
         Self {
             io: Io::new(tcp_socket_factory, udp_socket_factory),
-            role_state: GatewayState::new(rand::random(), Instant::now()),
+            role_state,
             buffers: Buffers::default(),
         }
     }
@@ -209,7 +249,8 @@ impl GatewayTunnel {
             }
 
             if let Some(trans) = self.role_state.poll_transmit() {
-                self.io.send_network(trans.src, trans.dst, &trans.payload);
+                self.io
+                    .send_network(trans.src, trans.dst, &trans.payload, trans.transport);
                 continue;
             }
 
@@ -228,18 +269,18 @@ impl GatewayTunnel {
                 Poll::Ready(io::Input::Device(packets)) => {
                     let now = Instant::now();
 
-                    for packet in packets {
-                        let Some(packet) = self
-                            .role_state
-                            .handle_tun_input(packet, now)
-                            .map_err(std::io::Error::other)?
-                        else {
+                    for result in self.role_state.handle_tun_input_batch(packets, now) {
+                        let Some(packet) = result.map_err(std::io::Error::other)? else {
                             self.role_state.handle_timeout(now, Utc::now());
                             continue;
                         };
 
-                        self.io
-                            .send_network(packet.src(), packet.dst(), packet.payload());
+                        self.io.send_network(
+                            packet.src(),
+                            packet.dst(),
+                            packet.payload(),
+                            packet.transport(),
+                        );
                     }
 
                     continue;
@@ -248,17 +289,16 @@ impl GatewayTunnel {
                     let now = Instant::now();
                     let utc_now = Utc::now();
 
-                    for received in packets {
-                        let Some(packet) = self
-                            .role_state
-                            .handle_network_input(
-                                received.local,
-                                received.from,
-                                received.packet,
-                                now,
-                            )
-                            .map_err(std::io::Error::other)?
-                        else {
+                    let packets = packets
+                        .map(|received| {
+                            self.io.capture_received(&received);
+
+                            (received.local, received.from, received.packet)
+                        })
+                        .collect::<Vec<_>>();
+
+                    for result in self.role_state.handle_network_input_batch(packets, now) {
+                        let Some(packet) = result.map_err(std::io::Error::other)? else {
                             self.role_state.handle_timeout(now, utc_now);
                             continue;
                         };
@@ -330,6 +370,8 @@ pub enum GatewayEvent {
         candidates: BTreeSet<String>,
     },
     ResolveDns(ResolveDnsRequest),
+    /// A flow started or ended, only emitted if flow logging is enabled.
+    Flow(FlowRecord),
 }
 
 /// Adapter-struct to [`fmt::Display`] a [`BTreeSet`].
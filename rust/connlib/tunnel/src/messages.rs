@@ -7,6 +7,7 @@ use ip_network::IpNetwork;
 use secrecy::{ExposeSecret as _, Secret};
 use serde::{Deserialize, Serialize};
 use std::fmt;
+use url::Url;
 
 pub mod client;
 pub mod gateway;
@@ -112,30 +113,46 @@ pub struct IceCredentials {
     pub password: String,
 }
 
-#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
 #[serde(tag = "protocol", rename_all = "snake_case")]
 pub enum DnsServer {
     IpPort(IpDnsServer),
+    DnsOverHttps(DohDnsServer),
+    DnsOverTls(DotDnsServer),
 }
 
 impl fmt::Debug for DnsServer {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::IpPort(IpDnsServer { address }) => address.fmt(f),
+            Self::DnsOverHttps(DohDnsServer { url, address }) => {
+                write!(f, "{url} ({address})")
+            }
+            Self::DnsOverTls(DotDnsServer { hostname, address }) => {
+                write!(f, "tls://{hostname} ({address})")
+            }
         }
     }
 }
 
 impl DnsServer {
     pub fn ip(&self) -> IpAddr {
-        match self {
-            DnsServer::IpPort(s) => s.address.ip(),
-        }
+        self.address().ip()
     }
 
     pub fn address(&self) -> SocketAddr {
         match self {
             DnsServer::IpPort(s) => s.address,
+            DnsServer::DnsOverHttps(s) => s.address,
+            DnsServer::DnsOverTls(s) => s.address,
+        }
+    }
+
+    /// Whether queries to this server are encrypted, i.e. have to be sent from the host instead of being forwarded through the tunnel.
+    pub fn is_encrypted(&self) -> bool {
+        match self {
+            DnsServer::IpPort(_) => false,
+            DnsServer::DnsOverHttps(_) | DnsServer::DnsOverTls(_) => true,
         }
     }
 }
@@ -156,6 +173,26 @@ pub struct IpDnsServer {
     pub address: SocketAddr,
 }
 
+/// A DNS-over-HTTPS resolver as per RFC 8484.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct DohDnsServer {
+    /// The URL we `POST` queries to, e.g. `https://dns.example.com/dns-query`.
+    pub url: Url,
+    /// The socket to connect to.
+    ///
+    /// We cannot resolve the domain in `url` ourselves because that query would need to go to this server.
+    pub address: SocketAddr,
+}
+
+/// A DNS-over-TLS resolver as per RFC 7858.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct DotDnsServer {
+    /// The name we validate the server's certificate against.
+    pub hostname: String,
+    /// The socket to connect to, typically on port 853.
+    pub address: SocketAddr,
+}
+
 /// Represents a wireguard interface configuration.
 ///
 /// Note that the ips are /32 for ipv4 and /128 for ipv6.
@@ -170,6 +207,22 @@ pub struct Interface {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     #[serde(default)]
     pub upstream_dns: Vec<DnsServer>,
+    /// Domains whose queries go to a different server than [`Interface::upstream_dns`].
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde(default)]
+    pub split_dns: Vec<SplitDnsRule>,
+}
+
+/// Forwards queries for a domain to a specific DNS server, a.k.a. conditional forwarding.
+///
+/// Queries for DNS resources are always answered by connlib, regardless of any rule.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq, Hash)]
+pub struct SplitDnsRule {
+    /// Either a domain like `corp.internal`, which also matches all its subdomains, or a pattern like `*.lab`.
+    ///
+    /// Patterns use the same syntax as the address of a DNS resource.
+    pub domain: String,
+    pub server: DnsServer,
 }
 
 /// A single relay
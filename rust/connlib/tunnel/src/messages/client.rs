@@ -181,6 +181,7 @@ pub enum EgressMessages {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::messages::{DnsServer, SplitDnsRule};
 
     #[test]
     fn can_deserialize_internet_resource() {
@@ -310,6 +311,86 @@ mod tests {
         assert!(matches!(message, IngressMessages::ConfigChanged(_)))
     }
 
+    #[test]
+    fn can_deserialize_encrypted_upstream_dns() {
+        let json = r#"
+        {
+            "event": "config_changed",
+            "ref": null,
+            "topic": "client",
+            "payload": {
+              "interface": {
+                "ipv6": "fd00:2021:1111::e:65ea",
+                "upstream_dns": [
+                  {
+                    "protocol": "dns_over_https",
+                    "url": "https://cloudflare-dns.com/dns-query",
+                    "address": "1.1.1.1:443"
+                  },
+                  {
+                    "protocol": "dns_over_tls",
+                    "hostname": "dns.quad9.net",
+                    "address": "9.9.9.9:853"
+                  }
+                ],
+                "ipv4": "100.67.138.25"
+              }
+            }
+          }
+        "#;
+
+        let message = serde_json::from_str::<IngressMessages>(json).unwrap();
+
+        let IngressMessages::ConfigChanged(config) = message else {
+            panic!("Unexpected message")
+        };
+        assert!(config
+            .interface
+            .upstream_dns
+            .iter()
+            .all(|server| server.is_encrypted()));
+    }
+
+    #[test]
+    fn can_deserialize_split_dns_rules() {
+        let json = r#"
+        {
+            "event": "config_changed",
+            "ref": null,
+            "topic": "client",
+            "payload": {
+              "interface": {
+                "ipv6": "fd00:2021:1111::e:65ea",
+                "upstream_dns": [],
+                "split_dns": [
+                  {
+                    "domain": "corp.internal",
+                    "server": {
+                      "protocol": "ip_port",
+                      "address": "10.0.0.53:53"
+                    }
+                  }
+                ],
+                "ipv4": "100.67.138.25"
+              }
+            }
+          }
+        "#;
+
+        let message = serde_json::from_str::<IngressMessages>(json).unwrap();
+
+        let IngressMessages::ConfigChanged(config) = message else {
+            panic!("Unexpected message")
+        };
+        assert_eq!(
+            config.interface.split_dns,
+            vec![SplitDnsRule {
+                domain: "corp.internal".to_owned(),
+                server: DnsServer::from(([10, 0, 0, 53], 53)),
+            }]
+        );
+    }
+
     #[test]
     fn can_deserialize_init_message() {
         let json = r#"{
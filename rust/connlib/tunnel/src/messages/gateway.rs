@@ -11,6 +11,7 @@ use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeSet,
     net::{Ipv4Addr, Ipv6Addr},
+    num::NonZeroU64,
 };
 
 use super::Offer;
@@ -30,6 +31,9 @@ pub struct ResourceDescriptionDns {
     pub name: String,
 
     pub filters: Filters,
+
+    #[serde(default)]
+    pub rate_limit: RateLimit,
 }
 
 /// Description of a resource that maps to a CIDR.
@@ -45,12 +49,18 @@ pub struct ResourceDescriptionCidr {
     pub name: String,
 
     pub filters: Filters,
+
+    #[serde(default)]
+    pub rate_limit: RateLimit,
 }
 
 /// Description of an Internet resource.
 #[derive(Debug, Deserialize, Clone)]
 pub struct ResourceDescriptionInternet {
     pub id: ResourceId,
+
+    #[serde(default)]
+    pub rate_limit: RateLimit,
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -61,6 +71,16 @@ pub enum ResourceDescription {
     Internet(ResourceDescriptionInternet),
 }
 
+/// How many bytes per second may flow from a client to a resource (upload) and back (download).
+///
+/// Each limit allows bursts of up to one second worth of traffic.
+/// Missing limits mean unlimited.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub upload_bytes_per_sec: Option<NonZeroU64>,
+    pub download_bytes_per_sec: Option<NonZeroU64>,
+}
+
 #[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
 #[serde(tag = "protocol", rename_all = "snake_case")]
 pub enum Filter {
@@ -105,6 +125,14 @@ impl ResourceDescription {
             ResourceDescription::Internet(_) => Vec::default(),
         }
     }
+
+    pub fn rate_limit(&self) -> RateLimit {
+        match self {
+            ResourceDescription::Dns(r) => r.rate_limit,
+            ResourceDescription::Cidr(r) => r.rate_limit,
+            ResourceDescription::Internet(r) => r.rate_limit,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -345,6 +373,27 @@ mod tests {
         serde_json::from_str::<Vec<ResourceDescription>>(resources).unwrap();
     }
 
+    #[test]
+    fn can_deserialize_rate_limit() {
+        let resource = r#"{
+            "id": "1106047c-cd5d-4151-b679-96b93da7383b",
+            "type": "internet",
+            "rate_limit": {
+                "download_bytes_per_sec": 1000000
+            }
+        }"#;
+
+        let resource = serde_json::from_str::<ResourceDescription>(resource).unwrap();
+
+        assert_eq!(
+            resource.rate_limit(),
+            RateLimit {
+                upload_bytes_per_sec: None,
+                download_bytes_per_sec: NonZeroU64::new(1_000_000),
+            }
+        );
+    }
+
     #[test]
     fn can_deserialize_request_connection_messages() {
         let json = r#"{
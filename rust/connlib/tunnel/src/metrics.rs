@@ -0,0 +1,121 @@
+//! Metrics shared between clients and gateways.
+
+use opentelemetry::metrics::Counter;
+use opentelemetry::KeyValue;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+
+/// How many peers we break down the data counters for, the traffic of all other peers is counted as `other`.
+///
+/// This bounds the number of time series a long-running gateway with many clients produces.
+const MAX_LABELLED_PEERS: usize = 100;
+
+/// Counters about our connections to other peers.
+pub(crate) struct PeerMetrics<TId> {
+    connections: Counter<u64>,
+    data_sent: Counter<u64>,
+    data_received: Counter<u64>,
+
+    peer_attributes: PeerAttributes<TId>,
+}
+
+impl<TId> PeerMetrics<TId>
+where
+    TId: Copy + Eq + Hash + fmt::Display,
+{
+    pub(crate) fn new() -> Self {
+        let meter = opentelemetry::global::meter("connlib");
+
+        Self {
+            connections: meter
+                .u64_counter("connections_total")
+                .with_description("The number of connection events, a connection is established once the WireGuard handshake completes")
+                .init(),
+            data_sent: meter
+                .u64_counter("data_sent_bytes")
+                .with_description("The number of bytes sent through the tunnel to a peer")
+                .with_unit("b")
+                .init(),
+            data_received: meter
+                .u64_counter("data_received_bytes")
+                .with_description("The number of bytes received through the tunnel from a peer")
+                .with_unit("b")
+                .init(),
+            peer_attributes: PeerAttributes::new(MAX_LABELLED_PEERS),
+        }
+    }
+
+    pub(crate) fn record_event(&self, event: &snownet::Event<TId>) {
+        let event = match event {
+            snownet::Event::ConnectionEstablished(_) => "established",
+            snownet::Event::ConnectionFailed(_) => "failed",
+            snownet::Event::ConnectionClosed(_) => "closed",
+            snownet::Event::ConnectionMigrated(_) => "migrated",
+            snownet::Event::NewIceCandidate { .. }
+            | snownet::Event::InvalidateIceCandidate { .. } => return,
+        };
+
+        self.connections.add(1, &[KeyValue::new("event", event)]);
+    }
+
+    pub(crate) fn record_sent(&mut self, peer: TId, num_bytes: usize) {
+        let attributes = self.peer_attributes.get(peer);
+
+        self.data_sent.add(num_bytes as u64, attributes);
+    }
+
+    pub(crate) fn record_received(&mut self, peer: TId, num_bytes: usize) {
+        let attributes = self.peer_attributes.get(peer);
+
+        self.data_received.add(num_bytes as u64, attributes);
+    }
+}
+
+/// The `peer` attribute of our data counters.
+///
+/// The first peers we see are labelled with their ID, all later ones share the `other` label.
+struct PeerAttributes<TId> {
+    labelled: HashMap<TId, [KeyValue; 1]>,
+    other: [KeyValue; 1],
+    max_labelled: usize,
+}
+
+impl<TId> PeerAttributes<TId>
+where
+    TId: Copy + Eq + Hash + fmt::Display,
+{
+    fn new(max_labelled: usize) -> Self {
+        Self {
+            labelled: HashMap::with_capacity(max_labelled),
+            other: [KeyValue::new("peer", "other")],
+            max_labelled,
+        }
+    }
+
+    fn get(&mut self, peer: TId) -> &[KeyValue] {
+        if !self.labelled.contains_key(&peer) && self.labelled.len() >= self.max_labelled {
+            return &self.other;
+        }
+
+        self.labelled
+            .entry(peer)
+            .or_insert_with(|| [KeyValue::new("peer", peer.to_string())])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels_first_peers_individually() {
+        let mut attributes = PeerAttributes::new(2);
+
+        assert_eq!(attributes.get(1u8), [KeyValue::new("peer", "1")]);
+        assert_eq!(attributes.get(2u8), [KeyValue::new("peer", "2")]);
+        assert_eq!(attributes.get(3u8), [KeyValue::new("peer", "other")]);
+
+        assert_eq!(attributes.get(1u8), [KeyValue::new("peer", "1")]);
+    }
+}
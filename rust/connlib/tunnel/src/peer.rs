@@ -1,10 +1,11 @@
 use std::collections::{hash_map, BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::iter;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use crate::client::{IPV4_RESOURCES, IPV6_RESOURCES};
 use crate::messages::gateway::Filters;
+use crate::messages::gateway::RateLimit;
 use crate::messages::gateway::ResourceDescription;
 use chrono::{DateTime, Utc};
 use connlib_model::{ClientId, DomainName, GatewayId, ResourceId};
@@ -14,13 +15,31 @@ use ip_network_table::IpNetworkTable;
 use ip_packet::{IpPacket, Protocol, UnsupportedProtocol};
 
 use crate::utils::network_contains_network;
-use crate::GatewayEvent;
+use crate::{GatewayEvent, ResolveDnsRequest};
 
 use anyhow::{bail, Context, Result};
+use flow_tracker::{FlowCloseReason, FlowRecord, FlowTracker};
 use nat_table::{NatTable, TranslateIncomingResult};
+use rate_limit::{Direction, RateLimiter};
 
 mod filter_engine;
+pub(crate) mod flow_tracker;
 mod nat_table;
+mod rate_limit;
+
+/// How long before the TTL of resolved records expires we re-resolve the domain.
+const DNS_REFRESH_AHEAD: Duration = Duration::from_secs(5);
+
+/// The minimum interval at which we re-resolve a domain, regardless of how short its TTL is.
+///
+/// This is also how long we wait before retrying a failed re-resolution the first time.
+const MIN_DNS_REFRESH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Repeatedly failing re-resolutions are retried with exponential backoff up to this interval.
+const MAX_DNS_RETRY_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// The maximum interval at which we re-resolve a domain, regardless of how long its TTL is.
+const MAX_DNS_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 /// The state of one gateway on a client.
 pub(crate) struct GatewayOnClient {
@@ -55,9 +74,16 @@ pub struct ClientOnGateway {
     resources: HashMap<ResourceId, ResourceOnGateway>,
     /// Caches the existence of internet resource
     internet_resource_enabled: bool,
+    /// Caches which resource a packet belongs to, see [`resource_for_dst`].
+    resource_lookup: ResourceLookup,
     filters: IpNetworkTable<FilterEngine>,
     permanent_translations: BTreeMap<IpAddr, TranslationState>,
+    /// When to next re-resolve the domains we have set up NAT for.
+    dns_refreshes: BTreeMap<(ResourceId, DomainName), DnsRefresh>,
     nat_table: NatTable,
+    /// Only present if flow logging is enabled.
+    flow_tracker: Option<FlowTracker>,
+    rate_limiter: RateLimiter,
     buffered_events: VecDeque<GatewayEvent>,
 }
 
@@ -68,14 +94,32 @@ impl ClientOnGateway {
             ipv4,
             ipv6,
             resources: HashMap::new(),
+            resource_lookup: ResourceLookup::default(),
             filters: IpNetworkTable::new(),
             permanent_translations: Default::default(),
+            dns_refreshes: Default::default(),
             nat_table: Default::default(),
+            flow_tracker: None,
+            rate_limiter: Default::default(),
             buffered_events: Default::default(),
             internet_resource_enabled: false,
         }
     }
 
+    pub(crate) fn enable_flow_tracking(&mut self) {
+        self.flow_tracker
+            .get_or_insert_with(|| FlowTracker::new(self.id));
+    }
+
+    /// Limits the throughput of this client across all resources.
+    pub(crate) fn set_rate_limit(&mut self, limit: RateLimit) {
+        self.rate_limiter.set_client_limit(limit);
+    }
+
+    pub(crate) fn num_nat_sessions(&self) -> usize {
+        self.nat_table.table.len()
+    }
+
     /// A client is only allowed to send packets from their (portal-assigned) tunnel IPs.
     ///
     /// Failure to enforce this would allow one client to send traffic masquarading as a different client.
@@ -84,6 +128,8 @@ impl ClientOnGateway {
     }
 
     /// Setup the NAT for a particular domain within a wildcard DNS resource.
+    ///
+    /// Calling this again for the same domain updates the translations in place.
     #[tracing::instrument(level = "debug", skip_all, fields(cid = %self.id))]
     pub(crate) fn setup_nat(
         &mut self,
@@ -91,6 +137,38 @@ impl ClientOnGateway {
         resource_id: ResourceId,
         resolved_ips: BTreeSet<IpAddr>,
         proxy_ips: BTreeSet<IpAddr>,
+        ttl: Duration,
+        now: Instant,
+    ) -> Result<()> {
+        self.update_nat(name, resource_id, resolved_ips, proxy_ips, ttl, now, false)
+    }
+
+    /// Updates the NAT for a domain after it has been re-resolved.
+    ///
+    /// Proxy IPs whose address is still part of the resolved ones keep it, so that existing NAT sessions are not disrupted.
+    #[tracing::instrument(level = "debug", skip_all, fields(cid = %self.id))]
+    pub(crate) fn refresh_nat(
+        &mut self,
+        name: DomainName,
+        resource_id: ResourceId,
+        resolved_ips: BTreeSet<IpAddr>,
+        proxy_ips: BTreeSet<IpAddr>,
+        ttl: Duration,
+        now: Instant,
+    ) -> Result<()> {
+        self.update_nat(name, resource_id, resolved_ips, proxy_ips, ttl, now, true)
+    }
+
+    #[expect(clippy::too_many_arguments)]
+    fn update_nat(
+        &mut self,
+        name: DomainName,
+        resource_id: ResourceId,
+        resolved_ips: BTreeSet<IpAddr>,
+        proxy_ips: BTreeSet<IpAddr>,
+        ttl: Duration,
+        now: Instant,
+        keep_valid_translations: bool,
     ) -> Result<()> {
         let resource = self
             .resources
@@ -122,15 +200,31 @@ impl ClientOnGateway {
         let ip_maps = ipv4_maps.chain(ipv6_maps);
 
         for (proxy_ip, real_ip) in ip_maps {
+            if keep_valid_translations
+                && self.permanent_translations.get(proxy_ip).is_some_and(|t| {
+                    t.resource_id == resource_id && resolved_ips.contains(&t.resolved_ip)
+                })
+            {
+                continue;
+            }
+
             tracing::debug!(%name, %proxy_ip, %real_ip);
 
             self.permanent_translations
                 .insert(*proxy_ip, TranslationState::new(resource_id, real_ip));
         }
 
-        tracing::debug!(domain = %name, ?resolved_ips, ?proxy_ips, "Set up DNS resource NAT");
+        tracing::debug!(domain = %name, ?resolved_ips, ?proxy_ips, ?ttl, "Set up DNS resource NAT");
 
-        domains.insert(name, resolved_ips);
+        domains.insert(name.clone(), resolved_ips);
+        self.dns_refreshes.insert(
+            (resource_id, name),
+            DnsRefresh {
+                proxy_ips: Vec::from_iter(proxy_ips),
+                refresh_at: now + dns_refresh_interval(ttl),
+                retry_interval: MIN_DNS_REFRESH_INTERVAL,
+            },
+        );
         self.recalculate_filters();
 
         Ok(())
@@ -142,35 +236,87 @@ impl ClientOnGateway {
 
     pub(crate) fn expire_resources(&mut self, now: DateTime<Utc>) {
         let cid = self.id;
-        let mut any_expired = false;
+
+        let mut expired = Vec::new();
 
         self.resources.retain(|rid, r| {
             let is_allowed = r.is_allowed(&now);
 
             if !is_allowed {
-                any_expired = true;
+                expired.push(*rid);
                 tracing::info!(%cid, %rid, "Access to resource expired");
             }
 
             is_allowed
         });
 
-        if any_expired {
-            self.recalculate_filters();
+        if expired.is_empty() {
+            return;
+        }
+
+        if let Some(flow_tracker) = self.flow_tracker.as_mut() {
+            for rid in expired {
+                flow_tracker.close_resource(rid, FlowCloseReason::AccessRevoked);
+            }
         }
+
+        self.recalculate_filters();
     }
 
     pub(crate) fn poll_event(&mut self) -> Option<GatewayEvent> {
-        self.buffered_events.pop_front()
+        if let Some(ev) = self.buffered_events.pop_front() {
+            return Some(ev);
+        }
+
+        self.poll_flow_record().map(GatewayEvent::Flow)
+    }
+
+    pub(crate) fn poll_flow_record(&mut self) -> Option<FlowRecord> {
+        self.flow_tracker.as_mut()?.poll_record()
+    }
+
+    /// Ends all flows, e.g. because the client disconnected.
+    pub(crate) fn close_all_flows(&mut self, reason: FlowCloseReason) {
+        if let Some(flow_tracker) = self.flow_tracker.as_mut() {
+            flow_tracker.close_all(reason);
+        }
     }
 
     pub(crate) fn handle_timeout(&mut self, now: Instant) {
         self.nat_table.handle_timeout(now);
+
+        if let Some(flow_tracker) = self.flow_tracker.as_mut() {
+            flow_tracker.handle_timeout(now);
+        }
+
+        for ((resource, domain), refresh) in self.dns_refreshes.iter_mut() {
+            if now < refresh.refresh_at {
+                continue;
+            }
+
+            tracing::debug!(cid = %self.id, %resource, %domain, "Re-resolving domain of DNS resource");
+
+            // Check again later in case the resolution fails; a successful one will reschedule the refresh based on the new TTL.
+            refresh.refresh_at = now + refresh.retry_interval;
+            refresh.retry_interval = (refresh.retry_interval * 2).min(MAX_DNS_RETRY_INTERVAL);
+
+            self.buffered_events
+                .push_back(GatewayEvent::ResolveDns(ResolveDnsRequest::refresh(
+                    domain.clone(),
+                    self.id,
+                    *resource,
+                    refresh.proxy_ips.clone(),
+                )));
+        }
     }
 
     pub(crate) fn remove_resource(&mut self, resource: &ResourceId) {
         self.resources.remove(resource);
         self.recalculate_filters();
+
+        if let Some(flow_tracker) = self.flow_tracker.as_mut() {
+            flow_tracker.close_resource(*resource, FlowCloseReason::AccessRevoked);
+        }
     }
 
     pub(crate) fn add_resource(
@@ -180,6 +326,9 @@ impl ClientOnGateway {
     ) {
         tracing::info!(client = %self.id, resource = %resource.id(), expires = ?expires_at.map(|e| e.to_rfc3339()), "Allowing access to resource");
 
+        self.rate_limiter
+            .set_resource_limit(resource.id(), resource.rate_limit());
+
         match self.resources.entry(resource.id()) {
             hash_map::Entry::Vacant(v) => {
                 v.insert(ResourceOnGateway::new(resource, expires_at));
@@ -190,7 +339,7 @@ impl ClientOnGateway {
         self.recalculate_filters();
     }
 
-    // Note: we only allow updating filters and names
+    // Note: we only allow updating filters, rate limits and names
     // but names updates have no effect on the gateway
     pub(crate) fn update_resource(&mut self, new_description: &ResourceDescription) {
         let Some(resource) = self.resources.get_mut(&new_description.id()) else {
@@ -198,6 +347,8 @@ impl ClientOnGateway {
         };
 
         resource.update(new_description);
+        self.rate_limiter
+            .set_resource_limit(new_description.id(), new_description.rate_limit());
 
         self.recalculate_filters();
     }
@@ -212,6 +363,11 @@ impl ClientOnGateway {
         self.recalculate_dns_filters();
 
         self.internet_resource_enabled = self.resources.values().any(|r| r.is_internet_resource());
+        self.resource_lookup = ResourceLookup::new(&self.resources);
+        self.dns_refreshes
+            .retain(|(resource, _), _| self.resources.contains_key(resource));
+        self.rate_limiter
+            .retain_resources(|resource| self.resources.contains_key(resource));
     }
 
     fn recalculate_cidr_filters(&mut self) {
@@ -277,6 +433,37 @@ impl ClientOnGateway {
             return Ok(None);
         }
 
+        if !self.rate_limiter.admit(
+            Direction::Upload,
+            || {
+                resource_for_dst(
+                    &self.resource_lookup,
+                    &self.permanent_translations,
+                    packet.destination(),
+                )
+                .map(|(id, _)| id)
+            },
+            packet.packet().len(),
+            now,
+        ) {
+            tracing::trace!(?packet, "Client exceeds upload limit; dropping");
+            return Ok(None);
+        }
+
+        if let Some(flow_tracker) = self.flow_tracker.as_mut() {
+            flow_tracker.on_outbound(
+                &packet,
+                || {
+                    resource_for_dst(
+                        &self.resource_lookup,
+                        &self.permanent_translations,
+                        packet.destination(),
+                    )
+                },
+                now,
+            );
+        }
+
         // Failing to transform is an error we want to know about further up.
         let packet = self.transform_network_to_tun(packet, now)?;
 
@@ -302,6 +489,27 @@ impl ClientOnGateway {
             return Ok(None);
         }
 
+        if !self.rate_limiter.admit(
+            Direction::Download,
+            || {
+                resource_for_dst(
+                    &self.resource_lookup,
+                    &self.permanent_translations,
+                    packet.source(),
+                )
+                .map(|(id, _)| id)
+            },
+            packet.packet().len(),
+            now,
+        ) {
+            tracing::trace!(?packet, "Client exceeds download limit; dropping");
+            return Ok(None);
+        }
+
+        if let Some(flow_tracker) = self.flow_tracker.as_mut() {
+            flow_tracker.on_inbound(&packet, now);
+        }
+
         Ok(Some(packet))
     }
 
@@ -540,6 +748,21 @@ impl TranslationState {
     }
 }
 
+#[derive(Debug)]
+struct DnsRefresh {
+    /// The proxy IPs the client assigned to the domain.
+    proxy_ips: Vec<IpAddr>,
+    /// When to re-resolve the domain.
+    refresh_at: Instant,
+    /// How long to wait for the pending re-resolution before trying again.
+    retry_interval: Duration,
+}
+
+fn dns_refresh_interval(ttl: Duration) -> Duration {
+    ttl.saturating_sub(DNS_REFRESH_AHEAD)
+        .clamp(MIN_DNS_REFRESH_INTERVAL, MAX_DNS_REFRESH_INTERVAL)
+}
+
 fn ipv4_addresses(ip: &BTreeSet<IpAddr>) -> BTreeSet<IpAddr> {
     ip.iter().filter(|ip| ip.is_ipv4()).copied().collect()
 }
@@ -564,6 +787,73 @@ fn mapped_ipv6(ips: &BTreeSet<IpAddr>) -> BTreeSet<IpAddr> {
     }
 }
 
+/// Finds the resource a packet to the given destination is for and the address it will be translated to, if any.
+///
+/// This also works for the source of inbound packets once they have been translated back to the proxy IP.
+/// It is called for every packet if any resource has a rate limit, thus it must not scan all resources.
+fn resource_for_dst(
+    lookup: &ResourceLookup,
+    permanent_translations: &BTreeMap<IpAddr, TranslationState>,
+    dst: IpAddr,
+) -> Option<(ResourceId, Option<IpAddr>)> {
+    if let Some(state) = permanent_translations.get(&dst) {
+        return Some((state.resource_id, Some(state.resolved_ip)));
+    }
+
+    if let Some((_, id)) = lookup.cidr.longest_match(dst) {
+        return Some((*id, None));
+    }
+
+    Some((lookup.internet?, None))
+}
+
+/// The CIDR and internet resources of a client, indexed by address.
+struct ResourceLookup {
+    /// CIDR resources may overlap, in which case we pick the most specific one, just like the filters do.
+    cidr: IpNetworkTable<ResourceId>,
+    internet: Option<ResourceId>,
+}
+
+impl Default for ResourceLookup {
+    fn default() -> Self {
+        Self {
+            cidr: IpNetworkTable::new(),
+            internet: None,
+        }
+    }
+}
+
+impl ResourceLookup {
+    #[expect(
+        clippy::disallowed_methods,
+        reason = "The result does not depend on the iteration order."
+    )]
+    fn new(resources: &HashMap<ResourceId, ResourceOnGateway>) -> Self {
+        let mut lookup = Self::default();
+
+        for (id, resource) in resources.iter() {
+            match resource {
+                ResourceOnGateway::Cidr { network, .. } => {
+                    // Resources for the same network are indistinguishable, always pick the same one.
+                    if lookup
+                        .cidr
+                        .exact_match(*network)
+                        .is_none_or(|other| other < id)
+                    {
+                        lookup.cidr.insert(*network, *id);
+                    }
+                }
+                ResourceOnGateway::Internet { .. } => {
+                    lookup.internet = lookup.internet.max(Some(*id));
+                }
+                ResourceOnGateway::Dns { .. } => {}
+            }
+        }
+
+        lookup
+    }
+}
+
 fn is_dns_addr(addr: IpAddr) -> bool {
     IpNetwork::from(IPV4_RESOURCES).contains(addr) || IpNetwork::from(IPV6_RESOURCES).contains(addr)
 }
@@ -583,13 +873,18 @@ fn insert_filters<'a>(
 mod tests {
     use std::{
         collections::BTreeSet,
-        net::{Ipv4Addr, Ipv6Addr},
+        net::{IpAddr, Ipv4Addr, Ipv6Addr},
+        num::NonZeroU64,
         time::{Duration, Instant},
     };
 
     use crate::{
-        messages::gateway::{Filter, PortRange, ResourceDescription, ResourceDescriptionCidr},
+        messages::gateway::{
+            Filter, PortRange, RateLimit, ResourceDescription, ResourceDescriptionCidr,
+            ResourceDescriptionInternet,
+        },
         peer::nat_table,
+        GatewayEvent,
     };
     use chrono::Utc;
     use connlib_model::{ClientId, ResourceId};
@@ -612,6 +907,7 @@ mod tests {
                     port_range_start: 20,
                     port_range_end: 100,
                 })],
+                rate_limit: Default::default(),
             }),
             Some(then),
         );
@@ -624,6 +920,7 @@ mod tests {
                     port_range_start: 20,
                     port_range_end: 100,
                 })],
+                rate_limit: Default::default(),
             }),
             Some(after_then),
         );
@@ -684,6 +981,8 @@ mod tests {
             resource_id(),
             BTreeSet::from([foo_real_ip().into()]),
             BTreeSet::from([foo_proxy_ip().into()]),
+            Duration::from_secs(300),
+            Instant::now(),
         )
         .unwrap();
 
@@ -740,6 +1039,203 @@ mod tests {
         assert!(peer.translate_outbound(pkt, Instant::now()).is_ok());
     }
 
+    #[test]
+    fn re_resolves_domain_before_ttl_expires() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let now = Instant::now();
+        peer.add_resource(foo_dns_resource(), None);
+        peer.setup_nat(
+            foo_name().parse().unwrap(),
+            resource_id(),
+            BTreeSet::from([foo_real_ip().into()]),
+            BTreeSet::from([foo_proxy_ip().into()]),
+            Duration::from_secs(60),
+            now,
+        )
+        .unwrap();
+
+        peer.handle_timeout(now + Duration::from_secs(54));
+        assert!(peer.poll_event().is_none());
+
+        peer.handle_timeout(now + Duration::from_secs(55));
+        let Some(GatewayEvent::ResolveDns(req)) = peer.poll_event() else {
+            panic!("Expected `ResolveDns` event");
+        };
+        assert_eq!(req.domain().to_string(), foo_name());
+        assert!(peer.poll_event().is_none());
+
+        // While the refresh is in-flight, we don't re-resolve again.
+        peer.handle_timeout(now + Duration::from_secs(56));
+        assert!(peer.poll_event().is_none());
+    }
+
+    #[test]
+    fn failed_re_resolutions_back_off() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let mut now = Instant::now();
+        peer.add_resource(foo_dns_resource(), None);
+        peer.setup_nat(
+            foo_name().parse().unwrap(),
+            resource_id(),
+            BTreeSet::from([foo_real_ip().into()]),
+            BTreeSet::from([foo_proxy_ip().into()]),
+            Duration::from_secs(60),
+            now,
+        )
+        .unwrap();
+
+        now += Duration::from_secs(55);
+        peer.handle_timeout(now);
+        assert!(matches!(
+            peer.poll_event(),
+            Some(GatewayEvent::ResolveDns(_))
+        ));
+
+        // Nobody answers the re-resolution, i.e. it failed.
+        for retry_secs in [10, 20, 40, 80, 160, 300, 300] {
+            peer.handle_timeout(now + Duration::from_secs(retry_secs - 1));
+            assert!(peer.poll_event().is_none());
+
+            now += Duration::from_secs(retry_secs);
+            peer.handle_timeout(now);
+            assert!(matches!(
+                peer.poll_event(),
+                Some(GatewayEvent::ResolveDns(_))
+            ));
+        }
+
+        // A successful re-resolution resets the backoff.
+        peer.refresh_nat(
+            foo_name().parse().unwrap(),
+            resource_id(),
+            BTreeSet::from([foo_real_ip().into()]),
+            BTreeSet::from([foo_proxy_ip().into()]),
+            Duration::from_secs(60),
+            now,
+        )
+        .unwrap();
+
+        now += Duration::from_secs(55);
+        peer.handle_timeout(now);
+        assert!(matches!(
+            peer.poll_event(),
+            Some(GatewayEvent::ResolveDns(_))
+        ));
+
+        now += Duration::from_secs(10);
+        peer.handle_timeout(now);
+        assert!(matches!(
+            peer.poll_event(),
+            Some(GatewayEvent::ResolveDns(_))
+        ));
+    }
+
+    #[test]
+    fn re_resolving_domain_updates_translation_in_place() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let now = Instant::now();
+        let new_real_ip = Ipv4Addr::new(10, 0, 0, 2);
+        peer.add_resource(foo_dns_resource(), None);
+        peer.setup_nat(
+            foo_name().parse().unwrap(),
+            resource_id(),
+            BTreeSet::from([foo_real_ip().into()]),
+            BTreeSet::from([foo_proxy_ip().into()]),
+            Duration::from_secs(60),
+            now,
+        )
+        .unwrap();
+
+        peer.setup_nat(
+            foo_name().parse().unwrap(),
+            resource_id(),
+            BTreeSet::from([new_real_ip.into()]),
+            BTreeSet::from([foo_proxy_ip().into()]),
+            Duration::from_secs(60),
+            now + Duration::from_secs(55),
+        )
+        .unwrap();
+
+        let pkt = ip_packet::make::udp_packet(
+            source_v4_addr(),
+            foo_proxy_ip(),
+            1,
+            foo_allowed_port(),
+            vec![0, 0, 0, 0, 0, 0, 0, 0],
+        )
+        .unwrap();
+
+        let pkt = peer
+            .translate_outbound(pkt, now + Duration::from_secs(56))
+            .unwrap()
+            .unwrap();
+        assert_eq!(pkt.destination(), IpAddr::from(new_real_ip));
+    }
+
+    #[test]
+    fn refreshing_nat_keeps_still_valid_translation() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let now = Instant::now();
+        let new_real_ip = Ipv4Addr::new(9, 9, 9, 9);
+        peer.add_resource(foo_dns_resource(), None);
+        peer.setup_nat(
+            foo_name().parse().unwrap(),
+            resource_id(),
+            BTreeSet::from([foo_real_ip().into()]),
+            BTreeSet::from([foo_proxy_ip().into()]),
+            Duration::from_secs(60),
+            now,
+        )
+        .unwrap();
+
+        // `new_real_ip` sorts before `foo_real_ip`, `setup_nat` would therefore pick it.
+        peer.refresh_nat(
+            foo_name().parse().unwrap(),
+            resource_id(),
+            BTreeSet::from([new_real_ip.into(), foo_real_ip().into()]),
+            BTreeSet::from([foo_proxy_ip().into()]),
+            Duration::from_secs(60),
+            now + Duration::from_secs(55),
+        )
+        .unwrap();
+
+        let pkt = ip_packet::make::udp_packet(
+            source_v4_addr(),
+            foo_proxy_ip(),
+            1,
+            foo_allowed_port(),
+            vec![0, 0, 0, 0, 0, 0, 0, 0],
+        )
+        .unwrap();
+
+        let pkt = peer
+            .translate_outbound(pkt, now + Duration::from_secs(56))
+            .unwrap()
+            .unwrap();
+        assert_eq!(pkt.destination(), IpAddr::from(foo_real_ip()));
+    }
+
+    #[test]
+    fn removing_resource_stops_re_resolving() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        let now = Instant::now();
+        peer.add_resource(foo_dns_resource(), None);
+        peer.setup_nat(
+            foo_name().parse().unwrap(),
+            resource_id(),
+            BTreeSet::from([foo_real_ip().into()]),
+            BTreeSet::from([foo_proxy_ip().into()]),
+            Duration::from_secs(60),
+            now,
+        )
+        .unwrap();
+
+        peer.remove_resource(&resource_id());
+        peer.handle_timeout(now + Duration::from_secs(120));
+
+        assert!(peer.poll_event().is_none());
+    }
+
     #[test]
     fn internet_resource_doesnt_allow_all_traffic_for_dns_resources() {
         let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
@@ -750,6 +1246,8 @@ mod tests {
             resource_id(),
             BTreeSet::from([foo_real_ip().into()]),
             BTreeSet::from([foo_proxy_ip().into()]),
+            Duration::from_secs(300),
+            Instant::now(),
         )
         .unwrap();
 
@@ -790,6 +1288,103 @@ mod tests {
         assert!(peer.translate_outbound(pkt, Instant::now()).is_ok());
     }
 
+    #[test]
+    fn packets_exceeding_resource_rate_limit_are_dropped() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        peer.add_resource(
+            ResourceDescription::Internet(ResourceDescriptionInternet {
+                id: resource_id(),
+                rate_limit: RateLimit {
+                    upload_bytes_per_sec: NonZeroU64::new(2000),
+                    download_bytes_per_sec: None,
+                },
+            }),
+            None,
+        );
+
+        let now = Instant::now();
+        let pkt = || {
+            ip_packet::make::udp_packet(
+                source_v4_addr(),
+                "1.1.1.1".parse().unwrap(),
+                1,
+                600,
+                vec![0; 972],
+            )
+            .unwrap()
+        };
+
+        // Each packet is 1000 bytes.
+        assert!(peer.translate_outbound(pkt(), now).unwrap().is_some());
+        assert!(peer.translate_outbound(pkt(), now).unwrap().is_some());
+        assert!(peer.translate_outbound(pkt(), now).unwrap().is_none());
+
+        let now = now + Duration::from_millis(500);
+
+        assert!(peer.translate_outbound(pkt(), now).unwrap().is_some());
+    }
+
+    #[test]
+    fn rate_limit_of_most_specific_cidr_resource_applies() {
+        let mut peer = ClientOnGateway::new(client_id(), source_v4_addr(), source_v6_addr());
+        peer.add_resource(
+            ResourceDescription::Cidr(ResourceDescriptionCidr {
+                id: resource_id(),
+                address: "10.0.0.0/8".parse().unwrap(),
+                name: "wide".to_owned(),
+                filters: vec![],
+                rate_limit: RateLimit {
+                    upload_bytes_per_sec: NonZeroU64::new(2000),
+                    download_bytes_per_sec: None,
+                },
+            }),
+            None,
+        );
+        peer.add_resource(
+            ResourceDescription::Cidr(ResourceDescriptionCidr {
+                id: resource2_id(),
+                address: cidr_v4_resource().into(),
+                name: "narrow".to_owned(),
+                filters: vec![],
+                rate_limit: Default::default(),
+            }),
+            None,
+        );
+
+        let now = Instant::now();
+        let pkt = |dst: &str| {
+            ip_packet::make::udp_packet(
+                source_v4_addr(),
+                dst.parse().unwrap(),
+                1,
+                600,
+                vec![0; 972],
+            )
+            .unwrap()
+        };
+
+        // Each packet is 1000 bytes, the narrow resource has no limit.
+        for _ in 0..3 {
+            assert!(peer
+                .translate_outbound(pkt("10.0.0.1"), now)
+                .unwrap()
+                .is_some());
+        }
+
+        assert!(peer
+            .translate_outbound(pkt("10.1.0.1"), now)
+            .unwrap()
+            .is_some());
+        assert!(peer
+            .translate_outbound(pkt("10.1.0.1"), now)
+            .unwrap()
+            .is_some());
+        assert!(peer
+            .translate_outbound(pkt("10.1.0.1"), now)
+            .unwrap()
+            .is_none());
+    }
+
     #[test]
     fn dns_resource_packet_is_dropped_after_nat_session_expires() {
         let _guard = firezone_logging::test("trace");
@@ -801,6 +1396,8 @@ mod tests {
             resource_id(),
             BTreeSet::from([foo_real_ip().into()]),
             BTreeSet::from([foo_proxy_ip().into()]),
+            Duration::from_secs(300),
+            Instant::now(),
         )
         .unwrap();
 
@@ -862,6 +1459,7 @@ mod tests {
                     port_range_end: foo_allowed_port(),
                     port_range_start: foo_allowed_port(),
                 })],
+                rate_limit: Default::default(),
             },
         )
     }
@@ -876,6 +1474,7 @@ mod tests {
                     port_range_end: bar_allowed_port(),
                     port_range_start: bar_allowed_port(),
                 })],
+                rate_limit: Default::default(),
             },
         )
     }
@@ -884,6 +1483,7 @@ mod tests {
         crate::messages::gateway::ResourceDescription::Internet(
             crate::messages::gateway::ResourceDescriptionInternet {
                 id: "ed29c148-2acf-4ceb-8db5-d796c267163a".parse().unwrap(),
+                rate_limit: Default::default(),
             },
         )
     }
@@ -1027,6 +1627,7 @@ mod proptests {
                     address: resource_addr,
                     name: String::new(),
                     filters: filters.clone(),
+                    rate_limit: Default::default(),
                 }),
                 None,
             );
@@ -1079,6 +1680,7 @@ mod proptests {
                 address: resource_addr,
                 name: String::new(),
                 filters,
+                rate_limit: Default::default(),
             }),
             None,
         );
@@ -1134,6 +1736,7 @@ mod proptests {
                 address: supernet(resource_addr).unwrap_or(resource_addr),
                 name: String::new(),
                 filters: filters_allowed,
+                rate_limit: Default::default(),
             }),
             None,
         );
@@ -1144,6 +1747,7 @@ mod proptests {
                 address: resource_addr,
                 name: String::new(),
                 filters: filters_removed,
+                rate_limit: Default::default(),
             }),
             None,
         );
@@ -1180,6 +1784,7 @@ mod proptests {
                             address,
                             name: String::new(),
                             filters,
+                            rate_limit: Default::default(),
                         }),
                         protocol,
                         host,
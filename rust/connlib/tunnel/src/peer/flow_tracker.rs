@@ -0,0 +1,553 @@
+//! Tracks the flows of a client through the gateway for auditing purposes.
+//!
+//! A flow is identified by the 5-tuple of the client's packets _before_ any NAT is applied.
+//! We emit a record when a flow starts and another one when it ends, the latter including the traffic counters.
+
+use connlib_model::{ClientId, ResourceId};
+use ip_packet::{IpPacket, Protocol};
+use std::collections::{btree_map, BTreeMap, VecDeque};
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+const TCP_IDLE_TIMEOUT: Duration = Duration::from_secs(2 * 60 * 60);
+const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+const ICMP_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long we keep a flow around that the resource never answered, e.g. a TCP SYN to a closed port.
+const UNANSWERED_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long we keep a closed TCP flow around to attribute the final ACKs and retransmissions to it.
+const TCP_CLOSE_LINGER: Duration = Duration::from_secs(10);
+
+/// How many flows we track per client.
+///
+/// Once reached, each new flow evicts the least recently seen one, preferring flows the resource never answered.
+const MAX_FLOWS_PER_CLIENT: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowProtocol {
+    Tcp,
+    Udp,
+    /// For ICMP flows, the "ports" of [`FlowRecord`] contain the identifier of the echo request.
+    Icmp,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FlowCloseReason {
+    /// No packets have been seen for a while.
+    IdleTimeout,
+    /// Both sides sent a TCP FIN.
+    TcpFin,
+    /// One of the sides sent a TCP RST.
+    TcpRst,
+    /// The client's access to the resource was removed or expired.
+    AccessRevoked,
+    /// The connection to the client was closed.
+    ClientDisconnected,
+    /// The client started too many flows, see [`MAX_FLOWS_PER_CLIENT`].
+    Evicted,
+}
+
+/// A record of a flow from a client to a resource.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlowRecord {
+    pub client: ClientId,
+    pub resource: ResourceId,
+    pub protocol: FlowProtocol,
+    /// The client's tunnel IP and port.
+    pub src: SocketAddr,
+    /// The IP and port the client sent the packets to, i.e. the proxy IP for DNS resources.
+    pub dst: SocketAddr,
+    /// The IP we actually forwarded the packets to, if it differs from [`FlowRecord::dst`].
+    pub translated_dst: Option<IpAddr>,
+    pub started_at: Instant,
+    /// Set if this record marks the end of the flow.
+    pub end: Option<FlowEnd>,
+    /// Traffic from the client to the resource.
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    /// Traffic from the resource to the client.
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlowEnd {
+    /// When we last saw a packet of this flow.
+    pub at: Instant,
+    pub reason: FlowCloseReason,
+}
+
+/// The 5-tuple of a flow from the perspective of the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct FlowKey {
+    src: (IpAddr, Protocol),
+    dst: (IpAddr, Protocol),
+}
+
+#[derive(Debug)]
+struct Flow {
+    resource: ResourceId,
+    translated_dst: Option<IpAddr>,
+    started_at: Instant,
+    last_seen: Instant,
+    tx_bytes: u64,
+    tx_packets: u64,
+    rx_bytes: u64,
+    rx_packets: u64,
+    client_fin: bool,
+    resource_fin: bool,
+    rst: bool,
+}
+
+impl Flow {
+    fn tcp_close_reason(&self) -> Option<FlowCloseReason> {
+        if self.rst {
+            return Some(FlowCloseReason::TcpRst);
+        }
+
+        if self.client_fin && self.resource_fin {
+            return Some(FlowCloseReason::TcpFin);
+        }
+
+        None
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct FlowTracker {
+    client: ClientId,
+    flows: BTreeMap<FlowKey, Flow>,
+    max_flows: usize,
+    records: VecDeque<FlowRecord>,
+}
+
+impl FlowTracker {
+    pub(crate) fn new(client: ClientId) -> Self {
+        Self {
+            client,
+            flows: Default::default(),
+            max_flows: MAX_FLOWS_PER_CLIENT,
+            records: Default::default(),
+        }
+    }
+
+    /// Accounts for a packet from the client to a resource.
+    ///
+    /// `packet` must be the packet as sent by the client, i.e. before any NAT.
+    /// `resource` is only consulted if the packet starts a new flow.
+    pub(crate) fn on_outbound(
+        &mut self,
+        packet: &IpPacket,
+        resource: impl FnOnce() -> Option<(ResourceId, Option<IpAddr>)>,
+        now: Instant,
+    ) {
+        let Some(key) = outbound_key(packet) else {
+            return;
+        };
+
+        if !self.flows.contains_key(&key) && self.flows.len() >= self.max_flows {
+            self.evict_one();
+        }
+
+        let flow = match self.flows.entry(key) {
+            btree_map::Entry::Occupied(o) => o.into_mut(),
+            btree_map::Entry::Vacant(v) => {
+                let Some((resource, translated_dst)) = resource() else {
+                    return;
+                };
+
+                let flow = Flow {
+                    resource,
+                    translated_dst,
+                    started_at: now,
+                    last_seen: now,
+                    tx_bytes: 0,
+                    tx_packets: 0,
+                    rx_bytes: 0,
+                    rx_packets: 0,
+                    client_fin: false,
+                    resource_fin: false,
+                    rst: false,
+                };
+
+                tracing::trace!(cid = %self.client, rid = %resource, ?key, "Flow started");
+
+                self.records
+                    .push_back(make_record(self.client, &key, &flow, None));
+
+                v.insert(flow)
+            }
+        };
+
+        flow.last_seen = now;
+        flow.tx_bytes += packet.packet().len() as u64;
+        flow.tx_packets += 1;
+
+        if let Some(tcp) = packet.as_tcp() {
+            flow.client_fin |= tcp.fin();
+            flow.rst |= tcp.rst();
+        }
+    }
+
+    /// Accounts for a packet from a resource to the client.
+    ///
+    /// `packet` must be the packet as it will be sent to the client, i.e. after any NAT.
+    pub(crate) fn on_inbound(&mut self, packet: &IpPacket, now: Instant) {
+        let Some(key) = inbound_key(packet) else {
+            return;
+        };
+
+        // Only the client can start flows.
+        let Some(flow) = self.flows.get_mut(&key) else {
+            return;
+        };
+
+        flow.last_seen = now;
+        flow.rx_bytes += packet.packet().len() as u64;
+        flow.rx_packets += 1;
+
+        if let Some(tcp) = packet.as_tcp() {
+            flow.resource_fin |= tcp.fin();
+            flow.rst |= tcp.rst();
+        }
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        let ended = self
+            .flows
+            .iter()
+            .filter_map(|(key, flow)| {
+                let (timeout, reason) = match flow.tcp_close_reason() {
+                    Some(reason) => (TCP_CLOSE_LINGER, reason),
+                    None if flow.rx_packets == 0 => (
+                        idle_timeout(key).min(UNANSWERED_IDLE_TIMEOUT),
+                        FlowCloseReason::IdleTimeout,
+                    ),
+                    None => (idle_timeout(key), FlowCloseReason::IdleTimeout),
+                };
+
+                (now.duration_since(flow.last_seen) >= timeout).then_some((*key, reason))
+            })
+            .collect::<Vec<_>>();
+
+        for (key, reason) in ended {
+            self.close(&key, reason);
+        }
+    }
+
+    /// Closes all flows to the given resource.
+    pub(crate) fn close_resource(&mut self, resource: ResourceId, reason: FlowCloseReason) {
+        let keys = self
+            .flows
+            .iter()
+            .filter(|(_, flow)| flow.resource == resource)
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+
+        for key in keys {
+            self.close(&key, reason);
+        }
+    }
+
+    pub(crate) fn close_all(&mut self, reason: FlowCloseReason) {
+        let keys = self.flows.keys().copied().collect::<Vec<_>>();
+
+        for key in keys {
+            self.close(&key, reason);
+        }
+    }
+
+    pub(crate) fn poll_record(&mut self) -> Option<FlowRecord> {
+        self.records.pop_front()
+    }
+
+    /// Ends the flow that is least likely to still be in use.
+    fn evict_one(&mut self) {
+        let Some(key) = self
+            .flows
+            .iter()
+            .min_by_key(|(_, flow)| (flow.rx_packets > 0, flow.last_seen))
+            .map(|(key, _)| *key)
+        else {
+            return;
+        };
+
+        tracing::trace!(cid = %self.client, max_flows = %self.max_flows, "Too many flows, evicting one");
+
+        self.close(&key, FlowCloseReason::Evicted);
+    }
+
+    fn close(&mut self, key: &FlowKey, reason: FlowCloseReason) {
+        let Some(flow) = self.flows.remove(key) else {
+            return;
+        };
+
+        let end = FlowEnd {
+            at: flow.last_seen,
+            reason,
+        };
+
+        tracing::trace!(cid = %self.client, rid = %flow.resource, ?key, ?reason, "Flow ended");
+
+        self.records
+            .push_back(make_record(self.client, key, &flow, Some(end)));
+    }
+}
+
+fn make_record(client: ClientId, key: &FlowKey, flow: &Flow, end: Option<FlowEnd>) -> FlowRecord {
+    let (src_ip, src_proto) = key.src;
+    let (dst_ip, dst_proto) = key.dst;
+
+    FlowRecord {
+        client,
+        resource: flow.resource,
+        protocol: flow_protocol(src_proto),
+        src: SocketAddr::new(src_ip, src_proto.value()),
+        dst: SocketAddr::new(dst_ip, dst_proto.value()),
+        translated_dst: flow.translated_dst,
+        started_at: flow.started_at,
+        end,
+        tx_bytes: flow.tx_bytes,
+        tx_packets: flow.tx_packets,
+        rx_bytes: flow.rx_bytes,
+        rx_packets: flow.rx_packets,
+    }
+}
+
+fn outbound_key(packet: &IpPacket) -> Option<FlowKey> {
+    Some(FlowKey {
+        src: (packet.source(), packet.source_protocol().ok()?),
+        dst: (packet.destination(), packet.destination_protocol().ok()?),
+    })
+}
+
+fn inbound_key(packet: &IpPacket) -> Option<FlowKey> {
+    Some(FlowKey {
+        src: (packet.destination(), packet.destination_protocol().ok()?),
+        dst: (packet.source(), packet.source_protocol().ok()?),
+    })
+}
+
+fn flow_protocol(protocol: Protocol) -> FlowProtocol {
+    match protocol {
+        Protocol::Tcp(_) => FlowProtocol::Tcp,
+        Protocol::Udp(_) => FlowProtocol::Udp,
+        Protocol::Icmp(_) => FlowProtocol::Icmp,
+    }
+}
+
+fn idle_timeout(key: &FlowKey) -> Duration {
+    match key.src.1 {
+        Protocol::Tcp(_) => TCP_IDLE_TIMEOUT,
+        Protocol::Udp(_) => UDP_IDLE_TIMEOUT,
+        Protocol::Icmp(_) => ICMP_IDLE_TIMEOUT,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::iter;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn emits_start_and_end_record_for_udp_flow() {
+        let mut tracker = FlowTracker::new(client_id());
+        let now = Instant::now();
+
+        tracker.on_outbound(&udp_request(), resource, now);
+        tracker.on_inbound(&udp_response(), now + Duration::from_secs(1));
+
+        let start = tracker.poll_record().unwrap();
+        assert_eq!(start.end, None);
+        assert_eq!(start.protocol, FlowProtocol::Udp);
+        assert_eq!(start.src, SocketAddr::new(client_ip(), 1000));
+        assert_eq!(start.dst, SocketAddr::new(resource_ip(), 53));
+        assert!(tracker.poll_record().is_none());
+
+        tracker.handle_timeout(now + Duration::from_secs(1) + UDP_IDLE_TIMEOUT);
+
+        let end = tracker.poll_record().unwrap();
+        assert_eq!(
+            end.end,
+            Some(FlowEnd {
+                at: now + Duration::from_secs(1),
+                reason: FlowCloseReason::IdleTimeout
+            })
+        );
+        assert_eq!(end.tx_packets, 1);
+        assert_eq!(end.rx_packets, 1);
+        assert_eq!(end.tx_bytes, udp_request().packet().len() as u64);
+        assert_eq!(end.rx_bytes, udp_response().packet().len() as u64);
+    }
+
+    #[test]
+    fn inbound_packets_do_not_start_flows() {
+        let mut tracker = FlowTracker::new(client_id());
+
+        tracker.on_inbound(&udp_response(), Instant::now());
+
+        assert!(tracker.poll_record().is_none());
+    }
+
+    #[test]
+    fn closing_resource_ends_its_flows() {
+        let mut tracker = FlowTracker::new(client_id());
+
+        tracker.on_outbound(&udp_request(), resource, Instant::now());
+        tracker.close_resource(resource_id(), FlowCloseReason::AccessRevoked);
+
+        let _start = tracker.poll_record().unwrap();
+        let end = tracker.poll_record().unwrap();
+        assert_eq!(end.end.unwrap().reason, FlowCloseReason::AccessRevoked);
+    }
+
+    #[test]
+    fn tcp_flow_ends_shortly_after_both_sides_sent_fin() {
+        let mut tracker = FlowTracker::new(client_id());
+        let now = Instant::now();
+
+        tracker.on_outbound(
+            &tcp_packet(client_ip(), resource_ip(), 1000, 443, false),
+            resource,
+            now,
+        );
+        tracker.on_outbound(
+            &tcp_packet(client_ip(), resource_ip(), 1000, 443, true),
+            resource,
+            now,
+        );
+        tracker.on_inbound(
+            &tcp_packet(resource_ip(), client_ip(), 443, 1000, true),
+            now,
+        );
+        tracker.on_outbound(
+            &tcp_packet(client_ip(), resource_ip(), 1000, 443, false),
+            resource,
+            now,
+        );
+
+        let _start = tracker.poll_record().unwrap();
+        assert!(
+            tracker.poll_record().is_none(),
+            "Final ACK should not start a new flow"
+        );
+
+        tracker.handle_timeout(now + TCP_CLOSE_LINGER);
+
+        let end = tracker.poll_record().unwrap();
+        assert_eq!(end.end.unwrap().reason, FlowCloseReason::TcpFin);
+        assert_eq!(end.tx_packets, 3);
+        assert_eq!(end.rx_packets, 1);
+    }
+
+    #[test]
+    fn evicts_unanswered_flows_first_when_full() {
+        let mut tracker = FlowTracker {
+            max_flows: 2,
+            ..FlowTracker::new(client_id())
+        };
+        let now = Instant::now();
+
+        tracker.on_outbound(&udp_request_from(1000), resource, now);
+        tracker.on_inbound(&udp_response_to(1000), now);
+        tracker.on_outbound(
+            &udp_request_from(1001),
+            resource,
+            now + Duration::from_secs(1),
+        );
+        tracker.on_outbound(
+            &udp_request_from(1002),
+            resource,
+            now + Duration::from_secs(2),
+        );
+
+        let records = iter::from_fn(|| tracker.poll_record()).collect::<Vec<_>>();
+        let ended = records
+            .iter()
+            .filter_map(|r| Some((r.src.port(), r.end?.reason)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(records.len(), 4);
+        assert_eq!(ended, vec![(1001, FlowCloseReason::Evicted)]);
+    }
+
+    #[test]
+    fn unanswered_flow_times_out_early() {
+        let mut tracker = FlowTracker::new(client_id());
+        let now = Instant::now();
+
+        tracker.on_outbound(
+            &tcp_packet(client_ip(), resource_ip(), 1000, 443, false),
+            resource,
+            now,
+        );
+        tracker.handle_timeout(now + UNANSWERED_IDLE_TIMEOUT);
+
+        let _start = tracker.poll_record().unwrap();
+        let end = tracker.poll_record().unwrap();
+        assert_eq!(end.end.unwrap().reason, FlowCloseReason::IdleTimeout);
+    }
+
+    fn tcp_packet(src: IpAddr, dst: IpAddr, sport: u16, dport: u16, fin: bool) -> IpPacket {
+        fn build(
+            src: Ipv4Addr,
+            dst: Ipv4Addr,
+            sport: u16,
+            dport: u16,
+            fin: bool,
+        ) -> anyhow::Result<IpPacket> {
+            let mut packet = ip_packet::PacketBuilder::ipv4(src.octets(), dst.octets(), 64)
+                .tcp(sport, dport, 0, 128);
+            if fin {
+                packet = packet.fin();
+            }
+            let payload = Vec::<u8>::new();
+
+            ip_packet::build!(packet, payload)
+        }
+
+        let (IpAddr::V4(src), IpAddr::V4(dst)) = (src, dst) else {
+            panic!("Only IPv4 is supported")
+        };
+
+        build(src, dst, sport, dport, fin).unwrap()
+    }
+
+    fn udp_request() -> IpPacket {
+        udp_request_from(1000)
+    }
+
+    fn udp_response() -> IpPacket {
+        udp_response_to(1000)
+    }
+
+    fn udp_request_from(port: u16) -> IpPacket {
+        ip_packet::make::udp_packet(client_ip(), resource_ip(), port, 53, vec![0; 8]).unwrap()
+    }
+
+    fn udp_response_to(port: u16) -> IpPacket {
+        ip_packet::make::udp_packet(resource_ip(), client_ip(), 53, port, vec![0; 16]).unwrap()
+    }
+
+    fn resource() -> Option<(ResourceId, Option<IpAddr>)> {
+        Some((resource_id(), None))
+    }
+
+    fn client_ip() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(100, 64, 0, 1))
+    }
+
+    fn resource_ip() -> IpAddr {
+        IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))
+    }
+
+    fn resource_id() -> ResourceId {
+        "9d4b79f6-1db7-4cb3-a077-712102204d73".parse().unwrap()
+    }
+
+    fn client_id() -> ClientId {
+        "ed29c148-2acf-4ceb-8db5-d796c2671631".parse().unwrap()
+    }
+}
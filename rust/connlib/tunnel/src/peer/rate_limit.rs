@@ -0,0 +1,226 @@
+use std::collections::HashMap;
+use std::num::NonZeroU64;
+use std::time::Instant;
+
+use connlib_model::ResourceId;
+use ip_packet::MAX_IP_SIZE;
+use token_bucket::TokenBucket;
+
+use crate::messages::gateway::RateLimit;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Direction {
+    /// From the client to a resource.
+    Upload,
+    /// From a resource to the client.
+    Download,
+}
+
+/// Limits the throughput of a single client, both in total and per resource.
+#[derive(Debug, Default)]
+pub(crate) struct RateLimiter {
+    client: Limiter,
+    resources: HashMap<ResourceId, Limiter>,
+}
+
+impl RateLimiter {
+    pub(crate) fn set_client_limit(&mut self, limit: RateLimit) {
+        self.client.set(limit);
+    }
+
+    pub(crate) fn set_resource_limit(&mut self, resource: ResourceId, limit: RateLimit) {
+        if limit == RateLimit::default() {
+            self.resources.remove(&resource);
+            return;
+        }
+
+        self.resources.entry(resource).or_default().set(limit);
+    }
+
+    pub(crate) fn retain_resources(&mut self, mut f: impl FnMut(&ResourceId) -> bool) {
+        self.resources.retain(|resource, _| f(resource));
+    }
+
+    /// Whether a packet of the given size is within the limits of the client and the resource it belongs to.
+    ///
+    /// If it is, the packet's bytes are consumed from both.
+    /// `resource` is only called if any resource has a limit.
+    pub(crate) fn admit(
+        &mut self,
+        direction: Direction,
+        resource: impl FnOnce() -> Option<ResourceId>,
+        num_bytes: usize,
+        now: Instant,
+    ) -> bool {
+        let num_bytes = num_bytes as u64;
+
+        let mut client = self.client.bucket(direction);
+        let mut resource = if self.resources.is_empty() {
+            None
+        } else {
+            resource()
+                .and_then(|r| self.resources.get_mut(&r))
+                .and_then(|l| l.bucket(direction))
+        };
+
+        let client_ok = client.as_mut().is_none_or(|b| b.has(num_bytes, now));
+        let resource_ok = resource.as_mut().is_none_or(|b| b.has(num_bytes, now));
+
+        if !client_ok || !resource_ok {
+            return false;
+        }
+
+        for bucket in [client, resource].into_iter().flatten() {
+            bucket.consume(num_bytes);
+        }
+
+        true
+    }
+}
+
+#[derive(Debug, Default)]
+struct Limiter {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+}
+
+impl Limiter {
+    fn set(&mut self, limit: RateLimit) {
+        update_bucket(&mut self.upload, limit.upload_bytes_per_sec);
+        update_bucket(&mut self.download, limit.download_bytes_per_sec);
+    }
+
+    fn bucket(&mut self, direction: Direction) -> Option<&mut TokenBucket> {
+        match direction {
+            Direction::Upload => self.upload.as_mut(),
+            Direction::Download => self.download.as_mut(),
+        }
+    }
+}
+
+/// Changing the rate of an existing bucket keeps its tokens, so updating a limit doesn't grant a new burst.
+fn update_bucket(bucket: &mut Option<TokenBucket>, rate: Option<NonZeroU64>) {
+    match (bucket.as_mut(), rate) {
+        (Some(bucket), Some(rate)) => bucket.set_rate(rate, capacity(rate)),
+        (None, Some(rate)) => *bucket = Some(TokenBucket::new(rate, capacity(rate))),
+        (Some(_) | None, None) => *bucket = None,
+    }
+}
+
+/// Allows bursts of up to one second worth of traffic but at least one maximum-size packet.
+///
+/// Otherwise, a limit below the size of a packet would drop every packet.
+fn capacity(rate: NonZeroU64) -> u64 {
+    rate.get().max(MAX_IP_SIZE as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn limits_client_per_direction() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::default();
+        limiter.set_client_limit(RateLimit {
+            upload_bytes_per_sec: NonZeroU64::new(10_000),
+            download_bytes_per_sec: None,
+        });
+
+        assert!(limiter.admit(Direction::Upload, || None, 10_000, now));
+        assert!(!limiter.admit(Direction::Upload, || None, 1, now));
+        assert!(limiter.admit(Direction::Download, || None, 1_000_000, now));
+
+        let now = now + Duration::from_millis(100);
+
+        assert!(limiter.admit(Direction::Upload, || None, 1000, now));
+        assert!(!limiter.admit(Direction::Upload, || None, 1, now));
+    }
+
+    #[test]
+    fn limits_resources_separately() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::default();
+        limiter.set_resource_limit(
+            resource(1),
+            RateLimit {
+                upload_bytes_per_sec: None,
+                download_bytes_per_sec: NonZeroU64::new(10_000),
+            },
+        );
+
+        assert!(limiter.admit(Direction::Download, || Some(resource(1)), 10_000, now));
+        assert!(!limiter.admit(Direction::Download, || Some(resource(1)), 1, now));
+        assert!(limiter.admit(Direction::Download, || Some(resource(2)), 10_000, now));
+        assert!(limiter.admit(Direction::Download, || None, 10_000, now));
+    }
+
+    #[test]
+    fn dropped_packets_consume_nothing() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::default();
+        limiter.set_client_limit(RateLimit {
+            upload_bytes_per_sec: NonZeroU64::new(20_000),
+            download_bytes_per_sec: None,
+        });
+        limiter.set_resource_limit(
+            resource(1),
+            RateLimit {
+                upload_bytes_per_sec: NonZeroU64::new(10_000),
+                download_bytes_per_sec: None,
+            },
+        );
+
+        assert!(!limiter.admit(Direction::Upload, || Some(resource(1)), 15_000, now));
+        assert!(limiter.admit(Direction::Upload, || Some(resource(2)), 20_000, now));
+    }
+
+    #[test]
+    fn updating_limit_does_not_refill() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::default();
+        limiter.set_client_limit(RateLimit {
+            upload_bytes_per_sec: NonZeroU64::new(10_000),
+            download_bytes_per_sec: None,
+        });
+
+        assert!(limiter.admit(Direction::Upload, || None, 10_000, now));
+
+        limiter.set_client_limit(RateLimit {
+            upload_bytes_per_sec: NonZeroU64::new(20_000),
+            download_bytes_per_sec: None,
+        });
+
+        assert!(!limiter.admit(Direction::Upload, || None, 1, now));
+    }
+
+    #[test]
+    fn limit_below_packet_size_still_admits_packets() {
+        let now = Instant::now();
+        let mut limiter = RateLimiter::default();
+        limiter.set_client_limit(RateLimit {
+            upload_bytes_per_sec: NonZeroU64::new(100),
+            download_bytes_per_sec: None,
+        });
+
+        assert!(limiter.admit(Direction::Upload, || None, MAX_IP_SIZE, now));
+        assert!(!limiter.admit(Direction::Upload, || None, MAX_IP_SIZE, now));
+    }
+
+    #[test]
+    fn skips_resource_lookup_without_resource_limits() {
+        let mut limiter = RateLimiter::default();
+
+        assert!(limiter.admit(
+            Direction::Upload,
+            || unreachable!("no resource has a limit"),
+            1000,
+            Instant::now()
+        ));
+    }
+
+    fn resource(n: u128) -> ResourceId {
+        ResourceId::from_u128(n)
+    }
+}
@@ -58,9 +58,20 @@ impl SimGateway {
         now: Instant,
         utc_now: DateTime<Utc>,
     ) -> Option<Transmit<'static>> {
-        let Some(packet) = self
+        let result = self
             .sut
-            .handle_network_input(transmit.dst, transmit.src.unwrap(), &transmit.payload, now)
+            .handle_network_input_batch(
+                [(
+                    transmit.dst,
+                    transmit.src.unwrap(),
+                    transmit.payload.as_ref(),
+                )],
+                now,
+            )
+            .pop()
+            .expect("one result per packet");
+
+        let Some(packet) = result
             .inspect_err(|e| tracing::warn!("{e:#}"))
             .ok()
             .flatten()
@@ -88,17 +99,14 @@ impl SimGateway {
             std::iter::from_fn(|| s.poll_outbound())
         });
 
-        udp_server_packets
+        let packets = udp_server_packets
             .chain(tcp_server_packets)
-            .filter_map(|packet| {
-                Some(
-                    self.sut
-                        .handle_tun_input(packet, now)
-                        .unwrap()?
-                        .to_transmit()
-                        .into_owned(),
-                )
-            })
+            .collect::<Vec<_>>();
+
+        self.sut
+            .handle_tun_input_batch(packets, now)
+            .into_iter()
+            .filter_map(|result| Some(result.unwrap()?.to_transmit().into_owned()))
             .collect()
     }
 
@@ -178,14 +186,8 @@ impl SimGateway {
 
         if let Some(reply) = icmp_error.or_else(|| echo_reply(packet.clone())) {
             self.request_received(&packet);
-            let transmit = self
-                .sut
-                .handle_tun_input(reply, now)
-                .unwrap()?
-                .to_transmit()
-                .into_owned();
-
-            return Some(transmit);
+
+            return self.encapsulate(reply, now);
         }
 
         tracing::error!(?packet, "Unhandled packet");
@@ -238,9 +240,15 @@ impl SimGateway {
             .expect("src and dst are taken from incoming packet")
         });
 
+        self.encapsulate(reply, now)
+    }
+
+    fn encapsulate(&mut self, packet: IpPacket, now: Instant) -> Option<Transmit<'static>> {
         let transmit = self
             .sut
-            .handle_tun_input(reply, now)
+            .handle_tun_input_batch([packet], now)
+            .pop()
+            .expect("one result per packet")
             .unwrap()?
             .to_transmit()
             .into_owned();
@@ -7,7 +7,7 @@ use firezone_relay::{AddressFamily, AllocationPort, ClientSocket, IpStack, PeerS
 use proptest::prelude::*;
 use rand::{rngs::StdRng, SeedableRng as _};
 use secrecy::SecretString;
-use snownet::{RelaySocket, Transmit};
+use snownet::{RelaySocket, Transmit, Transport};
 use std::{
     borrow::Cow,
     collections::HashSet,
@@ -110,6 +110,7 @@ impl SimRelay {
             payload,
             PeerSocket::new(sender),
             AllocationPort::new(dst.port()),
+            now,
         )
     }
 
@@ -157,6 +158,7 @@ impl SimRelay {
             src: Some(src),
             dst,
             payload: Cow::Owned(payload.to_vec()),
+            transport: Transport::Udp,
         })
     }
 
@@ -165,8 +167,9 @@ impl SimRelay {
         payload: &[u8],
         peer: PeerSocket,
         port: AllocationPort,
+        now: Instant,
     ) -> Option<Transmit<'static>> {
-        let (client, channel) = self.sut.handle_peer_traffic(payload, peer, port)?;
+        let (client, channel) = self.sut.handle_peer_traffic(payload, peer, port, now)?;
 
         let full_length = firezone_relay::ChannelData::encode_header_to_slice(
             channel,
@@ -184,6 +187,7 @@ impl SimRelay {
             src: Some(sending_socket),
             dst: receiving_socket,
             payload: Cow::Owned(self.buffer[..full_length].to_vec()),
+            transport: Transport::Udp,
         })
     }
 
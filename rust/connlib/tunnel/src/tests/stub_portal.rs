@@ -142,6 +142,7 @@ impl StubPortal {
                     address: r.address,
                     name: r.name.clone(),
                     filters: Vec::new(),
+                    rate_limit: Default::default(),
                 },
             ))
         });
@@ -151,11 +152,13 @@ impl StubPortal {
                 name: r.name.clone(),
                 filters: Vec::new(),
                 address: r.address.clone(),
+                rate_limit: Default::default(),
             })
         });
         let internet_resource = Some(gateway::ResourceDescription::Internet(
             gateway::ResourceDescriptionInternet {
                 id: self.internet_resource.id,
+                rate_limit: Default::default(),
             },
         ));
 
@@ -15,14 +15,14 @@ use crate::tests::assertions::*;
 use crate::tests::flux_capacitor::FluxCapacitor;
 use crate::tests::transition::Transition;
 use crate::utils::earliest;
-use crate::{dns, messages::Interface, ClientEvent, GatewayEvent};
+use crate::{dns, messages::Interface, ClientEvent, GatewayEvent, ResolvedAddresses};
 use connlib_model::{ClientId, GatewayId, PublicKey, RelayId};
 use domain::base::iana::{Class, Rcode};
 use domain::base::{Message, MessageBuilder, Record, RecordData, ToName as _, Ttl};
 use rand::distributions::DistString;
 use rand::SeedableRng;
 use sha2::Digest;
-use snownet::Transmit;
+use snownet::{Transmit, Transport};
 use std::iter;
 use std::{
     collections::BTreeMap,
@@ -236,6 +236,7 @@ impl TunnelTest {
                         ipv4: c.sut.tunnel_ip4().unwrap(),
                         ipv6: c.sut.tunnel_ip6().unwrap(),
                         upstream_dns: servers,
+                        split_dns: Vec::new(),
                     })
                 });
             }
@@ -267,6 +268,7 @@ impl TunnelTest {
                         ipv4,
                         ipv6,
                         upstream_dns,
+                        split_dns: Vec::new(),
                     });
                     c.update_relays(iter::empty(), state.relays.iter(), now);
                     c.sut.set_resources(all_resources);
@@ -419,12 +421,15 @@ impl TunnelTest {
                     &ref_state.global_dns_records,
                 );
                 self.client.exec_mut(|c| {
-                    c.sut.handle_dns_response(dns::RecursiveResponse {
-                        server,
-                        query: query.message,
-                        message: Ok(response), // TODO: Vary this?
-                        transport,
-                    })
+                    c.sut.handle_dns_response(
+                        dns::RecursiveResponse {
+                            server,
+                            query: query.message,
+                            message: Ok(response), // TODO: Vary this?
+                            transport,
+                        },
+                        now,
+                    )
                 });
 
                 continue;
@@ -447,6 +452,7 @@ impl TunnelTest {
                                 src: Some(src),
                                 dst,
                                 payload: payload.into(),
+                                transport: Transport::Udp,
                             },
                             relay,
                             now,
@@ -903,13 +909,13 @@ fn on_gateway_event(
             }
         }),
         GatewayEvent::ResolveDns(r) => {
-            let resolved_ips = global_dns_records.domain_ips_iter(r.domain()).collect();
+            let resolved = ResolvedAddresses {
+                addresses: global_dns_records.domain_ips_iter(r.domain()).collect(),
+                ttl: Duration::from_secs(300),
+            };
 
-            gateway.exec_mut(|g| {
-                g.sut
-                    .handle_domain_resolved(r, Ok(resolved_ips), now)
-                    .unwrap()
-            })
+            gateway.exec_mut(|g| g.sut.handle_domain_resolved(r, Ok(resolved), now).unwrap())
         }
+        GatewayEvent::Flow(_) => {}
     }
 }
@@ -5,7 +5,7 @@ if [ -f "${FIREZONE_TOKEN}" ]; then
     export FIREZONE_TOKEN
 fi
 
-IFACE="tun-firezone"
+IFACE="${FIREZONE_TUN_NAME:-tun-firezone}"
 # Enable masquerading for our TUN interface
 iptables -C FORWARD -i $IFACE -j ACCEPT >/dev/null 2>&1 || iptables -A FORWARD -i $IFACE -j ACCEPT
 iptables -C FORWARD -o $IFACE -j ACCEPT >/dev/null 2>&1 || iptables -A FORWARD -o $IFACE -j ACCEPT
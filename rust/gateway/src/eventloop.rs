@@ -1,8 +1,6 @@
 use anyhow::{Context as _, Result};
 use boringtun::x25519::PublicKey;
 use connlib_model::DomainName;
-#[cfg(not(target_os = "windows"))]
-use dns_lookup::{AddrInfoHints, AddrInfoIter, LookupError};
 use firezone_bin_shared::TunDeviceManager;
 use firezone_logging::{telemetry_event, telemetry_span};
 use firezone_tunnel::messages::gateway::{
@@ -11,12 +9,12 @@ use firezone_tunnel::messages::gateway::{
 };
 use firezone_tunnel::messages::{ConnectionAccepted, GatewayResponse, RelaysPresence};
 use firezone_tunnel::{
-    DnsResourceNatEntry, GatewayTunnel, ResolveDnsRequest, IPV4_PEERS, IPV6_PEERS,
+    DnsResourceNatEntry, GatewayTunnel, ResolveDnsRequest, ResolvedAddresses, IPV4_PEERS,
+    IPV6_PEERS,
 };
 use phoenix_channel::{PhoenixChannel, PublicKeyParam};
 use std::collections::BTreeSet;
 use std::convert::Infallible;
-use std::net::IpAddr;
 use std::sync::Arc;
 use std::task::{Context, Poll};
 use std::time::{Duration, Instant};
@@ -24,9 +22,13 @@ use std::{io, mem};
 use tokio::sync::Mutex;
 use tracing::Instrument;
 
+use crate::flow_log::FlowLogger;
+use crate::packet_capture::PacketCaptureToggle;
+use crate::resolver::{self, Resolver};
+
 pub const PHOENIX_TOPIC: &str = "gateway";
 
-/// How long we allow a DNS resolution via `libc::get_addr_info`.
+/// How long we allow the resolution of a DNS resource's domain to take.
 const DNS_RESOLUTION_TIMEOUT: Duration = Duration::from_secs(10);
 
 // DNS resolution happens as part of every connection setup.
@@ -47,7 +49,10 @@ pub struct Eventloop {
     portal: PhoenixChannel<(), IngressMessages, (), PublicKeyParam>,
     tun_device_manager: Arc<Mutex<TunDeviceManager>>,
 
-    resolve_tasks: futures_bounded::FuturesTupleSet<Result<Vec<IpAddr>>, ResolveTrigger>,
+    resolver: Arc<Resolver>,
+    flow_logger: Option<FlowLogger>,
+    packet_capture: Option<PacketCaptureToggle>,
+    resolve_tasks: futures_bounded::FuturesTupleSet<Result<ResolvedAddresses>, ResolveTrigger>,
     set_interface_tasks: futures_bounded::FuturesSet<Result<()>>,
 
     logged_permission_denied: bool,
@@ -58,6 +63,9 @@ impl Eventloop {
         tunnel: GatewayTunnel,
         mut portal: PhoenixChannel<(), IngressMessages, (), PublicKeyParam>,
         tun_device_manager: TunDeviceManager,
+        resolver: Resolver,
+        flow_logger: Option<FlowLogger>,
+        packet_capture: Option<PacketCaptureToggle>,
     ) -> Self {
         portal.connect(PublicKeyParam(tunnel.public_key().to_bytes()));
 
@@ -65,6 +73,9 @@ impl Eventloop {
             tunnel,
             portal,
             tun_device_manager: Arc::new(Mutex::new(tun_device_manager)),
+            resolver: Arc::new(resolver),
+            flow_logger,
+            packet_capture,
             resolve_tasks: futures_bounded::FuturesTupleSet::new(DNS_RESOLUTION_TIMEOUT, 1000),
             set_interface_tasks: futures_bounded::FuturesSet::new(Duration::from_secs(5), 10),
             logged_permission_denied: false,
@@ -162,6 +173,12 @@ impl Eventloop {
                 Poll::Pending => {}
             }
 
+            if let Some(packet_capture) = self.packet_capture.as_mut() {
+                if packet_capture.poll(cx, &mut self.tunnel).is_ready() {
+                    continue;
+                }
+            }
+
             return Poll::Pending;
         }
     }
@@ -196,7 +213,7 @@ impl Eventloop {
                 if self
                     .resolve_tasks
                     .try_push(
-                        resolve(Some(setup_nat.domain().clone())),
+                        resolve(self.resolver.clone(), Some(setup_nat.domain().clone())),
                         ResolveTrigger::SetupNat(setup_nat),
                     )
                     .is_err()
@@ -204,6 +221,11 @@ impl Eventloop {
                     tracing::warn!("Too many dns resolution requests, dropping existing one");
                 };
             }
+            firezone_tunnel::GatewayEvent::Flow(record) => {
+                if let Some(flow_logger) = self.flow_logger.as_mut() {
+                    flow_logger.log(record);
+                }
+            }
         }
     }
 
@@ -247,7 +269,10 @@ impl Eventloop {
                 if self
                     .resolve_tasks
                     .try_push(
-                        resolve(req.client.payload.domain.as_ref().map(|r| r.name.clone())),
+                        resolve(
+                            self.resolver.clone(),
+                            req.client.payload.domain.as_ref().map(|r| r.name.clone()),
+                        ),
                         ResolveTrigger::RequestConnection(req),
                     )
                     .is_err()
@@ -262,7 +287,10 @@ impl Eventloop {
                 if self
                     .resolve_tasks
                     .try_push(
-                        resolve(req.payload.as_ref().map(|r| r.name.clone())),
+                        resolve(
+                            self.resolver.clone(),
+                            req.payload.as_ref().map(|r| r.name.clone()),
+                        ),
                         ResolveTrigger::AllowAccess(req),
                     )
                     .is_err()
@@ -384,9 +412,9 @@ impl Eventloop {
         }
     }
 
-    pub fn accept_connection(&mut self, result: Result<Vec<IpAddr>>, req: RequestConnection) {
-        let addresses = match result {
-            Ok(addresses) => addresses,
+    pub fn accept_connection(&mut self, result: Result<ResolvedAddresses>, req: RequestConnection) {
+        let resolved = match result {
+            Ok(resolved) => resolved,
             Err(e) => {
                 tracing::debug!(client = %req.client.id, reference = %req.reference, "DNS resolution failed as part of connection request: {e:#}");
 
@@ -424,7 +452,8 @@ impl Eventloop {
             req.client
                 .payload
                 .domain
-                .map(|r| DnsResourceNatEntry::new(r, addresses)),
+                .map(|r| DnsResourceNatEntry::new(r, resolved)),
+            Instant::now(),
         ) {
             let client = req.client.id;
 
@@ -444,15 +473,18 @@ impl Eventloop {
         );
     }
 
-    pub fn allow_access(&mut self, result: Result<Vec<IpAddr>>, req: AllowAccess) {
+    pub fn allow_access(&mut self, result: Result<ResolvedAddresses>, req: AllowAccess) {
         // "allow access" doesn't have a response so we can't tell the client that things failed.
         // It is legacy code so don't bother ...
-        let addresses = match result {
-            Ok(addresses) => addresses,
+        let resolved = match result {
+            Ok(resolved) => resolved,
             Err(e) => {
                 tracing::debug!(client = %req.client_id, reference = %req.reference, "DNS resolution failed as part of allow access request: {e:#}");
 
-                vec![]
+                ResolvedAddresses {
+                    addresses: vec![],
+                    ttl: resolver::RETRY_INTERVAL,
+                }
             }
         };
 
@@ -462,7 +494,8 @@ impl Eventloop {
             req.client_ipv6,
             req.expires_at,
             req.resource,
-            req.payload.map(|r| DnsResourceNatEntry::new(r, addresses)),
+            req.payload.map(|r| DnsResourceNatEntry::new(r, resolved)),
+            Instant::now(),
         ) {
             tracing::warn!(client = %req.client_id, "Allow access request failed: {e:#}");
         };
@@ -477,59 +510,19 @@ pub enum Error {
     UpdateTun(#[from] anyhow::Error),
 }
 
-async fn resolve(domain: Option<DomainName>) -> Result<Vec<IpAddr>> {
-    let Some(domain) = domain.clone() else {
-        return Ok(vec![]);
+async fn resolve(resolver: Arc<Resolver>, domain: Option<DomainName>) -> Result<ResolvedAddresses> {
+    let Some(domain) = domain else {
+        return Ok(ResolvedAddresses {
+            addresses: vec![],
+            ttl: Duration::ZERO,
+        });
     };
 
-    let dname = domain.to_string();
-
-    let addresses = tokio::task::spawn_blocking(move || resolve_addresses(&dname))
+    let resolved = resolver
+        .resolve(domain)
         .instrument(telemetry_span!("resolve_dns_resource"))
         .await
-        .context("DNS resolution task failed")?
         .context("DNS resolution failed")?;
 
-    Ok(addresses)
-}
-
-#[cfg(target_os = "windows")]
-fn resolve_addresses(_: &str) -> std::io::Result<Vec<IpAddr>> {
-    unimplemented!()
-}
-
-#[cfg(not(target_os = "windows"))]
-fn resolve_addresses(addr: &str) -> std::io::Result<Vec<IpAddr>> {
-    use libc::{AF_INET, AF_INET6};
-    let addr_v4: std::io::Result<Vec<_>> = resolve_address_family(addr, AF_INET)
-        .map_err(|e| e.into())
-        .and_then(|a| a.collect());
-    let addr_v6: std::io::Result<Vec<_>> = resolve_address_family(addr, AF_INET6)
-        .map_err(|e| e.into())
-        .and_then(|a| a.collect());
-    match (addr_v4, addr_v6) {
-        (Ok(v4), Ok(v6)) => Ok(v6
-            .iter()
-            .map(|a| a.sockaddr.ip())
-            .chain(v4.iter().map(|a| a.sockaddr.ip()))
-            .collect()),
-        (Ok(v4), Err(_)) => Ok(v4.iter().map(|a| a.sockaddr.ip()).collect()),
-        (Err(_), Ok(v6)) => Ok(v6.iter().map(|a| a.sockaddr.ip()).collect()),
-        (Err(e), Err(_)) => Err(e),
-    }
-}
-
-#[cfg(not(target_os = "windows"))]
-fn resolve_address_family(addr: &str, family: i32) -> Result<AddrInfoIter, LookupError> {
-    use libc::SOCK_STREAM;
-
-    dns_lookup::getaddrinfo(
-        Some(addr),
-        None,
-        Some(AddrInfoHints {
-            socktype: SOCK_STREAM,
-            address: family,
-            ..Default::default()
-        }),
-    )
+    Ok(resolved)
 }
@@ -0,0 +1,315 @@
+//! Writes the flows of clients to a JSON-lines file and optionally exports them to an IPFIX collector.
+//!
+//! The event-loop must never block, so records are handed to a separate task via a bounded channel.
+
+use anyhow::{Context as _, Result};
+use chrono::{DateTime, Utc};
+use connlib_model::{ClientId, ResourceId};
+use firezone_tunnel::{FlowCloseReason, FlowProtocol, FlowRecord};
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Instant;
+use tokio::io::AsyncWriteExt as _;
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+
+mod ipfix;
+
+/// How many records we buffer before dropping new ones.
+const MAX_PENDING_RECORDS: usize = 10_000;
+
+/// Handle for the event-loop to submit flow records.
+pub struct FlowLogger {
+    records: mpsc::Sender<FlowLogEntry>,
+    num_dropped: u64,
+}
+
+impl FlowLogger {
+    /// Opens the log file, connects to the collector and spawns the task writing the records.
+    pub async fn spawn(file: Option<PathBuf>, collector: Option<SocketAddr>) -> Result<Self> {
+        let file = match file {
+            Some(path) => Some(
+                tokio::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .await
+                    .with_context(|| format!("Failed to open flow log `{}`", path.display()))?,
+            ),
+            None => None,
+        };
+
+        let collector = match collector {
+            Some(addr) => {
+                let unspecified = match addr {
+                    SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                };
+
+                let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?;
+                socket
+                    .connect(addr)
+                    .await
+                    .with_context(|| format!("Failed to connect to IPFIX collector {addr}"))?;
+
+                Some(socket)
+            }
+            None => None,
+        };
+
+        let (tx, rx) = mpsc::channel(MAX_PENDING_RECORDS);
+
+        tokio::spawn(write_records(rx, file, collector));
+
+        Ok(Self {
+            records: tx,
+            num_dropped: 0,
+        })
+    }
+
+    pub fn log(&mut self, record: FlowRecord) {
+        let entry = FlowLogEntry::new(record, Instant::now(), Utc::now());
+
+        match self.records.try_send(entry) {
+            Ok(()) => {}
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                self.num_dropped += 1;
+
+                // Don't spam the logs if we can't keep up.
+                if self.num_dropped.is_power_of_two() {
+                    tracing::warn!(num_dropped = %self.num_dropped, "Flow log is falling behind, dropping records");
+                }
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => {
+                tracing::debug!("Flow log task is gone");
+            }
+        }
+    }
+}
+
+/// A single line in the flow log.
+#[derive(Debug, Clone, Serialize, PartialEq)]
+struct FlowLogEntry {
+    event: FlowEvent,
+    client_id: ClientId,
+    resource_id: ResourceId,
+    protocol: Protocol,
+    src_ip: IpAddr,
+    src_port: u16,
+    dst_ip: IpAddr,
+    dst_port: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    translated_dst_ip: Option<IpAddr>,
+    start: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    end: Option<DateTime<Utc>>,
+    tx_bytes: u64,
+    tx_packets: u64,
+    rx_bytes: u64,
+    rx_packets: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    close_reason: Option<CloseReason>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum FlowEvent {
+    Start,
+    End,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum Protocol {
+    Tcp,
+    Udp,
+    Icmp,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+enum CloseReason {
+    IdleTimeout,
+    TcpFin,
+    TcpRst,
+    AccessRevoked,
+    ClientDisconnected,
+    Evicted,
+}
+
+impl FlowLogEntry {
+    /// Converts a record, using `now` and `utc_now` to translate its [`Instant`]s to wall-clock time.
+    fn new(record: FlowRecord, now: Instant, utc_now: DateTime<Utc>) -> Self {
+        let to_utc = |instant: Instant| {
+            let elapsed = now.saturating_duration_since(instant);
+
+            utc_now - chrono::Duration::from_std(elapsed).unwrap_or(chrono::Duration::zero())
+        };
+
+        Self {
+            event: match record.end {
+                Some(_) => FlowEvent::End,
+                None => FlowEvent::Start,
+            },
+            client_id: record.client,
+            resource_id: record.resource,
+            protocol: match record.protocol {
+                FlowProtocol::Tcp => Protocol::Tcp,
+                FlowProtocol::Udp => Protocol::Udp,
+                FlowProtocol::Icmp => Protocol::Icmp,
+            },
+            src_ip: record.src.ip(),
+            src_port: record.src.port(),
+            dst_ip: record.dst.ip(),
+            dst_port: record.dst.port(),
+            translated_dst_ip: record.translated_dst,
+            start: to_utc(record.started_at),
+            end: record.end.map(|e| to_utc(e.at)),
+            tx_bytes: record.tx_bytes,
+            tx_packets: record.tx_packets,
+            rx_bytes: record.rx_bytes,
+            rx_packets: record.rx_packets,
+            close_reason: record.end.map(|e| match e.reason {
+                FlowCloseReason::IdleTimeout => CloseReason::IdleTimeout,
+                FlowCloseReason::TcpFin => CloseReason::TcpFin,
+                FlowCloseReason::TcpRst => CloseReason::TcpRst,
+                FlowCloseReason::AccessRevoked => CloseReason::AccessRevoked,
+                FlowCloseReason::ClientDisconnected => CloseReason::ClientDisconnected,
+                FlowCloseReason::Evicted => CloseReason::Evicted,
+            }),
+        }
+    }
+
+    /// IPFIX collectors expect one record per flow, so we only export ended flows.
+    fn to_ipfix(&self) -> Option<ipfix::Flow> {
+        let end_reason = match self.close_reason? {
+            CloseReason::IdleTimeout => ipfix::EndReason::IdleTimeout,
+            CloseReason::TcpFin | CloseReason::TcpRst => ipfix::EndReason::EndOfFlowDetected,
+            CloseReason::AccessRevoked | CloseReason::ClientDisconnected | CloseReason::Evicted => {
+                ipfix::EndReason::ForcedEnd
+            }
+        };
+
+        let (protocol, src_port, dst_port) = match self.protocol {
+            Protocol::Tcp => (6, self.src_port, self.dst_port),
+            Protocol::Udp => (17, self.src_port, self.dst_port),
+            // For ICMP, our "ports" are the echo identifier which has no meaning for collectors.
+            Protocol::Icmp if self.src_ip.is_ipv4() => (1, 0, 0),
+            Protocol::Icmp => (58, 0, 0),
+        };
+
+        Some(ipfix::Flow {
+            protocol,
+            src_ip: self.src_ip,
+            src_port,
+            dst_ip: self.dst_ip,
+            dst_port,
+            start: self.start,
+            end: self.end?,
+            tx_bytes: self.tx_bytes,
+            tx_packets: self.tx_packets,
+            rx_bytes: self.rx_bytes,
+            rx_packets: self.rx_packets,
+            end_reason,
+        })
+    }
+}
+
+async fn write_records(
+    mut records: mpsc::Receiver<FlowLogEntry>,
+    mut file: Option<tokio::fs::File>,
+    collector: Option<UdpSocket>,
+) {
+    let mut encoder = ipfix::Encoder::default();
+    let mut batch = Vec::new();
+
+    while records.recv_many(&mut batch, 100).await > 0 {
+        if let Some(file) = file.as_mut() {
+            if let Err(e) = append_to_file(file, &batch).await {
+                tracing::warn!("Failed to write flow log: {e:#}");
+            }
+        }
+
+        if let Some(collector) = collector.as_ref() {
+            for flow in batch.iter().filter_map(|e| e.to_ipfix()) {
+                let message = encoder.encode(&flow, Utc::now());
+
+                if let Err(e) = collector.send(&message).await {
+                    tracing::debug!("Failed to send IPFIX message: {e}");
+                }
+            }
+        }
+
+        batch.clear();
+    }
+}
+
+async fn append_to_file(file: &mut tokio::fs::File, entries: &[FlowLogEntry]) -> Result<()> {
+    let mut lines = Vec::with_capacity(entries.len() * 256);
+
+    for entry in entries {
+        serde_json::to_writer(&mut lines, entry)?;
+        lines.push(b'\n');
+    }
+
+    file.write_all(&lines).await?;
+    file.flush().await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use firezone_tunnel::FlowEnd;
+    use std::time::Duration;
+
+    #[test]
+    fn serializes_end_record() {
+        let now = Instant::now();
+        let utc_now = "2025-01-01T12:00:00Z".parse::<DateTime<Utc>>().unwrap();
+
+        let record = FlowRecord {
+            client: "9d4b79f6-1db7-4cb3-a077-712102204d73".parse().unwrap(),
+            resource: "ed29c148-2acf-4ceb-8db5-d796c2671631".parse().unwrap(),
+            protocol: FlowProtocol::Tcp,
+            src: "100.64.0.1:50000".parse().unwrap(),
+            dst: "100.96.0.1:443".parse().unwrap(),
+            translated_dst: Some("10.0.0.1".parse().unwrap()),
+            started_at: now - Duration::from_secs(60),
+            end: Some(FlowEnd {
+                at: now - Duration::from_secs(10),
+                reason: FlowCloseReason::TcpFin,
+            }),
+            tx_bytes: 1000,
+            tx_packets: 10,
+            rx_bytes: 2000,
+            rx_packets: 12,
+        };
+
+        let entry = FlowLogEntry::new(record, now, utc_now);
+
+        assert_eq!(
+            serde_json::to_value(&entry).unwrap(),
+            serde_json::json!({
+                "event": "end",
+                "client_id": "9d4b79f6-1db7-4cb3-a077-712102204d73",
+                "resource_id": "ed29c148-2acf-4ceb-8db5-d796c2671631",
+                "protocol": "tcp",
+                "src_ip": "100.64.0.1",
+                "src_port": 50000,
+                "dst_ip": "100.96.0.1",
+                "dst_port": 443,
+                "translated_dst_ip": "10.0.0.1",
+                "start": "2025-01-01T11:59:00Z",
+                "end": "2025-01-01T11:59:50Z",
+                "tx_bytes": 1000,
+                "tx_packets": 10,
+                "rx_bytes": 2000,
+                "rx_packets": 12,
+                "close_reason": "tcp_fin",
+            })
+        );
+    }
+}
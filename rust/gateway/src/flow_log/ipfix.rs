@@ -0,0 +1,245 @@
+//! A minimal IPFIX (RFC 7011) encoder for ended flows.
+//!
+//! IPFIX over UDP requires templates to be re-sent periodically.
+//! We keep it simple and include them in every message.
+
+use chrono::{DateTime, Utc};
+use std::net::IpAddr;
+
+const VERSION: u16 = 10;
+const TEMPLATE_SET_ID: u16 = 2;
+const IPV4_TEMPLATE_ID: u16 = 256;
+const IPV6_TEMPLATE_ID: u16 = 257;
+
+/// The private enterprise number for reverse information elements of bidirectional flows (RFC 5103).
+const REVERSE_PEN: u32 = 29305;
+
+const ENTERPRISE_BIT: u16 = 0x8000;
+
+/// Information elements as per <https://www.iana.org/assignments/ipfix/ipfix.xhtml>.
+mod ie {
+    pub const OCTET_DELTA_COUNT: u16 = 1;
+    pub const PACKET_DELTA_COUNT: u16 = 2;
+    pub const PROTOCOL_IDENTIFIER: u16 = 4;
+    pub const SOURCE_TRANSPORT_PORT: u16 = 7;
+    pub const SOURCE_IPV4_ADDRESS: u16 = 8;
+    pub const DESTINATION_TRANSPORT_PORT: u16 = 11;
+    pub const DESTINATION_IPV4_ADDRESS: u16 = 12;
+    pub const SOURCE_IPV6_ADDRESS: u16 = 27;
+    pub const DESTINATION_IPV6_ADDRESS: u16 = 28;
+    pub const FLOW_END_REASON: u16 = 136;
+    pub const FLOW_START_MILLISECONDS: u16 = 152;
+    pub const FLOW_END_MILLISECONDS: u16 = 153;
+}
+
+/// The `flowEndReason` of a flow.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum EndReason {
+    IdleTimeout = 0x01,
+    EndOfFlowDetected = 0x03,
+    ForcedEnd = 0x04,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Flow {
+    pub protocol: u8,
+    pub src_ip: IpAddr,
+    pub src_port: u16,
+    pub dst_ip: IpAddr,
+    pub dst_port: u16,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    pub tx_bytes: u64,
+    pub tx_packets: u64,
+    pub rx_bytes: u64,
+    pub rx_packets: u64,
+    pub end_reason: EndReason,
+}
+
+/// Encodes IPFIX messages, keeping track of the sequence number.
+#[derive(Debug, Default)]
+pub struct Encoder {
+    sequence_number: u32,
+}
+
+impl Encoder {
+    /// Encodes a single flow into a message, including the template it refers to.
+    pub fn encode(&mut self, flow: &Flow, export_time: DateTime<Utc>) -> Vec<u8> {
+        let mut message = Vec::with_capacity(256);
+
+        // Message header, length is patched at the end.
+        message.extend_from_slice(&VERSION.to_be_bytes());
+        message.extend_from_slice(&0u16.to_be_bytes());
+        message.extend_from_slice(&(export_time.timestamp() as u32).to_be_bytes());
+        message.extend_from_slice(&self.sequence_number.to_be_bytes());
+        message.extend_from_slice(&0u32.to_be_bytes()); // Observation domain ID.
+
+        let is_ipv4 = flow.src_ip.is_ipv4() && flow.dst_ip.is_ipv4();
+
+        encode_set(&mut message, TEMPLATE_SET_ID, |buf| {
+            encode_template(buf, is_ipv4)
+        });
+        encode_set(
+            &mut message,
+            if is_ipv4 {
+                IPV4_TEMPLATE_ID
+            } else {
+                IPV6_TEMPLATE_ID
+            },
+            |buf| encode_record(buf, flow, is_ipv4),
+        );
+
+        let length = message.len() as u16;
+        message[2..4].copy_from_slice(&length.to_be_bytes());
+
+        self.sequence_number = self.sequence_number.wrapping_add(1);
+
+        message
+    }
+}
+
+fn encode_set(buf: &mut Vec<u8>, set_id: u16, content: impl FnOnce(&mut Vec<u8>)) {
+    let start = buf.len();
+
+    buf.extend_from_slice(&set_id.to_be_bytes());
+    buf.extend_from_slice(&0u16.to_be_bytes());
+    content(buf);
+
+    let length = (buf.len() - start) as u16;
+    buf[start + 2..start + 4].copy_from_slice(&length.to_be_bytes());
+}
+
+fn encode_template(buf: &mut Vec<u8>, is_ipv4: bool) {
+    let (template_id, src, dst, ip_len) = if is_ipv4 {
+        (
+            IPV4_TEMPLATE_ID,
+            ie::SOURCE_IPV4_ADDRESS,
+            ie::DESTINATION_IPV4_ADDRESS,
+            4,
+        )
+    } else {
+        (
+            IPV6_TEMPLATE_ID,
+            ie::SOURCE_IPV6_ADDRESS,
+            ie::DESTINATION_IPV6_ADDRESS,
+            16,
+        )
+    };
+
+    let fields: [(u16, u16, Option<u32>); 12] = [
+        (src, ip_len, None),
+        (dst, ip_len, None),
+        (ie::SOURCE_TRANSPORT_PORT, 2, None),
+        (ie::DESTINATION_TRANSPORT_PORT, 2, None),
+        (ie::PROTOCOL_IDENTIFIER, 1, None),
+        (ie::FLOW_START_MILLISECONDS, 8, None),
+        (ie::FLOW_END_MILLISECONDS, 8, None),
+        (ie::OCTET_DELTA_COUNT, 8, None),
+        (ie::PACKET_DELTA_COUNT, 8, None),
+        (ie::OCTET_DELTA_COUNT, 8, Some(REVERSE_PEN)),
+        (ie::PACKET_DELTA_COUNT, 8, Some(REVERSE_PEN)),
+        (ie::FLOW_END_REASON, 1, None),
+    ];
+
+    buf.extend_from_slice(&template_id.to_be_bytes());
+    buf.extend_from_slice(&(fields.len() as u16).to_be_bytes());
+
+    for (id, length, pen) in fields {
+        match pen {
+            Some(pen) => {
+                buf.extend_from_slice(&(id | ENTERPRISE_BIT).to_be_bytes());
+                buf.extend_from_slice(&length.to_be_bytes());
+                buf.extend_from_slice(&pen.to_be_bytes());
+            }
+            None => {
+                buf.extend_from_slice(&id.to_be_bytes());
+                buf.extend_from_slice(&length.to_be_bytes());
+            }
+        }
+    }
+}
+
+fn encode_record(buf: &mut Vec<u8>, flow: &Flow, is_ipv4: bool) {
+    encode_ip(buf, flow.src_ip, is_ipv4);
+    encode_ip(buf, flow.dst_ip, is_ipv4);
+    buf.extend_from_slice(&flow.src_port.to_be_bytes());
+    buf.extend_from_slice(&flow.dst_port.to_be_bytes());
+    buf.push(flow.protocol);
+    buf.extend_from_slice(&(flow.start.timestamp_millis() as u64).to_be_bytes());
+    buf.extend_from_slice(&(flow.end.timestamp_millis() as u64).to_be_bytes());
+    buf.extend_from_slice(&flow.tx_bytes.to_be_bytes());
+    buf.extend_from_slice(&flow.tx_packets.to_be_bytes());
+    buf.extend_from_slice(&flow.rx_bytes.to_be_bytes());
+    buf.extend_from_slice(&flow.rx_packets.to_be_bytes());
+    buf.push(flow.end_reason as u8);
+}
+
+fn encode_ip(buf: &mut Vec<u8>, ip: IpAddr, is_ipv4: bool) {
+    match (ip, is_ipv4) {
+        (IpAddr::V4(ip), true) => buf.extend_from_slice(&ip.octets()),
+        (IpAddr::V4(ip), false) => buf.extend_from_slice(&ip.to_ipv6_mapped().octets()),
+        (IpAddr::V6(ip), _) => buf.extend_from_slice(&ip.octets()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn message_length_matches_header() {
+        let mut encoder = Encoder::default();
+
+        let message = encoder.encode(&flow(), Utc::now());
+
+        assert_eq!(&message[0..2], &VERSION.to_be_bytes());
+        assert_eq!(
+            u16::from_be_bytes([message[2], message[3]]) as usize,
+            message.len()
+        );
+    }
+
+    #[test]
+    fn data_set_matches_template() {
+        let mut encoder = Encoder::default();
+
+        let message = encoder.encode(&flow(), Utc::now());
+
+        let template_set_len = u16::from_be_bytes([message[18], message[19]]) as usize;
+        let data_set = &message[16 + template_set_len..];
+
+        assert_eq!(&data_set[0..2], &IPV4_TEMPLATE_ID.to_be_bytes());
+        // Header + 2 * 4 (IPs) + 2 * 2 (ports) + 1 (protocol) + 6 * 8 (timestamps & counters) + 1 (end reason)
+        assert_eq!(data_set.len(), 4 + 8 + 4 + 1 + 48 + 1);
+    }
+
+    #[test]
+    fn increments_sequence_number() {
+        let mut encoder = Encoder::default();
+
+        let first = encoder.encode(&flow(), Utc::now());
+        let second = encoder.encode(&flow(), Utc::now());
+
+        assert_eq!(&first[8..12], &0u32.to_be_bytes());
+        assert_eq!(&second[8..12], &1u32.to_be_bytes());
+    }
+
+    fn flow() -> Flow {
+        Flow {
+            protocol: 17,
+            src_ip: Ipv4Addr::new(100, 64, 0, 1).into(),
+            src_port: 1000,
+            dst_ip: Ipv4Addr::new(10, 0, 0, 1).into(),
+            dst_port: 53,
+            start: Utc::now(),
+            end: Utc::now(),
+            tx_bytes: 100,
+            tx_packets: 1,
+            rx_bytes: 200,
+            rx_packets: 1,
+            end_reason: EndReason::IdleTimeout,
+        }
+    }
+}
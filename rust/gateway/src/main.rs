@@ -1,32 +1,40 @@
 use crate::eventloop::{Eventloop, PHOENIX_TOPIC};
+use crate::flow_log::FlowLogger;
+use crate::packet_capture::PacketCaptureToggle;
+use crate::resolver::Resolver;
 use anyhow::{Context, Result};
 use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
 use firezone_bin_shared::{
     http_health_check,
-    platform::{tcp_socket_factory, udp_socket_factory},
-    TunDeviceManager,
+    log_rotation::{parse_size, LogRotationArgs},
+    proxy::ProxyArgs,
+    TunDeviceConfig, TunDeviceManager,
 };
 
 use firezone_telemetry::Telemetry;
-use firezone_tunnel::GatewayTunnel;
+use firezone_tunnel::messages::gateway::RateLimit;
+use firezone_tunnel::{CandidatePolicy, GatewayTunnel};
 use phoenix_channel::get_user_agent;
 use phoenix_channel::LoginUrl;
 
 use futures::{future, TryFutureExt};
 use phoenix_channel::PhoenixChannel;
 use secrecy::{Secret, SecretString};
-use std::path::Path;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
 use std::pin::pin;
 use std::process::ExitCode;
-use std::sync::Arc;
 use tokio::io::AsyncWriteExt;
 use tokio::signal::ctrl_c;
-use tracing_subscriber::layer;
 use url::Url;
 use uuid::Uuid;
 
 mod eventloop;
+mod flow_log;
+mod packet_capture;
+mod resolver;
 
 const ID_PATH: &str = "/var/lib/firezone/gateway_id";
 
@@ -42,6 +50,13 @@ fn main() -> ExitCode {
         return ExitCode::FAILURE;
     }
 
+    // Threads inherit the network namespace, so enter it before telemetry and Tokio spawn any.
+    #[expect(clippy::print_stderr, reason = "No logger has been set up yet")]
+    if let Err(e) = cli.tun.enter_netns() {
+        eprintln!("{e:#}");
+        return ExitCode::FAILURE;
+    }
+
     rustls::crypto::ring::default_provider()
         .install_default()
         .expect("Calling `install_default` only once per process should always succeed");
@@ -98,8 +113,18 @@ fn has_necessary_permissions() -> bool {
 }
 
 async fn try_main(cli: Cli) -> Result<ExitCode> {
-    firezone_logging::setup_global_subscriber(layer::Identity::default())
-        .context("Failed to set up logging")?;
+    let (layer, _handle) = cli
+        .log_dir
+        .as_deref()
+        .map(|dir| {
+            firezone_logging::file::layer_with_rotation(
+                dir,
+                "firezone-gateway",
+                cli.log_rotation.rotation(),
+            )
+        })
+        .unzip();
+    firezone_logging::setup_global_subscriber(layer).context("Failed to set up logging")?;
 
     let firezone_id = get_firezone_id(cli.firezone_id).await
         .context("Couldn't read FIREZONE_ID or write it to disk: Please provide it through the env variable or provide rw access to /var/lib/firezone/")?;
@@ -113,7 +138,28 @@ async fn try_main(cli: Cli) -> Result<ExitCode> {
     )
     .context("Failed to construct URL for logging into portal")?;
 
-    let mut tunnel = GatewayTunnel::new(Arc::new(tcp_socket_factory), Arc::new(udp_socket_factory));
+    let metrics = http_health_check::install_prometheus_meter_provider();
+
+    let mut tunnel = GatewayTunnel::new(cli.tun.tcp_socket_factory(), cli.tun.udp_socket_factory());
+    if cli.no_relay {
+        tunnel
+            .state_mut()
+            .set_candidate_policy(CandidatePolicy::DirectOnly);
+    }
+    let crypto_threads = match cli.crypto_threads {
+        Some(n) => n,
+        None => std::thread::available_parallelism()
+            .context("Failed to determine number of CPUs")?
+            .get(),
+    };
+    tunnel
+        .state_mut()
+        .set_crypto_threads(crypto_threads)
+        .context("Failed to start crypto threads")?;
+    tunnel.state_mut().set_client_rate_limit(RateLimit {
+        upload_bytes_per_sec: cli.client_upload_bytes_per_sec.and_then(NonZeroU64::new),
+        download_bytes_per_sec: cli.client_download_bytes_per_sec.and_then(NonZeroU64::new),
+    });
     let portal = PhoenixChannel::disconnected(
         Secret::new(login),
         get_user_agent(None, env!("CARGO_PKG_VERSION")),
@@ -124,19 +170,52 @@ async fn try_main(cli: Cli) -> Result<ExitCode> {
                 .with_max_elapsed_time(None)
                 .build()
         },
-        Arc::new(tcp_socket_factory),
+        cli.tun.tcp_socket_factory(),
+        cli.proxy.config().context("Invalid proxy")?,
     )
     .context("Failed to resolve portal URL")?;
 
-    let mut tun_device_manager = TunDeviceManager::new(ip_packet::MAX_IP_SIZE, cli.tun_threads)
-        .context("Failed to create TUN device manager")?;
+    let resolver = if cli.dns_resolver.is_empty() {
+        Resolver::from_system().context("Failed to determine system DNS servers")?
+    } else {
+        Resolver::new(cli.dns_resolver)?
+    };
+    tracing::info!(servers = ?resolver.servers(), "Querying TTLs of DNS resources");
+
+    let flow_logger = if cli.flow_log_file.is_some() || cli.flow_log_collector.is_some() {
+        tunnel.state_mut().enable_flow_logs();
+
+        Some(
+            FlowLogger::spawn(cli.flow_log_file, cli.flow_log_collector)
+                .await
+                .context("Failed to set up flow log")?,
+        )
+    } else {
+        None
+    };
+
+    let packet_capture = cli
+        .packet_capture_dir
+        .map(|dir| PacketCaptureToggle::new(dir, cli.packet_capture_max_size))
+        .transpose()?;
+
+    let mut tun_device_manager =
+        TunDeviceManager::new(ip_packet::MAX_IP_SIZE, cli.tun_threads, cli.tun)
+            .context("Failed to create TUN device manager")?;
     let tun = tun_device_manager
         .make_tun()
         .context("Failed to create TUN device")?;
     tunnel.set_tun(Box::new(tun));
 
     let task = tokio::spawn(future::poll_fn({
-        let mut eventloop = Eventloop::new(tunnel, portal, tun_device_manager);
+        let mut eventloop = Eventloop::new(
+            tunnel,
+            portal,
+            tun_device_manager,
+            resolver,
+            flow_logger,
+            packet_capture,
+        );
 
         move |cx| eventloop.poll(cx)
     }))
@@ -146,6 +225,7 @@ async fn try_main(cli: Cli) -> Result<ExitCode> {
     tokio::spawn(http_health_check::serve(
         cli.health_check.health_check_addr,
         || true,
+        metrics,
     ));
 
     match future::try_select(task, ctrl_c)
@@ -222,6 +302,86 @@ struct Cli {
     /// How many threads to use for reading and writing to the TUN device.
     #[arg(long, env = "FIREZONE_NUM_TUN_THREADS", default_value_t = 2)]
     tun_threads: usize,
+
+    /// How many threads to use for encrypting and decrypting packets of different clients.
+    ///
+    /// Defaults to the number of CPUs.
+    #[arg(long, env = "FIREZONE_NUM_CRYPTO_THREADS")]
+    crypto_threads: Option<usize>,
+
+    /// The maximum number of bytes per second each client may send to resources, e.g. `10M`.
+    ///
+    /// Resources may have lower limits set in the portal.
+    #[arg(long, env = "FIREZONE_CLIENT_UPLOAD_BYTES_PER_SEC", value_parser = parse_size)]
+    client_upload_bytes_per_sec: Option<u64>,
+
+    /// The maximum number of bytes per second each client may receive from resources, e.g. `10M`.
+    ///
+    /// Resources may have lower limits set in the portal.
+    #[arg(long, env = "FIREZONE_CLIENT_DOWNLOAD_BYTES_PER_SEC", value_parser = parse_size)]
+    client_download_bytes_per_sec: Option<u64>,
+
+    #[command(flatten)]
+    tun: TunDeviceConfig,
+
+    /// Only accept direct connections from Clients, never relay traffic.
+    ///
+    /// Clients that cannot reach this Gateway directly will fail to connect.
+    #[arg(long, env = "FIREZONE_NO_RELAY", default_value_t = false)]
+    no_relay: bool,
+
+    /// DNS servers to query for the TTLs of DNS resources' records, e.g. `1.1.1.1` or `[2606:4700:4700::1111]:53`.
+    ///
+    /// The addresses themselves always come from the system's resolver.
+    /// Defaults to the nameservers in `/etc/resolv.conf`.
+    #[arg(
+        long,
+        env = "FIREZONE_DNS_RESOLVERS",
+        value_delimiter = ',',
+        value_parser = parse_dns_resolver
+    )]
+    dns_resolver: Vec<SocketAddr>,
+
+    /// Append a JSON record to this file whenever a client starts or ends a flow to a resource.
+    #[arg(long, env = "FIREZONE_FLOW_LOG_FILE")]
+    flow_log_file: Option<PathBuf>,
+
+    /// Export ended flows via IPFIX to the collector listening on this UDP address.
+    #[arg(long, env = "FIREZONE_FLOW_LOG_COLLECTOR")]
+    flow_log_collector: Option<SocketAddr>,
+
+    /// Also write logs to files in this directory, in addition to stdout.
+    #[arg(long, env = "FIREZONE_LOG_DIR")]
+    log_dir: Option<PathBuf>,
+
+    #[command(flatten)]
+    log_rotation: LogRotationArgs,
+
+    #[command(flatten)]
+    proxy: ProxyArgs,
+
+    /// Start a packet capture into a new pcapng file in this directory on `SIGUSR1`, stop it on the next.
+    #[arg(long, env = "FIREZONE_PACKET_CAPTURE_DIR")]
+    packet_capture_dir: Option<PathBuf>,
+
+    /// Stop a packet capture once its file reaches this size, e.g. `100M`.
+    #[arg(
+        long,
+        env = "FIREZONE_PACKET_CAPTURE_MAX_SIZE",
+        default_value = "100M",
+        value_parser = parse_size
+    )]
+    packet_capture_max_size: u64,
+}
+
+/// Parses a DNS server, defaulting to port 53 if none is given.
+fn parse_dns_resolver(s: &str) -> Result<SocketAddr, String> {
+    if let Ok(ip) = s.parse::<IpAddr>() {
+        return Ok(SocketAddr::new(ip, 53));
+    }
+
+    s.parse::<SocketAddr>()
+        .map_err(|_| format!("`{s}` is neither an IP address nor a socket address"))
 }
 
 impl Cli {
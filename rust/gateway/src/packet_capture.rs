@@ -0,0 +1,59 @@
+//! Toggles a capture of the tunnel's traffic whenever the Gateway receives `SIGUSR1`.
+
+use anyhow::{Context as _, Result};
+use firezone_bin_shared::packet_capture::create_capture_file;
+use firezone_tunnel::GatewayTunnel;
+use std::{
+    path::PathBuf,
+    task::{ready, Context, Poll},
+};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+
+pub struct PacketCaptureToggle {
+    sigusr1: Signal,
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl PacketCaptureToggle {
+    /// Must be called within a Tokio runtime context so we can register the signal handler.
+    pub fn new(dir: PathBuf, max_size: u64) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Failed to create `{}`", dir.display()))?;
+        let sigusr1 = signal(SignalKind::user_defined1())
+            .context("Failed to register handler for SIGUSR1")?;
+
+        Ok(Self {
+            sigusr1,
+            dir,
+            max_size,
+        })
+    }
+
+    /// Starts a capture into a new file or stops the running one, once per `SIGUSR1`.
+    pub fn poll(&mut self, cx: &mut Context<'_>, tunnel: &mut GatewayTunnel) -> Poll<()> {
+        if ready!(self.sigusr1.poll_recv(cx)).is_none() {
+            return Poll::Pending; // The signal stream never ends in practice.
+        }
+
+        if tunnel.is_capturing_packets() {
+            tunnel.stop_packet_capture();
+            return Poll::Ready(());
+        }
+
+        if let Err(e) = self.start(tunnel) {
+            tracing::warn!("Failed to start packet capture: {e:#}");
+        }
+
+        Poll::Ready(())
+    }
+
+    fn start(&self, tunnel: &mut GatewayTunnel) -> Result<()> {
+        let (file, path) = create_capture_file(&self.dir)?;
+        tunnel.start_packet_capture(file, self.max_size)?;
+
+        tracing::info!(path = %path.display(), "Capturing packets, send SIGUSR1 again to stop");
+
+        Ok(())
+    }
+}
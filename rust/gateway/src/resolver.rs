@@ -0,0 +1,353 @@
+//! Resolves the domains of DNS resources.
+//!
+//! The addresses come from the system's resolver, i.e. `getaddrinfo`, so `/etc/hosts`, `nsswitch.conf` and the `search` and `ndots` options keep working.
+//! `getaddrinfo` doesn't tell us the TTLs of the records though, so we also ask a nameserver directly, just for those.
+//! The tunnel uses the TTLs to re-resolve domains before they expire.
+
+use anyhow::{bail, Context as _, Result};
+use connlib_model::DomainName;
+#[cfg(not(target_os = "windows"))]
+use dns_lookup::{AddrInfoHints, AddrInfoIter, LookupError};
+use domain::base::iana::{Opcode, Rcode};
+use domain::base::{Message, MessageBuilder, Question, Rtype};
+use domain::rdata::{Aaaa, A};
+use firezone_tunnel::ResolvedAddresses;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+use tokio::net::{TcpStream, UdpSocket};
+
+const DNS_PORT: u16 = 53;
+
+/// How long we wait for a single upstream server to respond before trying the next one.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// The largest UDP response we accept; anything larger will be truncated by the server.
+const MAX_UDP_RESPONSE_SIZE: usize = 4096;
+
+/// The TTL we assume if we can't learn the actual one, e.g. because the domain is only listed in `/etc/hosts`.
+const UNKNOWN_TTL: Duration = Duration::from_secs(60);
+
+/// How soon we try again if we couldn't resolve a domain at all.
+pub const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+pub struct Resolver {
+    servers: Vec<SocketAddr>,
+}
+
+impl Resolver {
+    /// Creates a resolver that queries the TTLs of records from the given servers, in order.
+    pub fn new(servers: Vec<SocketAddr>) -> Result<Self> {
+        anyhow::ensure!(!servers.is_empty(), "Need at least one DNS server");
+
+        Ok(Self { servers })
+    }
+
+    /// Creates a resolver that queries the TTLs of records from the nameservers listed in `/etc/resolv.conf`.
+    pub fn from_system() -> Result<Self> {
+        let text = std::fs::read_to_string("/etc/resolv.conf")
+            .context("Failed to read `/etc/resolv.conf`")?;
+        let config =
+            resolv_conf::Config::parse(&text).context("Failed to parse `/etc/resolv.conf`")?;
+
+        let mut servers = config
+            .nameservers
+            .into_iter()
+            .map(|ip| SocketAddr::new(ip.into(), DNS_PORT))
+            .collect::<Vec<_>>();
+
+        // Same default as glibc.
+        if servers.is_empty() {
+            servers.push(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), DNS_PORT));
+        }
+
+        Self::new(servers)
+    }
+
+    pub fn servers(&self) -> &[SocketAddr] {
+        &self.servers
+    }
+
+    /// Resolves the addresses of the given domain via `getaddrinfo` and how long they are valid for.
+    pub async fn resolve(&self, domain: DomainName) -> Result<ResolvedAddresses> {
+        let name = domain.to_string();
+        let (addresses, ttl) = futures::future::join(
+            tokio::task::spawn_blocking(move || resolve_addresses(&name)),
+            self.ttl(&domain),
+        )
+        .await;
+
+        let addresses = addresses
+            .context("DNS resolution task failed")?
+            .context("DNS resolution failed")?;
+        let ttl = ttl.unwrap_or_else(|e| {
+            tracing::debug!(%domain, "Failed to query TTL of records: {e:#}");
+
+            UNKNOWN_TTL
+        });
+
+        Ok(ResolvedAddresses { addresses, ttl })
+    }
+
+    /// Queries the A and AAAA records of the given domain and returns the smallest TTL among them.
+    async fn ttl(&self, domain: &DomainName) -> Result<Duration> {
+        let (ipv4, ipv6) = futures::future::join(
+            self.query(domain, Rtype::A),
+            self.query(domain, Rtype::AAAA),
+        )
+        .await;
+
+        let records = match (ipv4, ipv6) {
+            (Ok(v4), Ok(v6)) => v6.into_iter().chain(v4).collect::<Vec<_>>(),
+            (Ok(v4), Err(e)) => {
+                tracing::debug!(%domain, "Failed to resolve AAAA records: {e:#}");
+
+                v4
+            }
+            (Err(e), Ok(v6)) => {
+                tracing::debug!(%domain, "Failed to resolve A records: {e:#}");
+
+                v6
+            }
+            (Err(e), Err(_)) => return Err(e),
+        };
+
+        records
+            .into_iter()
+            .map(|(_, ttl)| ttl)
+            .min()
+            .context("No A or AAAA records")
+    }
+
+    async fn query(&self, domain: &DomainName, rtype: Rtype) -> Result<Vec<(IpAddr, Duration)>> {
+        let query = make_query(domain, rtype)?;
+        let mut last_error = None;
+
+        for server in &self.servers {
+            match tokio::time::timeout(QUERY_TIMEOUT, exchange(*server, &query)).await {
+                Ok(Ok(response)) => return parse_response(&query, &response),
+                Ok(Err(e)) => {
+                    tracing::debug!(%server, %domain, %rtype, "DNS query failed: {e:#}");
+
+                    last_error = Some(e);
+                }
+                Err(_) => {
+                    tracing::debug!(%server, %domain, %rtype, "DNS query timed out");
+
+                    last_error = Some(anyhow::anyhow!("DNS query to {server} timed out"));
+                }
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No DNS servers configured")))
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn resolve_addresses(_: &str) -> std::io::Result<Vec<IpAddr>> {
+    unimplemented!()
+}
+
+#[cfg(not(target_os = "windows"))]
+fn resolve_addresses(addr: &str) -> std::io::Result<Vec<IpAddr>> {
+    use libc::{AF_INET, AF_INET6};
+    let addr_v4: std::io::Result<Vec<_>> = resolve_address_family(addr, AF_INET)
+        .map_err(|e| e.into())
+        .and_then(|a| a.collect());
+    let addr_v6: std::io::Result<Vec<_>> = resolve_address_family(addr, AF_INET6)
+        .map_err(|e| e.into())
+        .and_then(|a| a.collect());
+    match (addr_v4, addr_v6) {
+        (Ok(v4), Ok(v6)) => Ok(v6
+            .iter()
+            .map(|a| a.sockaddr.ip())
+            .chain(v4.iter().map(|a| a.sockaddr.ip()))
+            .collect()),
+        (Ok(v4), Err(_)) => Ok(v4.iter().map(|a| a.sockaddr.ip()).collect()),
+        (Err(_), Ok(v6)) => Ok(v6.iter().map(|a| a.sockaddr.ip()).collect()),
+        (Err(e), Err(_)) => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn resolve_address_family(addr: &str, family: i32) -> Result<AddrInfoIter, LookupError> {
+    use libc::SOCK_STREAM;
+
+    dns_lookup::getaddrinfo(
+        Some(addr),
+        None,
+        Some(AddrInfoHints {
+            socktype: SOCK_STREAM,
+            address: family,
+            ..Default::default()
+        }),
+    )
+}
+
+fn make_query(domain: &DomainName, rtype: Rtype) -> Result<Message<Vec<u8>>> {
+    let mut builder = MessageBuilder::new_vec();
+    builder.header_mut().set_opcode(Opcode::QUERY);
+    builder.header_mut().set_rd(true);
+    builder.header_mut().set_id(rand::random());
+
+    let mut builder = builder.question();
+    builder
+        .push(Question::new_in(domain, rtype))
+        .context("Failed to build DNS query")?;
+
+    Ok(builder.into_message())
+}
+
+/// Sends the query via UDP and falls back to TCP if the response is truncated.
+async fn exchange(server: SocketAddr, query: &Message<Vec<u8>>) -> Result<Message<Vec<u8>>> {
+    let response = exchange_udp(server, query).await?;
+
+    if !response.header().tc() {
+        return Ok(response);
+    }
+
+    exchange_tcp(server, query).await
+}
+
+async fn exchange_udp(server: SocketAddr, query: &Message<Vec<u8>>) -> Result<Message<Vec<u8>>> {
+    let unspecified = match server {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?;
+    socket.connect(server).await?;
+    socket.send(query.as_slice()).await?;
+
+    let mut buffer = vec![0u8; MAX_UDP_RESPONSE_SIZE];
+
+    // Only accept responses to our query, everything else could be spoofed.
+    loop {
+        let len = socket.recv(&mut buffer).await?;
+
+        let Ok(response) = Message::from_octets(buffer[..len].to_vec()) else {
+            continue;
+        };
+
+        if response.header().id() == query.header().id() {
+            return Ok(response);
+        }
+    }
+}
+
+async fn exchange_tcp(server: SocketAddr, query: &Message<Vec<u8>>) -> Result<Message<Vec<u8>>> {
+    let mut stream = TcpStream::connect(server).await?;
+
+    stream
+        .write_all(&(query.as_slice().len() as u16).to_be_bytes())
+        .await?;
+    stream.write_all(query.as_slice()).await?;
+
+    let mut response_length = [0u8; 2];
+    stream.read_exact(&mut response_length).await?;
+
+    // A u16 is at most 65k, meaning we are okay to allocate here based on what the remote is sending.
+    let mut response = vec![0u8; u16::from_be_bytes(response_length) as usize];
+    stream.read_exact(&mut response).await?;
+
+    Message::from_octets(response).context("Failed to parse DNS response")
+}
+
+/// Extracts the addresses and their TTLs from a response.
+///
+/// The answer section may also contain `CNAME` records which we skip.
+/// A compliant resolver includes the records of the canonical name in the same answer.
+fn parse_response(
+    query: &Message<Vec<u8>>,
+    response: &Message<Vec<u8>>,
+) -> Result<Vec<(IpAddr, Duration)>> {
+    anyhow::ensure!(
+        response.header().qr() && response.header().id() == query.header().id(),
+        "Response does not belong to query"
+    );
+    anyhow::ensure!(
+        response.first_question() == query.first_question(),
+        "Response is for a different question"
+    );
+
+    let rcode = response.header().rcode();
+    if rcode != Rcode::NOERROR {
+        bail!("DNS query failed with {rcode}")
+    }
+
+    let answer = response
+        .answer()
+        .context("Failed to parse answer section")?;
+
+    let ipv4 = answer
+        .limit_to::<A>()
+        .filter_map(|r| r.ok())
+        .map(|r| (IpAddr::from(r.data().addr()), r.ttl().into_duration()));
+    let ipv6 = answer
+        .limit_to::<Aaaa>()
+        .filter_map(|r| r.ok())
+        .map(|r| (IpAddr::from(r.data().addr()), r.ttl().into_duration()));
+
+    Ok(ipv4.chain(ipv6).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use domain::base::iana::Class;
+
+    #[test]
+    fn parses_addresses_and_ttls_from_response() {
+        let domain = "example.com".parse::<DomainName>().unwrap();
+        let query = make_query(&domain, Rtype::A).unwrap();
+
+        let mut answer = MessageBuilder::new_vec()
+            .start_answer(&query, Rcode::NOERROR)
+            .unwrap();
+        answer
+            .push((&domain, Class::IN, 60, A::new(Ipv4Addr::new(1, 1, 1, 1))))
+            .unwrap();
+        answer
+            .push((&domain, Class::IN, 30, A::new(Ipv4Addr::new(1, 0, 0, 1))))
+            .unwrap();
+        let response = answer.into_message();
+
+        let records = parse_response(&query, &response).unwrap();
+
+        assert_eq!(
+            records,
+            vec![
+                (IpAddr::from([1, 1, 1, 1]), Duration::from_secs(60)),
+                (IpAddr::from([1, 0, 0, 1]), Duration::from_secs(30)),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_response_to_different_query() {
+        let domain = "example.com".parse::<DomainName>().unwrap();
+        let query = make_query(&domain, Rtype::A).unwrap();
+        let other_query = make_query(&domain, Rtype::AAAA).unwrap();
+
+        let response = MessageBuilder::new_vec()
+            .start_answer(&other_query, Rcode::NOERROR)
+            .unwrap()
+            .into_message();
+
+        assert!(parse_response(&query, &response).is_err());
+    }
+
+    #[test]
+    fn fails_on_nxdomain() {
+        let domain = "does-not-exist.example.com".parse::<DomainName>().unwrap();
+        let query = make_query(&domain, Rtype::A).unwrap();
+
+        let response = MessageBuilder::new_vec()
+            .start_answer(&query, Rcode::NXDOMAIN)
+            .unwrap()
+            .into_message();
+
+        assert!(parse_response(&query, &response).is_err());
+    }
+}
@@ -579,6 +579,15 @@ impl<I: GuiIntegration> Controller<I> {
 
                 self.update_disabled_resources().await?;
             }
+            IpcServerMsg::OnUpdateConnectionStats(connections) => {
+                tracing::trace!(?connections, "Got connection stats");
+            }
+            IpcServerMsg::Status(_) => {
+                tracing::debug!("Ignoring status, the GUI never asks for it");
+            }
+            IpcServerMsg::PacketCaptureStarted(_) => {
+                tracing::debug!("Ignoring packet capture result, the GUI never starts one");
+            }
             IpcServerMsg::TerminatingGracefully => {
                 tracing::info!("IPC service exited gracefully");
                 self.integration
@@ -0,0 +1,226 @@
+//! The control socket of the headless Client and the IPC service
+//!
+//! `firezone-headless-client ctl` connects to it to query and change the state of a running tunnel.
+//! It speaks the same protocol as the IPC connection to the GUI, but each control client only
+//! receives the responses to its own requests and never any connlib callbacks.
+
+use crate::{
+    ipc::{self, ServiceId},
+    IpcClientMsg, IpcServerMsg, IpcStatus,
+};
+use anyhow::{bail, Context as _, Result};
+use futures::{SinkExt as _, StreamExt as _};
+use std::{
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::sync::{mpsc, oneshot};
+
+/// How many requests from control clients we queue before we stop reading from their sockets.
+const MAX_PENDING_REQUESTS: usize = 16;
+
+/// Accepts control clients in the background and hands their requests to the event loop.
+pub struct ControlServer {
+    requests: mpsc::Receiver<ControlRequest>,
+}
+
+/// A message from a control client.
+///
+/// Not every message has a response. Dropping `responder` without sending anything is fine for those.
+pub struct ControlRequest {
+    pub msg: IpcClientMsg,
+    pub responder: oneshot::Sender<IpcServerMsg>,
+}
+
+impl ControlServer {
+    pub async fn new(id: ServiceId) -> Result<Self> {
+        let server = ipc::Server::new(id)
+            .await
+            .context("Failed to bind control socket")?;
+        let (tx, rx) = mpsc::channel(MAX_PENDING_REQUESTS);
+
+        tokio::spawn(accept_clients(server, tx));
+
+        Ok(Self { requests: rx })
+    }
+
+    /// Returns the next request from any control client.
+    ///
+    /// Cancel-safe. Only returns `None` if the task accepting clients is gone.
+    pub async fn recv(&mut self) -> Option<ControlRequest> {
+        self.requests.recv().await
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<ControlRequest>> {
+        self.requests.poll_recv(cx)
+    }
+}
+
+async fn accept_clients(mut server: ipc::Server, requests: mpsc::Sender<ControlRequest>) {
+    loop {
+        let (rx, tx) = match server.next_client_split().await {
+            Ok(client) => client,
+            Err(e) => {
+                tracing::warn!("Failed to accept control client: {e:#}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let requests = requests.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_client(rx, tx, requests).await {
+                tracing::debug!("Control client failed: {e:#}");
+            }
+        });
+    }
+}
+
+async fn serve_client(
+    mut rx: ipc::ServerRead,
+    mut tx: ipc::ServerWrite,
+    requests: mpsc::Sender<ControlRequest>,
+) -> Result<()> {
+    while let Some(msg) = rx.next().await {
+        let msg = msg.context("Failed to read control message")?;
+
+        if !is_allowed(&msg) {
+            tracing::warn!(?msg, "Ignoring message that control clients may not send");
+            continue;
+        }
+
+        let (responder, response) = oneshot::channel();
+        requests
+            .send(ControlRequest { msg, responder })
+            .await
+            .context("Control requests are no longer handled")?;
+
+        // Handling requests one at a time means a client can rely on its earlier requests being done once it gets a response.
+        let Ok(response) = response.await else {
+            continue;
+        };
+
+        tx.send(&response)
+            .await
+            .with_context(|| format!("Failed to send `{response}` to control client"))?;
+    }
+
+    Ok(())
+}
+
+/// Signing in and out is up to the GUI or the headless Client itself, control clients may only change a running tunnel.
+fn is_allowed(msg: &IpcClientMsg) -> bool {
+    match msg {
+        IpcClientMsg::ApplyLogFilter { .. }
+        | IpcClientMsg::ClearLogs
+        | IpcClientMsg::GetStatus
+        | IpcClientMsg::Reset
+        | IpcClientMsg::SetDisabledResources(_)
+        | IpcClientMsg::StartPacketCapture { .. }
+        | IpcClientMsg::StopPacketCapture => true,
+        IpcClientMsg::Connect { .. }
+        | IpcClientMsg::Disconnect
+        | IpcClientMsg::SetDns(_)
+        | IpcClientMsg::StartTelemetry { .. } => false,
+    }
+}
+
+/// The client side of the control socket, used by `firezone-headless-client ctl`.
+pub struct ControlClient {
+    rx: ipc::ClientRead,
+    tx: ipc::ClientWrite,
+}
+
+impl ControlClient {
+    /// Connects to the control socket at `id`, usually [`ServiceId::Control`].
+    pub async fn connect(id: ServiceId) -> Result<Self, ipc::Error> {
+        let (rx, tx) = ipc::connect_to_service(id).await?;
+
+        Ok(Self { rx, tx })
+    }
+
+    /// Sends a message that has no response.
+    ///
+    /// Follow up with [`ControlClient::status`] to wait until the message was handled.
+    pub async fn send(&mut self, msg: &IpcClientMsg) -> Result<()> {
+        self.tx
+            .send(msg)
+            .await
+            .context("Failed to send control message")
+    }
+
+    /// Sends a message and waits for its response.
+    pub async fn request(&mut self, msg: &IpcClientMsg) -> Result<IpcServerMsg> {
+        self.send(msg).await?;
+
+        self.rx
+            .next()
+            .await
+            .context("Control socket closed before we got a response")?
+    }
+
+    pub async fn status(&mut self) -> Result<IpcStatus> {
+        match self.request(&IpcClientMsg::GetStatus).await? {
+            IpcServerMsg::Status(status) => Ok(status),
+            other => bail!("Unexpected response `{other}` to `GetStatus`"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TunnelState;
+    use connlib_model::ResourceId;
+    use std::collections::BTreeSet;
+
+    #[tokio::test]
+    async fn responds_to_own_requests_in_order() -> Result<()> {
+        let _guard = firezone_logging::test("trace");
+        const ID: ServiceId = ServiceId::Test("C7QX2MLE");
+
+        let mut server = ControlServer::new(ID).await?;
+        let mut client = ControlClient::connect(ID).await?;
+
+        let disabled = BTreeSet::from([ResourceId::from_u128(1)]);
+
+        let server_task = tokio::spawn(async move {
+            let mut disabled_resources = BTreeSet::new();
+
+            while let Some(ControlRequest { msg, responder }) = server.recv().await {
+                match msg {
+                    IpcClientMsg::SetDisabledResources(ids) => disabled_resources = ids,
+                    IpcClientMsg::GetStatus => {
+                        let _ = responder.send(IpcServerMsg::Status(IpcStatus {
+                            state: TunnelState::Connected,
+                            resources: vec![],
+                            disabled_resources: disabled_resources.clone(),
+                        }));
+                    }
+                    other => panic!("Unexpected control message {other:?}"),
+                }
+            }
+        });
+
+        client
+            .send(&IpcClientMsg::SetDisabledResources(disabled.clone()))
+            .await?;
+        let status = client.status().await?;
+
+        assert_eq!(status.state, TunnelState::Connected);
+        assert_eq!(status.disabled_resources, disabled);
+
+        server_task.abort();
+        Ok(())
+    }
+
+    #[test]
+    fn control_clients_cannot_sign_in_or_out() {
+        assert!(!is_allowed(&IpcClientMsg::Connect {
+            api_url: "wss://api.firezone.dev".to_owned(),
+            token: "token".to_owned(),
+        }));
+        assert!(!is_allowed(&IpcClientMsg::Disconnect));
+        assert!(is_allowed(&IpcClientMsg::Reset));
+    }
+}
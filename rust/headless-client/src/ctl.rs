@@ -0,0 +1,166 @@
+//! `firezone-headless-client ctl`, to query and control a running headless Client or IPC service
+
+use anyhow::{bail, Context as _, Result};
+use connlib_model::{ResourceId, ResourceView};
+use firezone_bin_shared::log_rotation::parse_size;
+use firezone_headless_client::{
+    control::ControlClient, ipc::ServiceId, IpcClientMsg, IpcServerMsg, IpcStatus,
+};
+use std::path::Path;
+
+#[derive(clap::Subcommand, Clone)]
+pub(crate) enum Cmd {
+    /// Show whether the tunnel is connected and how many Resources are enabled
+    Status,
+    /// List all Resources and whether they are enabled
+    Resources,
+    /// Enable a Resource that was disabled
+    Enable { id: ResourceId },
+    /// Disable a Resource, so its traffic no longer goes through Firezone
+    Disable { id: ResourceId },
+    /// Reconnect to the portal and the Gateways
+    Reset,
+    /// Change the log filter until the next restart, e.g. `debug` or `info,firezone_tunnel=trace`
+    SetLogFilter { directives: String },
+    /// Delete all log files except the most recent
+    ClearLogs,
+    /// Capture the traffic of the tunnel into a pcapng file in the log directory
+    StartCapture {
+        /// Stop capturing once the file reaches this size, e.g. `100M`.
+        #[arg(long, default_value = "100M", value_parser = parse_size)]
+        max_size: u64,
+    },
+    /// Stop a running packet capture
+    StopCapture,
+}
+
+pub(crate) fn run(cmd: &Cmd, id: ServiceId) -> Result<()> {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    rt.block_on(async {
+        let mut client = ControlClient::connect(id)
+            .await
+            .context("Couldn't connect to the control socket, is Firezone running?")?;
+
+        match cmd {
+            Cmd::Status => print_status(&client.status().await?),
+            Cmd::Resources => print_resources(&client.status().await?),
+            Cmd::Enable { id } => set_resource_disabled(&mut client, *id, false).await?,
+            Cmd::Disable { id } => set_resource_disabled(&mut client, *id, true).await?,
+            Cmd::Reset => {
+                client.send(&IpcClientMsg::Reset).await?;
+                print_status(&client.status().await?);
+            }
+            Cmd::SetLogFilter { directives } => {
+                // Check locally, the tunnel would only log the error.
+                firezone_logging::try_filter(directives).context("Invalid log filter")?;
+
+                client
+                    .send(&IpcClientMsg::ApplyLogFilter {
+                        directives: directives.clone(),
+                    })
+                    .await?;
+                client.status().await?;
+            }
+            Cmd::ClearLogs => match client.request(&IpcClientMsg::ClearLogs).await? {
+                IpcServerMsg::ClearedLogs(Ok(())) => {}
+                IpcServerMsg::ClearedLogs(Err(e)) => bail!("Failed to clear logs: {e}"),
+                other => bail!("Unexpected response `{other}` to `ClearLogs`"),
+            },
+            Cmd::StartCapture { max_size } => match client
+                .request(&IpcClientMsg::StartPacketCapture {
+                    max_size: *max_size,
+                })
+                .await?
+            {
+                IpcServerMsg::PacketCaptureStarted(Ok(path)) => print_capture_path(&path),
+                IpcServerMsg::PacketCaptureStarted(Err(e)) => {
+                    bail!("Failed to start packet capture: {e}")
+                }
+                other => bail!("Unexpected response `{other}` to `StartPacketCapture`"),
+            },
+            Cmd::StopCapture => {
+                client.send(&IpcClientMsg::StopPacketCapture).await?;
+                client.status().await?;
+            }
+        }
+
+        Ok(())
+    })
+}
+
+async fn set_resource_disabled(
+    client: &mut ControlClient,
+    id: ResourceId,
+    disabled: bool,
+) -> Result<()> {
+    let mut status = client.status().await?;
+
+    if !status.resources.iter().any(|r| r.id() == id) {
+        bail!("There is no Resource with ID {id}");
+    }
+
+    if disabled {
+        status.disabled_resources.insert(id);
+    } else {
+        status.disabled_resources.remove(&id);
+    }
+
+    client
+        .send(&IpcClientMsg::SetDisabledResources(
+            status.disabled_resources,
+        ))
+        .await?;
+    print_resources(&client.status().await?);
+
+    Ok(())
+}
+
+#[expect(clippy::print_stdout, reason = "This is the output of the CLI")]
+fn print_status(status: &IpcStatus) {
+    let num_disabled = status
+        .resources
+        .iter()
+        .filter(|r| status.disabled_resources.contains(&r.id()))
+        .count();
+
+    println!("Tunnel: {}", status.state);
+    println!(
+        "Resources: {} enabled, {num_disabled} disabled",
+        status.resources.len() - num_disabled
+    );
+}
+
+#[expect(clippy::print_stdout, reason = "This is the output of the CLI")]
+fn print_resources(status: &IpcStatus) {
+    for resource in &status.resources {
+        let enabled = if status.disabled_resources.contains(&resource.id()) {
+            "disabled"
+        } else {
+            "enabled"
+        };
+
+        println!(
+            "{}  {enabled:<8}  {:<7}  {} ({})",
+            resource.id(),
+            resource.status().to_string(),
+            resource.name(),
+            address(resource)
+        );
+    }
+}
+
+#[expect(clippy::print_stdout, reason = "This is the output of the CLI")]
+fn print_capture_path(path: &Path) {
+    println!("Capturing packets to {}", path.display());
+}
+
+fn address(resource: &ResourceView) -> String {
+    if resource.is_internet_resource() {
+        return "Internet".to_owned();
+    }
+
+    resource.pastable().into_owned()
+}
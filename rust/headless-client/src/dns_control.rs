@@ -28,6 +28,10 @@ use platform::system_resolvers;
 /// Only one of these should exist on the entire system at a time.
 pub struct DnsController {
     pub dns_control_method: DnsControlMethod,
+    /// Name of our TUN interface, for `systemd-resolved`.
+    ///
+    /// Unused on Windows.
+    pub tun_name: String,
 }
 
 impl Drop for DnsController {
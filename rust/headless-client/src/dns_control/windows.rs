@@ -248,7 +248,8 @@ mod tests {
             .build()
             .unwrap();
 
-        let mut tun_dev_manager = firezone_bin_shared::TunDeviceManager::new(1280, 1).unwrap(); // Note: num_threads (`1`) is unused on windows.
+        let mut tun_dev_manager =
+            firezone_bin_shared::TunDeviceManager::new(1280, 1, Default::default()).unwrap(); // Note: num_threads (`1`) is unused on windows.
         let _tun = tun_dev_manager.make_tun().unwrap();
 
         rt.block_on(async {
@@ -263,6 +264,7 @@ mod tests {
 
         let mut dns_controller = DnsController {
             dns_control_method: DnsControlMethod::Nrpt,
+            tun_name: String::new(),
         };
 
         let fz_dns_servers = vec![
@@ -1,14 +1,15 @@
 use crate::{
-    device_id, dns_control::DnsController, known_dirs, signals, CallbackHandler, CliCommon,
-    ConnlibMsg, LogFilterReloader,
+    control::{ControlRequest, ControlServer},
+    device_id,
+    dns_control::DnsController,
+    known_dirs, signals, CallbackHandler, CliCommon, ConnlibMsg, LogFilterReloader,
 };
 use anyhow::{bail, Context as _, Result};
 use atomicwrites::{AtomicFile, OverwriteBehavior};
 use clap::Parser;
-use connlib_model::ResourceView;
+use connlib_model::{GatewayConnectionView, ResourceView};
 use firezone_bin_shared::{
-    platform::{tcp_socket_factory, udp_socket_factory, DnsControlMethod},
-    TunDeviceManager, TOKEN_ENV_KEY,
+    packet_capture, platform::DnsControlMethod, TunDeviceConfig, TunDeviceManager, TOKEN_ENV_KEY,
 };
 use firezone_logging::{err_with_src, sentry_layer, telemetry_span};
 use firezone_telemetry::Telemetry;
@@ -22,11 +23,11 @@ use secrecy::SecretString;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::BTreeSet,
+    fmt,
     io::{self, Write},
     net::IpAddr,
     path::PathBuf,
     pin::pin,
-    sync::Arc,
     time::Duration,
 };
 use tokio::{sync::mpsc, time::Instant};
@@ -37,7 +38,7 @@ pub mod ipc;
 use backoff::ExponentialBackoffBuilder;
 use connlib_model::ResourceId;
 use ipc::{Server as IpcServer, ServiceId};
-use phoenix_channel::{get_user_agent, PhoenixChannel};
+use phoenix_channel::{get_user_agent, PhoenixChannel, ProxyConfig};
 use secrecy::Secret;
 
 #[cfg(target_os = "linux")]
@@ -93,9 +94,18 @@ pub enum ClientMsg {
     ApplyLogFilter {
         directives: String,
     },
+    /// Asks for a [`ServerMsg::Status`]
+    GetStatus,
     Reset,
     SetDns(Vec<IpAddr>),
     SetDisabledResources(BTreeSet<ResourceId>),
+    /// Starts capturing the traffic of the tunnel into a new pcapng file of at most `max_size` bytes.
+    ///
+    /// The file is created in the log directory, the response is a [`ServerMsg::PacketCaptureStarted`].
+    StartPacketCapture {
+        max_size: u64,
+    },
+    StopPacketCapture,
     StartTelemetry {
         environment: String,
         release: String,
@@ -115,6 +125,12 @@ pub enum ServerMsg {
         is_authentication_error: bool,
     },
     OnUpdateResources(Vec<ResourceView>),
+    /// Path, RTT and traffic of each connection to a Gateway, sent periodically.
+    OnUpdateConnectionStats(Vec<GatewayConnectionView>),
+    /// Response to [`ClientMsg::StartPacketCapture`], with the path of the capture file.
+    PacketCaptureStarted(Result<PathBuf, String>),
+    /// Response to [`ClientMsg::GetStatus`]
+    Status(Status),
     /// The IPC service is terminating, maybe due to a software update
     ///
     /// This is a hint that the Client should exit with a message like,
@@ -125,6 +141,32 @@ pub enum ServerMsg {
     TunnelReady,
 }
 
+/// A snapshot of the tunnel, e.g. for `firezone-headless-client ctl status`
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct Status {
+    pub state: TunnelState,
+    pub resources: Vec<ResourceView>,
+    pub disabled_resources: BTreeSet<ResourceId>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum TunnelState {
+    #[default]
+    SignedOut,
+    Connecting,
+    Connected,
+}
+
+impl fmt::Display for TunnelState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TunnelState::SignedOut => write!(f, "signed out"),
+            TunnelState::Connecting => write!(f, "connecting"),
+            TunnelState::Connected => write!(f, "connected"),
+        }
+    }
+}
+
 // All variants are `String` because almost no error type implements `Serialize`
 #[derive(Debug, Deserialize, Serialize, thiserror::Error)]
 pub enum Error {
@@ -176,6 +218,7 @@ fn run_debug_ipc_service(cli: Cli) -> Result<()> {
     if !platform::elevation_check()? {
         bail!("IPC service failed its elevation check, try running as admin / root");
     }
+    cli.common.tun.enter_netns()?;
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?;
@@ -185,6 +228,10 @@ fn run_debug_ipc_service(cli: Cli) -> Result<()> {
 
     rt.block_on(ipc_listen(
         cli.common.dns_control,
+        cli.common.tun.clone(),
+        cli.common.proxy.config().context("Invalid proxy")?,
+        cli.common.ipc_service_id(),
+        cli.common.control_service_id(),
         &log_filter_reloader,
         &mut signals,
         &mut telemetry,
@@ -215,8 +262,10 @@ fn run_smoke_test() -> Result<()> {
         .enable_all()
         .build()?;
     let _guard = rt.enter();
+    let tun_config = TunDeviceConfig::default();
     let mut dns_controller = DnsController {
         dns_control_method: Default::default(),
+        tun_name: tun_config.name().to_owned(),
     };
     // Deactivate Firezone DNS control in case the system or IPC service crashed
     // and we need to recover. <https://github.com/firezone/firezone/issues/4899>
@@ -228,14 +277,17 @@ fn run_smoke_test() -> Result<()> {
     rt.block_on(async {
         device_id::get_or_create().context("Failed to read / create device ID")?;
         let mut server = IpcServer::new(ServiceId::Prod).await?;
+        let mut control = ControlServer::new(ServiceId::Control).await?;
         let _ = Handler::new(
             &mut server,
             &mut dns_controller,
+            &tun_config,
+            &ProxyConfig::default(),
             &log_filter_reloader,
             &mut telemetry,
         )
         .await?
-        .run(&mut signals)
+        .run(&mut signals, &mut control)
         .await;
         Ok::<_, anyhow::Error>(())
     })
@@ -247,6 +299,10 @@ fn run_smoke_test() -> Result<()> {
 /// client a hint about that before we exit.
 async fn ipc_listen(
     dns_control_method: DnsControlMethod,
+    tun_config: TunDeviceConfig,
+    proxy: ProxyConfig,
+    ipc_id: ServiceId,
+    control_id: ServiceId,
     log_filter_reloader: &LogFilterReloader,
     signals: &mut signals::Terminate,
     telemetry: &mut Telemetry,
@@ -259,47 +315,116 @@ async fn ipc_listen(
 
     Telemetry::set_firezone_id(firezone_id);
 
-    let mut server = IpcServer::new(ServiceId::Prod).await?;
-    let mut dns_controller = DnsController { dns_control_method };
+    let mut server = IpcServer::new(ipc_id).await?;
+    let mut control = ControlServer::new(control_id).await?;
+    platform::notify_service_controller()?;
+
+    let mut dns_controller = DnsController {
+        dns_control_method,
+        tun_name: tun_config.name().to_owned(),
+    };
     loop {
         let mut handler_fut = pin!(Handler::new(
             &mut server,
             &mut dns_controller,
+            &tun_config,
+            &proxy,
             log_filter_reloader,
             telemetry,
         ));
-        let Some(handler) = poll_fn(|cx| {
-            if let Poll::Ready(()) = signals.poll_recv(cx) {
-                Poll::Ready(None)
-            } else if let Poll::Ready(handler) = handler_fut.as_mut().poll(cx) {
-                Poll::Ready(Some(handler))
-            } else {
-                Poll::Pending
+        let handler = loop {
+            let event = poll_fn(|cx| {
+                if let Poll::Ready(()) = signals.poll_recv(cx) {
+                    Poll::Ready(WaitEvent::Terminate)
+                } else if let Poll::Ready(handler) = handler_fut.as_mut().poll(cx) {
+                    Poll::Ready(WaitEvent::Handler(handler))
+                } else if let Poll::Ready(request) = control.poll_recv(cx) {
+                    Poll::Ready(WaitEvent::Control(request))
+                } else {
+                    Poll::Pending
+                }
+            })
+            .await;
+
+            match event {
+                WaitEvent::Terminate => break None,
+                WaitEvent::Handler(handler) => break Some(handler),
+                WaitEvent::Control(request) => {
+                    let request = request.context("Impossible - Control server stopped")?;
+                    handle_control_while_signed_out(request, log_filter_reloader).await;
+                }
             }
-        })
-        .await
-        else {
+        };
+        let Some(handler) = handler else {
             tracing::info!("Caught SIGINT / SIGTERM / Ctrl+C while waiting on the next client.");
             break;
         };
         let mut handler = handler?;
-        if let HandlerOk::ServiceTerminating = handler.run(signals).await {
+        if let HandlerOk::ServiceTerminating = handler.run(signals, &mut control).await {
             break;
         }
     }
     Ok(())
 }
 
+/// What can happen while we wait for a GUI to connect
+enum WaitEvent<'a> {
+    Control(Option<ControlRequest>),
+    Handler(Result<Handler<'a>>),
+    Terminate,
+}
+
+/// Answers control clients while no GUI is connected, so we are always signed out
+async fn handle_control_while_signed_out(
+    request: ControlRequest,
+    log_filter_reloader: &LogFilterReloader,
+) {
+    let ControlRequest { msg, responder } = request;
+
+    let response = match msg {
+        ClientMsg::ClearLogs => Some(ServerMsg::ClearedLogs(clear_service_logs().await)),
+        ClientMsg::GetStatus => Some(ServerMsg::Status(Status::default())),
+        ClientMsg::StartPacketCapture { .. } => Some(ServerMsg::PacketCaptureStarted(Err(
+            "Cannot capture packets while signed out".to_owned(),
+        ))),
+        ClientMsg::ApplyLogFilter { directives } => {
+            if let Err(error) = apply_log_filter(log_filter_reloader, directives) {
+                tracing::error!("Failed to apply log filter: {error:#}");
+            }
+            None
+        }
+        ClientMsg::Connect { .. }
+        | ClientMsg::Disconnect
+        | ClientMsg::Reset
+        | ClientMsg::SetDns(_)
+        | ClientMsg::SetDisabledResources(_)
+        | ClientMsg::StopPacketCapture
+        | ClientMsg::StartTelemetry { .. } => {
+            tracing::debug!(?msg, "Ignoring control message since we're signed out");
+            None
+        }
+    };
+
+    if let Some(response) = response {
+        // The control client may have disconnected in the meantime, that's fine.
+        let _ = responder.send(response);
+    }
+}
+
 /// Handles one IPC client
 struct Handler<'a> {
+    disabled_resources: BTreeSet<ResourceId>,
     dns_controller: &'a mut DnsController,
     ipc_rx: ipc::ServerRead,
     ipc_tx: ipc::ServerWrite,
     last_connlib_start_instant: Option<Instant>,
     log_filter_reloader: &'a LogFilterReloader,
+    resources: Vec<ResourceView>,
     session: Option<Session>,
     telemetry: &'a mut Telemetry, // Handle to the sentry.io telemetry module
+    tun_config: &'a TunDeviceConfig,
     tun_device: TunDeviceManager,
+    proxy: &'a ProxyConfig,
 }
 
 struct Session {
@@ -310,6 +435,8 @@ struct Session {
 enum Event {
     Callback(ConnlibMsg),
     CallbackChannelClosed,
+    Control(ControlRequest),
+    ControlServerStopped,
     Ipc(ClientMsg),
     IpcDisconnected,
     IpcError(anyhow::Error),
@@ -328,6 +455,8 @@ impl<'a> Handler<'a> {
     async fn new(
         server: &mut IpcServer,
         dns_controller: &'a mut DnsController,
+        tun_config: &'a TunDeviceConfig,
+        proxy: &'a ProxyConfig,
         log_filter_reloader: &'a LogFilterReloader,
         telemetry: &'a mut Telemetry,
     ) -> Result<Self> {
@@ -336,17 +465,25 @@ impl<'a> Handler<'a> {
             .next_client_split()
             .await
             .context("Failed to wait for incoming IPC connection from a GUI")?;
-        let tun_device = TunDeviceManager::new(ip_packet::MAX_IP_SIZE, crate::NUM_TUN_THREADS)?;
+        let tun_device = TunDeviceManager::new(
+            ip_packet::MAX_IP_SIZE,
+            crate::NUM_TUN_THREADS,
+            tun_config.clone(),
+        )?;
 
         Ok(Self {
+            disabled_resources: Default::default(),
             dns_controller,
             ipc_rx,
             ipc_tx,
             last_connlib_start_instant: None,
             log_filter_reloader,
+            resources: Default::default(),
             session: None,
             telemetry,
+            tun_config,
             tun_device,
+            proxy,
         })
     }
 
@@ -355,10 +492,16 @@ impl<'a> Handler<'a> {
     /// If the IPC service needs to terminate, we catch that from `signals` and send
     /// the client a hint to shut itself down gracefully.
     ///
+    /// Requests from `control` are handled alongside, their responses only go to the control client.
+    ///
     /// The return type is infallible so that we only give up on an IPC client explicitly
-    async fn run(&mut self, signals: &mut signals::Terminate) -> HandlerOk {
+    async fn run(
+        &mut self,
+        signals: &mut signals::Terminate,
+        control: &mut ControlServer,
+    ) -> HandlerOk {
         loop {
-            match poll_fn(|cx| self.next_event(cx, signals)).await {
+            match poll_fn(|cx| self.next_event(cx, signals, control)).await {
                 Event::Callback(x) => {
                     if let Err(error) = self.handle_connlib_cb(x).await {
                         tracing::error!("Error while handling connlib callback: {error:#}");
@@ -369,12 +512,40 @@ impl<'a> Handler<'a> {
                     tracing::error!("Impossible - Callback channel closed");
                     break HandlerOk::Err;
                 }
+                Event::Control(ControlRequest { msg, responder }) => {
+                    let msg_variant = serde_variant::to_variant_name(&msg)
+                        .expect("IPC messages should be enums, not structs or anything else.");
+                    let _entered =
+                        tracing::error_span!("handle_control_msg", msg = %msg_variant).entered();
+                    match self.handle_ipc_msg(msg).await {
+                        Ok(Some(response)) => {
+                            // The control client may have disconnected in the meantime, that's fine.
+                            let _ = responder.send(response);
+                        }
+                        Ok(None) => {}
+                        Err(error) => {
+                            tracing::error!(
+                                "Error while handling control message from client: {error:#}"
+                            );
+                            continue;
+                        }
+                    }
+                }
+                Event::ControlServerStopped => {
+                    tracing::error!("Impossible - Control server stopped");
+                    break HandlerOk::Err;
+                }
                 Event::Ipc(msg) => {
                     let msg_variant = serde_variant::to_variant_name(&msg)
                         .expect("IPC messages should be enums, not structs or anything else.");
                     let _entered =
                         tracing::error_span!("handle_ipc_msg", msg = %msg_variant).entered();
-                    if let Err(error) = self.handle_ipc_msg(msg).await {
+                    let result = match self.handle_ipc_msg(msg).await {
+                        Ok(Some(response)) => self.send_ipc(response).await,
+                        Ok(None) => Ok(()),
+                        Err(error) => Err(error),
+                    };
+                    if let Err(error) = result {
                         tracing::error!("Error while handling IPC message from client: {error:#}");
                         continue;
                     }
@@ -403,6 +574,7 @@ impl<'a> Handler<'a> {
         &mut self,
         cx: &mut Context<'_>,
         signals: &mut signals::Terminate,
+        control: &mut ControlServer,
     ) -> Poll<Event> {
         // `recv` on signals is cancel-safe.
         if let Poll::Ready(()) = signals.poll_recv(cx) {
@@ -416,6 +588,13 @@ impl<'a> Handler<'a> {
                 None => Event::IpcDisconnected,
             });
         }
+        // `tokio::sync::mpsc::Receiver::recv` is cancel-safe.
+        if let Poll::Ready(option) = control.poll_recv(cx) {
+            return Poll::Ready(match option {
+                Some(x) => Event::Control(x),
+                None => Event::ControlServerStopped,
+            });
+        }
         if let Some(session) = self.session.as_mut() {
             // `tokio::sync::mpsc::Receiver::recv` is cancel-safe.
             if let Poll::Ready(option) = session.cb_rx.poll_recv(cx) {
@@ -438,6 +617,7 @@ impl<'a> Handler<'a> {
                     // Identical to dropping, but looks nicer
                     session.connlib.disconnect();
                 }
+                self.resources.clear();
                 self.dns_controller.deactivate()?;
                 self.send_ipc(ServerMsg::OnDisconnect {
                     error_msg,
@@ -465,29 +645,28 @@ impl<'a> Handler<'a> {
             ConnlibMsg::OnUpdateResources(resources) => {
                 // On every resources update, flush DNS to mitigate <https://github.com/firezone/firezone/issues/5052>
                 self.dns_controller.flush()?;
+                self.resources.clone_from(&resources);
                 self.send_ipc(ServerMsg::OnUpdateResources(resources))
                     .await?;
             }
+            ConnlibMsg::OnUpdateConnectionStats(connections) => {
+                self.send_ipc(ServerMsg::OnUpdateConnectionStats(connections))
+                    .await?;
+            }
         }
         Ok(())
     }
 
-    async fn handle_ipc_msg(&mut self, msg: ClientMsg) -> Result<()> {
-        match msg {
-            ClientMsg::ClearLogs => {
-                let result = crate::clear_logs(
-                    &crate::known_dirs::ipc_service_logs().context("Can't compute logs dir")?,
-                )
-                .await;
-                self.send_ipc(ServerMsg::ClearedLogs(result.map_err(|e| e.to_string())))
-                    .await?
-            }
+    /// Handles a message from the GUI or a control client and returns the response, if the message has one.
+    async fn handle_ipc_msg(&mut self, msg: ClientMsg) -> Result<Option<ServerMsg>> {
+        let response = match msg {
+            ClientMsg::ClearLogs => ServerMsg::ClearedLogs(clear_service_logs().await),
             ClientMsg::Connect { api_url, token } => {
                 // Warning: Connection errors don't bubble to callers of `handle_ipc_msg`.
                 let token = secrecy::SecretString::from(token);
                 let result = self.connect_to_firezone(&api_url, token);
 
-                self.send_ipc(ServerMsg::ConnectResult(result)).await?
+                ServerMsg::ConnectResult(result)
             }
             ClientMsg::Disconnect => {
                 if let Some(session) = self.session.take() {
@@ -495,50 +674,77 @@ impl<'a> Handler<'a> {
                     session.connlib.disconnect();
                     self.dns_controller.deactivate()?;
                 }
+                self.resources.clear();
                 // Always send `DisconnectedGracefully` even if we weren't connected,
                 // so this will be idempotent.
-                self.send_ipc(ServerMsg::DisconnectedGracefully).await?;
+                ServerMsg::DisconnectedGracefully
             }
             ClientMsg::ApplyLogFilter { directives } => {
-                self.log_filter_reloader.reload(directives.clone())?;
-
-                let path = known_dirs::ipc_log_filter()?;
-
-                if let Err(e) = AtomicFile::new(&path, OverwriteBehavior::AllowOverwrite)
-                    .write(|f| f.write_all(directives.as_bytes()))
-                {
-                    tracing::warn!(path = %path.display(), %directives, "Failed to write new log directives: {}", err_with_src(&e));
-                }
+                apply_log_filter(self.log_filter_reloader, directives)?;
+                return Ok(None);
             }
+            ClientMsg::GetStatus => ServerMsg::Status(self.status()),
             ClientMsg::Reset => {
                 if self.last_connlib_start_instant.is_some() {
                     tracing::debug!("Ignoring reset since we're still signing in");
-                    return Ok(());
+                    return Ok(None);
                 }
                 let Some(session) = self.session.as_ref() else {
                     tracing::debug!("Cannot reset if we're signed out");
-                    return Ok(());
+                    return Ok(None);
                 };
 
                 session.connlib.reset();
+                return Ok(None);
             }
             ClientMsg::SetDns(resolvers) => {
                 let Some(session) = self.session.as_ref() else {
                     tracing::debug!("Cannot set DNS resolvers if we're signed out");
-                    return Ok(());
+                    return Ok(None);
                 };
 
                 tracing::debug!(?resolvers);
                 session.connlib.set_dns(resolvers);
+                return Ok(None);
             }
             ClientMsg::SetDisabledResources(disabled_resources) => {
+                self.disabled_resources.clone_from(&disabled_resources);
+
                 let Some(session) = self.session.as_ref() else {
                     // At this point, the GUI has already saved the disabled Resources to disk, so it'll be correct on the next sign-in anyway.
                     tracing::debug!("Cannot set disabled resources if we're signed out");
-                    return Ok(());
+                    return Ok(None);
                 };
 
                 session.connlib.set_disabled_resources(disabled_resources);
+                return Ok(None);
+            }
+            ClientMsg::StartPacketCapture { max_size } => {
+                let Some(session) = self.session.as_ref() else {
+                    return Ok(Some(ServerMsg::PacketCaptureStarted(Err(
+                        "Cannot capture packets while signed out".to_owned(),
+                    ))));
+                };
+
+                let result = known_dirs::ipc_service_logs()
+                    .context("Can't compute logs dir")
+                    .and_then(|dir| packet_capture::create_capture_file(&dir))
+                    .map(|(file, path)| {
+                        session.connlib.start_packet_capture(file, max_size);
+                        path
+                    })
+                    .map_err(|e| format!("{e:#}"));
+
+                ServerMsg::PacketCaptureStarted(result)
+            }
+            ClientMsg::StopPacketCapture => {
+                let Some(session) = self.session.as_ref() else {
+                    tracing::debug!("Cannot stop packet capture if we're signed out");
+                    return Ok(None);
+                };
+
+                session.connlib.stop_packet_capture();
+                return Ok(None);
             }
             ClientMsg::StartTelemetry {
                 environment,
@@ -551,9 +757,24 @@ impl<'a> Handler<'a> {
                 if let Some(account_slug) = account_slug {
                     Telemetry::set_account_slug(account_slug);
                 }
+                return Ok(None);
             }
+        };
+        Ok(Some(response))
+    }
+
+    fn status(&self) -> Status {
+        let state = match (&self.session, self.last_connlib_start_instant) {
+            (None, _) => TunnelState::SignedOut,
+            (Some(_), Some(_)) => TunnelState::Connecting,
+            (Some(_), None) => TunnelState::Connected,
+        };
+
+        Status {
+            state,
+            resources: self.resources.clone(),
+            disabled_resources: self.disabled_resources.clone(),
         }
-        Ok(())
     }
 
     /// Connects connlib
@@ -595,14 +816,15 @@ impl<'a> Handler<'a> {
                     .with_max_elapsed_time(Some(Duration::from_secs(60 * 60 * 24 * 30)))
                     .build()
             },
-            Arc::new(tcp_socket_factory),
+            self.tun_config.tcp_socket_factory(),
+            self.proxy.clone(),
         )?; // Turn this `io::Error` directly into an `Error` so we can distinguish it from others in the GUI client.
 
         // Read the resolvers before starting connlib, in case connlib's startup interferes.
         let dns = self.dns_controller.system_resolvers();
         let connlib = connlib_client_shared::Session::connect(
-            Arc::new(tcp_socket_factory),
-            Arc::new(udp_socket_factory),
+            self.tun_config.tcp_socket_factory(),
+            self.tun_config.udp_socket_factory(),
             callbacks,
             portal,
             tokio::runtime::Handle::current(),
@@ -636,12 +858,36 @@ impl<'a> Handler<'a> {
     }
 }
 
+async fn clear_service_logs() -> Result<(), String> {
+    let dir = known_dirs::ipc_service_logs()
+        .context("Can't compute logs dir")
+        .map_err(|e| format!("{e:#}"))?;
+
+    crate::clear_logs(&dir).await.map_err(|e| e.to_string())
+}
+
+/// Applies new log directives and saves them so they survive a restart
+fn apply_log_filter(log_filter_reloader: &LogFilterReloader, directives: String) -> Result<()> {
+    log_filter_reloader.reload(directives.clone())?;
+
+    let path = known_dirs::ipc_log_filter()?;
+
+    if let Err(e) = AtomicFile::new(&path, OverwriteBehavior::AllowOverwrite)
+        .write(|f| f.write_all(directives.as_bytes()))
+    {
+        tracing::warn!(path = %path.display(), %directives, "Failed to write new log directives: {}", err_with_src(&e));
+    }
+
+    Ok(())
+}
+
 /// Starts logging for the production IPC service
 ///
 /// Returns: A `Handle` that must be kept alive. Dropping it stops logging
 /// and flushes the log file.
 fn setup_logging(
     log_dir: Option<PathBuf>,
+    rotation: firezone_logging::file::Rotation,
 ) -> Result<(firezone_logging::file::Handle, LogFilterReloader)> {
     // If `log_dir` is Some, use that. Else call `ipc_service_logs`
     let log_dir = log_dir.map_or_else(
@@ -651,7 +897,8 @@ fn setup_logging(
     std::fs::create_dir_all(&log_dir)
         .context("We should have permissions to create our log dir")?;
 
-    let (layer, handle) = firezone_logging::file::layer(&log_dir, "ipc-service");
+    let (layer, handle) =
+        firezone_logging::file::layer_with_rotation(&log_dir, "ipc-service", rotation);
 
     let directives = get_log_filter().context("Couldn't read log filter")?;
     let (filter, reloader) = reload::Layer::new(firezone_logging::try_filter(&directives)?);
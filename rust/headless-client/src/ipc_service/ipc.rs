@@ -1,5 +1,7 @@
 use crate::{IpcClientMsg, IpcServerMsg};
 use anyhow::{Context as _, Result};
+#[cfg(target_os = "linux")]
+use std::path::PathBuf;
 use tokio::io::{ReadHalf, WriteHalf};
 use tokio_util::{
     bytes::BytesMut,
@@ -54,13 +56,19 @@ pub enum Error {
 ///
 /// Because the paths are so different (and Windows actually uses a `String`),
 /// we have this `ServiceId` abstraction instead of just a `PathBuf`.
-#[derive(Clone, Copy)]
+#[derive(Clone, Debug)]
 pub enum ServiceId {
     /// The IPC service used by Firezone GUI Client in production
     ///
     /// This must go in `/run/dev.firezone.client` on Linux, which requires
     /// root permission
     Prod,
+    /// The control socket of the headless Client and the IPC service
+    ///
+    /// `firezone-headless-client ctl` connects to this to query and control
+    /// whichever of the two is running. Like `Prod`, this goes in
+    /// `/run/dev.firezone.client` on Linux.
+    Control,
     /// An IPC service used for unit tests.
     ///
     /// This must go in `/run/user/$UID/dev.firezone.client` on Linux so
@@ -71,6 +79,11 @@ pub enum ServiceId {
     ///
     /// The ID should have A-Z, 0-9 only, no dots or slashes, because of Windows named pipes name restrictions.
     Test(&'static str),
+    /// A Unix Domain Socket at a path given by the user
+    ///
+    /// Allows running multiple instances of the IPC service or headless Client on one host.
+    #[cfg(target_os = "linux")]
+    Custom(PathBuf),
 }
 
 pub struct Decoder<D> {
@@ -134,7 +147,7 @@ pub async fn connect_to_service(id: ServiceId) -> Result<(ClientRead, ClientWrit
     let mut last_err = None;
 
     for _ in 0..10 {
-        match platform::connect_to_service(id).await {
+        match platform::connect_to_service(id.clone()).await {
             Ok(stream) => {
                 let (rx, tx) = tokio::io::split(stream);
                 let rx = FramedRead::new(rx, Decoder::default());
@@ -57,9 +57,6 @@ impl Server {
         let perms = std::fs::Permissions::from_mode(0o660);
         tokio::fs::set_permissions(&sock_path, perms).await?;
 
-        // TODO: Change this to `notify_service_controller` and put it in
-        // the same place in the IPC service's main loop as in the Headless Client.
-        sd_notify::notify(true, &[sd_notify::NotifyState::Ready])?;
         Ok(Self { listener })
     }
 
@@ -89,8 +86,10 @@ impl Server {
 fn ipc_path(id: ServiceId) -> PathBuf {
     match id {
         ServiceId::Prod => PathBuf::from("/run").join(BUNDLE_ID).join("ipc.sock"),
+        ServiceId::Control => PathBuf::from("/run").join(BUNDLE_ID).join("ctl.sock"),
         ServiceId::Test(id) => crate::known_dirs::runtime()
             .expect("`known_dirs::runtime()` should always work")
             .join(format!("ipc_test_{id}.sock")),
+        ServiceId::Custom(path) => path,
     }
 }
@@ -158,6 +158,7 @@ fn create_pipe_server(pipe_path: &str) -> Result<named_pipe::NamedPipeServer, Pi
 fn ipc_path(id: ServiceId) -> String {
     let name = match id {
         ServiceId::Prod => format!("{BUNDLE_ID}.ipc_service"),
+        ServiceId::Control => format!("{BUNDLE_ID}.ctl"),
         ServiceId::Test(id) => format!("{BUNDLE_ID}_test_{id}.ipc_service"),
     };
     named_pipe_path(&name)
@@ -1,6 +1,6 @@
 use super::CliCommon;
 use crate::signals;
-use anyhow::{bail, Result};
+use anyhow::{bail, Context as _, Result};
 
 use firezone_telemetry::Telemetry;
 
@@ -8,10 +8,12 @@ use firezone_telemetry::Telemetry;
 ///
 /// Linux uses the CLI args from here, Windows does not
 pub(crate) fn run_ipc_service(cli: CliCommon) -> Result<()> {
-    let (_handle, log_filter_reloader) = super::setup_logging(cli.log_dir)?;
+    let (_handle, log_filter_reloader) =
+        super::setup_logging(cli.log_dir.clone(), cli.log_rotation.rotation())?;
     if !elevation_check()? {
         bail!("IPC service failed its elevation check, try running as admin / root");
     }
+    cli.tun.enter_netns()?;
     let rt = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?;
@@ -21,6 +23,10 @@ pub(crate) fn run_ipc_service(cli: CliCommon) -> Result<()> {
 
     rt.block_on(super::ipc_listen(
         cli.dns_control,
+        cli.tun.clone(),
+        cli.proxy.config().context("Invalid proxy")?,
+        cli.ipc_service_id(),
+        cli.control_service_id(),
         &log_filter_reloader,
         &mut signals,
         &mut telemetry,
@@ -40,6 +46,10 @@ pub(crate) fn elevation_check() -> Result<bool> {
     Ok(nix::unistd::getuid().is_root())
 }
 
+pub(crate) fn notify_service_controller() -> Result<()> {
+    Ok(sd_notify::notify(true, &[sd_notify::NotifyState::Ready])?)
+}
+
 pub(crate) fn install_ipc_service() -> Result<()> {
     bail!("`install_ipc_service` not implemented and not needed on Linux")
 }
@@ -1,8 +1,10 @@
+use super::ipc::ServiceId;
 use crate::CliCommon;
 use anyhow::{bail, Context as _, Result};
-use firezone_bin_shared::platform::DnsControlMethod;
+use firezone_bin_shared::{platform::DnsControlMethod, TunDeviceConfig};
 use firezone_telemetry::Telemetry;
 use futures::channel::mpsc;
+use phoenix_channel::ProxyConfig;
 use std::{
     ffi::{c_void, OsStr, OsString},
     mem::size_of,
@@ -147,6 +149,12 @@ impl Drop for ProcessToken {
     }
 }
 
+// Does nothing, `fallible_service_run` already told the service controller that we're running.
+#[expect(clippy::unnecessary_wraps)]
+pub(crate) fn notify_service_controller() -> Result<()> {
+    Ok(())
+}
+
 pub(crate) fn install_ipc_service() -> Result<()> {
     let manager_access = ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE;
     let service_manager = ServiceManager::local_computer(None::<&str>, manager_access)?;
@@ -200,7 +208,7 @@ fn service_run(arguments: Vec<OsString>) {
     // `arguments` doesn't seem to work right when running as a Windows service
     // (even though it's meant for that) so just use the default log dir.
     let (handle, log_filter_reloader) =
-        super::setup_logging(None).expect("Should be able to set up logging");
+        super::setup_logging(None, Default::default()).expect("Should be able to set up logging");
     if let Err(error) = fallible_service_run(arguments, handle, log_filter_reloader) {
         tracing::error!("`fallible_windows_service_run` returned an error: {error:#}");
     }
@@ -338,6 +346,10 @@ async fn service_run_async(
     let mut signals = crate::signals::Terminate::from_channel(shutdown_rx);
     super::ipc_listen(
         DnsControlMethod::Nrpt,
+        TunDeviceConfig::default(),
+        ProxyConfig::from_env().context("Invalid proxy in `HTTPS_PROXY` or `ALL_PROXY`")?,
+        ServiceId::Prod,
+        ServiceId::Control,
         log_filter_reloader,
         &mut signals,
         telemetry,
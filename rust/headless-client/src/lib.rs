@@ -12,8 +12,11 @@
 
 use anyhow::{Context as _, Result};
 use connlib_client_shared::Callbacks;
-use connlib_model::ResourceView;
-use firezone_bin_shared::platform::DnsControlMethod;
+use connlib_model::{GatewayConnectionView, ResourceView};
+use firezone_bin_shared::{
+    log_rotation::LogRotationArgs, platform::DnsControlMethod, proxy::ProxyArgs, TunDeviceConfig,
+};
+use ipc_service::ipc::ServiceId;
 use std::{
     net::{IpAddr, Ipv4Addr, Ipv6Addr},
     path::PathBuf,
@@ -22,6 +25,7 @@ use tokio::sync::mpsc;
 use tracing_subscriber::{fmt, layer::SubscriberExt as _, EnvFilter, Layer as _, Registry};
 
 mod clear_logs;
+pub mod control;
 /// Generate a persistent device ID, stores it to disk, and reads it back.
 pub mod device_id;
 // Pub because the GUI reads the system resolvers
@@ -36,7 +40,7 @@ pub use clear_logs::clear_logs;
 pub use dns_control::DnsController;
 pub use ipc_service::{
     ipc, run_only_ipc_service, ClientMsg as IpcClientMsg, Error as IpcServiceError,
-    ServerMsg as IpcServerMsg,
+    ServerMsg as IpcServerMsg, Status as IpcStatus, TunnelState,
 };
 
 use ip_network::{Ipv4Network, Ipv6Network};
@@ -64,10 +68,48 @@ pub struct CliCommon {
     #[arg(short, long, env = "LOG_DIR")]
     pub log_dir: Option<PathBuf>,
 
+    #[command(flatten)]
+    pub log_rotation: LogRotationArgs,
+
     /// Maximum length of time to retry connecting to the portal if we're having internet issues or
     /// it's down. Accepts human times. e.g. "5m" or "1h" or "30d".
     #[arg(short, long, env = "MAX_PARTITION_TIME")]
     pub max_partition_time: Option<humantime::Duration>,
+
+    #[command(flatten)]
+    pub tun: TunDeviceConfig,
+
+    #[command(flatten)]
+    pub proxy: ProxyArgs,
+
+    /// Directory for the IPC and control sockets, to run multiple instances on one host.
+    ///
+    /// Defaults to `/run/dev.firezone.client`.
+    #[cfg(target_os = "linux")]
+    #[arg(long, env = "FIREZONE_IPC_DIR")]
+    pub ipc_dir: Option<PathBuf>,
+}
+
+impl CliCommon {
+    /// Where the IPC service listens for the GUI
+    pub fn ipc_service_id(&self) -> ServiceId {
+        #[cfg(target_os = "linux")]
+        if let Some(dir) = &self.ipc_dir {
+            return ServiceId::Custom(dir.join("ipc.sock"));
+        }
+
+        ServiceId::Prod
+    }
+
+    /// Where the IPC service or headless Client listens for `ctl`
+    pub fn control_service_id(&self) -> ServiceId {
+        #[cfg(target_os = "linux")]
+        if let Some(dir) = &self.ipc_dir {
+            return ServiceId::Custom(dir.join("ctl.sock"));
+        }
+
+        ServiceId::Control
+    }
 }
 
 /// Messages that connlib can produce and send to the headless Client, IPC service, or GUI process.
@@ -89,6 +131,7 @@ pub enum ConnlibMsg {
         ipv6_routes: Vec<Ipv6Network>,
     },
     OnUpdateResources(Vec<ResourceView>),
+    OnUpdateConnectionStats(Vec<GatewayConnectionView>),
 }
 
 #[derive(Clone)]
@@ -131,6 +174,17 @@ impl Callbacks for CallbackHandler {
             .try_send(ConnlibMsg::OnUpdateResources(resources))
             .expect("Should be able to send OnUpdateResources");
     }
+
+    fn on_update_connection_stats(&self, connections: Vec<GatewayConnectionView>) {
+        // Stats are sent periodically, it is fine to drop an update if we are busy.
+        if self
+            .cb_tx
+            .try_send(ConnlibMsg::OnUpdateConnectionStats(connections))
+            .is_err()
+        {
+            tracing::debug!("Dropping connection stats update");
+        }
+    }
 }
 
 /// Sets up logging for stdout only, with INFO level by default
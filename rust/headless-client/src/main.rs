@@ -5,16 +5,16 @@
 use anyhow::{anyhow, Context as _, Result};
 use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
-use connlib_client_shared::Session;
+use connlib_client_shared::{CandidatePolicy, Session, SplitDnsRule};
 use firezone_bin_shared::{
-    new_dns_notifier, new_network_notifier,
-    platform::{tcp_socket_factory, udp_socket_factory},
-    TunDeviceManager, TOKEN_ENV_KEY,
+    http_health_check, new_dns_notifier, new_network_notifier, TunDeviceManager, TOKEN_ENV_KEY,
 };
 use firezone_headless_client::{
-    device_id, signals, CallbackHandler, CliCommon, ConnlibMsg, DnsController,
+    control::{ControlRequest, ControlServer},
+    device_id, signals, CallbackHandler, CliCommon, ConnlibMsg, DnsController, IpcClientMsg,
+    IpcServerMsg, IpcStatus, TunnelState,
 };
-use firezone_logging::telemetry_span;
+use firezone_logging::{telemetry_span, FilterReloadHandle};
 use firezone_telemetry::Telemetry;
 use futures::StreamExt as _;
 use phoenix_channel::get_user_agent;
@@ -22,12 +22,15 @@ use phoenix_channel::LoginUrl;
 use phoenix_channel::PhoenixChannel;
 use secrecy::{Secret, SecretString};
 use std::{
+    collections::BTreeSet,
+    net::{IpAddr, SocketAddr},
     path::{Path, PathBuf},
-    sync::Arc,
 };
 use tokio::{sync::mpsc, time::Instant};
 use tokio_stream::wrappers::ReceiverStream;
 
+mod ctl;
+
 #[cfg(target_os = "linux")]
 #[path = "linux.rs"]
 mod platform;
@@ -42,10 +45,8 @@ use platform::default_token_path;
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    // Needed to preserve CLI arg compatibility
-    // TODO: Remove when we can break CLI compatibility for headless Clients
     #[command(subcommand)]
-    _command: Option<Cmd>,
+    command: Option<Cmd>,
 
     #[command(flatten)]
     common: CliCommon,
@@ -93,6 +94,55 @@ struct Cli {
     // on disk somewhere anyway.)
     #[arg(default_value = default_token_path().display().to_string(), env = "FIREZONE_TOKEN_PATH", long)]
     token_path: PathBuf,
+
+    /// Serve metrics in the Prometheus format at `http://<metrics_addr>/metrics`.
+    #[arg(long, env = "FIREZONE_METRICS_ADDR", hide = true)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Force all connections to Gateways through a relay.
+    ///
+    /// Useful for reproducing issues that only occur on relayed connections.
+    #[arg(
+        long,
+        env = "FIREZONE_RELAY_ONLY",
+        hide = true,
+        default_value_t = false
+    )]
+    relay_only: bool,
+
+    /// Send DNS queries for a domain and its subdomains to a specific server, e.g. `corp.internal=10.0.0.53`.
+    ///
+    /// Wildcards work like in the address of a DNS resource, e.g. `*.lab=192.168.1.1:5353`.
+    /// These rules win over the portal's rules for the same domain.
+    #[arg(
+        long,
+        env = "FIREZONE_SPLIT_DNS",
+        value_delimiter = ',',
+        value_parser = parse_split_dns_rule
+    )]
+    split_dns: Vec<SplitDnsRule>,
+}
+
+fn parse_split_dns_rule(s: &str) -> Result<SplitDnsRule, String> {
+    let (domain, server) = s
+        .split_once('=')
+        .ok_or_else(|| format!("`{s}` is not of the form `DOMAIN=SERVER`"))?;
+
+    if domain.is_empty() {
+        return Err(format!("`{s}` has no domain"));
+    }
+
+    let server = match server.parse::<IpAddr>() {
+        Ok(ip) => SocketAddr::new(ip, 53),
+        Err(_) => server
+            .parse::<SocketAddr>()
+            .map_err(|_| format!("`{server}` is neither an IP address nor a socket address"))?,
+    };
+
+    Ok(SplitDnsRule {
+        domain: domain.trim_end_matches('.').to_owned(),
+        server: server.into(),
+    })
 }
 
 impl Cli {
@@ -101,9 +151,16 @@ impl Cli {
     }
 }
 
-#[derive(clap::Subcommand, Clone, Copy)]
-#[clap(hide = true)]
+#[derive(clap::Subcommand, Clone)]
 enum Cmd {
+    /// Query and control a running headless Client or IPC service
+    Ctl {
+        #[command(subcommand)]
+        command: ctl::Cmd,
+    },
+    // Needed to preserve CLI arg compatibility
+    // TODO: Remove when we can break CLI compatibility for headless Clients
+    #[command(hide = true)]
     Standalone,
 }
 
@@ -116,6 +173,10 @@ fn main() -> Result<()> {
 
     let cli = Cli::try_parse()?;
 
+    if let Some(Cmd::Ctl { command }) = &cli.command {
+        return ctl::run(command, cli.common.control_service_id());
+    }
+
     // Modifying the environment of a running process is unsafe. If any other
     // thread is reading or writing the environment, something bad can happen.
     // So `run` must take over as early as possible during startup, and
@@ -138,14 +199,27 @@ fn main() -> Result<()> {
         .common
         .log_dir
         .as_deref()
-        .map(|dir| firezone_logging::file::layer(dir, "firezone-headless-client"))
+        .map(|dir| {
+            firezone_logging::file::layer_with_rotation(
+                dir,
+                "firezone-headless-client",
+                cli.common.log_rotation.rotation(),
+            )
+        })
         .unzip();
-    firezone_logging::setup_global_subscriber(layer).context("Failed to set up logging")?;
+    let log_filter_reloader =
+        firezone_logging::setup_global_subscriber(layer).context("Failed to set up logging")?;
+
+    // Before starting any threads that talk to the network, they inherit our namespace.
+    cli.common.tun.enter_netns()?;
 
     // Deactivate DNS control before starting telemetry or connecting to the portal,
     // in case a previous run of Firezone left DNS control on and messed anything up.
     let dns_control_method = cli.common.dns_control;
-    let mut dns_controller = DnsController { dns_control_method };
+    let mut dns_controller = DnsController {
+        dns_control_method,
+        tun_name: cli.common.tun.name().to_owned(),
+    };
     // Deactivate Firezone DNS control in case the system or IPC service crashed
     // and we need to recover. <https://github.com/firezone/firezone/issues/4899>
     dns_controller.deactivate()?;
@@ -203,6 +277,12 @@ fn main() -> Result<()> {
     rt.block_on(async {
         let connect_span = telemetry_span!("connect_to_firezone").entered();
 
+        if let Some(metrics_addr) = cli.metrics_addr {
+            let metrics = http_health_check::install_prometheus_meter_provider();
+
+            tokio::spawn(http_health_check::serve(metrics_addr, || true, metrics));
+        }
+
         // The Headless Client will bail out here if there's no Internet, because `PhoenixChannel` will try to
         // resolve the portal host and fail. This is intentional behavior. The Headless Client should always be running under a manager like `systemd` or Windows' Service Controller,
         // so when it fails it will be restarted with backoff. `systemd` can additionally make us wait
@@ -218,22 +298,38 @@ fn main() -> Result<()> {
                     .with_max_elapsed_time(max_partition_time)
                     .build()
             },
-            Arc::new(tcp_socket_factory),
+            cli.common.tun.tcp_socket_factory(),
+            cli.common.proxy.config().context("Invalid proxy")?,
         )?;
         let session = Session::connect(
-            Arc::new(tcp_socket_factory),
-            Arc::new(udp_socket_factory),
+            cli.common.tun.tcp_socket_factory(),
+            cli.common.tun.udp_socket_factory(),
             callbacks,
             portal,
             rt.handle().clone(),
         );
+        if cli.relay_only {
+            session.set_candidate_policy(CandidatePolicy::RelayOnly);
+        }
+        if !cli.split_dns.is_empty() {
+            session.set_split_dns(cli.split_dns.clone());
+        }
 
         let mut terminate = signals::Terminate::new()?;
         let mut hangup = signals::Hangup::new()?;
 
+        // Not being able to bind the control socket, e.g. because we don't run as root, shouldn't keep us from connecting.
+        let mut control = ControlServer::new(cli.common.control_service_id())
+            .await
+            .inspect_err(|e| tracing::warn!("Failed to start control socket: {e:#}"))
+            .ok();
+        let mut resources = Vec::new();
+        let mut disabled_resources = BTreeSet::new();
+
         let mut tun_device = TunDeviceManager::new(
             ip_packet::MAX_IP_SIZE,
             firezone_headless_client::NUM_TUN_THREADS,
+            cli.common.tun.clone(),
         )?;
         let mut cb_rx = ReceiverStream::new(cb_rx).fuse();
 
@@ -280,6 +376,81 @@ fn main() -> Result<()> {
                     session.reset();
                     continue;
                 },
+                request = next_control_request(&mut control) => {
+                    let Some(ControlRequest { msg, responder }) = request else {
+                        break Err(anyhow!("Impossible - Control server stopped"));
+                    };
+
+                    let response = match msg {
+                        IpcClientMsg::ApplyLogFilter { directives } => {
+                            if let Err(e) = apply_log_filter(&log_filter_reloader, &directives) {
+                                tracing::warn!(%directives, "Failed to apply log filter: {e:#}");
+                            }
+                            None
+                        }
+                        IpcClientMsg::ClearLogs => {
+                            let result = match cli.common.log_dir.as_deref() {
+                                Some(dir) => firezone_headless_client::clear_logs(dir)
+                                    .await
+                                    .map_err(|e| e.to_string()),
+                                None => Ok(()),
+                            };
+                            Some(IpcServerMsg::ClearedLogs(result))
+                        }
+                        IpcClientMsg::GetStatus => {
+                            let state = if last_connlib_start_instant.is_some() {
+                                TunnelState::Connecting
+                            } else {
+                                TunnelState::Connected
+                            };
+
+                            Some(IpcServerMsg::Status(IpcStatus {
+                                state,
+                                resources: resources.clone(),
+                                disabled_resources: disabled_resources.clone(),
+                            }))
+                        }
+                        IpcClientMsg::Reset => {
+                            tracing::info!("Control client asked for reset");
+                            session.reset();
+                            None
+                        }
+                        IpcClientMsg::SetDisabledResources(ids) => {
+                            session.set_disabled_resources(ids.clone());
+                            disabled_resources = ids;
+                            None
+                        }
+                        IpcClientMsg::StartPacketCapture { max_size } => {
+                            let result = cli
+                                .common
+                                .log_dir
+                                .as_deref()
+                                .context("Packet captures are written to the log dir, start the Client with `--log-dir`")
+                                .and_then(firezone_bin_shared::packet_capture::create_capture_file)
+                                .map(|(file, path)| {
+                                    session.start_packet_capture(file, max_size);
+                                    path
+                                })
+                                .map_err(|e| format!("{e:#}"));
+                            Some(IpcServerMsg::PacketCaptureStarted(result))
+                        }
+                        IpcClientMsg::StopPacketCapture => {
+                            session.stop_packet_capture();
+                            None
+                        }
+                        // The headless Client signs in by itself and `ControlServer` doesn't forward these.
+                        IpcClientMsg::Connect { .. }
+                        | IpcClientMsg::Disconnect
+                        | IpcClientMsg::SetDns(_)
+                        | IpcClientMsg::StartTelemetry { .. } => None,
+                    };
+
+                    if let Some(response) = response {
+                        // The control client may have disconnected in the meantime, that's fine.
+                        let _ = responder.send(response);
+                    }
+                    continue;
+                },
                 cb = cb_rx.next() => cb.context("cb_rx unexpectedly ran empty")?,
             };
 
@@ -289,9 +460,15 @@ fn main() -> Result<()> {
                     error_msg,
                     is_authentication_error: _,
                 } => break Err(anyhow!(error_msg).context("Firezone disconnected")),
-                ConnlibMsg::OnUpdateResources(_) => {
+                ConnlibMsg::OnUpdateResources(new_resources) => {
                     // On every Resources update, flush DNS to mitigate <https://github.com/firezone/firezone/issues/5052>
                     dns_controller.flush()?;
+                    resources = new_resources;
+                }
+                ConnlibMsg::OnUpdateConnectionStats(connections) => {
+                    for c in connections {
+                        tracing::debug!(gateway = %c.gateway_id, path = ?c.path, rtt = ?c.rtt, handshake_age = ?c.handshake_age, tx_bytes = %c.tx_bytes, rx_bytes = %c.rx_bytes, "Connection stats");
+                    }
                 }
                 ConnlibMsg::OnSetInterfaceConfig {
                     ipv4,
@@ -328,6 +505,20 @@ fn main() -> Result<()> {
     })
 }
 
+/// Returns the next request from a control client, or never if we couldn't bind the control socket
+async fn next_control_request(control: &mut Option<ControlServer>) -> Option<ControlRequest> {
+    match control.as_mut() {
+        Some(control) => control.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn apply_log_filter(log_filter_reloader: &FilterReloadHandle, directives: &str) -> Result<()> {
+    log_filter_reloader.reload(firezone_logging::try_filter(directives)?)?;
+
+    Ok(())
+}
+
 /// Read the token from disk if it was not in the environment
 ///
 /// # Returns
@@ -382,7 +573,7 @@ fn read_token_file(path: &Path) -> Result<Option<SecretString>> {
 
 #[cfg(test)]
 mod tests {
-    use super::Cli;
+    use super::{ctl, Cli, Cmd};
     use clap::Parser;
     use std::path::PathBuf;
     use url::Url;
@@ -404,5 +595,52 @@ mod tests {
             Cli::try_parse_from([exe_name, "--check", "--log-dir", "bogus_log_dir"]).unwrap();
         assert!(actual.check);
         assert_eq!(actual.common.log_dir, Some(PathBuf::from("bogus_log_dir")));
+
+        let actual = Cli::try_parse_from([
+            exe_name,
+            "ctl",
+            "disable",
+            "ed29c148-2acf-4ceb-8db5-d796c2671631",
+        ])
+        .unwrap();
+        assert!(matches!(
+            actual.command,
+            Some(Cmd::Ctl {
+                command: ctl::Cmd::Disable { .. }
+            })
+        ));
+
+        let actual = Cli::try_parse_from([
+            exe_name,
+            "--split-dns",
+            "corp.internal=10.0.0.53,*.lab=[::1]:5353",
+        ])
+        .unwrap();
+        assert_eq!(actual.split_dns.len(), 2);
+        assert_eq!(actual.split_dns[0].domain, "corp.internal");
+        assert_eq!(actual.split_dns[1].domain, "*.lab");
+        assert!(Cli::try_parse_from([exe_name, "--split-dns", "corp.internal"]).is_err());
+
+        #[cfg(target_os = "linux")]
+        {
+            let actual = Cli::try_parse_from([
+                exe_name,
+                "--tun-name",
+                "tun-fz-staging",
+                "--ipc-dir",
+                "/run/firezone-staging",
+            ])
+            .unwrap();
+            assert_eq!(actual.common.tun.name(), "tun-fz-staging");
+            assert!(matches!(
+                actual.common.control_service_id(),
+                firezone_headless_client::ipc::ServiceId::Custom(path)
+                    if path == PathBuf::from("/run/firezone-staging/ctl.sock")
+            ));
+
+            assert!(
+                Cli::try_parse_from([exe_name, "--tun-name", "tun-firezone-too-long"]).is_err()
+            );
+        }
     }
 }
@@ -2,10 +2,12 @@
 //!
 //! This module implements a file-based logger for connlib using tracing-appender.
 //!
-//! The log files are never rotated for the duration of the process; this prevents
+//! By default, the log files are never rotated for the duration of the process; this prevents
 //! tracing_appender from trying to prune old log files which triggers privacy
 //! alerts in Apple app store submissions.
 //!
+//! Long-running processes can opt into size- and age-based rotation via [`layer_with_rotation`].
+//!
 //! Since these will be leaving the user's device, these logs should contain *only*
 //! the necessary debugging information, and **not** any sensitive information,
 //! including but not limited to:
@@ -15,8 +17,11 @@
 //! - Device serials
 //! - MAC addresses
 
+use std::ffi::OsString;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
 use std::{fs, io};
 
 use time::OffsetDateTime;
@@ -37,6 +42,32 @@ pub const TIME_FORMAT: &str = "[year]-[month]-[day]-[hour]-[minute]-[second]";
 /// As per docs on [`tracing_appender::non_blocking::DEFAULT_BUFFERED_LINES_LIMIT`], this is a power of 2.
 const MAX_BUFFERED_LINES: usize = 1024;
 
+/// When to start a new log file and which of the old ones to keep.
+///
+/// The default never rotates and never deletes any files.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Rotation {
+    /// Start a new file once the current one has at least this many bytes.
+    pub max_file_size: Option<u64>,
+    /// Start a new file once the current one has been open for this long.
+    pub max_file_age: Option<Duration>,
+    /// How many log files to keep at most, including the current one.
+    ///
+    /// Whenever we start a new file, the oldest files with the same base name are deleted.
+    pub max_files: Option<usize>,
+    /// Gzip log files once we stop writing to them.
+    pub compress: bool,
+}
+
+impl Rotation {
+    fn is_due(&self, current: &CurrentFile) -> bool {
+        self.max_file_size.is_some_and(|max| current.size >= max)
+            || self
+                .max_file_age
+                .is_some_and(|max| current.opened_at.elapsed() >= max)
+    }
+}
+
 /// Create a new file logger layer.
 pub fn layer<T>(
     log_dir: &Path,
@@ -45,7 +76,20 @@ pub fn layer<T>(
 where
     T: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
 {
-    let (appender_fmt, handle_fmt) = new_appender(log_dir.to_path_buf(), file_base_name, "log");
+    layer_with_rotation(log_dir, file_base_name, Rotation::default())
+}
+
+/// Create a new file logger layer that rotates and prunes its files according to `rotation`.
+pub fn layer_with_rotation<T>(
+    log_dir: &Path,
+    file_base_name: &'static str,
+    rotation: Rotation,
+) -> (Box<dyn Layer<T> + Send + Sync + 'static>, Handle)
+where
+    T: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    let (appender_fmt, handle_fmt) =
+        new_appender(log_dir.to_path_buf(), file_base_name, "log", rotation);
     let layer_fmt = tracing_subscriber::fmt::layer()
         .with_ansi(false)
         .with_writer(appender_fmt)
@@ -66,12 +110,15 @@ fn new_appender(
     directory: PathBuf,
     file_base_name: &'static str,
     file_extension: &'static str,
+    rotation: Rotation,
 ) -> (NonBlocking, WorkerGuard) {
     let appender = Appender {
         directory,
         current: None,
         file_extension,
         file_base_name,
+        rotation,
+        compression: None,
     };
 
     let (non_blocking, guard) = tracing_appender::non_blocking::NonBlockingBuilder::default()
@@ -96,9 +143,21 @@ struct Appender {
     directory: PathBuf,
     file_base_name: &'static str,
     file_extension: &'static str,
+    rotation: Rotation,
     // Leaving this so that I/O errors come up through `write` instead of panicking
     // in `layer`
-    current: Option<(fs::File, String)>,
+    current: Option<CurrentFile>,
+    /// The thread compressing the most recently rotated file, if any.
+    compression: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+struct CurrentFile {
+    file: fs::File,
+    name: String,
+    /// How many bytes are in the file, including those written before we opened it.
+    size: u64,
+    opened_at: Instant,
 }
 
 impl Appender {
@@ -106,47 +165,156 @@ impl Appender {
         &mut self,
         cb: impl Fn(&mut fs::File) -> io::Result<R>,
     ) -> io::Result<R> {
-        match self.current.as_mut() {
-            None => {
-                let (mut file, name) = self.create_new_writer()?;
-
-                let ret = cb(&mut file);
+        if self
+            .current
+            .as_ref()
+            .is_some_and(|current| self.rotation.is_due(current))
+        {
+            self.rotate()?;
+        }
 
-                self.current = Some((file, name));
+        let current = match self.current.take() {
+            Some(current) => current,
+            None => {
+                let current = self.create_new_writer()?;
+                self.prune();
 
-                ret
+                current
             }
-            Some((file, _)) => cb(file),
-        }
+        };
+
+        cb(&mut self.current.insert(current).file)
     }
 
     // Inspired from `tracing-appender/src/rolling.rs`.
-    fn create_new_writer(&self) -> io::Result<(fs::File, String)> {
+    fn create_new_writer(&self) -> io::Result<CurrentFile> {
         let format = time::format_description::parse(TIME_FORMAT).map_err(io::Error::other)?;
         let date = OffsetDateTime::now_utc()
             .format(&format)
             .map_err(|_| io::Error::other("Failed to format timestamp"))?;
 
-        let filename = format!("{}.{date}.{}", self.file_base_name, self.file_extension);
+        let mut filename = format!("{}.{date}.{}", self.file_base_name, self.file_extension);
+
+        // When rotating more than once per second, the timestamp alone is not unique.
+        if self.rotation != Rotation::default() {
+            let mut n = 0;
+            while self.directory.join(&filename).exists()
+                || self.directory.join(format!("{filename}.gz")).exists()
+            {
+                n += 1;
+                filename = format!("{}.{date}_{n}.{}", self.file_base_name, self.file_extension);
+            }
+        }
 
         let path = self.directory.join(&filename);
         let mut open_options = fs::OpenOptions::new();
         open_options.append(true).create(true);
 
-        let new_file = open_options.open(path.as_path());
-        if new_file.is_err() {
-            if let Some(parent) = path.parent() {
+        let file = match open_options.open(path.as_path()) {
+            Ok(file) => file,
+            Err(e) => {
+                let Some(parent) = path.parent() else {
+                    return Err(e);
+                };
+
                 fs::create_dir_all(parent)?;
-                let file = open_options.open(path)?;
+                open_options.open(&path)?
+            }
+        };
+        Self::set_permissions(&file)?;
+
+        Ok(CurrentFile {
+            size: file.metadata()?.len(),
+            file,
+            name: filename,
+            opened_at: Instant::now(),
+        })
+    }
+
+    /// Closes the current file and compresses it in the background if configured.
+    ///
+    /// The next write opens a new file.
+    fn rotate(&mut self) -> io::Result<()> {
+        let Some(previous) = self.current.take() else {
+            return Ok(());
+        };
+        drop(previous.file);
+
+        if !self.rotation.compress {
+            return Ok(());
+        }
+
+        let path = self.directory.join(previous.name);
+        let handle = std::thread::Builder::new()
+            .name("log-compression".to_owned())
+            .spawn(move || {
+                if let Err(e) = compress(&path) {
+                    tracing::warn!(path = %path.display(), "Failed to compress log file: {e}");
+                }
+            })?;
+        self.compression = Some(handle);
+
+        Ok(())
+    }
+
+    /// Deletes the oldest log files with our base name until at most [`Rotation::max_files`] remain.
+    ///
+    /// This is best-effort, files we fail to delete are simply kept.
+    fn prune(&self) {
+        let Some(max_files) = self.rotation.max_files else {
+            return;
+        };
+        let Ok(entries) = fs::read_dir(&self.directory) else {
+            return;
+        };
 
-                return Ok((file, filename));
+        let mut logs = entries
+            .filter_map(|entry| entry.ok())
+            .filter_map(|entry| {
+                let name = entry.file_name().into_string().ok()?;
+                let key = self.sort_key(&name)?;
+
+                Some((key, name))
+            })
+            .collect::<Vec<_>>();
+        logs.sort();
+
+        // A file that is currently being compressed shows up twice, once with and once without `.gz`.
+        let mut files = logs.iter().map(|(key, _)| key).collect::<Vec<_>>();
+        files.dedup();
+
+        let num_to_delete = files.len().saturating_sub(max_files.max(1));
+        let Some(newest_to_delete) = num_to_delete
+            .checked_sub(1)
+            .and_then(|i| files.get(i).copied())
+        else {
+            return;
+        };
+
+        for (key, name) in &logs {
+            if key > newest_to_delete {
+                break;
             }
+
+            let _ = fs::remove_file(self.directory.join(name));
         }
+    }
 
-        let file = new_file?;
-        Self::set_permissions(&file)?;
+    /// Parses the timestamp and counter out of a name created by [`Appender::create_new_writer`].
+    ///
+    /// Returns `None` for files that don't belong to us.
+    fn sort_key(&self, name: &str) -> Option<(String, u32)> {
+        let name = name.strip_suffix(".gz").unwrap_or(name);
+        let stamp = name
+            .strip_prefix(self.file_base_name)?
+            .strip_prefix('.')?
+            .strip_suffix(self.file_extension)?
+            .strip_suffix('.')?;
 
-        Ok((file, filename))
+        match stamp.split_once('_') {
+            Some((date, n)) => Some((date.to_owned(), n.parse().ok()?)),
+            None => Some((stamp.to_owned(), 0)),
+        }
     }
 
     /// Make the logs group-readable so that the GUI, running as a user in the `firezone`
@@ -169,12 +337,158 @@ impl Appender {
     }
 }
 
+impl Drop for Appender {
+    /// Waits for the compression of the last rotated file, so it isn't cut short when the process exits.
+    fn drop(&mut self) {
+        if let Some(compression) = self.compression.take() {
+            let _ = compression.join();
+        }
+    }
+}
+
+/// Gzips the file at `path` into `path.gz` and deletes the original.
+fn compress(path: &Path) -> io::Result<()> {
+    let mut gz_path = OsString::from(path.as_os_str());
+    gz_path.push(".gz");
+
+    let mut input = fs::File::open(path)?;
+    let output = fs::File::create(&gz_path)?;
+    Appender::set_permissions(&output)?;
+
+    let mut encoder = flate2::write::GzEncoder::new(output, flate2::Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+
+    if let Err(e) = fs::remove_file(path) {
+        // The file got pruned whilst we were compressing it, so we don't want to keep the compressed copy either.
+        if e.kind() == io::ErrorKind::NotFound {
+            fs::remove_file(&gz_path)?;
+            return Ok(());
+        }
+
+        return Err(e);
+    }
+
+    Ok(())
+}
+
 impl io::Write for Appender {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.with_current_file(|f| f.write(buf))
+        let written = self.with_current_file(|f| f.write(buf))?;
+
+        if let Some(current) = self.current.as_mut() {
+            current.size += written as u64;
+        }
+
+        Ok(written)
     }
 
     fn flush(&mut self) -> io::Result<()> {
         self.with_current_file(|f| f.flush())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write as _;
+
+    #[test]
+    fn rotates_once_file_reaches_max_size() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut appender = appender(
+            dir.path(),
+            Rotation {
+                max_file_size: Some(10),
+                ..Default::default()
+            },
+        );
+
+        appender.write_all(b"12345678\n").unwrap();
+        appender.write_all(b"12345678\n").unwrap();
+        appender.write_all(b"12345678\n").unwrap();
+
+        assert_eq!(log_files(dir.path()).len(), 2);
+    }
+
+    #[test]
+    fn never_rotates_by_default() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut appender = appender(dir.path(), Rotation::default());
+
+        for _ in 0..100 {
+            appender.write_all(b"12345678\n").unwrap();
+        }
+
+        assert_eq!(log_files(dir.path()).len(), 1);
+    }
+
+    #[test]
+    fn prunes_oldest_files() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "test.2024-01-01-00-00-00.log",
+            "test.2024-01-01-00-00-01_2.log",
+            "test.2024-01-01-00-00-01_10.log.gz",
+            "other.2020-01-01-00-00-00.log",
+        ] {
+            fs::write(dir.path().join(name), b"").unwrap();
+        }
+        let mut appender = appender(
+            dir.path(),
+            Rotation {
+                max_files: Some(2),
+                ..Default::default()
+            },
+        );
+
+        appender.write_all(b"hello\n").unwrap();
+
+        let files = log_files(dir.path());
+        assert_eq!(files.len(), 3);
+        assert!(files.contains(&"other.2020-01-01-00-00-00.log".to_owned()));
+        assert!(files.contains(&"test.2024-01-01-00-00-01_10.log.gz".to_owned()));
+    }
+
+    #[test]
+    fn compresses_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut appender = appender(
+            dir.path(),
+            Rotation {
+                max_file_size: Some(1),
+                compress: true,
+                ..Default::default()
+            },
+        );
+
+        appender.write_all(b"first\n").unwrap();
+        appender.write_all(b"second\n").unwrap();
+        drop(appender); // Waits for the compression to finish.
+
+        let files = log_files(dir.path());
+        assert_eq!(files.len(), 2);
+        assert_eq!(files.iter().filter(|f| f.ends_with(".log.gz")).count(), 1);
+    }
+
+    fn appender(dir: &Path, rotation: Rotation) -> Appender {
+        Appender {
+            directory: dir.to_path_buf(),
+            file_base_name: "test",
+            file_extension: "log",
+            rotation,
+            current: None,
+            compression: None,
+        }
+    }
+
+    fn log_files(dir: &Path) -> Vec<String> {
+        let mut files = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        files.sort();
+
+        files
+    }
+}
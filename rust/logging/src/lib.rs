@@ -12,7 +12,7 @@ use sentry_tracing::EventFilter;
 use tracing::{subscriber::DefaultGuard, Subscriber};
 use tracing_log::LogTracer;
 use tracing_subscriber::{
-    filter::ParseError, fmt, layer::SubscriberExt as _, registry::LookupSpan,
+    filter::ParseError, fmt, layer::SubscriberExt as _, registry::LookupSpan, reload,
     util::SubscriberInitExt, EnvFilter, Layer, Registry,
 };
 
@@ -20,8 +20,13 @@ pub use ansi::stdout_supports_ansi;
 pub use err_with_sources::{err_with_src, ErrorWithSources};
 pub use format::Format;
 
+/// Changes the log filter of the subscriber registered by [`setup_global_subscriber`] at runtime.
+pub type FilterReloadHandle = reload::Handle<EnvFilter, Registry>;
+
 /// Registers a global subscriber with stdout logging and `additional_layer`
-pub fn setup_global_subscriber<L>(additional_layer: L) -> Result<()>
+///
+/// Both are filtered by the directives in `RUST_LOG`, which can be replaced later via the returned handle.
+pub fn setup_global_subscriber<L>(additional_layer: L) -> Result<FilterReloadHandle>
 where
     L: Layer<Registry> + Send + Sync,
 {
@@ -30,21 +35,22 @@ where
     }
 
     let directives = std::env::var("RUST_LOG").unwrap_or_default();
+    let (filter, reload_handle) =
+        reload::Layer::new(try_filter(&directives).context("Failed to parse directives")?);
     let subscriber = Registry::default()
         .with(
             additional_layer
-                .with_filter(try_filter(&directives).context("Failed to parse directives")?),
+                .and_then(
+                    fmt::layer()
+                        .with_ansi(stdout_supports_ansi())
+                        .event_format(Format::new()),
+                )
+                .with_filter(filter),
         )
-        .with(sentry_layer())
-        .with(
-            fmt::layer()
-                .with_ansi(stdout_supports_ansi())
-                .event_format(Format::new())
-                .with_filter(try_filter(&directives).context("Failed to parse directives")?),
-        );
+        .with(sentry_layer());
     init(subscriber)?;
 
-    Ok(())
+    Ok(reload_handle)
 }
 
 #[expect(
@@ -2,6 +2,7 @@
 
 mod get_user_agent;
 mod login_url;
+mod proxy;
 
 use std::collections::{HashSet, VecDeque};
 use std::net::{IpAddr, SocketAddr, ToSocketAddrs as _};
@@ -31,6 +32,7 @@ use url::Url;
 
 pub use get_user_agent::get_user_agent;
 pub use login_url::{DeviceInfo, LoginUrl, LoginUrlError, NoParams, PublicKeyParam};
+pub use proxy::{Proxy, ProxyConfig, ProxyError};
 pub use tokio_tungstenite::tungstenite::http::StatusCode;
 
 const MAX_BUFFERED_MESSAGES: usize = 32; // Chosen pretty arbitrarily. If we are connected, these should never build up.
@@ -47,6 +49,10 @@ pub struct PhoenixChannel<TInitReq, TInboundMsg, TOutboundRes, TFinish> {
     _phantom: PhantomData<(TInboundMsg, TOutboundRes)>,
 
     pending_join_requests: HashSet<OutboundRequestId>,
+    /// Rooms we sent a `phx_leave` for.
+    ///
+    /// Phoenix confirms leaving a room with a `phx_close` which must not trigger a reconnect.
+    leaving_topics: HashSet<String>,
 
     // Stored here to allow re-connecting.
     url_prototype: Secret<LoginUrl<TFinish>>,
@@ -56,6 +62,7 @@ pub struct PhoenixChannel<TInitReq, TInboundMsg, TOutboundRes, TFinish> {
     reconnect_backoff: Option<ExponentialBackoff>,
 
     resolved_addresses: Vec<IpAddr>,
+    proxy: Option<Proxy>,
 
     login: &'static str,
     init_req: TInitReq,
@@ -77,9 +84,11 @@ impl State {
         host: String,
         user_agent: String,
         socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+        proxy: Option<Proxy>,
     ) -> Self {
         Self::Connecting(
-            create_and_connect_websocket(url, addresses, host, user_agent, socket_factory).boxed(),
+            create_and_connect_websocket(url, addresses, host, user_agent, socket_factory, proxy)
+                .boxed(),
         )
     }
 }
@@ -90,13 +99,28 @@ async fn create_and_connect_websocket(
     host: String,
     user_agent: String,
     socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+    proxy: Option<Proxy>,
 ) -> Result<WebSocketStream<MaybeTlsStream<TcpStream>>, InternalError> {
-    tracing::debug!(%host, ?addresses, %user_agent, "Connecting to portal");
-
     let duration = Duration::from_secs(5);
-    let socket = tokio::time::timeout(duration, connect(addresses, &*socket_factory))
-        .await
-        .map_err(|_| InternalError::Timeout { duration })??;
+    let socket = match proxy {
+        Some(proxy) => {
+            tracing::debug!(%host, %proxy, %user_agent, "Connecting to portal through proxy");
+
+            let port = url.port_or_known_default().unwrap_or(443);
+
+            tokio::time::timeout(
+                duration,
+                connect_via_proxy(&proxy, &host, port, &*socket_factory),
+            )
+            .await
+        }
+        None => {
+            tracing::debug!(%host, ?addresses, %user_agent, "Connecting to portal");
+
+            tokio::time::timeout(duration, connect(addresses, &*socket_factory)).await
+        }
+    }
+    .map_err(|_| InternalError::Timeout { duration })??;
 
     let (stream, _) = client_async_tls(make_request(url, host, user_agent), socket)
         .await
@@ -127,6 +151,26 @@ async fn connect(
     Err(InternalError::SocketConnection(errors))
 }
 
+async fn connect_via_proxy(
+    proxy: &Proxy,
+    host: &str,
+    port: u16,
+    socket_factory: &dyn SocketFactory<TcpSocket>,
+) -> Result<TcpStream, InternalError> {
+    let addresses = tokio::net::lookup_host(proxy.addr())
+        .await
+        .map_err(InternalError::Proxy)?
+        .collect();
+
+    let mut socket = connect(addresses, socket_factory).await?;
+    proxy
+        .handshake(&mut socket, host, port)
+        .await
+        .map_err(InternalError::Proxy)?;
+
+    Ok(socket)
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("Failed to establish WebSocket connection: {0}")]
@@ -157,6 +201,7 @@ enum InternalError {
     CloseMessage,
     StreamClosed,
     SocketConnection(Vec<(SocketAddr, std::io::Error)>),
+    Proxy(std::io::Error),
     Timeout { duration: Duration },
 }
 
@@ -187,6 +232,7 @@ impl fmt::Display for InternalError {
                         .join(", ")
                 )
             }
+            InternalError::Proxy(_) => write!(f, "failed to connect through proxy"),
             InternalError::Timeout { duration, .. } => {
                 write!(f, "operation timed out after {duration:?}")
             }
@@ -201,6 +247,7 @@ impl std::error::Error for InternalError {
             InternalError::WebSocket(e) => Some(e),
             InternalError::Serde(e) => Some(e),
             InternalError::SocketConnection(_) => None,
+            InternalError::Proxy(e) => Some(e),
             InternalError::CloseMessage => None,
             InternalError::StreamClosed => None,
             InternalError::Timeout { .. } => None,
@@ -250,6 +297,8 @@ where
     ///
     /// The provided URL must contain a host.
     /// Additionally, you must already provide any query parameters required for authentication.
+    ///
+    /// If `proxy` has a proxy for the host, we connect through it and leave resolving the host to the proxy.
     pub fn disconnected(
         url: Secret<LoginUrl<TFinish>>,
         user_agent: String,
@@ -257,17 +306,29 @@ where
         init_req: TInitReq,
         make_reconnect_backoff: impl Fn() -> ExponentialBackoff + Send + 'static,
         socket_factory: Arc<dyn SocketFactory<TcpSocket>>,
+        proxy: ProxyConfig,
     ) -> io::Result<Self> {
         let host_and_port = url.expose_secret().host_and_port();
+        let proxy = proxy.proxy_for(host_and_port.0).cloned();
 
-        let _span = telemetry_span!("resolve_portal_url", host = %host_and_port.0).entered();
+        let resolved_addresses = match &proxy {
+            Some(proxy) => {
+                tracing::info!(host = %host_and_port.0, %proxy, "Using proxy for portal connection");
 
-        // Statically resolve the host in the URL to a set of addresses.
-        // We use these when connecting the socket to avoid a dependency on DNS resolution later on.
-        let resolved_addresses = host_and_port
-            .to_socket_addrs()?
-            .map(|addr| addr.ip())
-            .collect();
+                Vec::new()
+            }
+            None => {
+                let _span =
+                    telemetry_span!("resolve_portal_url", host = %host_and_port.0).entered();
+
+                // Statically resolve the host in the URL to a set of addresses.
+                // We use these when connecting the socket to avoid a dependency on DNS resolution later on.
+                host_and_port
+                    .to_socket_addrs()?
+                    .map(|addr| addr.ip())
+                    .collect()
+            }
+        };
 
         Ok(Self {
             make_reconnect_backoff: Box::new(make_reconnect_backoff),
@@ -282,9 +343,11 @@ where
             heartbeat: tokio::time::interval(Duration::from_secs(30)),
             next_request_id: 0,
             pending_join_requests: Default::default(),
+            leaving_topics: Default::default(),
             login,
             init_req,
             resolved_addresses,
+            proxy,
             last_url: None,
         })
     }
@@ -299,6 +362,35 @@ where
         self.pending_join_requests.insert(request_id);
     }
 
+    /// Replaces the payload we join the room with and re-joins it if we are connected.
+    ///
+    /// All future reconnects will also join with the new payload.
+    pub fn rejoin(&mut self, init_req: TInitReq) {
+        self.init_req = init_req;
+
+        if !matches!(self.state, State::Connected(_)) {
+            return;
+        }
+
+        // Phoenix doesn't let us join a room twice, so we have to leave it first.
+        // Both messages are queued at the front, thus we queue `phx_leave` last to send it first.
+        self.join(self.login, self.init_req.clone());
+        self.leave(self.login);
+    }
+
+    /// Leave the provided room, ahead of all other messages.
+    fn leave(&mut self, topic: impl Into<String>) {
+        let topic = topic.into();
+
+        let (_, msg) = self.make_message(
+            topic.clone(),
+            EgressControlMessage::<()>::PhxLeave(Empty {}),
+        );
+        self.pending_messages.push_front(msg);
+
+        self.leaving_topics.insert(topic);
+    }
+
     /// Send a message to a topic.
     pub fn send(&mut self, topic: impl Into<String>, message: impl Serialize) -> OutboundRequestId {
         if self.pending_messages.len() > MAX_BUFFERED_MESSAGES {
@@ -333,6 +425,7 @@ where
             self.host(),
             user_agent,
             self.socket_factory.clone(),
+            self.proxy.clone(),
         );
         self.last_url = Some(url);
 
@@ -385,6 +478,7 @@ where
                     Poll::Ready(Ok(stream)) => {
                         self.reconnect_backoff = None;
                         self.heartbeat.reset();
+                        self.leaving_topics.clear();
                         self.state = State::Connected(stream);
 
                         let (host, _) = self.url_prototype.expose_secret().host_and_port();
@@ -421,6 +515,7 @@ where
                             .clone();
                         let user_agent = self.user_agent.clone();
                         let socket_factory = self.socket_factory.clone();
+                        let proxy = self.proxy.clone();
 
                         self.state = State::Connecting(Box::pin(async move {
                             tokio::time::sleep(backoff).await;
@@ -430,6 +525,7 @@ where
                                 host,
                                 user_agent,
                                 socket_factory,
+                                proxy,
                             )
                             .await
                         }));
@@ -566,6 +662,11 @@ where
                             continue;
                         }
                         (Payload::Close(Empty {}), _) => {
+                            if self.leaving_topics.remove(&message.topic) {
+                                tracing::debug!("Left {} room on portal", message.topic);
+                                continue;
+                            }
+
                             self.reconnect_on_transient_error(InternalError::CloseMessage);
                             continue;
                         }
@@ -817,6 +918,7 @@ fn make_request(url: Url, host: String, user_agent: String) -> Request {
 #[serde(rename_all = "snake_case", tag = "event", content = "payload")]
 enum EgressControlMessage<T> {
     PhxJoin(T),
+    PhxLeave(Empty),
     Heartbeat(Empty),
 }
 
@@ -974,4 +1076,137 @@ mod tests {
 
         assert_eq!(actual, expected)
     }
+
+    #[test]
+    fn phx_leave() {
+        let msg = serialize_msg(
+            "relay",
+            EgressControlMessage::<()>::PhxLeave(Empty {}),
+            OutboundRequestId(3),
+        );
+
+        assert_eq!(
+            serde_json::from_str::<serde_json::Value>(&msg).unwrap(),
+            serde_json::json!({ "topic": "relay", "event": "phx_leave", "payload": {}, "ref": 3 })
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_close_of_room_we_are_leaving() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let portal = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut portal = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+            let join = recv_event(&mut portal, "phx_join").await;
+            reply(&mut portal, &join, serde_json::Value::Null).await;
+
+            let leave = recv_event(&mut portal, "phx_leave").await;
+            reply(&mut portal, &leave, serde_json::json!({})).await;
+            send_close(&mut portal).await;
+
+            let join = recv_event(&mut portal, "phx_join").await;
+            reply(&mut portal, &join, serde_json::Value::Null).await;
+
+            // We are not leaving the room this time, meaning the portal kicked us out.
+            send_close(&mut portal).await;
+
+            portal // Keep the connection open until the test is done.
+        });
+
+        let mut channel = PhoenixChannel::<(), (), (), NoParams>::disconnected(
+            Secret::new(
+                LoginUrl::relay(
+                    format!("ws://127.0.0.1:{port}").as_str(),
+                    &secrecy::SecretString::new("token".to_owned()),
+                    None,
+                    3478,
+                    None,
+                    None,
+                )
+                .unwrap(),
+            ),
+            "test".to_owned(),
+            "relay",
+            (),
+            ExponentialBackoff::default,
+            Arc::new(socket_factory::tcp),
+            ProxyConfig::default(),
+        )
+        .unwrap();
+        channel.connect(NoParams);
+
+        assert!(matches!(
+            next_event(&mut channel).await,
+            Event::JoinedRoom { topic } if topic == "relay"
+        ));
+
+        channel.rejoin(());
+
+        assert!(matches!(
+            next_event(&mut channel).await,
+            Event::JoinedRoom { topic } if topic == "relay"
+        ));
+        assert!(matches!(
+            next_event(&mut channel).await,
+            Event::Hiccup { .. }
+        ));
+
+        portal.await.unwrap();
+    }
+
+    async fn next_event(channel: &mut PhoenixChannel<(), (), (), NoParams>) -> Event<(), ()> {
+        loop {
+            match future::poll_fn(|cx| channel.poll(cx)).await.unwrap() {
+                Event::HeartbeatSent | Event::SuccessResponse { .. } => continue,
+                event => return event,
+            }
+        }
+    }
+
+    async fn recv_event(
+        portal: &mut WebSocketStream<tokio::net::TcpStream>,
+        event: &str,
+    ) -> serde_json::Value {
+        loop {
+            let message = portal.next().await.unwrap().unwrap().into_text().unwrap();
+            let message = serde_json::from_str::<serde_json::Value>(&message).unwrap();
+
+            if message["event"] == "heartbeat" {
+                continue;
+            }
+
+            assert_eq!(message["event"], event);
+
+            return message;
+        }
+    }
+
+    async fn reply(
+        portal: &mut WebSocketStream<tokio::net::TcpStream>,
+        request: &serde_json::Value,
+        response: serde_json::Value,
+    ) {
+        let reply = serde_json::json!({
+            "topic": request["topic"],
+            "event": "phx_reply",
+            "payload": { "status": "ok", "response": response },
+            "ref": request["ref"]
+        });
+
+        portal.send(Message::text(reply.to_string())).await.unwrap();
+    }
+
+    async fn send_close(portal: &mut WebSocketStream<tokio::net::TcpStream>) {
+        let close = serde_json::json!({
+            "topic": "relay",
+            "event": "phx_close",
+            "payload": {},
+            "ref": null
+        });
+
+        portal.send(Message::text(close.to_string())).await.unwrap();
+    }
 }
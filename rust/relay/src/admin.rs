@@ -0,0 +1,90 @@
+//! A local-only HTTP API for inspecting and managing a running relay.
+//!
+//! Each worker's [`Server`](crate::Server) is owned by its event-loop, so all requests are forwarded to every worker via a channel and answered through a [`oneshot`] channel.
+
+use crate::{AllocationInfo, AllocationPort};
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use axum::routing::{delete, get};
+use axum::{Json, Router};
+use std::net::SocketAddr;
+use tokio::sync::{mpsc, oneshot};
+
+/// How many requests we queue for the event-loop before rejecting new ones.
+const MAX_PENDING_REQUESTS: usize = 32;
+
+/// A request from the admin API to the event-loop.
+#[derive(Debug)]
+pub enum Request {
+    /// List all allocations, see [`Server::allocations`](crate::Server::allocations).
+    ListAllocations(oneshot::Sender<Vec<AllocationInfo>>),
+    /// Free an allocation, see [`Server::free_allocation`](crate::Server::free_allocation).
+    FreeAllocation(AllocationPort, oneshot::Sender<bool>),
+}
+
+/// Creates the channel connecting the admin API with the event-loop.
+pub fn channel() -> (mpsc::Sender<Request>, mpsc::Receiver<Request>) {
+    mpsc::channel(MAX_PENDING_REQUESTS)
+}
+
+/// Runs an HTTP server exposing the admin API on the given address.
+///
+/// - `GET /allocations` responds with all allocations and their channels as JSON.
+/// - `DELETE /allocations/:port` frees the allocation on the given port and responds with 204 NO CONTENT or 404 NOT FOUND.
+///
+/// Requests are sent to all workers and their responses combined.
+pub async fn serve(addr: SocketAddr, workers: Vec<mpsc::Sender<Request>>) -> std::io::Result<()> {
+    let service = Router::new()
+        .route("/allocations", get(list_allocations))
+        .route("/allocations/:port", delete(free_allocation))
+        .with_state(workers)
+        .into_make_service();
+
+    axum::serve(tokio::net::TcpListener::bind(addr).await?, service).await?;
+
+    Ok(())
+}
+
+async fn list_allocations(
+    State(workers): State<Vec<mpsc::Sender<Request>>>,
+) -> Result<Json<Vec<AllocationInfo>>, StatusCode> {
+    let mut allocations = Vec::new();
+
+    for requests in &workers {
+        let (tx, rx) = oneshot::channel();
+
+        requests
+            .send(Request::ListAllocations(tx))
+            .await
+            .map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?;
+        allocations.extend(rx.await.map_err(|_| StatusCode::SERVICE_UNAVAILABLE)?);
+    }
+
+    Ok(Json(allocations))
+}
+
+async fn free_allocation(
+    State(workers): State<Vec<mpsc::Sender<Request>>>,
+    Path(port): Path<u16>,
+) -> StatusCode {
+    // Ports are unique across workers, so at most one of them can free the allocation.
+    for requests in &workers {
+        let (tx, rx) = oneshot::channel();
+
+        if requests
+            .send(Request::FreeAllocation(AllocationPort::new(port), tx))
+            .await
+            .is_err()
+        {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+
+        match rx.await {
+            Ok(true) => return StatusCode::NO_CONTENT,
+            Ok(false) => continue,
+            Err(_) => return StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    StatusCode::NOT_FOUND
+}
@@ -18,6 +18,11 @@
 //! As such, a TURN client can never create a set of credentials themselves because they are missing the `relay_secret`.
 //! In addition, a relay can validate such a username and password combination without having to store any state other than the `relay_secret`.
 //!
+//! ## Secret rotation
+//!
+//! The `relay_secret` can be rotated at runtime.
+//! The previous secret remains valid for an overlap window, allowing clients to refresh their existing allocations until they obtain credentials for the new secret from the portal.
+//!
 //! All STUN messages other than `BINDING` requests MUST be authenticated by the client.
 //!
 //! ## Server authentication
@@ -43,7 +48,7 @@ use sha2::digest::FixedOutput;
 use sha2::Sha256;
 use std::collections::hash_map::Entry;
 use std::collections::HashMap;
-use std::time::{Duration, SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use stun_codec::rfc5389::attributes::{MessageIntegrity, Realm, Username};
 use stun_codec::Message;
 use uuid::Uuid;
@@ -54,10 +59,82 @@ use crate::Attribute;
 pub static FIREZONE: Lazy<Realm> =
     Lazy::new(|| Realm::new("firezone".to_owned()).expect("static realm is less than 128 chars"));
 
+/// The secrets we accept credentials for.
+///
+/// After a rotation, the previous secret remains valid until the overlap window has passed.
+#[derive(Debug)]
+pub(crate) struct RelaySecrets {
+    current: SecretString,
+    previous: Option<(SecretString, Instant)>,
+}
+
+impl RelaySecrets {
+    pub(crate) fn new(current: SecretString) -> Self {
+        Self {
+            current,
+            previous: None,
+        }
+    }
+
+    pub(crate) fn current(&self) -> &SecretString {
+        &self.current
+    }
+
+    /// Replaces the current secret with `new`, keeping the current one valid until `now + overlap`.
+    pub(crate) fn rotate(&mut self, new: SecretString, overlap: Duration, now: Instant) {
+        let previous = std::mem::replace(&mut self.current, new);
+
+        self.previous = Some((previous, now + overlap));
+    }
+
+    pub(crate) fn poll_timeout(&self) -> Option<Instant> {
+        self.previous.as_ref().map(|(_, expires_at)| *expires_at)
+    }
+
+    pub(crate) fn handle_timeout(&mut self, now: Instant) {
+        if self
+            .previous
+            .as_ref()
+            .is_some_and(|(_, expires_at)| *expires_at <= now)
+        {
+            tracing::info!(target: "relay", "Previous auth secret expired");
+
+            self.previous = None;
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &SecretString> {
+        std::iter::once(&self.current).chain(self.previous.as_ref().map(|(secret, _)| secret))
+    }
+
+    /// Finds the secret that the given [`MessageIntegrity`] was computed with.
+    fn find(
+        &self,
+        message_integrity: &MessageIntegrity,
+        username: &str,
+    ) -> Result<&SecretString, Error> {
+        let (expiry_unix_timestamp, salt) = split_username(username)?;
+        let expired = systemtime_from_unix(expiry_unix_timestamp);
+
+        let username = Username::new(format!("{}:{}", expiry_unix_timestamp, salt))
+            .map_err(|_| Error::InvalidUsername)?;
+
+        self.iter()
+            .find(|relay_secret| {
+                let password = generate_password(relay_secret, expired, salt);
+
+                message_integrity
+                    .check_long_term_credential(&username, &FIREZONE, &password)
+                    .is_ok()
+            })
+            .ok_or(Error::InvalidPassword)
+    }
+}
+
 pub(crate) trait MessageIntegrityExt {
     fn verify(
         &self,
-        relay_secret: &SecretString,
+        relay_secrets: &RelaySecrets,
         username: &str,
         now: SystemTime,
     ) -> Result<(), Error>;
@@ -66,26 +143,18 @@ pub(crate) trait MessageIntegrityExt {
 impl MessageIntegrityExt for MessageIntegrity {
     fn verify(
         &self,
-        relay_secret: &SecretString,
+        relay_secrets: &RelaySecrets,
         username: &str,
         now: SystemTime,
     ) -> Result<(), Error> {
-        let (expiry_unix_timestamp, salt) = split_username(username)?;
+        let (expiry_unix_timestamp, _) = split_username(username)?;
         let expired = systemtime_from_unix(expiry_unix_timestamp);
 
         if expired < now {
             return Err(Error::Expired);
         }
 
-        let password = generate_password(relay_secret, expired, salt);
-
-        self.check_long_term_credential(
-            &Username::new(format!("{}:{}", expiry_unix_timestamp, salt))
-                .map_err(|_| Error::InvalidUsername)?,
-            &FIREZONE,
-            &password,
-        )
-        .map_err(|_| Error::InvalidPassword)?;
+        relay_secrets.find(self, username)?;
 
         Ok(())
     }
@@ -101,11 +170,19 @@ impl AuthenticatedMessage {
         Self(message)
     }
 
+    /// Authenticates `message` with the credentials of the given username.
+    ///
+    /// The message is authenticated with the same secret as the request it responds to, falling back to the current secret.
     pub(crate) fn new(
-        relay_secret: &SecretString,
+        relay_secrets: &RelaySecrets,
         username: &str,
+        request_integrity: Option<&MessageIntegrity>,
         mut message: Message<Attribute>,
     ) -> Result<Self, Error> {
+        let relay_secret = request_integrity
+            .and_then(|mi| relay_secrets.find(mi, username).ok())
+            .unwrap_or(relay_secrets.current());
+
         let (expiry_unix_timestamp, salt) = split_username(username)?;
         let expired = systemtime_from_unix(expiry_unix_timestamp);
 
@@ -295,7 +372,7 @@ mod tests {
         );
 
         let result = message_integrity.verify(
-            &RELAY_SECRET_1.parse().unwrap(),
+            &relay_secrets(RELAY_SECRET_1),
             "1685200000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(1685200000 - 1000),
         );
@@ -312,7 +389,7 @@ mod tests {
         );
 
         let result = message_integrity.verify(
-            &RELAY_SECRET_1.parse().unwrap(),
+            &relay_secrets(RELAY_SECRET_1),
             "1685199000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(1685200000),
         );
@@ -329,7 +406,7 @@ mod tests {
         );
 
         let result = message_integrity.verify(
-            &RELAY_SECRET_1.parse().unwrap(),
+            &relay_secrets(RELAY_SECRET_1),
             "1685200000:n23JJ2wKKtt30oXi",
             systemtime_from_unix(168520000 + 1000),
         );
@@ -337,6 +414,75 @@ mod tests {
         assert!(matches!(result.unwrap_err(), Error::InvalidPassword))
     }
 
+    #[test]
+    fn previous_relay_secret_is_valid_during_overlap() {
+        let now = Instant::now();
+        let message_integrity = message_integrity(
+            &RELAY_SECRET_1.parse().unwrap(),
+            1685200000,
+            "n23JJ2wKKtt30oXi",
+        );
+
+        let mut secrets = relay_secrets(RELAY_SECRET_1);
+        secrets.rotate(
+            RELAY_SECRET_2.parse().unwrap(),
+            Duration::from_secs(60),
+            now,
+        );
+
+        message_integrity
+            .verify(
+                &secrets,
+                "1685200000:n23JJ2wKKtt30oXi",
+                systemtime_from_unix(1685200000 - 1000),
+            )
+            .expect("previous secret to be valid");
+
+        secrets.handle_timeout(now + Duration::from_secs(60));
+
+        let result = message_integrity.verify(
+            &secrets,
+            "1685200000:n23JJ2wKKtt30oXi",
+            systemtime_from_unix(1685200000 - 1000),
+        );
+
+        assert!(matches!(result.unwrap_err(), Error::InvalidPassword))
+    }
+
+    #[test]
+    fn responds_with_secret_of_request() {
+        let message_integrity = message_integrity(
+            &RELAY_SECRET_1.parse().unwrap(),
+            1685200000,
+            "n23JJ2wKKtt30oXi",
+        );
+
+        let mut secrets = relay_secrets(RELAY_SECRET_1);
+        secrets.rotate(
+            RELAY_SECRET_2.parse().unwrap(),
+            Duration::from_secs(60),
+            Instant::now(),
+        );
+
+        let response = AuthenticatedMessage::new(
+            &secrets,
+            "1685200000:n23JJ2wKKtt30oXi",
+            Some(&message_integrity),
+            sample_message(),
+        )
+        .unwrap();
+
+        response
+            .get_attribute::<MessageIntegrity>()
+            .unwrap()
+            .verify(
+                &relay_secrets(RELAY_SECRET_1),
+                "1685200000:n23JJ2wKKtt30oXi",
+                systemtime_from_unix(1685200000 - 1000),
+            )
+            .expect("response to be authenticated with previous secret");
+    }
+
     #[test]
     fn invalid_username_format_fails() {
         let message_integrity = message_integrity(
@@ -346,7 +492,7 @@ mod tests {
         );
 
         let result = message_integrity.verify(
-            &RELAY_SECRET_1.parse().unwrap(),
+            &relay_secrets(RELAY_SECRET_1),
             "foobar",
             systemtime_from_unix(168520000 + 1000),
         );
@@ -382,6 +528,10 @@ mod tests {
         ));
     }
 
+    fn relay_secrets(current: &str) -> RelaySecrets {
+        RelaySecrets::new(current.parse().unwrap())
+    }
+
     fn message_integrity(
         relay_secret: &SecretString,
         username_expiry: u64,
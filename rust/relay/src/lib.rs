@@ -4,16 +4,19 @@ mod net_ext;
 mod server;
 mod sleep;
 
+pub mod admin;
 pub mod auth;
 #[cfg(feature = "proptest")]
 #[allow(clippy::unwrap_used)]
 pub mod proptest;
 pub mod sockets;
+pub mod stream;
 
 pub use net_ext::IpAddrExt;
 pub use server::{
-    Allocate, AllocationPort, Attribute, Binding, ChannelBind, ChannelData, ClientMessage, Command,
-    CreatePermission, Refresh, Server,
+    Allocate, AllocationInfo, AllocationPort, Attribute, Binding, ChannelBind, ChannelData,
+    ChannelInfo, ClientMessage, Command, CreatePermission, PeerPolicy, RateLimits, Refresh, Server,
+    SourceIpLimiters,
 };
 pub use sleep::Sleep;
 use stun_codec::rfc5389::attributes::Software;
@@ -103,20 +106,42 @@ impl From<(Option<Ipv4Addr>, Option<Ipv6Addr>)> for IpStack {
 /// From the [spec](https://www.rfc-editor.org/rfc/rfc8656#section-2-4.4):
 ///
 /// > A STUN client that implements this specification.
+///
+/// The same address may be used by different clients via UDP and via TCP, hence we also track the transport.
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
-pub struct ClientSocket(SocketAddr);
+pub struct ClientSocket {
+    addr: SocketAddr,
+    transport: ClientTransport,
+}
+
+/// How a client is connected to us.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy, PartialOrd, Ord)]
+pub enum ClientTransport {
+    Udp,
+    Tcp,
+    Tls,
+}
 
 impl ClientSocket {
+    /// A client that sends us datagrams via UDP.
     pub fn new(addr: SocketAddr) -> Self {
-        Self(addr)
+        Self::with_transport(addr, ClientTransport::Udp)
+    }
+
+    pub fn with_transport(addr: SocketAddr, transport: ClientTransport) -> Self {
+        Self { addr, transport }
     }
 
     pub fn into_socket(self) -> SocketAddr {
-        self.0
+        self.addr
+    }
+
+    pub fn transport(&self) -> ClientTransport {
+        self.transport
     }
 
     pub fn family(&self) -> AddressFamily {
-        match self.0 {
+        match self.addr {
             SocketAddr::V4(_) => AddressFamily::V4,
             SocketAddr::V6(_) => AddressFamily::V6,
         }
@@ -125,7 +150,11 @@ impl ClientSocket {
 
 impl fmt::Display for ClientSocket {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        self.0.fmt(f)
+        match self.transport {
+            ClientTransport::Udp => self.addr.fmt(f),
+            ClientTransport::Tcp => write!(f, "{} (TCP)", self.addr),
+            ClientTransport::Tls => write!(f, "{} (TLS)", self.addr),
+        }
     }
 }
 
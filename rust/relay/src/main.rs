@@ -3,26 +3,33 @@
 use anyhow::{bail, Context, Result};
 use backoff::ExponentialBackoffBuilder;
 use clap::Parser;
-use firezone_bin_shared::http_health_check;
+use firezone_bin_shared::{http_health_check, proxy::ProxyArgs};
 use firezone_logging::{err_with_src, sentry_layer};
 use firezone_relay::sockets::Sockets;
+use firezone_relay::stream::{self, Streams};
 use firezone_relay::{
-    sockets, AddressFamily, AllocationPort, ChannelData, ClientSocket, Command, IpStack,
-    PeerSocket, Server, Sleep, VERSION,
+    admin, sockets, AddressFamily, AllocationPort, ChannelData, ClientSocket, ClientTransport,
+    Command, IpStack, PeerPolicy, PeerSocket, RateLimits, Server, Sleep, SourceIpLimiters, VERSION,
 };
 use firezone_telemetry::{Telemetry, RELAY_DSN};
 use futures::{future, FutureExt};
+use ip_network::IpNetwork;
 use phoenix_channel::{Event, LoginUrl, NoParams, PhoenixChannel};
+use rand::distributions::Alphanumeric;
 use rand::rngs::StdRng;
 use rand::{Rng, SeedableRng};
 use secrecy::{ExposeSecret, Secret, SecretString};
 use std::borrow::Cow;
-use std::net::{Ipv4Addr, Ipv6Addr};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::{NonZeroU64, NonZeroUsize};
+use std::ops::RangeInclusive;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::{ready, Poll};
-use std::time::{Duration, Instant};
-use tracing::{level_filters::LevelFilter, Subscriber};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::mpsc;
+use tracing::{level_filters::LevelFilter, Instrument as _, Subscriber};
 use tracing_core::Dispatch;
 use tracing_stackdriver::CloudTraceConfiguration;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
@@ -33,7 +40,11 @@ const STATS_LOG_INTERVAL: Duration = Duration::from_secs(10);
 const MAX_PARTITION_TIME: Duration = Duration::from_secs(60 * 15);
 
 #[derive(Parser, Debug)]
+#[command(subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Cmd>,
+
     /// The public (i.e. internet-reachable) IPv4 address of the relay server.
     #[arg(long, env)]
     public_ip4_addr: Option<Ipv4Addr>,
@@ -43,6 +54,22 @@ struct Args {
     /// The port to listen on for STUN messages.
     #[arg(long, env, hide = true, default_value = "3478")]
     listen_port: u16,
+    /// The port to listen on for STUN messages via TCP.
+    ///
+    /// Clients behind firewalls that drop UDP traffic fall back to this.
+    #[arg(long, env, hide = true, default_value = "3478")]
+    tcp_listen_port: u16,
+    /// The port to listen on for STUN messages via TLS.
+    ///
+    /// Only used if both `--tls-cert-file` and `--tls-key-file` are set.
+    #[arg(long, env, hide = true, default_value = "443")]
+    tls_listen_port: u16,
+    /// Path to a PEM-encoded certificate chain for accepting TLS connections.
+    #[arg(long, env, requires = "tls_key_file")]
+    tls_cert_file: Option<PathBuf>,
+    /// Path to a PEM-encoded private key for accepting TLS connections.
+    #[arg(long, env, requires = "tls_cert_file")]
+    tls_key_file: Option<PathBuf>,
     // See https://www.rfc-editor.org/rfc/rfc8656.html#name-allocations
     /// The lowest port used for TURN allocations.
     #[arg(long, env, hide = true, default_value = "49152")]
@@ -50,11 +77,45 @@ struct Args {
     /// The highest port used for TURN allocations.
     #[arg(long, env, hide = true, default_value = "65535")]
     highest_port: u16,
-    #[arg(long, env = "FIREZONE_API_URL")]
-    api_url: Url,
+    /// How many threads should relay traffic.
+    ///
+    /// Clients are spread across the threads by the kernel and each thread gets an equal share of the port range for allocations.
+    /// Defaults to the number of available CPU cores.
+    #[arg(long, env)]
+    workers: Option<NonZeroUsize>,
+    /// The maximum number of bytes per second we relay for a single allocation.
+    #[arg(long, env)]
+    allocation_bytes_per_sec: Option<NonZeroU64>,
+    /// The maximum number of packets per second we relay for a single allocation.
+    #[arg(long, env)]
+    allocation_packets_per_sec: Option<NonZeroU64>,
+    /// The maximum number of bytes per second we relay for all allocations of a single client IP.
+    #[arg(long, env)]
+    source_ip_bytes_per_sec: Option<NonZeroU64>,
+    /// The maximum number of packets per second we relay for all allocations of a single client IP.
+    #[arg(long, env)]
+    source_ip_packets_per_sec: Option<NonZeroU64>,
+    /// Peer networks that clients may always relay to, even if they are in `--denied-peer-cidrs`.
+    #[arg(long, env, value_delimiter = ',')]
+    allowed_peer_cidrs: Vec<IpNetwork>,
+    /// Peer networks that clients may not relay to.
+    ///
+    /// Defaults to loopback, link-local, multicast and private networks.
+    #[arg(long, env, value_delimiter = ',')]
+    denied_peer_cidrs: Option<Vec<IpNetwork>>,
+    #[arg(long, env = "FIREZONE_API_URL", required_unless_present = "standalone")]
+    api_url: Option<Url>,
     /// Token generated by the portal to authorize websocket connection.
-    #[arg(env = "FIREZONE_TOKEN")]
-    token: SecretString,
+    #[arg(env = "FIREZONE_TOKEN", required_unless_present = "standalone")]
+    token: Option<SecretString>,
+    /// Run without connecting to the portal.
+    ///
+    /// Instead of generating a secret and sharing it with the portal, the relay validates credentials against a static secret.
+    /// Use the `mint-credentials` subcommand to create credentials for that secret.
+    #[arg(long, env)]
+    standalone: bool,
+    #[command(flatten)]
+    auth_secret: AuthSecretArgs,
     /// Used as the human name for this Relay to display in the portal. If not provided,
     /// the system hostname is used by default.
     #[arg(env = "FIREZONE_NAME")]
@@ -83,11 +144,66 @@ struct Args {
     #[command(flatten)]
     health_check: http_health_check::HealthCheckArgs,
 
+    #[command(flatten)]
+    proxy: ProxyArgs,
+
+    /// The address of the local interface where we should serve the admin API.
+    ///
+    /// `GET http://<admin_addr>/allocations` lists all allocations and `DELETE http://<admin_addr>/allocations/<port>` frees one.
+    /// The admin API is unauthenticated and must therefore only be bound to a loopback address.
+    #[arg(long, env, hide = true)]
+    admin_addr: Option<SocketAddr>,
+
     /// Enable sentry.io crash-reporting agent.
     #[arg(long, env = "FIREZONE_TELEMETRY", default_value_t = false)]
     telemetry: bool,
 }
 
+#[derive(clap::Subcommand, Debug)]
+enum Cmd {
+    /// Mints a username and password for a relay running in `--standalone` mode.
+    MintCredentials {
+        #[command(flatten)]
+        auth_secret: AuthSecretArgs,
+        /// How long the credentials should be valid for, in seconds.
+        #[arg(long, default_value = "86400")]
+        valid_for_secs: u64,
+    },
+}
+
+/// The static secret used to validate credentials in `--standalone` mode.
+#[derive(clap::Args, Debug)]
+struct AuthSecretArgs {
+    /// The secret to validate credentials against.
+    #[arg(long, env, conflicts_with = "auth_secret_file")]
+    auth_secret: Option<SecretString>,
+    /// Path to a file containing the secret to validate credentials against.
+    #[arg(long, env)]
+    auth_secret_file: Option<PathBuf>,
+}
+
+impl AuthSecretArgs {
+    fn load(&self) -> Result<SecretString> {
+        if let Some(secret) = &self.auth_secret {
+            return Ok(secret.clone());
+        }
+
+        let Some(path) = &self.auth_secret_file else {
+            bail!("Either `--auth-secret` or `--auth-secret-file` must be set")
+        };
+
+        let secret = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read `{}`", path.display()))?;
+        let secret = secret.trim();
+
+        if secret.is_empty() {
+            bail!("`{}` is empty", path.display())
+        }
+
+        Ok(SecretString::from(secret.to_owned()))
+    }
+}
+
 #[derive(clap::ValueEnum, Debug, Clone, Copy)]
 enum LogFormat {
     Human,
@@ -102,13 +218,19 @@ fn main() {
 
     let args = Args::parse();
 
+    if let Some(Cmd::MintCredentials {
+        auth_secret,
+        valid_for_secs,
+    }) = &args.command
+    {
+        print_credentials(auth_secret, Duration::from_secs(*valid_for_secs));
+
+        return;
+    }
+
     let mut telemetry = Telemetry::default();
-    if args.telemetry {
-        telemetry.start(
-            args.api_url.as_str(),
-            VERSION.unwrap_or("unknown"),
-            RELAY_DSN,
-        );
+    if let (true, Some(api_url)) = (args.telemetry, &args.api_url) {
+        telemetry.start(api_url.as_str(), VERSION.unwrap_or("unknown"), RELAY_DSN);
     }
 
     let runtime = tokio::runtime::Builder::new_current_thread()
@@ -130,6 +252,17 @@ fn main() {
 async fn try_main(args: Args) -> Result<()> {
     setup_tracing(&args)?;
 
+    // Without an OTLP collector, we expose our metrics for Prometheus to scrape instead.
+    let metrics = if args.otlp_grpc_endpoint.is_none() {
+        http_health_check::install_prometheus_meter_provider()
+    } else {
+        Default::default()
+    };
+
+    if args.admin_addr.is_some_and(|addr| !addr.ip().is_loopback()) {
+        bail!("The admin API must only be bound to a loopback address")
+    }
+
     let public_addr = match (args.public_ip4_addr, args.public_ip6_addr) {
         (Some(ip4), Some(ip6)) => IpStack::Dual { ip4, ip6 },
         (Some(ip4), None) => IpStack::Ip4(ip4),
@@ -139,23 +272,152 @@ async fn try_main(args: Args) -> Result<()> {
         }
     };
 
-    let server = Server::new(
-        public_addr,
-        make_rng(args.rng_seed),
-        args.listen_port,
-        args.lowest_port..=args.highest_port,
+    let num_workers = args
+        .workers
+        .unwrap_or_else(|| std::thread::available_parallelism().unwrap_or(NonZeroUsize::MIN));
+    let mut port_ranges =
+        partition_ports(args.lowest_port..=args.highest_port, num_workers)?.into_iter();
+
+    let mut rng = make_rng(args.rng_seed);
+    let source_ip_limiters = SourceIpLimiters::default();
+    let mut make_server = |ports| {
+        // Derive each server's RNG from the main one so `--rng-seed` stays deterministic.
+        let mut server = Server::new(
+            public_addr,
+            StdRng::seed_from_u64(rng.gen()),
+            args.listen_port,
+            ports,
+        );
+        server.set_rate_limits(RateLimits {
+            allocation_bytes_per_sec: args.allocation_bytes_per_sec,
+            allocation_packets_per_sec: args.allocation_packets_per_sec,
+            source_ip_bytes_per_sec: args.source_ip_bytes_per_sec,
+            source_ip_packets_per_sec: args.source_ip_packets_per_sec,
+        });
+        server.set_source_ip_limiters(source_ip_limiters.clone());
+        server.set_peer_policy(PeerPolicy::new(
+            args.allowed_peer_cidrs.clone(),
+            args.denied_peer_cidrs.clone(),
+        ));
+
+        server
+    };
+
+    let mut server = make_server(
+        port_ranges
+            .next()
+            .context("Need at least one range of allocation ports")?,
     );
 
+    if args.standalone {
+        server.set_auth_secret(args.auth_secret.load()?);
+
+        tracing::info!(target: "relay", "Running in standalone mode");
+    }
+
     let last_heartbeat_sent = Arc::new(Mutex::new(Option::<Instant>::None));
 
     tokio::spawn(http_health_check::serve(
         args.health_check.health_check_addr,
         make_is_healthy(last_heartbeat_sent.clone()),
+        metrics,
     ));
 
+    let channel = if args.standalone {
+        None
+    } else {
+        Some(connect_to_portal(&args, &server)?)
+    };
+
+    let stream_ports = StreamPorts {
+        tcp: args.tcp_listen_port,
+        tls: match (&args.tls_cert_file, &args.tls_key_file) {
+            (Some(cert_file), Some(key_file)) => Some((
+                args.tls_listen_port,
+                stream::tls_acceptor(cert_file, key_file)?,
+            )),
+            (Some(_) | None, Some(_) | None) => None,
+        },
+    };
+
+    let mut admin_requests = Vec::new();
+    let mut workers = Workers::default();
+
+    // The remaining port ranges are served by additional worker threads.
+    for (id, ports) in (1..).zip(port_ranges) {
+        let mut worker_server = make_server(ports);
+        worker_server.set_auth_secret(server.auth_secret().clone());
+
+        let (secrets_tx, secrets_rx) = mpsc::unbounded_channel();
+        let worker_admin_requests = args.admin_addr.map(|_| {
+            let (tx, rx) = admin::channel();
+            admin_requests.push(tx);
+
+            rx
+        });
+
+        spawn_worker(
+            id,
+            worker_server,
+            public_addr,
+            stream_ports.clone(),
+            secrets_rx,
+            worker_admin_requests,
+            workers.exits_tx.clone(),
+        )?;
+        workers.auth_secrets.push(secrets_tx);
+    }
+
+    let mut eventloop = Eventloop::new(server, channel, public_addr, last_heartbeat_sent)?;
+    eventloop.set_workers(workers);
+
+    tracing::info!(target: "relay", "Listening for incoming traffic on UDP port {0} with {num_workers} workers", args.listen_port);
+
+    eventloop.listen_streams(&stream_ports, public_addr)?;
+
+    tracing::info!(target: "relay", "Listening for incoming traffic on TCP port {0}", stream_ports.tcp);
+
+    if let Some((tls_port, _)) = &stream_ports.tls {
+        tracing::info!(target: "relay", "Listening for incoming traffic on TLS port {tls_port}");
+    }
+
+    if let Some(admin_addr) = args.admin_addr {
+        let (tx, rx) = admin::channel();
+        admin_requests.insert(0, tx);
+        eventloop.handle_admin_requests(rx);
+
+        tokio::spawn(async move {
+            if let Err(e) = admin::serve(admin_addr, admin_requests).await {
+                tracing::warn!(target: "relay", "Admin API failed: {}", err_with_src(&e));
+            }
+        });
+
+        tracing::info!(target: "relay", "Serving admin API on {admin_addr}");
+    }
+
+    future::poll_fn(|cx| eventloop.poll(cx))
+        .await
+        .context("event loop failed")?;
+
+    tracing::info!("Goodbye!");
+
+    Ok(())
+}
+
+fn connect_to_portal<R>(
+    args: &Args,
+    server: &Server<R>,
+) -> Result<PhoenixChannel<JoinMessage, IngressMessage, (), NoParams>>
+where
+    R: Rng,
+{
+    let (Some(api_url), Some(token)) = (&args.api_url, &args.token) else {
+        bail!("`--api-url` and a token are required unless running in `--standalone` mode")
+    };
+
     let login = LoginUrl::relay(
-        args.api_url.clone(),
-        &args.token,
+        api_url.clone(),
+        token,
         args.name.clone(),
         args.listen_port,
         args.public_ip4_addr,
@@ -175,20 +437,55 @@ async fn try_main(args: Args) -> Result<()> {
                 .build()
         },
         Arc::new(socket_factory::tcp),
+        args.proxy.config().context("Invalid proxy")?,
     )?;
     channel.connect(NoParams);
 
-    let mut eventloop = Eventloop::new(server, channel, public_addr, last_heartbeat_sent)?;
-
-    tracing::info!(target: "relay", "Listening for incoming traffic on UDP port {0}", args.listen_port);
+    Ok(channel)
+}
 
-    future::poll_fn(|cx| eventloop.poll(cx))
-        .await
-        .context("event loop failed")?;
+/// Prints a username and password that are valid for the given secret or exits with an error.
+#[expect(
+    clippy::print_stdout,
+    clippy::print_stderr,
+    reason = "This is the output of the CLI"
+)]
+fn print_credentials(auth_secret: &AuthSecretArgs, valid_for: Duration) {
+    let credentials = auth_secret
+        .load()
+        .and_then(|secret| mint_credentials(&secret, valid_for));
+
+    match credentials {
+        Ok((username, password)) => {
+            println!("username: {username}");
+            println!("password: {password}");
+        }
+        Err(e) => {
+            eprintln!("{e:#}");
 
-    tracing::info!("Goodbye!");
+            std::process::exit(1);
+        }
+    }
+}
 
-    Ok(())
+/// Mints a username and password that are valid for the given secret.
+///
+/// See [`firezone_relay::auth`] for how credentials are constructed.
+fn mint_credentials(secret: &SecretString, valid_for: Duration) -> Result<(String, String)> {
+    let expiry = SystemTime::now() + valid_for;
+    let expiry_secs = expiry
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .context("Expiry is before the UNIX epoch")?
+        .as_secs();
+    let salt = rand::thread_rng()
+        .sample_iter(Alphanumeric)
+        .take(16)
+        .map(char::from)
+        .collect::<String>();
+
+    let password = firezone_relay::auth::generate_password(secret, expiry, &salt);
+
+    Ok((format!("{expiry_secs}:{salt}"), password))
 }
 
 /// Sets up our tracing infrastructure.
@@ -302,16 +599,121 @@ fn env_filter() -> EnvFilter {
 #[serde(rename_all = "snake_case", tag = "event", content = "payload")]
 enum IngressMessage {
     Init(Init),
+    RotateSecret(RotateSecret),
 }
 
 #[derive(serde::Deserialize, Debug)]
 struct Init {}
 
+#[derive(serde::Deserialize, Debug)]
+struct RotateSecret {}
+
 #[derive(serde::Serialize, PartialEq, Debug, Clone)]
 struct JoinMessage {
     stamp_secret: String,
 }
 
+fn address_families(public_address: IpStack) -> impl Iterator<Item = AddressFamily> {
+    let v4 = public_address.as_v4().map(|_| AddressFamily::V4);
+    let v6 = public_address.as_v6().map(|_| AddressFamily::V6);
+
+    v4.into_iter().chain(v6)
+}
+
+/// Splits the allocation port range into one contiguous range per worker.
+///
+/// Each [`Server`] picks the ports for new allocations from its own range, thus workers never compete for the same port.
+fn partition_ports(
+    ports: RangeInclusive<u16>,
+    num_workers: NonZeroUsize,
+) -> Result<Vec<RangeInclusive<u16>>> {
+    let lowest = usize::from(*ports.start());
+    let num_ports = (usize::from(*ports.end()) + 1).saturating_sub(lowest);
+    let num_workers = num_workers.get();
+
+    if num_ports < num_workers {
+        bail!("Cannot split {num_ports} allocation ports across {num_workers} workers")
+    }
+
+    let ports_per_worker = num_ports / num_workers;
+    let remainder = num_ports % num_workers;
+
+    let mut start = lowest;
+    let ranges = (0..num_workers)
+        .map(|i| {
+            let len = ports_per_worker + usize::from(i < remainder);
+            let range = start as u16..=(start + len - 1) as u16;
+            start += len;
+
+            range
+        })
+        .collect();
+
+    Ok(ranges)
+}
+
+/// Runs a [`Server`] on its own thread, sharing the TURN port with all other workers via `SO_REUSEPORT`.
+///
+/// The kernel hashes the 4-tuple of incoming packets to pick a socket, meaning a client always talks to the same worker.
+/// Its allocations are therefore owned by that worker which also receives all traffic from peers on the allocated ports.
+/// The same applies to TCP and TLS connections, which every worker accepts on the shared stream ports.
+/// Only the primary event-loop talks to the portal.
+fn spawn_worker(
+    id: usize,
+    server: Server<StdRng>,
+    public_address: IpStack,
+    stream_ports: StreamPorts,
+    auth_secrets: mpsc::UnboundedReceiver<SecretString>,
+    admin_requests: Option<mpsc::Receiver<admin::Request>>,
+    exit: mpsc::UnboundedSender<Result<()>>,
+) -> Result<()> {
+    std::thread::Builder::new()
+        .name(format!("relay-worker-{id}"))
+        .spawn(move || {
+            let result = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()
+                .context("Failed to build tokio runtime")
+                .and_then(|runtime| {
+                    runtime.block_on(
+                        run_worker(
+                            server,
+                            public_address,
+                            stream_ports,
+                            auth_secrets,
+                            admin_requests,
+                        )
+                        .instrument(tracing::info_span!("worker", %id)),
+                    )
+                });
+
+            let _ = exit.send(result);
+        })
+        .context("Failed to spawn worker thread")?;
+
+    Ok(())
+}
+
+async fn run_worker(
+    server: Server<StdRng>,
+    public_address: IpStack,
+    stream_ports: StreamPorts,
+    auth_secrets: mpsc::UnboundedReceiver<SecretString>,
+    admin_requests: Option<mpsc::Receiver<admin::Request>>,
+) -> Result<()> {
+    let mut eventloop = Eventloop::new(server, None, public_address, Arc::default())?;
+    eventloop.follow_auth_secrets(auth_secrets);
+    eventloop.listen_streams(&stream_ports, public_address)?;
+
+    if let Some(admin_requests) = admin_requests {
+        eventloop.handle_admin_requests(admin_requests);
+    }
+
+    future::poll_fn(|cx| eventloop.poll(cx))
+        .await
+        .context("event loop failed")
+}
+
 fn make_rng(seed: Option<u64>) -> StdRng {
     let Some(seed) = seed else {
         return StdRng::from_entropy();
@@ -324,15 +726,59 @@ fn make_rng(seed: Option<u64>) -> StdRng {
 
 const MAX_UDP_SIZE: usize = 65536;
 
+/// The ports on which every worker accepts TURN over TCP and TLS.
+#[derive(Clone)]
+struct StreamPorts {
+    tcp: u16,
+    tls: Option<(u16, tokio_rustls::TlsAcceptor)>,
+}
+
+/// The primary event-loop's handles to the other workers.
+struct Workers {
+    /// Where to send the new auth secret after a rotation.
+    auth_secrets: Vec<mpsc::UnboundedSender<SecretString>>,
+
+    exits_tx: mpsc::UnboundedSender<Result<()>>,
+    exits_rx: mpsc::UnboundedReceiver<Result<()>>,
+}
+
+impl Default for Workers {
+    fn default() -> Self {
+        let (exits_tx, exits_rx) = mpsc::unbounded_channel();
+
+        Self {
+            auth_secrets: Vec::new(),
+            exits_tx,
+            exits_rx,
+        }
+    }
+}
+
+impl Workers {
+    fn num_running(&self) -> usize {
+        self.auth_secrets.iter().filter(|s| !s.is_closed()).count()
+    }
+}
+
 struct Eventloop<R> {
     sockets: Sockets,
+    streams: Streams,
 
     server: Server<R>,
     channel: Option<PhoenixChannel<JoinMessage, IngressMessage, (), NoParams>>,
+    admin_requests: Option<mpsc::Receiver<admin::Request>>,
     sleep: Sleep,
 
+    /// The other workers, only set on the primary event-loop.
+    workers: Option<Workers>,
+    /// Auth secrets rotated by the primary, only set on workers.
+    auth_secrets: Option<mpsc::UnboundedReceiver<SecretString>>,
+
     #[cfg(unix)]
     sigterm: tokio::signal::unix::Signal,
+    /// Only the primary rotates the auth secret on `SIGHUP`.
+    #[cfg(unix)]
+    sighup: Option<tokio::signal::unix::Signal>,
     shutting_down: bool,
 
     stats_log_interval: tokio::time::Interval,
@@ -349,7 +795,7 @@ where
 {
     fn new(
         server: Server<R>,
-        channel: PhoenixChannel<JoinMessage, IngressMessage, (), NoParams>,
+        channel: Option<PhoenixChannel<JoinMessage, IngressMessage, (), NoParams>>,
         public_address: IpStack,
         last_heartbeat_sent: Arc<Mutex<Option<Instant>>>,
     ) -> Result<Self> {
@@ -357,7 +803,7 @@ where
 
         if public_address.as_v4().is_some() {
             sockets
-                .bind(server.listen_port(), AddressFamily::V4)
+                .bind_reuse_port(server.listen_port(), AddressFamily::V4)
                 .with_context(|| {
                     format!(
                         "Failed to bind to port {0} on IPv4 interfaces",
@@ -367,7 +813,7 @@ where
         }
         if public_address.as_v6().is_some() {
             sockets
-                .bind(server.listen_port(), AddressFamily::V6)
+                .bind_reuse_port(server.listen_port(), AddressFamily::V6)
                 .with_context(|| {
                     format!(
                         "Failed to bind to port {0} on IPv6 interfaces",
@@ -378,24 +824,90 @@ where
 
         Ok(Self {
             server,
-            channel: Some(channel),
+            channel,
+            admin_requests: None,
             sleep: Sleep::default(),
+            workers: None,
+            auth_secrets: None,
             stats_log_interval: tokio::time::interval(STATS_LOG_INTERVAL),
             last_num_bytes_relayed: 0,
             sockets,
+            streams: Streams::new(),
             buffer: [0u8; MAX_UDP_SIZE],
             last_heartbeat_sent,
             #[cfg(unix)]
             sigterm: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?,
+            #[cfg(unix)]
+            sighup: Some(tokio::signal::unix::signal(
+                tokio::signal::unix::SignalKind::hangup(),
+            )?),
             shutting_down: false,
         })
     }
 
+    fn listen_streams(&mut self, ports: &StreamPorts, public_address: IpStack) -> Result<()> {
+        for family in address_families(public_address) {
+            self.streams.listen_tcp(ports.tcp, family)?;
+
+            if let Some((port, acceptor)) = &ports.tls {
+                self.streams.listen_tls(*port, family, acceptor.clone())?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_admin_requests(&mut self, requests: mpsc::Receiver<admin::Request>) {
+        self.admin_requests = Some(requests);
+    }
+
+    fn set_workers(&mut self, workers: Workers) {
+        self.workers = Some(workers);
+    }
+
+    fn follow_auth_secrets(&mut self, auth_secrets: mpsc::UnboundedReceiver<SecretString>) {
+        self.auth_secrets = Some(auth_secrets);
+
+        #[cfg(unix)]
+        {
+            self.sighup = None;
+        }
+    }
+
+    fn handle_admin_request(&mut self, request: admin::Request) {
+        match request {
+            admin::Request::ListAllocations(responder) => {
+                let _ = responder.send(self.server.allocations(Instant::now()));
+            }
+            admin::Request::FreeAllocation(port, responder) => {
+                let _ = responder.send(self.server.free_allocation(port));
+            }
+        }
+    }
+
+    /// Sends a message to a client, using its stream connection if it has one.
+    fn send_to_client(
+        &mut self,
+        client: ClientSocket,
+        payload: Cow<'_, [u8]>,
+    ) -> std::io::Result<()> {
+        if client.transport() != ClientTransport::Udp {
+            return self.streams.try_send(client, &payload);
+        }
+
+        self.sockets
+            .try_send(self.server.listen_port(), client.into_socket(), payload)
+    }
+
     fn poll(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<()>> {
         loop {
             let mut ready = false;
 
-            if self.shutting_down && self.channel.is_none() && self.server.num_allocations() == 0 {
+            if self.shutting_down
+                && self.channel.is_none()
+                && self.server.num_allocations() == 0
+                && self.workers.as_ref().is_none_or(|w| w.num_running() == 0)
+            {
                 return Poll::Ready(Ok(()));
             }
 
@@ -405,11 +917,7 @@ where
             if let Some(next_command) = self.server.next_command() {
                 match next_command {
                     Command::SendMessage { payload, recipient } => {
-                        if let Err(e) = self.sockets.try_send(
-                            self.server.listen_port(),
-                            recipient.into_socket(),
-                            Cow::Owned(payload),
-                        ) {
+                        if let Err(e) = self.send_to_client(recipient, Cow::Owned(payload)) {
                             tracing::warn!(target: "relay", %recipient, "Failed to send message: {}", err_with_src(&e));
                         }
                     }
@@ -496,6 +1004,7 @@ where
                         packet,
                         PeerSocket::new(from),
                         AllocationPort::new(port),
+                        Instant::now(),
                     ) {
                         let total_length = ChannelData::encode_header_to_slice(
                             channel,
@@ -503,11 +1012,19 @@ where
                             header,
                         );
 
-                        if let Err(e) = self.sockets.try_send(
-                            self.server.listen_port(), // Packets coming in from peers always go out on the TURN port
-                            client.into_socket(),
-                            Cow::Borrowed(&self.buffer[..total_length]),
-                        ) {
+                        let channel_data = &self.buffer[..total_length];
+
+                        let result = if client.transport() != ClientTransport::Udp {
+                            self.streams.try_send(client, channel_data)
+                        } else {
+                            self.sockets.try_send(
+                                self.server.listen_port(), // Packets coming in from peers always go out on the TURN port
+                                client.into_socket(),
+                                Cow::Borrowed(channel_data),
+                            )
+                        };
+
+                        if let Err(e) = result {
                             tracing::warn!(target: "relay", %client, "Failed to relay data to client: {}", err_with_src(&e));
                         };
                     };
@@ -523,6 +1040,36 @@ where
                 Poll::Pending => {}
             }
 
+            // Priority 2b: Read from clients connected via TCP or TLS.
+            match self.streams.poll_recv(cx) {
+                Poll::Ready(stream::Received::Message { from, packet }) => {
+                    if let Some((port, peer)) =
+                        self.server
+                            .handle_client_input(&packet, from, Instant::now())
+                    {
+                        let payload = ChannelData::parse(&packet)
+                            .expect("valid ChannelData if we should relay it")
+                            .data();
+
+                        if let Err(e) = self.sockets.try_send(
+                            port.value(),
+                            peer.into_socket(),
+                            Cow::Borrowed(payload),
+                        ) {
+                            tracing::warn!(target: "relay", %peer, "Failed to relay data to peer: {}", err_with_src(&e));
+                        }
+                    }
+
+                    ready = true;
+                }
+                Poll::Ready(stream::Received::Closed(client)) => {
+                    self.server.handle_client_disconnected(client);
+
+                    ready = true;
+                }
+                Poll::Pending => {}
+            }
+
             // Priority 3: Check when we need to next be woken. This needs to happen after all state modifications.
             if let Some(timeout) = self.server.poll_timeout() {
                 Pin::new(&mut self.sleep).reset(timeout);
@@ -547,6 +1094,51 @@ where
                 Some(Poll::Pending) | None => {}
             }
 
+            // Priority 6: Handle admin API requests
+            match self.admin_requests.as_mut().map(|r| r.poll_recv(cx)) {
+                Some(Poll::Ready(Some(request))) => {
+                    self.handle_admin_request(request);
+
+                    ready = true;
+                }
+                Some(Poll::Ready(None)) => {
+                    self.admin_requests = None; // The admin API server has stopped.
+                }
+                Some(Poll::Pending) | None => {}
+            }
+
+            // Priority 7: Check on the other workers.
+            match self.workers.as_mut().map(|w| w.exits_rx.poll_recv(cx)) {
+                Some(Poll::Ready(Some(Ok(())))) => {
+                    ready = true;
+                }
+                Some(Poll::Ready(Some(Err(e)))) => {
+                    return Poll::Ready(Err(e.context("Worker failed")));
+                }
+                Some(Poll::Ready(None) | Poll::Pending) | None => {}
+            }
+
+            // Priority 8: Adopt auth secrets rotated by the primary.
+            match self.auth_secrets.as_mut().map(|r| r.poll_recv(cx)) {
+                Some(Poll::Ready(Some(secret))) => {
+                    self.server.adopt_auth_secret(secret, Instant::now());
+
+                    tracing::info!(target: "relay", "Adopted rotated auth secret");
+
+                    ready = true;
+                }
+                Some(Poll::Ready(None) | Poll::Pending) | None => {}
+            }
+
+            #[cfg(unix)]
+            if let Some(Poll::Ready(Some(()))) = self.sighup.as_mut().map(|s| s.poll_recv(cx)) {
+                tracing::info!(target: "relay", "Received SIGHUP, rotating auth secret");
+
+                self.rotate_auth_secret();
+
+                ready = true;
+            }
+
             #[cfg(unix)]
             match self.sigterm.poll_recv(cx) {
                 Poll::Ready(Some(())) => {
@@ -595,6 +1187,27 @@ where
         }
     }
 
+    /// Rotates the auth secret of the server and shares the new one with the portal.
+    ///
+    /// The previous secret remains valid for a while, thus existing allocations are unaffected.
+    fn rotate_auth_secret(&mut self) {
+        // Without a portal, nobody could mint credentials for the new secret.
+        let Some(portal) = self.channel.as_mut() else {
+            tracing::warn!(target: "relay", "Not connected to the portal, refusing to rotate auth secret");
+            return;
+        };
+
+        self.server.rotate_auth_secret(Instant::now());
+
+        for worker in self.workers.iter().flat_map(|w| &w.auth_secrets) {
+            let _ = worker.send(self.server.auth_secret().clone());
+        }
+
+        portal.rejoin(JoinMessage {
+            stamp_secret: self.server.auth_secret().expose_secret().to_string(),
+        });
+    }
+
     fn handle_portal_event(&mut self, event: phoenix_channel::Event<IngressMessage, ()>) {
         match event {
             Event::SuccessResponse { res: (), .. } => {}
@@ -615,6 +1228,14 @@ where
                 msg: IngressMessage::Init(Init {}),
                 ..
             } => {}
+            Event::InboundMessage {
+                msg: IngressMessage::RotateSecret(RotateSecret {}),
+                ..
+            } => {
+                tracing::info!(target: "relay", "Portal requested to rotate auth secret");
+
+                self.rotate_auth_secret();
+            }
             Event::Closed => {
                 self.channel = None;
             }
@@ -721,6 +1342,58 @@ mod tests {
         assert!(!is_healthy)
     }
 
+    #[test]
+    fn minted_credentials_authenticate_allocation() {
+        let secret = SecretString::from("standalone-secret".to_owned());
+        let (username, password) = mint_credentials(&secret, Duration::from_secs(60)).unwrap();
+
+        let nonce = uuid::Uuid::new_v4();
+        let mut server = Server::new(
+            Ipv4Addr::new(1, 1, 1, 1),
+            StdRng::seed_from_u64(0),
+            3478,
+            49152..=65535,
+        );
+        server.set_auth_secret(secret);
+        server.add_nonce(nonce);
+
+        let allocate = allocate_request(&username, &password, nonce);
+        server.handle_client_input(
+            &allocate,
+            ClientSocket::new("10.0.0.1:50000".parse().unwrap()),
+            Instant::now(),
+        );
+
+        assert!(matches!(
+            server.next_command(),
+            Some(Command::CreateAllocation {
+                family: AddressFamily::V4,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn partitions_ports_evenly() {
+        let ranges = partition_ports(49152..=49161, NonZeroUsize::new(3).unwrap()).unwrap();
+
+        assert_eq!(ranges, vec![49152..=49155, 49156..=49158, 49159..=49161]);
+    }
+
+    #[test]
+    fn single_worker_gets_all_ports() {
+        let ranges = partition_ports(49152..=65535, NonZeroUsize::MIN).unwrap();
+
+        assert_eq!(ranges, vec![49152..=65535]);
+    }
+
+    #[test]
+    fn fails_to_partition_fewer_ports_than_workers() {
+        let result = partition_ports(49152..=49153, NonZeroUsize::new(3).unwrap());
+
+        assert!(result.is_err());
+    }
+
     // Regression tests to ensure we can parse sockets as well as domains for the otlp-grpc endpoint.
     #[test]
     fn args_can_parse_otlp_endpoint_from_socket() {
@@ -751,4 +1424,35 @@ mod tests {
 
         assert_eq!(args.otlp_grpc_endpoint.unwrap(), "localhost:4317");
     }
+
+    /// Encodes an ALLOCATE request authenticated with the given long-term credentials, just like a client would.
+    fn allocate_request(username: &str, password: &str, nonce: uuid::Uuid) -> Vec<u8> {
+        use bytecodec::EncodeExt as _;
+        use stun_codec::rfc5389::attributes::{MessageIntegrity, Nonce, Username};
+        use stun_codec::rfc5766::attributes::RequestedTransport;
+        use stun_codec::rfc5766::methods::ALLOCATE;
+        use stun_codec::{Message, MessageClass, MessageEncoder, TransactionId};
+
+        let username = Username::new(username.to_owned()).unwrap();
+
+        let mut message = Message::<firezone_relay::Attribute>::new(
+            MessageClass::Request,
+            ALLOCATE,
+            TransactionId::new([1; 12]),
+        );
+        message.add_attribute(RequestedTransport::new(17)); // UDP
+        message.add_attribute(username.clone());
+        message.add_attribute(Nonce::new(nonce.as_hyphenated().to_string()).unwrap());
+
+        let message_integrity = MessageIntegrity::new_long_term_credential(
+            &message,
+            &username,
+            &firezone_relay::auth::FIREZONE,
+            password,
+        )
+        .unwrap();
+        message.add_attribute(message_integrity);
+
+        MessageEncoder::new().encode_into_bytes(message).unwrap()
+    }
 }
@@ -1,13 +1,20 @@
 mod channel_data;
 mod client_message;
+mod peer_policy;
+mod rate_limit;
 
 pub use crate::server::channel_data::ChannelData;
 pub use crate::server::client_message::{
     Allocate, Binding, ChannelBind, ClientMessage, CreatePermission, Refresh,
 };
+pub use crate::server::peer_policy::PeerPolicy;
+pub use crate::server::rate_limit::{RateLimits, SourceIpLimiters};
 
-use crate::auth::{self, AuthenticatedMessage, MessageIntegrityExt, Nonces, FIREZONE};
+use crate::auth::{
+    self, AuthenticatedMessage, MessageIntegrityExt, Nonces, RelaySecrets, FIREZONE,
+};
 use crate::net_ext::IpAddrExt;
+use crate::server::rate_limit::Limiter;
 use crate::{ClientSocket, IpStack, PeerSocket, SOFTWARE};
 use anyhow::Result;
 use bytecodec::EncodeExt;
@@ -19,8 +26,9 @@ use opentelemetry::KeyValue;
 use rand::Rng;
 use secrecy::SecretString;
 use smallvec::SmallVec;
-use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::collections::{hash_map, BTreeMap, HashMap, VecDeque};
 use std::hash::Hash;
+use std::iter;
 use std::net::{IpAddr, SocketAddr};
 use std::ops::RangeInclusive;
 use std::time::{Duration, Instant, SystemTime};
@@ -32,7 +40,7 @@ use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::rfc5766::attributes::{
     ChannelNumber, Lifetime, RequestedTransport, XorPeerAddress, XorRelayAddress,
 };
-use stun_codec::rfc5766::errors::{AllocationMismatch, InsufficientCapacity};
+use stun_codec::rfc5766::errors::{AllocationMismatch, Forbidden, InsufficientCapacity};
 use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH};
 use stun_codec::rfc8656::attributes::{
     AdditionalAddressFamily, AddressFamily, RequestedAddressFamily,
@@ -77,14 +85,42 @@ pub struct Server<R> {
 
     rng: R,
 
-    auth_secret: SecretString,
+    auth_secrets: RelaySecrets,
 
     nonces: Nonces,
 
+    rate_limits: RateLimits,
+    allocation_limiters: HashMap<AllocationPort, Limiter>,
+    source_ip_limiters: SourceIpLimiters,
+
+    peer_policy: PeerPolicy,
+
     allocations_up_down_counter: UpDownCounter<i64>,
     data_relayed_counter: Counter<u64>,
     data_relayed: u64, // Keep a separate counter because `Counter` doesn't expose the current value :(
     responses_counter: Counter<u64>,
+    data_dropped_counter: Counter<u64>,
+    denied_peers_counter: Counter<u64>,
+}
+
+/// A snapshot of an allocation, see [`Server::allocations`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct AllocationInfo {
+    pub client: SocketAddr,
+    pub port: u16,
+    pub relay_addresses: Vec<IpAddr>,
+    pub expires_in_secs: u64,
+    pub bytes_relayed: u64,
+    pub channels: Vec<ChannelInfo>,
+}
+
+/// A snapshot of a channel binding, see [`Server::allocations`].
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ChannelInfo {
+    pub number: u16,
+    pub peer: SocketAddr,
+    pub bound: bool,
+    pub expires_in_secs: u64,
 }
 
 /// The commands returned from a [`Server`].
@@ -144,6 +180,11 @@ const CHANNEL_BINDING_DURATION: Duration = Duration::from_secs(600);
 /// See <https://www.rfc-editor.org/rfc/rfc8656#section-12-14>.
 const CHANNEL_REBIND_TIMEOUT: Duration = Duration::from_secs(300);
 
+/// How long credentials minted with the previous auth secret remain valid after a rotation.
+///
+/// This is the maximum lifetime of an allocation, allowing clients to refresh their allocations at least once more with their old credentials.
+const AUTH_SECRET_OVERLAP: Duration = Duration::from_secs(60 * 60);
+
 impl<R> Server<R>
 where
     R: Rng,
@@ -179,6 +220,15 @@ where
             .with_description("The number of bytes relayed")
             .with_unit("b")
             .init();
+        let data_dropped_counter = meter
+            .u64_counter("data_dropped_bytes")
+            .with_description("The number of bytes dropped because they exceeded a rate limit")
+            .with_unit("b")
+            .init();
+        let denied_peers_counter = meter
+            .u64_counter("denied_peer_addresses_total")
+            .with_description("The number of requests denied because of the peer address")
+            .init();
 
         Self {
             decoder: Default::default(),
@@ -191,19 +241,51 @@ where
             channels_by_client_and_number: Default::default(),
             channel_numbers_by_client_and_peer: Default::default(),
             pending_commands: Default::default(),
-            auth_secret: SecretString::from(hex::encode(rng.gen::<[u8; 32]>())),
+            auth_secrets: RelaySecrets::new(generate_auth_secret(&mut rng)),
             rng,
             nonces: Default::default(),
+            rate_limits: Default::default(),
+            allocation_limiters: Default::default(),
+            source_ip_limiters: Default::default(),
+            peer_policy: Default::default(),
             allocations_up_down_counter,
             responses_counter,
             data_relayed_counter,
+            data_dropped_counter,
+            denied_peers_counter,
             data_relayed: 0,
             channel_and_client_by_port_and_peer: Default::default(),
         }
     }
 
+    /// The secret that the portal should use to mint new credentials.
     pub fn auth_secret(&self) -> &SecretString {
-        &self.auth_secret
+        self.auth_secrets.current()
+    }
+
+    /// Replaces the [`Server::auth_secret`], immediately invalidating all credentials minted with the previous one.
+    pub fn set_auth_secret(&mut self, secret: SecretString) {
+        self.auth_secrets = RelaySecrets::new(secret);
+    }
+
+    /// Generates a new [`Server::auth_secret`].
+    ///
+    /// Credentials minted with the previous secret remain valid for another [`AUTH_SECRET_OVERLAP`].
+    /// This gives clients time to obtain new credentials without losing their allocations.
+    pub fn rotate_auth_secret(&mut self, now: Instant) {
+        let new_secret = generate_auth_secret(&mut self.rng);
+
+        self.auth_secrets
+            .rotate(new_secret, AUTH_SECRET_OVERLAP, now);
+
+        tracing::info!(target: "relay", "Rotated auth secret");
+    }
+
+    /// Rotates to a secret that was generated elsewhere, e.g. by another [`Server`] of the same relay.
+    ///
+    /// Like with [`Server::rotate_auth_secret`], credentials minted with the previous secret remain valid for another [`AUTH_SECRET_OVERLAP`].
+    pub fn adopt_auth_secret(&mut self, secret: SecretString, now: Instant) {
+        self.auth_secrets.rotate(secret, AUTH_SECRET_OVERLAP, now);
     }
 
     pub fn public_address(&self) -> IpStack {
@@ -222,6 +304,27 @@ where
         self.listen_port
     }
 
+    /// Configures the limits on how much data we relay.
+    ///
+    /// Traffic exceeding these limits is dropped.
+    pub fn set_rate_limits(&mut self, rate_limits: RateLimits) {
+        self.rate_limits = rate_limits;
+        self.allocation_limiters.clear();
+        self.source_ip_limiters.clear();
+    }
+
+    /// Makes this server share its limiters per source IP with other servers, e.g. the other workers of the same relay.
+    pub fn set_source_ip_limiters(&mut self, limiters: SourceIpLimiters) {
+        self.source_ip_limiters = limiters;
+    }
+
+    /// Configures which peer addresses clients may create permissions and bind channels for.
+    ///
+    /// Existing channel bindings are not affected.
+    pub fn set_peer_policy(&mut self, peer_policy: PeerPolicy) {
+        self.peer_policy = peer_policy;
+    }
+
     /// Registers a new, valid nonce.
     ///
     /// Each nonce is valid for 10 requests.
@@ -237,6 +340,55 @@ where
         self.allocations.len()
     }
 
+    /// Returns a snapshot of all allocations and their channels.
+    pub fn allocations(&self, now: Instant) -> Vec<AllocationInfo> {
+        self.allocations
+            .iter()
+            .map(|(client, allocation)| {
+                let channels = self
+                    .channels_by_client_and_number
+                    .iter()
+                    .filter(|((cs, _), c)| cs == client && c.allocation == allocation.port)
+                    .map(|((_, number), c)| ChannelInfo {
+                        number: number.value(),
+                        peer: c.peer_address.into_socket(),
+                        bound: c.bound,
+                        expires_in_secs: c.expiry.saturating_duration_since(now).as_secs(),
+                    })
+                    .collect();
+
+                AllocationInfo {
+                    client: client.into_socket(),
+                    port: allocation.port.value(),
+                    relay_addresses: iter::once(allocation.first_relay_addr)
+                        .chain(allocation.second_relay_addr)
+                        .collect(),
+                    expires_in_secs: allocation
+                        .expires_at
+                        .saturating_duration_since(now)
+                        .as_secs(),
+                    bytes_relayed: allocation.bytes_relayed,
+                    channels,
+                }
+            })
+            .collect()
+    }
+
+    /// Frees an allocation and all its channels, regardless of its lifetime.
+    ///
+    /// Returns `false` if there is no such allocation.
+    pub fn free_allocation(&mut self, port: AllocationPort) -> bool {
+        if !self.clients_by_allocation.contains_key(&port) {
+            return false;
+        }
+
+        tracing::info!(target: "relay", %port, "Force-freeing allocation");
+
+        self.delete_allocation(port);
+
+        true
+    }
+
     pub fn num_active_channels(&self) -> usize {
         self.channels_by_client_and_number
             .iter()
@@ -309,7 +461,7 @@ where
                 return None;
             }
             ClientMessage::ChannelData(msg) => {
-                return self.handle_channel_data_message(msg, sender);
+                return self.handle_channel_data_message(msg, sender, now);
             }
         };
 
@@ -332,8 +484,12 @@ where
 
         let message = match message.username() {
             Some(username) => {
-                match AuthenticatedMessage::new(&self.auth_secret, username.name(), error_response)
-                {
+                match AuthenticatedMessage::new(
+                    &self.auth_secrets,
+                    username.name(),
+                    message.message_integrity(),
+                    error_response,
+                ) {
                     Ok(message) => message,
                     Err(e) => {
                         tracing::warn!(target: "relay", "Failed to create error response: {}", err_with_src(&e));
@@ -365,10 +521,12 @@ where
         msg: &[u8],
         sender: PeerSocket,
         allocation: AllocationPort,
+        now: Instant,
     ) -> Option<(ClientSocket, ChannelNumber)> {
         let Some((client, channel_number)) = self
             .channel_and_client_by_port_and_peer
             .get(&(allocation, sender))
+            .copied()
         else {
             tracing::debug!(target: "relay", "no channel");
 
@@ -377,12 +535,19 @@ where
 
         Span::current().record("recipient", field::display(&client));
 
+        if !self.is_within_rate_limits(allocation, client, msg.len(), now) {
+            return None;
+        }
+
         self.data_relayed_counter.add(msg.len() as u64, &[]);
         self.data_relayed += msg.len() as u64;
+        if let Some(allocation) = self.allocations.get_mut(&client) {
+            allocation.bytes_relayed += msg.len() as u64;
+        }
 
         tracing::trace!(target: "wire", num_bytes = %msg.len());
 
-        Some((*client, *channel_number))
+        Some((client, channel_number))
     }
 
     /// An allocation failed.
@@ -391,6 +556,18 @@ where
         self.delete_allocation(allocation)
     }
 
+    /// A client connected via TCP or TLS closed its connection.
+    ///
+    /// Allocations are bound to the stream they were created on, see <https://www.rfc-editor.org/rfc/rfc8656#section-3.1-7>.
+    #[tracing::instrument(level = "debug", skip(self), fields(%client))]
+    pub fn handle_client_disconnected(&mut self, client: ClientSocket) {
+        let Some(allocation) = self.allocations.get(&client) else {
+            return;
+        };
+
+        self.delete_allocation(allocation.port)
+    }
+
     /// Return the next command to be executed.
     pub fn next_command(&mut self) -> Option<Command> {
         self.pending_commands.pop_front()
@@ -409,6 +586,7 @@ where
 
         channel_expiries
             .chain(allocation_expiries)
+            .chain(self.auth_secrets.poll_timeout())
             .fold(None, |current, next| earliest(current, Some(next)))
     }
 
@@ -423,6 +601,11 @@ where
             self.delete_allocation(id);
         }
 
+        if self.rate_limits.has_source_ip_limit() {
+            self.source_ip_limiters.remove_idle(now);
+        }
+        self.auth_secrets.handle_timeout(now);
+
         for ((client, number), channel) in self
             .channels_by_client_and_number
             .iter_mut()
@@ -671,6 +854,17 @@ where
             return Err(error_response);
         }
 
+        if !self.peer_policy.is_allowed(peer_address.into_socket().ip()) {
+            let (error_response, msg) = make_error_response(Forbidden, request);
+
+            tracing::warn!(target: "relay", "{msg}: Peer address is not allowed");
+
+            self.denied_peers_counter
+                .add(1, &[KeyValue::new("method", "channelbind")]);
+
+            return Err(error_response);
+        }
+
         // Ensure the same address isn't already bound to a different channel.
         if let Some(number) = self
             .channel_numbers_by_client_and_peer
@@ -744,7 +938,7 @@ where
     /// See <https://www.rfc-editor.org/rfc/rfc8656#name-receiving-a-createpermissio> for details.
     ///
     /// This TURN server implementation does not support relaying data other than through channels.
-    /// Thus, creating a permission is a no-op that always succeeds.
+    /// Thus, creating a permission is a no-op that succeeds unless one of the peer addresses is not allowed.
     #[tracing::instrument(level = "info", skip_all, fields(software = request.software().map(|s| field::display(s.description())), tid = %format_args!("{:X}", request.transaction_id().as_bytes().hex()), %sender))]
     fn handle_create_permission_request(
         &mut self,
@@ -753,6 +947,23 @@ where
     ) -> Result<(), Message<Attribute>> {
         let username = self.verify_auth(request)?;
 
+        // If any of the addresses is forbidden, we must not install any permissions.
+        if let Some(peer) = request
+            .xor_peer_addresses()
+            .iter()
+            .map(|a| a.address())
+            .find(|a| !self.peer_policy.is_allowed(a.ip()))
+        {
+            let (error_response, msg) = make_error_response(Forbidden, request);
+
+            tracing::warn!(target: "relay", %peer, "{msg}: Peer address is not allowed");
+
+            self.denied_peers_counter
+                .add(1, &[KeyValue::new("method", "createpermission")]);
+
+            return Err(error_response);
+        }
+
         self.authenticate_and_send(
             username.name(),
             request,
@@ -768,6 +979,7 @@ where
         &mut self,
         message: &ChannelData,
         sender: ClientSocket,
+        now: Instant,
     ) -> Option<(AllocationPort, PeerSocket)> {
         let channel_number = message.channel();
         let data = message.data();
@@ -788,16 +1000,95 @@ where
             return None;
         }
 
-        Span::current().record("allocation", field::display(&channel.allocation));
-        Span::current().record("recipient", field::display(&channel.peer_address));
+        let allocation = channel.allocation;
+        let peer = channel.peer_address;
+
+        Span::current().record("allocation", field::display(&allocation));
+        Span::current().record("recipient", field::display(&peer));
         Span::current().record("channel", field::display(&channel_number.value()));
 
+        if !self.is_within_rate_limits(allocation, sender, data.len(), now) {
+            return None;
+        }
+
         tracing::trace!(target: "wire", num_bytes = %data.len());
 
         self.data_relayed_counter.add(data.len() as u64, &[]);
         self.data_relayed += data.len() as u64;
+        if let Some(allocation) = self.allocations.get_mut(&sender) {
+            allocation.bytes_relayed += data.len() as u64;
+        }
 
-        Some((channel.allocation, channel.peer_address))
+        Some((allocation, peer))
+    }
+
+    /// Checks whether relaying the given number of bytes for this allocation and client is within our rate limits.
+    ///
+    /// Traffic in both directions counts towards the limits.
+    /// The limit per source IP applies to the IP of the client that owns the allocation.
+    fn is_within_rate_limits(
+        &mut self,
+        allocation: AllocationPort,
+        client: ClientSocket,
+        num_bytes: usize,
+        now: Instant,
+    ) -> bool {
+        let mut allocation_limiter = match self.allocation_limiters.entry(allocation) {
+            hash_map::Entry::Occupied(o) => Some(o.into_mut()),
+            hash_map::Entry::Vacant(v) => {
+                self.rate_limits.allocation_limiter().map(|l| v.insert(l))
+            }
+        };
+        let source_ip = client.into_socket().ip();
+        // Only lock the shared limiters if there is a limit to enforce.
+        let mut source_ip_shard = self
+            .rate_limits
+            .has_source_ip_limit()
+            .then(|| self.source_ip_limiters.shard(source_ip));
+        let mut source_ip_limiter =
+            source_ip_shard
+                .as_mut()
+                .and_then(|shard| match shard.entry(source_ip) {
+                    hash_map::Entry::Occupied(o) => Some(o.into_mut()),
+                    hash_map::Entry::Vacant(v) => {
+                        self.rate_limits.source_ip_limiter().map(|l| v.insert(l))
+                    }
+                });
+
+        if allocation_limiter
+            .as_mut()
+            .is_some_and(|l| !l.admits(num_bytes, now))
+        {
+            tracing::trace!(target: "relay", %allocation, "Allocation exceeded rate limit, dropping packet");
+
+            self.data_dropped_counter.add(
+                num_bytes as u64,
+                &[KeyValue::new("reason", "allocation_rate_limit")],
+            );
+            return false;
+        }
+
+        if source_ip_limiter
+            .as_mut()
+            .is_some_and(|l| !l.admits(num_bytes, now))
+        {
+            tracing::trace!(target: "relay", %client, "Source IP exceeded rate limit, dropping packet");
+
+            self.data_dropped_counter.add(
+                num_bytes as u64,
+                &[KeyValue::new("reason", "source_ip_rate_limit")],
+            );
+            return false;
+        }
+
+        if let Some(l) = allocation_limiter {
+            l.consume(num_bytes);
+        }
+        if let Some(l) = source_ip_limiter {
+            l.consume(num_bytes);
+        }
+
+        true
     }
 
     fn verify_auth(
@@ -841,7 +1132,7 @@ where
         })?;
 
         message_integrity
-            .verify(&self.auth_secret, username.name(), SystemTime::now()) // This is impure but we don't need to control this in our tests.
+            .verify(&self.auth_secrets, username.name(), SystemTime::now()) // This is impure but we don't need to control this in our tests.
             .map_err(|e| {
                 let (error_response, msg) = make_error_response(Unauthorized, request);
 
@@ -885,6 +1176,7 @@ where
             expires_at: now + lifetime.lifetime(),
             first_relay_addr,
             second_relay_addr,
+            bytes_relayed: 0,
         }
     }
 
@@ -929,13 +1221,14 @@ where
     fn authenticate_and_send(
         &mut self,
         username: &str,
-        request: &impl StunRequest,
+        request: &(impl StunRequest + ProtectedRequest),
         message: Message<Attribute>,
         recipient: ClientSocket,
     ) {
         let authenticated_message = match AuthenticatedMessage::new(
-            &self.auth_secret,
+            &self.auth_secrets,
             username,
+            request.message_integrity(),
             message,
         ) {
             Ok(message) => message,
@@ -1041,6 +1334,7 @@ where
                 false
             });
 
+        self.allocation_limiters.remove(&port);
         self.allocations_up_down_counter.add(-1, &[]);
         self.pending_commands.push_back(Command::FreeAllocation {
             port,
@@ -1128,6 +1422,9 @@ struct Allocation {
 
     first_relay_addr: IpAddr,
     second_relay_addr: Option<IpAddr>,
+
+    /// How many bytes we relayed for this allocation, in both directions.
+    bytes_relayed: u64,
 }
 
 #[derive(Debug, Clone)]
@@ -1361,6 +1658,10 @@ fn error_response(
     message
 }
 
+fn generate_auth_secret(rng: &mut impl Rng) -> SecretString {
+    SecretString::from(hex::encode(rng.gen::<[u8; 32]>()))
+}
+
 fn earliest(left: Option<Instant>, right: Option<Instant>) -> Option<Instant> {
     match (left, right) {
         (None, None) => None,
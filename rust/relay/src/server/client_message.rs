@@ -7,6 +7,7 @@ use bytecodec::DecodeExt;
 use secrecy::SecretString;
 use std::io;
 use std::time::Duration;
+use stun_codec::convert::TryAsRef;
 use stun_codec::rfc5389::attributes::{ErrorCode, MessageIntegrity, Nonce, Software, Username};
 use stun_codec::rfc5389::errors::BadRequest;
 use stun_codec::rfc5389::methods::BINDING;
@@ -119,6 +120,16 @@ impl ClientMessage<'_> {
             ClientMessage::CreatePermission(request) => request.username(),
         }
     }
+
+    pub fn message_integrity(&self) -> Option<&MessageIntegrity> {
+        match self {
+            ClientMessage::ChannelData(_) | ClientMessage::Binding(_) => None,
+            ClientMessage::Allocate(request) => request.message_integrity(),
+            ClientMessage::Refresh(request) => request.message_integrity(),
+            ClientMessage::ChannelBind(request) => request.message_integrity(),
+            ClientMessage::CreatePermission(request) => request.message_integrity(),
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -551,15 +562,57 @@ impl ChannelBind {
 pub struct CreatePermission {
     transaction_id: TransactionId,
     message_integrity: Option<MessageIntegrity>,
+    xor_peer_addresses: Vec<XorPeerAddress>,
     username: Option<Username>,
     nonce: Option<Nonce>,
     software: Option<Software>,
 }
 
 impl CreatePermission {
+    pub fn new(
+        transaction_id: TransactionId,
+        xor_peer_addresses: Vec<XorPeerAddress>,
+        username: Username,
+        relay_secret: &SecretString,
+        nonce: Uuid,
+    ) -> Result<Self> {
+        let nonce = Nonce::new(nonce.as_hyphenated().to_string()).context("Invalid nonce")?;
+
+        let mut message =
+            Message::<Attribute>::new(MessageClass::Request, CREATE_PERMISSION, transaction_id);
+        message.add_attribute(username.clone());
+        for xor_peer_address in &xor_peer_addresses {
+            message.add_attribute(xor_peer_address.clone());
+        }
+        message.add_attribute(nonce.clone());
+
+        let (expiry, salt) = split_username(username.name())?;
+        let expiry_systemtime = systemtime_from_unix(expiry);
+
+        let password = generate_password(relay_secret, expiry_systemtime, salt);
+
+        let message_integrity =
+            MessageIntegrity::new_long_term_credential(&message, &username, &FIREZONE, &password)?;
+
+        Ok(Self {
+            transaction_id,
+            message_integrity: Some(message_integrity),
+            xor_peer_addresses,
+            username: Some(username),
+            nonce: Some(nonce),
+            software: None,
+        })
+    }
+
     pub fn parse(message: &Message<Attribute>) -> Self {
         let transaction_id = message.transaction_id();
         let message_integrity = message.get_attribute::<MessageIntegrity>().cloned();
+        // A single request may create permissions for several peers.
+        let xor_peer_addresses = message
+            .attributes()
+            .filter_map(|a| TryAsRef::<XorPeerAddress>::try_as_ref(a))
+            .cloned()
+            .collect();
         let username = message.get_attribute::<Username>().cloned();
         let nonce = message.get_attribute::<Nonce>().cloned();
         let software = message.get_attribute::<Software>().cloned();
@@ -567,12 +620,17 @@ impl CreatePermission {
         CreatePermission {
             transaction_id,
             message_integrity,
+            xor_peer_addresses,
             username,
             nonce,
             software,
         }
     }
 
+    pub fn xor_peer_addresses(&self) -> &[XorPeerAddress] {
+        &self.xor_peer_addresses
+    }
+
     pub fn transaction_id(&self) -> TransactionId {
         self.transaction_id
     }
@@ -0,0 +1,123 @@
+use ip_network::IpNetwork;
+use std::net::IpAddr;
+
+/// Networks that peers are not allowed to be in unless configured otherwise.
+///
+/// Relaying to these would allow clients to reach services that are only meant to be accessible from the relay's host or its private network.
+/// See <https://www.rfc-editor.org/rfc/rfc8656#section-21.3>.
+const DEFAULT_DENIED: &[&str] = &[
+    "0.0.0.0/8",
+    "10.0.0.0/8",
+    "127.0.0.0/8",
+    "169.254.0.0/16",
+    "172.16.0.0/12",
+    "192.168.0.0/16",
+    "224.0.0.0/4",
+    "255.255.255.255/32",
+    "::/128",
+    "::1/128",
+    "::ffff:0:0/96",
+    "fc00::/7",
+    "fe80::/10",
+    "ff00::/8",
+];
+
+/// Which peer addresses clients may create permissions and bind channels for.
+///
+/// An address matching any of the allowed networks is always allowed, even if it also matches a denied network.
+/// This allows operators to carve out exceptions from the default list of denied networks.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerPolicy {
+    allowed: Vec<IpNetwork>,
+    denied: Vec<IpNetwork>,
+}
+
+impl Default for PeerPolicy {
+    /// Denies loopback, link-local, multicast and private networks.
+    fn default() -> Self {
+        Self {
+            allowed: Vec::new(),
+            denied: DEFAULT_DENIED
+                .iter()
+                .map(|n| n.parse().expect("default denied networks are valid CIDRs"))
+                .collect(),
+        }
+    }
+}
+
+impl PeerPolicy {
+    /// Creates a policy from the given networks.
+    ///
+    /// If `denied` is `None`, the networks denied by [`PeerPolicy::default`] are used.
+    pub fn new(allowed: Vec<IpNetwork>, denied: Option<Vec<IpNetwork>>) -> Self {
+        Self {
+            allowed,
+            denied: denied.unwrap_or_else(|| Self::default().denied),
+        }
+    }
+
+    /// A policy that allows any peer address.
+    pub fn allow_all() -> Self {
+        Self {
+            allowed: Vec::new(),
+            denied: Vec::new(),
+        }
+    }
+
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        if self.allowed.iter().any(|n| n.contains(ip)) {
+            return true;
+        }
+
+        !self.denied.iter().any(|n| n.contains(ip))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_denies_loopback_and_private_networks() {
+        let policy = PeerPolicy::default();
+
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "::1",
+            "::ffff:127.0.0.1",
+            "fd00::1",
+            "fe80::1",
+        ] {
+            assert!(!policy.is_allowed(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn default_allows_public_addresses() {
+        let policy = PeerPolicy::default();
+
+        for ip in ["1.1.1.1", "203.0.113.1", "2606:4700:4700::1111"] {
+            assert!(policy.is_allowed(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn allowed_networks_take_precedence() {
+        let policy = PeerPolicy::new(vec!["10.0.1.0/24".parse().unwrap()], None);
+
+        assert!(policy.is_allowed("10.0.1.5".parse().unwrap()));
+        assert!(!policy.is_allowed("10.0.2.5".parse().unwrap()));
+    }
+
+    #[test]
+    fn custom_denied_networks_replace_defaults() {
+        let policy = PeerPolicy::new(Vec::new(), Some(vec!["203.0.113.0/24".parse().unwrap()]));
+
+        assert!(policy.is_allowed("127.0.0.1".parse().unwrap()));
+        assert!(!policy.is_allowed("203.0.113.1".parse().unwrap()));
+    }
+}
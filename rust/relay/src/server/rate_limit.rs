@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, RandomState};
+use std::net::IpAddr;
+use std::num::NonZeroU64;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Instant;
+use token_bucket::TokenBucket;
+
+/// The largest datagram we may have to relay.
+///
+/// Byte limits always allow a burst of at least this size, otherwise a limit below the size of a packet would drop every packet.
+const MAX_DATAGRAM_SIZE: u64 = 65_536;
+
+/// The number of shards the [`SourceIpLimiters`] are split into.
+///
+/// Workers only contend for a lock if their clients' IPs fall into the same shard.
+const NUM_SOURCE_IP_SHARDS: usize = 64;
+
+/// Limits on how much data we relay, either per allocation or per source IP of a client.
+///
+/// Each limit allows bursts of up to one second worth of traffic, byte limits at least one maximum-size datagram.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimits {
+    pub allocation_bytes_per_sec: Option<NonZeroU64>,
+    pub allocation_packets_per_sec: Option<NonZeroU64>,
+    pub source_ip_bytes_per_sec: Option<NonZeroU64>,
+    pub source_ip_packets_per_sec: Option<NonZeroU64>,
+}
+
+impl RateLimits {
+    pub(crate) fn allocation_limiter(&self) -> Option<Limiter> {
+        Limiter::new(
+            self.allocation_bytes_per_sec,
+            self.allocation_packets_per_sec,
+        )
+    }
+
+    pub(crate) fn source_ip_limiter(&self) -> Option<Limiter> {
+        Limiter::new(self.source_ip_bytes_per_sec, self.source_ip_packets_per_sec)
+    }
+
+    pub(crate) fn has_source_ip_limit(&self) -> bool {
+        self.source_ip_bytes_per_sec.is_some() || self.source_ip_packets_per_sec.is_some()
+    }
+}
+
+/// The limiters per source IP, shared between all [`Server`](crate::Server)s of a relay.
+///
+/// Each worker of a relay runs its own [`Server`](crate::Server) but a client's traffic may reach several of them.
+/// Sharing the limiters makes the configured limit apply to the relay as a whole rather than to each worker.
+/// To keep the workers from contending for a single lock, the limiters are sharded by IP.
+#[derive(Debug, Clone)]
+pub struct SourceIpLimiters(Arc<Shards>);
+
+#[derive(Debug)]
+struct Shards {
+    hasher: RandomState,
+    shards: Box<[Mutex<HashMap<IpAddr, Limiter>>]>,
+}
+
+impl Default for SourceIpLimiters {
+    fn default() -> Self {
+        Self(Arc::new(Shards {
+            hasher: RandomState::new(),
+            shards: (0..NUM_SOURCE_IP_SHARDS)
+                .map(|_| Mutex::default())
+                .collect(),
+        }))
+    }
+}
+
+impl SourceIpLimiters {
+    /// Locks the shard that holds the limiter of the given IP.
+    pub(crate) fn shard(&self, ip: IpAddr) -> MutexGuard<'_, HashMap<IpAddr, Limiter>> {
+        let index = self.0.hasher.hash_one(ip) as usize % self.0.shards.len();
+
+        lock(&self.0.shards[index])
+    }
+
+    pub(crate) fn clear(&self) {
+        for shard in self.0.shards.iter() {
+            lock(shard).clear();
+        }
+    }
+
+    pub(crate) fn remove_idle(&self, now: Instant) {
+        for shard in self.0.shards.iter() {
+            lock(shard).retain(|_, l| !l.is_idle(now));
+        }
+    }
+}
+
+fn lock(shard: &Mutex<HashMap<IpAddr, Limiter>>) -> MutexGuard<'_, HashMap<IpAddr, Limiter>> {
+    shard.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Limits bandwidth and packet-rate at the same time.
+#[derive(Debug)]
+pub(crate) struct Limiter {
+    bytes: Option<TokenBucket>,
+    packets: Option<TokenBucket>,
+}
+
+impl Limiter {
+    fn new(bytes_per_sec: Option<NonZeroU64>, packets_per_sec: Option<NonZeroU64>) -> Option<Self> {
+        if bytes_per_sec.is_none() && packets_per_sec.is_none() {
+            return None;
+        }
+
+        Some(Self {
+            bytes: bytes_per_sec
+                .map(|rate| TokenBucket::new(rate, rate.get().max(MAX_DATAGRAM_SIZE))),
+            packets: packets_per_sec.map(|rate| TokenBucket::new(rate, rate.get())),
+        })
+    }
+
+    /// Whether a packet of the given size is within the limits.
+    ///
+    /// This does not consume any tokens, see [`Limiter::consume`].
+    pub(crate) fn admits(&mut self, num_bytes: usize, now: Instant) -> bool {
+        let bytes_ok = self
+            .bytes
+            .as_mut()
+            .is_none_or(|b| b.has(num_bytes as u64, now));
+        let packets_ok = self.packets.as_mut().is_none_or(|p| p.has(1, now));
+
+        bytes_ok && packets_ok
+    }
+
+    pub(crate) fn consume(&mut self, num_bytes: usize) {
+        if let Some(bytes) = self.bytes.as_mut() {
+            bytes.consume(num_bytes as u64);
+        }
+        if let Some(packets) = self.packets.as_mut() {
+            packets.consume(1);
+        }
+    }
+
+    /// Whether this limiter has not seen any traffic for at least a second.
+    ///
+    /// An idle limiter is indistinguishable from a new one and can be discarded.
+    pub(crate) fn is_idle(&mut self, now: Instant) -> bool {
+        self.bytes.as_mut().is_none_or(|b| b.is_full(now))
+            && self.packets.as_mut().is_none_or(|p| p.is_full(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn allows_burst_of_one_second() {
+        let now = Instant::now();
+        let mut limiter = Limiter::new(NonZeroU64::new(100_000), None).unwrap();
+
+        assert!(limiter.admits(100_000, now));
+        limiter.consume(100_000);
+
+        assert!(!limiter.admits(1, now));
+    }
+
+    #[test]
+    fn byte_limit_below_packet_size_still_admits_packets() {
+        let now = Instant::now();
+        let mut limiter = Limiter::new(NonZeroU64::new(1000), None).unwrap();
+
+        assert!(limiter.admits(1200, now));
+        limiter.consume(1200);
+
+        let now = now + Duration::from_secs(1);
+
+        assert!(limiter.admits(1200, now));
+    }
+
+    #[test]
+    fn refills_over_time() {
+        let now = Instant::now();
+        let mut limiter = Limiter::new(None, NonZeroU64::new(10)).unwrap();
+
+        for _ in 0..10 {
+            assert!(limiter.admits(1200, now));
+            limiter.consume(1200);
+        }
+        assert!(!limiter.admits(1200, now));
+
+        let now = now + Duration::from_millis(100);
+
+        assert!(limiter.admits(1200, now));
+        limiter.consume(1200);
+        assert!(!limiter.admits(1200, now));
+    }
+
+    #[test]
+    fn is_idle_once_refilled() {
+        let now = Instant::now();
+        let mut limiter = Limiter::new(NonZeroU64::new(100_000), None).unwrap();
+
+        limiter.consume(50_000);
+        assert!(!limiter.is_idle(now));
+
+        assert!(limiter.is_idle(now + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn no_limits_means_no_limiter() {
+        assert!(Limiter::new(None, None).is_none());
+    }
+
+    #[test]
+    fn shards_are_shared_between_clones() {
+        let limiters = SourceIpLimiters::default();
+        let other_worker = limiters.clone();
+        let ip = IpAddr::from([192, 0, 2, 1]);
+
+        limiters
+            .shard(ip)
+            .insert(ip, Limiter::new(None, NonZeroU64::new(10)).unwrap());
+
+        assert!(other_worker.shard(ip).contains_key(&ip));
+    }
+
+    #[test]
+    fn removes_idle_limiters_from_all_shards() {
+        let now = Instant::now();
+        let limiters = SourceIpLimiters::default();
+        let mut ips = (0..=255).map(|i| IpAddr::from([192, 0, 2, i]));
+
+        for ip in ips.clone() {
+            let mut limiter = Limiter::new(None, NonZeroU64::new(10)).unwrap();
+            assert!(limiter.admits(1, now));
+            limiter.consume(1);
+
+            limiters.shard(ip).insert(ip, limiter);
+        }
+
+        limiters.remove_idle(now + Duration::from_secs(1));
+
+        assert!(ips.all(|ip| limiters.shard(ip).is_empty()));
+    }
+}
@@ -75,8 +75,25 @@ impl Sockets {
     ///  - full (not expected to happen in production)
     ///  - disconnected (we can't operate without the [`mio`] worker thread)
     pub fn bind(&mut self, port: u16, address_family: AddressFamily) -> Result<()> {
-        self.cmd_tx
-            .try_send(Command::NewSocket((port, address_family)))?;
+        self.cmd_tx.try_send(Command::NewSocket {
+            port,
+            address_family,
+            reuse_port: false,
+        })?;
+
+        Ok(())
+    }
+
+    /// Like [`Sockets::bind`] but sets `SO_REUSEPORT`, allowing other [`Sockets`] to bind the same port.
+    ///
+    /// The kernel distributes incoming packets across all sockets bound to the port by hashing the 4-tuple.
+    /// Thus, all packets from a particular remote arrive on the same socket.
+    pub fn bind_reuse_port(&mut self, port: u16, address_family: AddressFamily) -> Result<()> {
+        self.cmd_tx.try_send(Command::NewSocket {
+            port,
+            address_family,
+            reuse_port: true,
+        })?;
 
         Ok(())
     }
@@ -229,7 +246,11 @@ pub enum Error {
 }
 
 enum Command {
-    NewSocket((u16, AddressFamily)),
+    NewSocket {
+        port: u16,
+        address_family: AddressFamily,
+        reuse_port: bool,
+    },
     DisposeSocket(mio::net::UdpSocket),
 }
 
@@ -276,8 +297,13 @@ fn mio_worker_task(
             match cmd_rx.try_recv() {
                 Err(mpsc::error::TryRecvError::Empty) => break, // Drain all events from the channel until it is empty.
 
-                Ok(Command::NewSocket((port, af))) => {
-                    let mut socket = mio::net::UdpSocket::from_std(make_wildcard_socket(af, port)?);
+                Ok(Command::NewSocket {
+                    port,
+                    address_family: af,
+                    reuse_port,
+                }) => {
+                    let mut socket =
+                        mio::net::UdpSocket::from_std(make_wildcard_socket(af, port, reuse_port)?);
                     let token = token_from_port_and_address_family(port, af);
 
                     poll.registry().register(
@@ -328,7 +354,11 @@ fn token_to_port_and_address_family(token: mio::Token) -> (u16, AddressFamily) {
 /// Creates an [std::net::UdpSocket] via the [socket2] library that is configured for our needs.
 ///
 /// Most importantly, this sets the `IPV6_V6ONLY` flag to ensure we disallow IP4-mapped IPv6 addresses and can bind to IP4 and IP6 addresses on the same port.
-fn make_wildcard_socket(family: AddressFamily, port: u16) -> io::Result<std::net::UdpSocket> {
+fn make_wildcard_socket(
+    family: AddressFamily,
+    port: u16,
+    reuse_port: bool,
+) -> io::Result<std::net::UdpSocket> {
     use socket2::*;
 
     let domain = match family {
@@ -344,6 +374,9 @@ fn make_wildcard_socket(family: AddressFamily, port: u16) -> io::Result<std::net
     if family == AddressFamily::V6 {
         socket.set_only_v6(true)?;
     }
+    if reuse_port {
+        socket.set_reuse_port(true)?;
+    }
 
     socket.set_nonblocking(true)?;
     socket.bind(&SockAddr::from(SocketAddr::new(address, port)))?;
@@ -0,0 +1,308 @@
+//! TURN over TCP and TLS.
+//!
+//! Clients that cannot reach us via UDP may connect via TCP or TLS instead, see <https://www.rfc-editor.org/rfc/rfc8656#section-3.1>.
+//! Thanks to the framing in [`turn_over_tcp`], we can hand the received messages to [`Server`](crate::Server) exactly as if they had been received via UDP.
+
+use crate::{ClientSocket, ClientTransport};
+use anyhow::{Context as _, Result};
+use std::{
+    collections::HashMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::Arc,
+    task::{Context, Poll},
+};
+use stun_codec::rfc8656::attributes::AddressFamily;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::TcpListener,
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_rustls::TlsAcceptor;
+use turn_over_tcp::{write_message, MessageReader};
+
+/// How many messages we buffer for a single client before we start dropping them.
+const MAX_BUFFERED_MESSAGES: usize = 1000;
+
+/// All currently active TCP and TLS connections of clients.
+pub struct Streams {
+    connections: HashMap<ClientSocket, mpsc::Sender<Vec<u8>>>,
+    listeners: Vec<JoinHandle<()>>,
+
+    event_tx: mpsc::Sender<Event>,
+    event_rx: mpsc::Receiver<Event>,
+}
+
+#[derive(Debug)]
+pub enum Received {
+    /// A client sent us a STUN or channel-data message.
+    Message { from: ClientSocket, packet: Vec<u8> },
+    /// A client closed its connection.
+    Closed(ClientSocket),
+}
+
+enum Event {
+    Connected {
+        client: ClientSocket,
+        outbound: mpsc::Sender<Vec<u8>>,
+    },
+    Received(Received),
+}
+
+impl Default for Streams {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Streams {
+    pub fn new() -> Self {
+        let (event_tx, event_rx) = mpsc::channel(1_024);
+
+        Self {
+            connections: Default::default(),
+            listeners: Default::default(),
+            event_tx,
+            event_rx,
+        }
+    }
+
+    /// Listens for TURN over TCP on the given port and address family.
+    pub fn listen_tcp(&mut self, port: u16, family: AddressFamily) -> Result<()> {
+        let listener = make_wildcard_listener(family, port)?;
+
+        self.listeners.push(tokio::spawn(accept_loop(
+            listener,
+            None,
+            self.event_tx.clone(),
+        )));
+
+        Ok(())
+    }
+
+    /// Listens for TURN over TLS on the given port and address family.
+    pub fn listen_tls(
+        &mut self,
+        port: u16,
+        family: AddressFamily,
+        acceptor: TlsAcceptor,
+    ) -> Result<()> {
+        let listener = make_wildcard_listener(family, port)?;
+
+        self.listeners.push(tokio::spawn(accept_loop(
+            listener,
+            Some(acceptor),
+            self.event_tx.clone(),
+        )));
+
+        Ok(())
+    }
+
+    /// Queues a message to be sent to the given client.
+    ///
+    /// Messages are padded to a multiple of 4 bytes before being written to the stream.
+    pub fn try_send(&mut self, client: ClientSocket, payload: &[u8]) -> io::Result<()> {
+        let Some(outbound) = self.connections.get(&client) else {
+            return Err(io::Error::from(io::ErrorKind::NotConnected));
+        };
+
+        outbound.try_send(payload.to_vec()).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => io::Error::from(io::ErrorKind::WouldBlock),
+            mpsc::error::TrySendError::Closed(_) => io::Error::from(io::ErrorKind::BrokenPipe),
+        })
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Received> {
+        loop {
+            let event =
+                std::task::ready!(self.event_rx.poll_recv(cx)).expect("we always hold a sender");
+
+            match event {
+                Event::Connected { client, outbound } => {
+                    tracing::debug!(target: "relay", %client, "New stream connection");
+
+                    self.connections.insert(client, outbound);
+                }
+                Event::Received(Received::Closed(client)) => {
+                    // Connections that failed during the TLS handshake were never registered.
+                    if self.connections.remove(&client).is_none() {
+                        continue;
+                    }
+
+                    tracing::debug!(target: "relay", %client, "Stream connection closed");
+
+                    return Poll::Ready(Received::Closed(client));
+                }
+                Event::Received(received @ Received::Message { .. }) => {
+                    return Poll::Ready(received)
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Streams {
+    fn drop(&mut self) {
+        for listener in &self.listeners {
+            listener.abort();
+        }
+    }
+}
+
+/// Creates a [`TlsAcceptor`] from a PEM-encoded certificate chain and private key.
+pub fn tls_acceptor(cert_file: &Path, key_file: &Path) -> Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut io::BufReader::new(
+        std::fs::File::open(cert_file)
+            .with_context(|| format!("Failed to open `{}`", cert_file.display()))?,
+    ))
+    .collect::<Result<Vec<_>, _>>()
+    .context("Failed to parse certificates")?;
+    let key = rustls_pemfile::private_key(&mut io::BufReader::new(
+        std::fs::File::open(key_file)
+            .with_context(|| format!("Failed to open `{}`", key_file.display()))?,
+    ))
+    .context("Failed to parse private key")?
+    .context("No private key found")?;
+
+    let mut config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid certificate or private key")?;
+    config.alpn_protocols = vec![b"stun.turn".to_vec()]; // See <https://www.rfc-editor.org/rfc/rfc7443#section-6>.
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    tls: Option<TlsAcceptor>,
+    event_tx: mpsc::Sender<Event>,
+) {
+    loop {
+        let (stream, from) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                tracing::debug!(target: "relay", "Failed to accept connection: {e}");
+                continue;
+            }
+        };
+
+        let _ = stream.set_nodelay(true);
+
+        let tls = tls.clone();
+        let event_tx = event_tx.clone();
+
+        tokio::spawn(async move {
+            let transport = match tls {
+                Some(_) => ClientTransport::Tls,
+                None => ClientTransport::Tcp,
+            };
+            let client = ClientSocket::with_transport(from, transport);
+
+            let result = match tls {
+                Some(acceptor) => match acceptor.accept(stream).await {
+                    Ok(stream) => handle_connection(stream, client, event_tx.clone()).await,
+                    Err(e) => Err(e),
+                },
+                None => handle_connection(stream, client, event_tx.clone()).await,
+            };
+
+            if let Err(e) = result {
+                tracing::debug!(target: "relay", %client, "Stream connection failed: {e}");
+            }
+
+            let _ = event_tx
+                .send(Event::Received(Received::Closed(client)))
+                .await;
+        });
+    }
+}
+
+async fn handle_connection<S>(
+    stream: S,
+    client: ClientSocket,
+    event_tx: mpsc::Sender<Event>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    let (outbound, mut outbound_rx) = mpsc::channel::<Vec<u8>>(MAX_BUFFERED_MESSAGES);
+    let (reader, mut writer) = tokio::io::split(stream);
+
+    if event_tx
+        .send(Event::Connected { client, outbound })
+        .await
+        .is_err()
+    {
+        return Ok(());
+    }
+
+    let send = async {
+        while let Some(message) = outbound_rx.recv().await {
+            write_message(&mut writer, &message).await?;
+        }
+
+        io::Result::Ok(())
+    };
+
+    let recv = async {
+        let mut reader = MessageReader::new(reader);
+
+        while let Some(packet) = reader.read().await? {
+            if event_tx
+                .send(Event::Received(Received::Message {
+                    from: client,
+                    packet,
+                }))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+
+        io::Result::Ok(())
+    };
+
+    // Whichever side finishes first terminates the connection.
+    tokio::select! {
+        result = send => result,
+        result = recv => result,
+    }
+}
+
+/// Creates a [`TcpListener`] via the [socket2] library that is configured for our needs.
+///
+/// Like for our UDP sockets, this sets the `IPV6_V6ONLY` flag to ensure we can bind to IP4 and IP6 addresses on the same port.
+/// Every worker listens on the same port, thus we also set `SO_REUSEPORT` and let the kernel distribute the connections.
+fn make_wildcard_listener(family: AddressFamily, port: u16) -> Result<TcpListener> {
+    use socket2::*;
+
+    let domain = match family {
+        AddressFamily::V4 => Domain::IPV4,
+        AddressFamily::V6 => Domain::IPV6,
+    };
+    let address = match family {
+        AddressFamily::V4 => IpAddr::from(Ipv4Addr::UNSPECIFIED),
+        AddressFamily::V6 => IpAddr::from(Ipv6Addr::UNSPECIFIED),
+    };
+
+    let socket = Socket::new(domain, Type::STREAM, Some(Protocol::TCP))?;
+    if family == AddressFamily::V6 {
+        socket.set_only_v6(true)?;
+    }
+
+    socket.set_reuse_address(true)?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&SockAddr::from(SocketAddr::new(address, port)))
+        .with_context(|| format!("Failed to bind to TCP port {port} on {family} interfaces"))?;
+    socket.listen(1024)?;
+
+    let listener = TcpListener::from_std(std::net::TcpListener::from(socket))?;
+
+    Ok(listener)
+}
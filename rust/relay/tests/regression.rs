@@ -3,12 +3,14 @@
 use bytecodec::{DecodeExt, EncodeExt};
 use firezone_relay::{
     AddressFamily, Allocate, AllocationPort, Attribute, Binding, ChannelBind, ChannelData,
-    ClientMessage, ClientSocket, Command, IpStack, PeerSocket, Refresh, Server, SOFTWARE,
+    ClientMessage, ClientSocket, ClientTransport, Command, CreatePermission, IpStack, PeerPolicy,
+    PeerSocket, RateLimits, Refresh, Server, SourceIpLimiters, SOFTWARE,
 };
 use rand::rngs::mock::StepRng;
-use secrecy::SecretString;
+use secrecy::{ExposeSecret as _, SecretString};
 use std::iter;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::num::NonZeroU64;
 use std::time::{Duration, Instant, SystemTime};
 use stun_codec::rfc5389::attributes::{
     ErrorCode, MessageIntegrity, Nonce, Realm, Username, XorMappedAddress,
@@ -16,8 +18,9 @@ use stun_codec::rfc5389::attributes::{
 use stun_codec::rfc5389::errors::Unauthorized;
 use stun_codec::rfc5389::methods::BINDING;
 use stun_codec::rfc5766::attributes::{ChannelNumber, Lifetime, XorPeerAddress, XorRelayAddress};
-use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, REFRESH};
-use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId};
+use stun_codec::rfc5766::errors::Forbidden;
+use stun_codec::rfc5766::methods::{ALLOCATE, CHANNEL_BIND, CREATE_PERMISSION, REFRESH};
+use stun_codec::{Message, MessageClass, MessageDecoder, MessageEncoder, Method, TransactionId};
 use test_strategy::proptest;
 use uuid::Uuid;
 use Output::{CreateAllocation, FreeAllocation};
@@ -89,6 +92,141 @@ fn deallocate_once_time_expired(
     );
 }
 
+#[proptest]
+fn deallocate_once_stream_disconnects(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                secret,
+                nonce,
+            )
+            .unwrap(),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        client_disconnected(source),
+        [free_allocation(49152, AddressFamily::V4)],
+    );
+
+    assert_eq!(server.server.num_allocations(), 0);
+}
+
+#[proptest]
+fn stream_disconnect_keeps_udp_allocation_of_same_address(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                secret,
+                nonce,
+            )
+            .unwrap(),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    server.assert_commands(tcp_client_disconnected(source), []);
+
+    assert_eq!(server.server.num_allocations(), 1);
+}
+
+#[proptest]
+fn deallocate_when_force_freed(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    let secret = server.auth_secret();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                secret,
+                nonce,
+            )
+            .unwrap(),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(transaction_id, public_relay_addr, 49152, source, &lifetime),
+            ),
+        ],
+    );
+
+    let allocations = server.server.allocations(now);
+    assert_eq!(allocations.len(), 1);
+    assert_eq!(allocations[0].port, 49152);
+    assert_eq!(allocations[0].client, SocketAddr::from(source));
+
+    server.assert_commands(
+        force_free(49152),
+        [free_allocation(49152, AddressFamily::V4)],
+    );
+
+    assert_eq!(server.server.num_allocations(), 0);
+    assert!(!server.server.free_allocation(AllocationPort::new(49152)));
+}
+
 #[proptest]
 fn unauthenticated_allocate_triggers_authentication(
     #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
@@ -226,6 +364,82 @@ fn when_refreshed_in_time_allocation_does_not_expire(
     );
 }
 
+#[proptest]
+fn previous_secret_remains_valid_after_rotation(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())] refresh_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] allocate_lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] refresh_lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    // Use a stepping RNG so the rotated secret differs from the initial one.
+    let mut server = TestServer::with_server(Server::new(
+        public_relay_addr,
+        StepRng::new(0, 1),
+        3478,
+        49152..=65535,
+    ))
+    .with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(allocate_lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            )
+            .unwrap(),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &allocate_lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.server.rotate_auth_secret(now);
+    assert_ne!(server.auth_secret().expose_secret(), secret.expose_secret());
+
+    let now = now + allocate_lifetime.lifetime() / 2;
+
+    server.assert_commands(
+        from_client(
+            source,
+            Refresh::new(
+                refresh_transaction_id,
+                Some(refresh_lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            )
+            .unwrap(),
+            now,
+        ),
+        [send_message(
+            source,
+            refresh_response(refresh_transaction_id, refresh_lifetime.clone()),
+        )],
+    );
+}
+
 #[proptest]
 fn when_receiving_lifetime_0_for_existing_allocation_then_delete(
     #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
@@ -474,6 +688,7 @@ fn ping_pong_relay(
         peer_to_client_ping.as_slice(),
         PeerSocket::new(peer.into()),
         AllocationPort::new(49152),
+        now,
     );
 
     assert_eq!(
@@ -485,6 +700,183 @@ fn ping_pong_relay(
     );
 }
 
+#[proptest]
+fn drops_traffic_exceeding_allocation_rate_limit(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    peer_to_client_ping: [u8; 32],
+    #[strategy(firezone_relay::proptest::channel_data())] client_to_peer_ping: ChannelData<'static>,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let _ = env_logger::try_init();
+
+    let mut server = TestServer::new(public_relay_addr).with_nonce(nonce);
+    server.server.set_rate_limits(RateLimits {
+        allocation_packets_per_sec: NonZeroU64::new(1),
+        ..Default::default()
+    });
+    let secret = server.auth_secret().to_owned();
+
+    let _ = server.server.handle_client_message(
+        ClientMessage::Allocate(
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                None,
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            )
+            .unwrap(),
+        ),
+        ClientSocket::new(source.into()),
+        now,
+    );
+    let _ = server.server.handle_client_message(
+        ClientMessage::ChannelBind(
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                client_to_peer_ping.channel(),
+                XorPeerAddress::new(peer.into()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            )
+            .unwrap(),
+        ),
+        ClientSocket::new(source.into()),
+        now,
+    );
+
+    let maybe_forward = server.server.handle_client_input(
+        client_to_peer_ping.as_msg(),
+        ClientSocket::new(source.into()),
+        now,
+    );
+    assert!(maybe_forward.is_some());
+
+    // Traffic in both directions counts towards the limit.
+    let maybe_forward = server.server.handle_peer_traffic(
+        peer_to_client_ping.as_slice(),
+        PeerSocket::new(peer.into()),
+        AllocationPort::new(49152),
+        now,
+    );
+    assert_eq!(maybe_forward, None);
+
+    let now = now + Duration::from_secs(1);
+
+    let maybe_forward = server.server.handle_peer_traffic(
+        peer_to_client_ping.as_slice(),
+        PeerSocket::new(peer.into()),
+        AllocationPort::new(49152),
+        now,
+    );
+    assert!(maybe_forward.is_some());
+}
+
+#[proptest]
+fn source_ip_rate_limit_applies_across_workers(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer: SocketAddrV4,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::channel_data())] client_to_peer_ping: ChannelData<'static>,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+
+    let _ = env_logger::try_init();
+
+    let rate_limits = RateLimits {
+        source_ip_packets_per_sec: NonZeroU64::new(1),
+        ..Default::default()
+    };
+    let source_ip_limiters = SourceIpLimiters::default();
+
+    // Two workers, each with their own range of ports, like `main` sets them up.
+    let mut workers = [49152..=57343, 57344..=65535].map(|ports| {
+        let mut server = Server::new(public_relay_addr, StepRng::new(0, 0), 3478, ports);
+        server.set_rate_limits(rate_limits);
+        server.set_source_ip_limiters(source_ip_limiters.clone());
+
+        TestServer::with_server(server).with_nonce(nonce)
+    });
+    let secret = workers[0].auth_secret().to_owned();
+    workers[1].server.set_auth_secret(secret.clone());
+
+    // The same client IP reaches each worker from a different port, e.g. through multiple sockets.
+    let sources = [
+        source,
+        SocketAddrV4::new(*source.ip(), source.port().wrapping_add(1)),
+    ];
+
+    for (worker, source) in workers.iter_mut().zip(sources) {
+        let _ = worker.server.handle_client_message(
+            ClientMessage::Allocate(
+                Allocate::new_authenticated_udp_implicit_ip4(
+                    allocate_transaction_id,
+                    None,
+                    valid_username(&username_salt),
+                    &secret,
+                    nonce,
+                )
+                .unwrap(),
+            ),
+            ClientSocket::new(source.into()),
+            now,
+        );
+        let _ = worker.server.handle_client_message(
+            ClientMessage::ChannelBind(
+                ChannelBind::new(
+                    channel_bind_transaction_id,
+                    client_to_peer_ping.channel(),
+                    XorPeerAddress::new(peer.into()),
+                    valid_username(&username_salt),
+                    &secret,
+                    nonce,
+                )
+                .unwrap(),
+            ),
+            ClientSocket::new(source.into()),
+            now,
+        );
+    }
+
+    let maybe_forward = workers[0].server.handle_client_input(
+        client_to_peer_ping.as_msg(),
+        ClientSocket::new(sources[0].into()),
+        now,
+    );
+    assert!(maybe_forward.is_some());
+
+    // The first worker already used up the limit of this source IP.
+    let maybe_forward = workers[1].server.handle_client_input(
+        client_to_peer_ping.as_msg(),
+        ClientSocket::new(sources[1].into()),
+        now,
+    );
+    assert_eq!(maybe_forward, None);
+
+    let now = now + Duration::from_secs(1);
+
+    let maybe_forward = workers[1].server.handle_client_input(
+        client_to_peer_ping.as_msg(),
+        ClientSocket::new(sources[1].into()),
+        now,
+    );
+    assert!(maybe_forward.is_some());
+}
+
 #[proptest]
 fn allows_rebind_channel_after_expiry(
     #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
@@ -707,6 +1099,7 @@ fn ping_pong_ip6_relay(
         peer_to_client_ping.as_slice(),
         PeerSocket::new(peer.into()),
         AllocationPort::new(49152),
+        now,
     );
 
     assert_eq!(
@@ -715,15 +1108,181 @@ fn ping_pong_ip6_relay(
     );
 }
 
+#[proptest]
+fn denies_channel_bind_to_loopback_peer_by_default(
+    #[strategy(firezone_relay::proptest::transaction_id())] allocate_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::transaction_id())]
+    channel_bind_transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::allocation_lifetime())] lifetime: Lifetime,
+    #[strategy(firezone_relay::proptest::channel_number())] channel: ChannelNumber,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer_port: u16,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+    let peer = SocketAddrV4::new(Ipv4Addr::LOCALHOST, peer_port);
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_peer_policy(PeerPolicy::default())
+        .with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            Allocate::new_authenticated_udp_implicit_ip4(
+                allocate_transaction_id,
+                Some(lifetime.clone()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            )
+            .unwrap(),
+            now,
+        ),
+        [
+            create_allocation(49152, AddressFamily::V4),
+            send_message(
+                source,
+                allocate_response(
+                    allocate_transaction_id,
+                    public_relay_addr,
+                    49152,
+                    source,
+                    &lifetime,
+                ),
+            ),
+        ],
+    );
+
+    server.assert_commands(
+        from_client(
+            source,
+            ChannelBind::new(
+                channel_bind_transaction_id,
+                channel,
+                XorPeerAddress::new(peer.into()),
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            )
+            .unwrap(),
+            now,
+        ),
+        [send_message(
+            source,
+            forbidden_response(CHANNEL_BIND, channel_bind_transaction_id),
+        )],
+    );
+
+    assert_eq!(server.server.num_active_channels(), 0);
+}
+
+#[proptest]
+fn denies_create_permission_if_any_peer_is_not_allowed(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer_port: u16,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+    let allowed_peer = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 1), peer_port);
+    let denied_peer = SocketAddrV4::new(Ipv4Addr::new(192, 168, 1, 1), peer_port);
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_peer_policy(PeerPolicy::default())
+        .with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                transaction_id,
+                vec![
+                    XorPeerAddress::new(allowed_peer.into()),
+                    XorPeerAddress::new(denied_peer.into()),
+                ],
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            )
+            .unwrap(),
+            now,
+        ),
+        [send_message(
+            source,
+            forbidden_response(CREATE_PERMISSION, transaction_id),
+        )],
+    );
+}
+
+#[proptest]
+fn allows_create_permission_for_public_peer(
+    #[strategy(firezone_relay::proptest::transaction_id())] transaction_id: TransactionId,
+    #[strategy(firezone_relay::proptest::username_salt())] username_salt: String,
+    source: SocketAddrV4,
+    peer_port: u16,
+    public_relay_addr: Ipv4Addr,
+    #[strategy(firezone_relay::proptest::nonce())] nonce: Uuid,
+) {
+    let now = Instant::now();
+    let peer = SocketAddrV4::new(Ipv4Addr::new(203, 0, 113, 1), peer_port);
+
+    let mut server = TestServer::new(public_relay_addr)
+        .with_peer_policy(PeerPolicy::default())
+        .with_nonce(nonce);
+    let secret = server.auth_secret().to_owned();
+
+    server.assert_commands(
+        from_client(
+            source,
+            CreatePermission::new(
+                transaction_id,
+                vec![XorPeerAddress::new(peer.into())],
+                valid_username(&username_salt),
+                &secret,
+                nonce,
+            )
+            .unwrap(),
+            now,
+        ),
+        [send_message(
+            source,
+            create_permission_response(transaction_id),
+        )],
+    );
+}
+
 struct TestServer {
     server: Server<StepRng>,
 }
 
 impl TestServer {
     fn new(relay_public_addr: impl Into<IpStack>) -> Self {
-        Self {
-            server: Server::new(relay_public_addr, StepRng::new(0, 0), 3478, 49152..=65535),
-        }
+        Self::with_server(Server::new(
+            relay_public_addr,
+            StepRng::new(0, 0),
+            3478,
+            49152..=65535,
+        ))
+    }
+
+    /// Most tests use arbitrary peer addresses, so we allow all of them unless a test opts back into the default policy.
+    fn with_server(mut server: Server<StepRng>) -> Self {
+        server.set_peer_policy(PeerPolicy::allow_all());
+
+        Self { server }
+    }
+
+    fn with_peer_policy(mut self, peer_policy: PeerPolicy) -> Self {
+        self.server.set_peer_policy(peer_policy);
+
+        self
     }
 
     fn with_nonce(mut self, nonce: Uuid) -> Self {
@@ -744,6 +1303,12 @@ impl TestServer {
             Input::Time(now) => {
                 self.server.handle_timeout(now);
             }
+            Input::Disconnected(client) => {
+                self.server.handle_client_disconnected(client);
+            }
+            Input::ForceFree(port) => {
+                assert!(self.server.free_allocation(port));
+            }
         }
 
         for expected_output in output {
@@ -892,6 +1457,26 @@ fn channel_bind_response(transaction_id: TransactionId) -> Message<Attribute> {
     message
 }
 
+fn create_permission_response(transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message = Message::<Attribute>::new(
+        MessageClass::SuccessResponse,
+        CREATE_PERMISSION,
+        transaction_id,
+    );
+    message.add_attribute(SOFTWARE.clone());
+
+    message
+}
+
+fn forbidden_response(method: Method, transaction_id: TransactionId) -> Message<Attribute> {
+    let mut message =
+        Message::<Attribute>::new(MessageClass::ErrorResponse, method, transaction_id);
+    message.add_attribute(SOFTWARE.clone());
+    message.add_attribute(ErrorCode::from(Forbidden));
+
+    message
+}
+
 fn parse_message(message: &[u8]) -> Message<Attribute> {
     MessageDecoder::new()
         .decode_from_bytes(message)
@@ -902,6 +1487,8 @@ fn parse_message(message: &[u8]) -> Message<Attribute> {
 enum Input<'a> {
     Client(ClientSocket, ClientMessage<'a>, Instant),
     Time(Instant),
+    Disconnected(ClientSocket),
+    ForceFree(AllocationPort),
 }
 
 fn from_client<'a>(
@@ -916,6 +1503,21 @@ fn forward_time_to<'a>(when: Instant) -> Input<'a> {
     Input::Time(when)
 }
 
+fn client_disconnected<'a>(from: impl Into<SocketAddr>) -> Input<'a> {
+    Input::Disconnected(ClientSocket::new(from.into()))
+}
+
+fn tcp_client_disconnected<'a>(from: impl Into<SocketAddr>) -> Input<'a> {
+    Input::Disconnected(ClientSocket::with_transport(
+        from.into(),
+        ClientTransport::Tcp,
+    ))
+}
+
+fn force_free<'a>(port: u16) -> Input<'a> {
+    Input::ForceFree(AllocationPort::new(port))
+}
+
 #[derive(Debug)]
 enum Output {
     SendMessage((ClientSocket, Message<Attribute>)),
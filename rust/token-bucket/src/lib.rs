@@ -0,0 +1,138 @@
+//! A token bucket for limiting the rate of packets or bytes.
+//!
+//! The bucket refills continuously at its rate, up to its capacity, which is the largest burst it allows.
+
+#![cfg_attr(test, allow(clippy::unwrap_used))]
+
+use std::num::NonZeroU64;
+use std::time::Instant;
+
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: u64,
+    /// The maximum number of tokens, i.e. the largest burst.
+    capacity: u64,
+    tokens: f64,
+    /// Unset until the bucket is used for the first time.
+    last_refill: Option<Instant>,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    ///
+    /// The capacity should be at least the largest amount ever passed to [`TokenBucket::has`], otherwise that amount is never admitted.
+    pub fn new(rate: NonZeroU64, capacity: u64) -> Self {
+        Self {
+            rate: rate.get(),
+            capacity,
+            tokens: capacity as f64,
+            last_refill: None,
+        }
+    }
+
+    /// Changes the rate and capacity of this bucket.
+    ///
+    /// The bucket keeps its tokens, so changing the rate doesn't grant a new burst.
+    pub fn set_rate(&mut self, rate: NonZeroU64, capacity: u64) {
+        self.rate = rate.get();
+        self.capacity = capacity;
+        self.tokens = self.tokens.min(capacity as f64);
+    }
+
+    /// Whether the bucket has at least the given number of tokens.
+    ///
+    /// This does not consume any tokens, see [`TokenBucket::consume`].
+    pub fn has(&mut self, amount: u64, now: Instant) -> bool {
+        self.refill(now);
+
+        self.tokens >= amount as f64
+    }
+
+    pub fn consume(&mut self, amount: u64) {
+        self.tokens = (self.tokens - amount as f64).max(0.0);
+    }
+
+    /// Whether the bucket is full again, i.e. indistinguishable from a new one.
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+
+        self.tokens >= self.capacity as f64
+    }
+
+    fn refill(&mut self, now: Instant) {
+        if let Some(last_refill) = self.last_refill {
+            let elapsed = now.saturating_duration_since(last_refill);
+
+            self.tokens =
+                (self.tokens + elapsed.as_secs_f64() * self.rate as f64).min(self.capacity as f64);
+        }
+
+        self.last_refill = Some(now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn starts_full() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(NonZeroU64::new(10).unwrap(), 15);
+
+        assert!(bucket.is_full(now));
+        assert!(bucket.has(15, now));
+        assert!(!bucket.has(16, now));
+    }
+
+    #[test]
+    fn refills_at_rate_up_to_capacity() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(NonZeroU64::new(10).unwrap(), 15);
+
+        bucket.consume(15);
+        assert!(!bucket.has(1, now));
+
+        let now = now + Duration::from_millis(500);
+        assert!(bucket.has(5, now));
+        assert!(!bucket.has(6, now));
+
+        let now = now + Duration::from_secs(10);
+        assert!(bucket.is_full(now));
+        assert!(!bucket.has(16, now));
+    }
+
+    #[test]
+    fn consuming_more_than_available_empties_bucket() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(NonZeroU64::new(10).unwrap(), 10);
+
+        bucket.consume(20);
+
+        assert!(!bucket.has(1, now));
+        assert!(bucket.has(1, now + Duration::from_millis(100)));
+    }
+
+    #[test]
+    fn changing_rate_keeps_tokens() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(NonZeroU64::new(10).unwrap(), 10);
+
+        bucket.consume(10);
+        bucket.set_rate(NonZeroU64::new(20).unwrap(), 20);
+
+        assert!(!bucket.has(1, now));
+    }
+
+    #[test]
+    fn lowering_capacity_drops_excess_tokens() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(NonZeroU64::new(10).unwrap(), 10);
+
+        bucket.set_rate(NonZeroU64::new(5).unwrap(), 5);
+
+        assert!(bucket.has(5, now));
+        assert!(!bucket.has(6, now));
+    }
+}
@@ -40,6 +40,14 @@ impl Request<SetTunFlagsPayload> {
             },
         }
     }
+
+    /// Prefixes every packet read from and written to the device with a `virtio_net_hdr`.
+    ///
+    /// This is required for offloading segmentation via `TUNSETOFFLOAD`.
+    pub fn with_vnet_hdr(mut self) -> Self {
+        self.payload.flags |= libc::IFF_VNET_HDR as std::ffi::c_short;
+        self
+    }
 }
 
 impl Request<GetInterfaceNamePayload> {
@@ -0,0 +1,175 @@
+//! Framing of STUN and channel-data messages on TCP and TLS streams.
+//!
+//! STUN messages are self-delimiting and channel-data messages are padded to a multiple of 4 bytes on streams, see <https://www.rfc-editor.org/rfc/rfc8656#section-12.5>.
+//! This allows us to send and receive the same messages as via UDP and process them as if they were datagrams.
+
+#![cfg_attr(test, allow(clippy::unwrap_used))]
+
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+
+const STUN_HEADER_LEN: usize = 20;
+const CHANNEL_DATA_HEADER_LEN: usize = 4;
+
+/// Writes a single STUN or channel-data message to the stream, including its padding.
+pub async fn write_message<W>(writer: &mut W, message: &[u8]) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    let padding = message.len().next_multiple_of(4) - message.len();
+
+    writer.write_all(message).await?;
+    writer.write_all(&[0u8; 3][..padding]).await?;
+
+    Ok(())
+}
+
+/// Reads STUN and channel-data messages from a stream.
+pub struct MessageReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+}
+
+impl<R> MessageReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::with_capacity(u16::MAX as usize),
+        }
+    }
+
+    /// Reads the next message from the stream, without its padding.
+    ///
+    /// Returns `None` if the stream ended in between two messages.
+    pub async fn read(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let mut chunk = [0u8; 4096];
+
+        loop {
+            if let Some((message_len, framed_len)) = next_frame(&self.buffer)? {
+                if self.buffer.len() >= framed_len {
+                    let message = self.buffer[..message_len].to_vec();
+                    self.buffer.drain(..framed_len);
+
+                    return Ok(Some(message));
+                }
+            }
+
+            let num_read = self.reader.read(&mut chunk).await?;
+
+            if num_read == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+            }
+
+            self.buffer.extend_from_slice(&chunk[..num_read]);
+        }
+    }
+}
+
+/// Parses the header of the next message in the buffer.
+///
+/// Returns the length of the message and the number of bytes it occupies on the stream, including padding.
+fn next_frame(buffer: &[u8]) -> io::Result<Option<(usize, usize)>> {
+    let Some(header) = buffer.get(..CHANNEL_DATA_HEADER_LEN) else {
+        return Ok(None);
+    };
+
+    let length = u16::from_be_bytes([header[2], header[3]]) as usize;
+
+    // The first two bits of a STUN message are always 0, those of a channel-data message are always 01.
+    let (message_len, framed_len) = match header[0] >> 6 {
+        0b00 => (STUN_HEADER_LEN + length, STUN_HEADER_LEN + length),
+        0b01 => {
+            let message_len = CHANNEL_DATA_HEADER_LEN + length;
+
+            (message_len, message_len.next_multiple_of(4))
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Neither a STUN nor a channel-data message",
+            ))
+        }
+    };
+
+    // Received messages are handed out as if they were UDP datagrams.
+    if message_len > u16::MAX as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Message is too large",
+        ));
+    }
+
+    Ok(Some((message_len, framed_len)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn needs_full_header() {
+        assert_eq!(next_frame(&[0x00, 0x01, 0x00]).unwrap(), None);
+    }
+
+    #[test]
+    fn stun_message_is_not_padded() {
+        assert_eq!(
+            next_frame(&[0x00, 0x01, 0x00, 0x08]).unwrap(),
+            Some((28, 28))
+        );
+    }
+
+    #[test]
+    fn channel_data_message_is_padded() {
+        assert_eq!(
+            next_frame(&[0x40, 0x00, 0x00, 0x05]).unwrap(),
+            Some((9, 12))
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_message() {
+        assert!(next_frame(&[0xC0, 0x00, 0x00, 0x05]).is_err());
+    }
+
+    #[tokio::test]
+    async fn reads_back_written_messages() {
+        let stun = [[0x00, 0x01, 0x00, 0x04].as_slice(), &[0u8; 20]].concat();
+        let channel_data = [0x40, 0x00, 0x00, 0x03, 0xAA, 0xBB, 0xCC];
+
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        let mut reader = MessageReader::new(reader);
+
+        write_message(&mut writer, &channel_data).await.unwrap();
+        write_message(&mut writer, &stun).await.unwrap();
+        drop(writer);
+
+        assert_eq!(reader.read().await.unwrap().unwrap(), channel_data);
+        assert_eq!(reader.read().await.unwrap().unwrap(), stun);
+        assert_eq!(reader.read().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn stream_ending_within_message_is_an_error() {
+        let (mut writer, reader) = tokio::io::duplex(1024);
+        let mut reader = MessageReader::new(reader);
+
+        writer
+            .write_all(&[0x40, 0x00, 0x00, 0x08, 0xAA])
+            .await
+            .unwrap();
+        drop(writer);
+
+        assert_eq!(
+            reader.read().await.unwrap_err().kind(),
+            io::ErrorKind::UnexpectedEof
+        );
+    }
+}